@@ -0,0 +1,145 @@
+use bson::{doc, Bson, Document};
+
+pub mod roles;
+mod scram;
+pub mod users;
+
+pub use scram::{ScramConversation, ScramCredentials, ScramError, ScramMechanism};
+
+/// Commands that can run before the connection is authenticated.
+const UNAUTHENTICATED_COMMANDS: &[&str] = &[
+    "hello",
+    "isMaster",
+    "ismaster",
+    "saslStart",
+    "saslContinue",
+    "logout",
+    "ping",
+    "buildInfo",
+    "buildinfo",
+    "whatsmyuri",
+];
+
+pub fn requires_authentication(command: &str) -> bool {
+    !UNAUTHENTICATED_COMMANDS.contains(&command)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthenticatedUser {
+    pub user: String,
+    pub db: String,
+}
+
+impl AuthenticatedUser {
+    pub fn to_doc(&self) -> Document {
+        doc! { "user": &self.user, "db": &self.db }
+    }
+}
+
+/// A SASL conversation in progress on a connection.
+#[derive(Debug, Clone)]
+pub struct SaslSession {
+    pub db: String,
+    pub conversation: ScramConversation,
+    pub skip_empty_exchange: bool,
+}
+
+/// Authentication state of a client connection.
+#[derive(Debug, Default)]
+pub struct AuthState {
+    enabled: bool,
+    users: Vec<AuthenticatedUser>,
+    session: Option<SaslSession>,
+}
+
+impl AuthState {
+    pub fn new(enabled: bool) -> Self {
+        AuthState {
+            enabled,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        !self.users.is_empty()
+    }
+
+    pub fn authenticated_users(&self) -> &Vec<AuthenticatedUser> {
+        &self.users
+    }
+
+    pub fn authenticate(&mut self, user: &str, db: &str) {
+        // a connection holds one user per database, like mongod
+        self.users.retain(|u| u.db != db);
+        self.users.push(AuthenticatedUser {
+            user: user.to_string(),
+            db: db.to_string(),
+        });
+    }
+
+    pub fn logout(&mut self, db: &str) {
+        self.users.retain(|u| u.db != db);
+    }
+
+    /// Keeps the SASL conversation in progress. There's only one per
+    /// connection, so its id is always 1.
+    pub fn start_session(&mut self, session: SaslSession) -> i32 {
+        self.session = Some(session);
+        1
+    }
+
+    pub fn take_session(&mut self) -> Option<SaslSession> {
+        self.session.take()
+    }
+
+    /// Whether a cursor opened by `users` can be used on this connection,
+    /// which takes one of them being authenticated on it, like in mongod.
+    pub fn is_coauthorized_with(&self, users: &[AuthenticatedUser]) -> bool {
+        if users.is_empty() && self.users.is_empty() {
+            return true;
+        }
+        users.iter().any(|user| self.users.contains(user))
+    }
+
+    pub fn authenticated_users_bson(&self) -> Bson {
+        Bson::Array(self.users.iter().map(|u| u.to_doc().into()).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authenticate_and_logout() {
+        let mut state = AuthState::new(true);
+        assert!(!state.is_authenticated());
+
+        state.authenticate("ana", "admin");
+        state.authenticate("bruno", "test");
+        state.authenticate("carla", "test");
+        assert_eq!(
+            state.authenticated_users_bson(),
+            Bson::Array(vec![
+                doc! { "user": "ana", "db": "admin" }.into(),
+                doc! { "user": "carla", "db": "test" }.into(),
+            ])
+        );
+
+        state.logout("admin");
+        state.logout("test");
+        assert!(!state.is_authenticated());
+    }
+
+    #[test]
+    fn test_requires_authentication() {
+        assert!(!requires_authentication("saslStart"));
+        assert!(!requires_authentication("hello"));
+        assert!(requires_authentication("find"));
+        assert!(requires_authentication("createUser"));
+    }
+}
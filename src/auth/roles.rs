@@ -0,0 +1,651 @@
+use super::users::parse_roles;
+use bson::{doc, Bson, Document};
+use eyre::Result;
+use std::collections::HashSet;
+
+// Role documents, stored with the same layout as mongod's `admin.system.roles`:
+//
+// { _id: "db.role", role, db,
+//   privileges: [{ resource: { db, collection } | { cluster: true }, actions: [...] }],
+//   roles: [{ role, db }] }
+//
+// Built-in roles aren't stored, their privileges are defined here.
+
+const READ_ACTIONS: &[&str] = &[
+    "changeStream",
+    "collStats",
+    "dbStats",
+    "find",
+    "killCursors",
+    "listCollections",
+    "listIndexes",
+];
+
+const WRITE_ACTIONS: &[&str] = &[
+    "createCollection",
+    "createIndex",
+    "dropCollection",
+    "dropIndex",
+    "insert",
+    "remove",
+    "update",
+];
+
+const DB_ADMIN_ACTIONS: &[&str] = &[
+    "collStats",
+    "createCollection",
+    "createIndex",
+    "dbStats",
+    "dropCollection",
+    "dropDatabase",
+    "dropIndex",
+    "listCollections",
+    "listIndexes",
+];
+
+const USER_ADMIN_ACTIONS: &[&str] = &[
+    "changeCustomData",
+    "changePassword",
+    "createRole",
+    "createUser",
+    "dropRole",
+    "dropUser",
+    "grantRole",
+    "revokeRole",
+    "viewRole",
+    "viewUser",
+];
+
+const CLUSTER_ACTIONS: &[&str] = &["getCmdLineOpts", "getParameter", "listDatabases"];
+
+const ANY_ACTION: &str = "anyAction";
+
+const BUILTIN_ROLES: &[&str] = &["read", "readWrite", "dbAdmin", "userAdmin", "dbOwner"];
+
+/// Built-in roles that only exist on the admin database.
+const ADMIN_BUILTIN_ROLES: &[&str] = &[
+    "readAnyDatabase",
+    "readWriteAnyDatabase",
+    "dbAdminAnyDatabase",
+    "userAdminAnyDatabase",
+    "root",
+];
+
+#[derive(Debug, Clone)]
+pub struct InvalidRoleError {
+    pub message: String,
+}
+
+impl InvalidRoleError {
+    fn new(message: String) -> Self {
+        InvalidRoleError { message }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RoleName {
+    pub role: String,
+    pub db: String,
+}
+
+impl RoleName {
+    pub fn new(role: &str, db: &str) -> Self {
+        RoleName {
+            role: role.to_string(),
+            db: db.to_string(),
+        }
+    }
+
+    /// Reads a `{ role, db }` document as normalized by `parse_roles`.
+    pub fn from_bson(role: &Bson) -> Option<Self> {
+        let role = role.as_document()?;
+        Some(RoleName::new(
+            role.get_str("role").ok()?,
+            role.get_str("db").ok()?,
+        ))
+    }
+
+    pub fn to_doc(&self) -> Document {
+        doc! { "role": &self.role, "db": &self.db }
+    }
+
+    pub fn is_builtin(&self) -> bool {
+        BUILTIN_ROLES.contains(&self.role.as_str())
+            || (self.db == "admin" && ADMIN_BUILTIN_ROLES.contains(&self.role.as_str()))
+    }
+}
+
+impl std::fmt::Display for RoleName {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}@{}", self.role, self.db)
+    }
+}
+
+pub fn role_id(db: &str, role: &str) -> String {
+    format!("{}.{}", db, role)
+}
+
+/// The roles of a user or role document.
+pub fn role_names(doc: &Document) -> Vec<RoleName> {
+    match doc.get_array("roles") {
+        Ok(roles) => roles.iter().filter_map(RoleName::from_bson).collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// What a privilege applies to. An empty database or collection name matches
+/// any of them, so `{ db: "test", collection: "" }` is the whole database.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resource {
+    AnyResource,
+    Cluster,
+    Namespace { db: String, collection: String },
+}
+
+impl Resource {
+    pub fn namespace(db: &str, collection: &str) -> Self {
+        Resource::Namespace {
+            db: db.to_string(),
+            collection: collection.to_string(),
+        }
+    }
+
+    pub fn database(db: &str) -> Self {
+        Resource::namespace(db, "")
+    }
+
+    fn from_doc(doc: &Document) -> Result<Self, InvalidRoleError> {
+        if doc.get_bool("anyResource").unwrap_or(false) {
+            return Ok(Resource::AnyResource);
+        }
+        if doc.get_bool("cluster").unwrap_or(false) {
+            return Ok(Resource::Cluster);
+        }
+        match (doc.get_str("db"), doc.get_str("collection")) {
+            (Ok(db), Ok(collection)) => Ok(Resource::namespace(db, collection)),
+            _ => Err(InvalidRoleError::new(format!(
+                "resource must have \"db\" and \"collection\" fields or be the cluster: {}",
+                doc
+            ))),
+        }
+    }
+
+    fn to_doc(&self) -> Document {
+        match self {
+            Resource::AnyResource => doc! { "anyResource": true },
+            Resource::Cluster => doc! { "cluster": true },
+            Resource::Namespace { db, collection } => doc! { "db": db, "collection": collection },
+        }
+    }
+
+    fn covers(&self, other: &Resource) -> bool {
+        match (self, other) {
+            (Resource::AnyResource, _) => true,
+            (Resource::Cluster, Resource::Cluster) => true,
+            (
+                Resource::Namespace { db, collection },
+                Resource::Namespace {
+                    db: other_db,
+                    collection: other_collection,
+                },
+            ) => {
+                (db.is_empty() || db == other_db)
+                    && (collection.is_empty() || collection == other_collection)
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Privilege {
+    pub resource: Resource,
+    pub actions: Vec<String>,
+}
+
+impl Privilege {
+    pub fn new(resource: Resource, actions: &[&str]) -> Self {
+        Privilege {
+            resource,
+            actions: actions.iter().map(|a| a.to_string()).collect(),
+        }
+    }
+
+    fn from_doc(doc: &Document) -> Result<Self, InvalidRoleError> {
+        let resource = match doc.get_document("resource") {
+            Ok(resource) => Resource::from_doc(resource)?,
+            Err(_) => {
+                return Err(InvalidRoleError::new(
+                    "privilege must have a \"resource\" document".to_string(),
+                ))
+            }
+        };
+        let actions = match doc.get_array("actions") {
+            Ok(actions) if !actions.is_empty() => actions,
+            _ => {
+                return Err(InvalidRoleError::new(
+                    "privilege must have a non-empty \"actions\" array".to_string(),
+                ))
+            }
+        };
+        let actions = actions
+            .iter()
+            .map(|action| match action.as_str() {
+                Some(action) if is_action(action) => Ok(action.to_string()),
+                Some(action) => Err(InvalidRoleError::new(format!(
+                    "Unrecognized action privilege string: {}",
+                    action
+                ))),
+                None => Err(InvalidRoleError::new(format!(
+                    "Actions must be strings: {}",
+                    action
+                ))),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Privilege { resource, actions })
+    }
+
+    pub fn to_doc(&self) -> Document {
+        doc! { "resource": self.resource.to_doc(), "actions": &self.actions }
+    }
+
+    fn allows(&self, resource: &Resource, action: &str) -> bool {
+        self.resource.covers(resource)
+            && self.actions.iter().any(|a| a == action || a == ANY_ACTION)
+    }
+}
+
+fn is_action(action: &str) -> bool {
+    action == ANY_ACTION
+        || [
+            READ_ACTIONS,
+            WRITE_ACTIONS,
+            DB_ADMIN_ACTIONS,
+            USER_ADMIN_ACTIONS,
+            CLUSTER_ACTIONS,
+        ]
+        .iter()
+        .any(|actions| actions.contains(&action))
+}
+
+/// Validates the privileges of a role defined on `db`. Only roles on the
+/// admin database can hold privileges on other databases or the cluster.
+pub fn parse_privileges(privileges: &Bson, db: &str) -> Result<Vec<Bson>, InvalidRoleError> {
+    let privileges = match privileges {
+        Bson::Array(privileges) => privileges,
+        _ => {
+            return Err(InvalidRoleError::new(
+                "\"privileges\" field must be an array".to_string(),
+            ))
+        }
+    };
+
+    privileges
+        .iter()
+        .map(|privilege| {
+            let privilege = match privilege {
+                Bson::Document(privilege) => Privilege::from_doc(privilege)?,
+                _ => {
+                    return Err(InvalidRoleError::new(format!(
+                        "Privilege must be an object: {}",
+                        privilege
+                    )))
+                }
+            };
+            let on_own_db = matches!(&privilege.resource, Resource::Namespace { db: target, .. } if target == db);
+            if db != "admin" && !on_own_db {
+                return Err(InvalidRoleError::new(format!(
+                    "Roles on the '{}' database cannot be granted privileges that target other databases or the cluster",
+                    db
+                )));
+            }
+            Ok(privilege.to_doc().into())
+        })
+        .collect()
+}
+
+fn builtin_privileges(role: &RoleName) -> Vec<Privilege> {
+    let db = Resource::database(&role.db);
+    let any_db = Resource::database("");
+    let list_databases = Privilege::new(Resource::Cluster, &["listDatabases"]);
+
+    match (role.role.as_str(), role.db.as_str()) {
+        ("read", _) => vec![Privilege::new(db, READ_ACTIONS)],
+        ("readWrite", _) => vec![
+            Privilege::new(db.clone(), READ_ACTIONS),
+            Privilege::new(db, WRITE_ACTIONS),
+        ],
+        ("dbAdmin", _) => vec![Privilege::new(db, DB_ADMIN_ACTIONS)],
+        ("userAdmin", _) => vec![Privilege::new(db, USER_ADMIN_ACTIONS)],
+        ("dbOwner", _) => vec![
+            Privilege::new(db.clone(), READ_ACTIONS),
+            Privilege::new(db.clone(), WRITE_ACTIONS),
+            Privilege::new(db.clone(), DB_ADMIN_ACTIONS),
+            Privilege::new(db, USER_ADMIN_ACTIONS),
+        ],
+        ("readAnyDatabase", "admin") => {
+            vec![Privilege::new(any_db, READ_ACTIONS), list_databases]
+        }
+        ("readWriteAnyDatabase", "admin") => vec![
+            Privilege::new(any_db.clone(), READ_ACTIONS),
+            Privilege::new(any_db, WRITE_ACTIONS),
+            list_databases,
+        ],
+        ("dbAdminAnyDatabase", "admin") => {
+            vec![Privilege::new(any_db, DB_ADMIN_ACTIONS), list_databases]
+        }
+        ("userAdminAnyDatabase", "admin") => vec![Privilege::new(any_db, USER_ADMIN_ACTIONS)],
+        ("root", "admin") => vec![Privilege::new(Resource::AnyResource, &[ANY_ACTION])],
+        _ => vec![],
+    }
+}
+
+/// Collects the privileges granted by `roles` and every role they inherit
+/// from. `find_role` looks up custom roles, missing ones grant nothing.
+pub fn resolve_privileges<F>(roles: Vec<RoleName>, mut find_role: F) -> Result<Vec<Privilege>>
+where
+    F: FnMut(&RoleName) -> Result<Option<Document>>,
+{
+    let mut privileges = vec![];
+    let mut seen = HashSet::new();
+    let mut pending = roles;
+
+    while let Some(role) = pending.pop() {
+        if !seen.insert(role.clone()) {
+            continue;
+        }
+        if role.is_builtin() {
+            privileges.extend(builtin_privileges(&role));
+            continue;
+        }
+        if let Some(doc) = find_role(&role)? {
+            if let Ok(granted) = doc.get_array("privileges") {
+                for privilege in granted {
+                    if let Some(Ok(privilege)) = privilege.as_document().map(Privilege::from_doc) {
+                        privileges.push(privilege);
+                    }
+                }
+            }
+            pending.extend(role_names(&doc));
+        }
+    }
+
+    Ok(privileges)
+}
+
+/// The actions a command performs, with the resource each one acts on.
+pub fn required_privileges(command: &str, doc: &Document) -> Vec<(Resource, &'static str)> {
+    let db = doc.get_str("$db").unwrap_or_default();
+    let collection = |field: &str| match doc.get_str(field) {
+        Ok(collection) => Resource::namespace(db, collection),
+        Err(_) => Resource::database(db),
+    };
+    let granted_roles = || {
+        let roles = parse_roles(doc.get("roles").unwrap_or(&Bson::Array(vec![])), db);
+        roles
+            .unwrap_or_default()
+            .iter()
+            .filter_map(RoleName::from_bson)
+            .map(|role| (Resource::database(&role.db), "grantRole"))
+            .collect::<Vec<_>>()
+    };
+    let upserts = || match doc.get_array("updates") {
+        Ok(updates) => updates.iter().any(|u| {
+            u.as_document()
+                .and_then(|u| u.get_bool("upsert").ok())
+                .unwrap_or(false)
+        }),
+        Err(_) => false,
+    };
+    let change_stream = || {
+        doc.get_array("pipeline")
+            .ok()
+            .and_then(|pipeline| pipeline.first())
+            .and_then(|stage| stage.as_document())
+            .and_then(|stage| stage.get_document("$changeStream").ok())
+    };
+
+    match command {
+        "aggregate" if change_stream().is_some() => {
+            let all_changes = change_stream()
+                .and_then(|stage| stage.get_bool("allChangesForCluster").ok())
+                .unwrap_or(false);
+            // `aggregate: 1` watches the whole database
+            let resource = if all_changes {
+                Resource::AnyResource
+            } else {
+                collection(command)
+            };
+            vec![(resource.clone(), "changeStream"), (resource, "find")]
+        }
+        "find" | "count" | "aggregate" => vec![(collection(command), "find")],
+        "getMore" => vec![(collection("collection"), "find")],
+        "insert" => vec![(collection(command), "insert")],
+        "update" if upserts() => vec![
+            (collection(command), "update"),
+            (collection(command), "insert"),
+        ],
+        "update" => vec![(collection(command), "update")],
+        "delete" => vec![(collection(command), "remove")],
+        "findAndModify" => {
+            let mut required = vec![(collection(command), "find")];
+            if doc.get_bool("remove").unwrap_or(false) {
+                required.push((collection(command), "remove"));
+            } else {
+                required.push((collection(command), "update"));
+                if doc.get_bool("upsert").unwrap_or(false) {
+                    required.push((collection(command), "insert"));
+                }
+            }
+            required
+        }
+        "killCursors" => vec![(collection(command), "killCursors")],
+        "create" => vec![(collection(command), "createCollection")],
+        "drop" => vec![(collection(command), "dropCollection")],
+        "createIndexes" => vec![(collection(command), "createIndex")],
+        "listIndexes" => vec![(collection(command), "listIndexes")],
+        "collStats" => vec![(collection(command), "collStats")],
+        "dropDatabase" => vec![(Resource::database(db), "dropDatabase")],
+        "listCollections" => vec![(Resource::database(db), "listCollections")],
+        "dbStats" => vec![(Resource::database(db), "dbStats")],
+        "listDatabases" => vec![(Resource::Cluster, "listDatabases")],
+        "getCmdLineOpts" => vec![(Resource::Cluster, "getCmdLineOpts")],
+        "getParameter" => vec![(Resource::Cluster, "getParameter")],
+        "createUser" => {
+            let mut required = vec![(Resource::database(db), "createUser")];
+            required.extend(granted_roles());
+            required
+        }
+        "updateUser" => {
+            let mut required = vec![];
+            if doc.contains_key("pwd") {
+                required.push((Resource::database(db), "changePassword"));
+            }
+            if doc.contains_key("customData") {
+                required.push((Resource::database(db), "changeCustomData"));
+            }
+            if doc.contains_key("roles") {
+                // replacing the roles may revoke any of the current ones
+                required.push((Resource::database(db), "revokeRole"));
+                required.extend(granted_roles());
+            }
+            required
+        }
+        "dropUser" => vec![(Resource::database(db), "dropUser")],
+        "usersInfo" => vec![(Resource::database(db), "viewUser")],
+        "createRole" => {
+            let mut required = vec![(Resource::database(db), "createRole")];
+            required.extend(granted_roles());
+            required
+        }
+        "dropRole" => vec![(Resource::database(db), "dropRole")],
+        "grantRolesToUser" => granted_roles(),
+        "revokeRolesFromUser" => granted_roles()
+            .into_iter()
+            .map(|(resource, _)| (resource, "revokeRole"))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+pub fn is_authorized(privileges: &[Privilege], required: &[(Resource, &str)]) -> bool {
+    required.iter().all(|(resource, action)| {
+        privileges
+            .iter()
+            .any(|privilege| privilege.allows(resource, action))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn privileges_of(
+        roles: &[(&str, &str)],
+        custom: &HashMap<RoleName, Document>,
+    ) -> Vec<Privilege> {
+        let roles = roles.iter().map(|(r, db)| RoleName::new(r, db)).collect();
+        resolve_privileges(roles, |role| Ok(custom.get(role).cloned())).unwrap()
+    }
+
+    #[test]
+    fn test_builtin_roles() {
+        let custom = HashMap::new();
+        let find = required_privileges("find", &doc! { "find": "col", "$db": "test" });
+        let insert = required_privileges("insert", &doc! { "insert": "col", "$db": "test" });
+        let list_databases = required_privileges(
+            "listDatabases",
+            &doc! { "listDatabases": 1, "$db": "admin" },
+        );
+
+        let read = privileges_of(&[("read", "test")], &custom);
+        assert!(is_authorized(&read, &find));
+        assert!(!is_authorized(&read, &insert));
+        assert!(!is_authorized(&read, &list_databases));
+
+        let other = privileges_of(&[("readWrite", "other")], &custom);
+        assert!(!is_authorized(&other, &find));
+
+        let db_admin = privileges_of(&[("dbAdmin", "test")], &custom);
+        assert!(!is_authorized(&db_admin, &find));
+
+        // root only exists on admin
+        assert!(privileges_of(&[("root", "test")], &custom).is_empty());
+        let root = privileges_of(&[("root", "admin")], &custom);
+        assert!(is_authorized(&root, &insert));
+        assert!(is_authorized(&root, &list_databases));
+    }
+
+    #[test]
+    fn test_change_stream_privileges() {
+        let custom = HashMap::new();
+        let stream = |aggregate: Bson, db: &str, stage: Document| {
+            required_privileges(
+                "aggregate",
+                &doc! { "aggregate": aggregate, "pipeline": [{ "$changeStream": stage }], "$db": db },
+            )
+        };
+
+        let on_collection = stream("col".into(), "test", doc! {});
+        assert_eq!(
+            on_collection,
+            vec![
+                (Resource::namespace("test", "col"), "changeStream"),
+                (Resource::namespace("test", "col"), "find"),
+            ]
+        );
+        let on_database = stream(1.into(), "test", doc! {});
+        assert_eq!(
+            on_database,
+            vec![
+                (Resource::database("test"), "changeStream"),
+                (Resource::database("test"), "find"),
+            ]
+        );
+        let on_cluster = stream(1.into(), "admin", doc! { "allChangesForCluster": true });
+        assert_eq!(
+            on_cluster,
+            vec![
+                (Resource::AnyResource, "changeStream"),
+                (Resource::AnyResource, "find"),
+            ]
+        );
+
+        let read = privileges_of(&[("read", "test")], &custom);
+        assert!(is_authorized(&read, &on_collection));
+        assert!(is_authorized(&read, &on_database));
+        let read_admin = privileges_of(&[("read", "admin")], &custom);
+        assert!(!is_authorized(&read_admin, &on_cluster));
+        let root = privileges_of(&[("root", "admin")], &custom);
+        assert!(is_authorized(&root, &on_cluster));
+
+        let mut custom = HashMap::new();
+        custom.insert(
+            RoleName::new("finder", "test"),
+            doc! {
+                "privileges": [{ "resource": { "db": "test", "collection": "" }, "actions": ["find"] }],
+            },
+        );
+        let finder = privileges_of(&[("finder", "test")], &custom);
+        assert!(!is_authorized(&finder, &on_collection));
+        assert!(!is_authorized(&finder, &on_database));
+    }
+
+    #[test]
+    fn test_custom_roles() {
+        let mut custom = HashMap::new();
+        custom.insert(
+            RoleName::new("reader", "test"),
+            doc! {
+                "privileges": [{ "resource": { "db": "test", "collection": "a" }, "actions": ["find"] }],
+                "roles": [{ "role": "inserter", "db": "test" }],
+            },
+        );
+        custom.insert(
+            RoleName::new("inserter", "test"),
+            doc! {
+                "privileges": [{ "resource": { "db": "test", "collection": "" }, "actions": ["insert"] }],
+                // cycles are ignored
+                "roles": [{ "role": "reader", "db": "test" }],
+            },
+        );
+
+        let privileges = privileges_of(&[("reader", "test")], &custom);
+        assert!(is_authorized(
+            &privileges,
+            &[(Resource::namespace("test", "a"), "find")]
+        ));
+        assert!(!is_authorized(
+            &privileges,
+            &[(Resource::namespace("test", "b"), "find")]
+        ));
+        assert!(is_authorized(
+            &privileges,
+            &[(Resource::namespace("test", "b"), "insert")]
+        ));
+        assert!(!is_authorized(
+            &privileges,
+            &[(Resource::database("test"), "listCollections")]
+        ));
+    }
+
+    #[test]
+    fn test_parse_privileges() {
+        let privileges =
+            bson::bson!([{ "resource": { "db": "test", "collection": "" }, "actions": ["find"] }]);
+        assert!(parse_privileges(&privileges, "test").is_ok());
+        assert!(parse_privileges(&privileges, "other").is_err());
+        assert!(parse_privileges(&privileges, "admin").is_ok());
+
+        let privileges =
+            bson::bson!([{ "resource": { "cluster": true }, "actions": ["listDatabases"] }]);
+        assert!(parse_privileges(&privileges, "test").is_err());
+        assert!(parse_privileges(&privileges, "admin").is_ok());
+
+        let privileges =
+            bson::bson!([{ "resource": { "db": "test", "collection": "" }, "actions": ["fly"] }]);
+        let err = parse_privileges(&privileges, "test").unwrap_err();
+        assert_eq!(err.message, "Unrecognized action privilege string: fly");
+    }
+}
@@ -0,0 +1,445 @@
+use bson::{doc, Document};
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use rand::Rng;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+// Server side of the SCRAM conversation (RFC 5802 and RFC 7677), as used by
+// MongoDB drivers for the SCRAM-SHA-1 and SCRAM-SHA-256 mechanisms.
+
+const SHA1_ITERATIONS: u32 = 10000;
+const SHA256_ITERATIONS: u32 = 15000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScramMechanism {
+    Sha1,
+    Sha256,
+}
+
+impl ScramMechanism {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "SCRAM-SHA-1" => Some(ScramMechanism::Sha1),
+            "SCRAM-SHA-256" => Some(ScramMechanism::Sha256),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScramMechanism::Sha1 => "SCRAM-SHA-1",
+            ScramMechanism::Sha256 => "SCRAM-SHA-256",
+        }
+    }
+
+    fn hash(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            ScramMechanism::Sha1 => Sha1::digest(data).to_vec(),
+            ScramMechanism::Sha256 => Sha256::digest(data).to_vec(),
+        }
+    }
+
+    fn hmac(&self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        match self {
+            ScramMechanism::Sha1 => {
+                let mut mac = Hmac::<Sha1>::new_from_slice(key).unwrap();
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+            ScramMechanism::Sha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    fn salted_password(&self, password: &[u8], salt: &[u8], iterations: u32) -> Vec<u8> {
+        match self {
+            ScramMechanism::Sha1 => {
+                let mut out = [0u8; 20];
+                pbkdf2::pbkdf2::<Hmac<Sha1>>(password, salt, iterations, &mut out);
+                out.to_vec()
+            }
+            ScramMechanism::Sha256 => {
+                let mut out = [0u8; 32];
+                pbkdf2::pbkdf2::<Hmac<Sha256>>(password, salt, iterations, &mut out);
+                out.to_vec()
+            }
+        }
+    }
+
+    /// The password as it's fed to PBKDF2. SCRAM-SHA-1 uses the legacy MongoDB
+    /// digest while SCRAM-SHA-256 uses the SASLprep'd password.
+    fn prepare_password(&self, user: &str, password: &str) -> Result<String, ScramError> {
+        match self {
+            ScramMechanism::Sha1 => Ok(format!(
+                "{:x}",
+                Md5::digest(format!("{}:mongo:{}", user, password).as_bytes())
+            )),
+            ScramMechanism::Sha256 => match stringprep::saslprep(password) {
+                Ok(password) => Ok(password.to_string()),
+                Err(_) => Err(ScramError::new("Error preparing password with SASLprep")),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ScramError {
+    pub message: String,
+}
+
+impl ScramError {
+    pub fn new(message: &str) -> Self {
+        ScramError {
+            message: message.to_string(),
+        }
+    }
+}
+
+impl std::error::Error for ScramError {}
+
+impl std::fmt::Display for ScramError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// What the server keeps about a password for a given mechanism. The
+/// password itself can't be recovered from it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScramCredentials {
+    pub iteration_count: u32,
+    pub salt: Vec<u8>,
+    pub stored_key: Vec<u8>,
+    pub server_key: Vec<u8>,
+}
+
+impl ScramCredentials {
+    pub fn generate(
+        mechanism: ScramMechanism,
+        user: &str,
+        password: &str,
+    ) -> Result<Self, ScramError> {
+        let (iteration_count, salt_len) = match mechanism {
+            ScramMechanism::Sha1 => (SHA1_ITERATIONS, 16),
+            ScramMechanism::Sha256 => (SHA256_ITERATIONS, 28),
+        };
+        let mut salt = vec![0u8; salt_len];
+        rand::thread_rng().fill(&mut salt[..]);
+
+        let password = mechanism.prepare_password(user, password)?;
+        let salted = mechanism.salted_password(password.as_bytes(), &salt, iteration_count);
+        let client_key = mechanism.hmac(&salted, b"Client Key");
+
+        Ok(ScramCredentials {
+            iteration_count,
+            salt,
+            stored_key: mechanism.hash(&client_key),
+            server_key: mechanism.hmac(&salted, b"Server Key"),
+        })
+    }
+
+    pub fn to_doc(&self) -> Document {
+        doc! {
+            "iterationCount": self.iteration_count as i32,
+            "salt": base64::encode(&self.salt),
+            "storedKey": base64::encode(&self.stored_key),
+            "serverKey": base64::encode(&self.server_key),
+        }
+    }
+
+    pub fn from_doc(doc: &Document) -> Option<Self> {
+        Some(ScramCredentials {
+            iteration_count: doc.get_i32("iterationCount").ok()? as u32,
+            salt: base64::decode(doc.get_str("salt").ok()?).ok()?,
+            stored_key: base64::decode(doc.get_str("storedKey").ok()?).ok()?,
+            server_key: base64::decode(doc.get_str("serverKey").ok()?).ok()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Step {
+    ClientFinal {
+        client_first_bare: String,
+        server_first: String,
+        nonce: String,
+        gs2_header: String,
+    },
+    Done,
+}
+
+#[derive(Debug, Clone)]
+pub struct ScramConversation {
+    pub mechanism: ScramMechanism,
+    pub user: String,
+    credentials: ScramCredentials,
+    step: Step,
+}
+
+impl ScramConversation {
+    /// Reads the `client-first-message` and returns the user name it asks to
+    /// authenticate as, so its credentials can be looked up.
+    pub fn parse_user(payload: &[u8]) -> Result<String, ScramError> {
+        let (_, client_first_bare) = split_client_first(payload)?;
+        let attrs = parse_attributes(&client_first_bare)?;
+        match attrs.iter().find(|(k, _)| *k == 'n') {
+            Some((_, user)) => Ok(decode_username(user)),
+            None => Err(ScramError::new("missing user name in client first message")),
+        }
+    }
+
+    /// Starts a conversation from the `client-first-message`, returning the
+    /// `server-first-message` to be sent back.
+    pub fn start(
+        mechanism: ScramMechanism,
+        payload: &[u8],
+        credentials: ScramCredentials,
+    ) -> Result<(Self, Vec<u8>), ScramError> {
+        let (gs2_header, client_first_bare) = split_client_first(payload)?;
+        let attrs = parse_attributes(&client_first_bare)?;
+        let user = match attrs.iter().find(|(k, _)| *k == 'n') {
+            Some((_, user)) => decode_username(user),
+            None => return Err(ScramError::new("missing user name in client first message")),
+        };
+        let client_nonce = match attrs.iter().find(|(k, _)| *k == 'r') {
+            Some((_, nonce)) => nonce.to_string(),
+            None => return Err(ScramError::new("missing nonce in client first message")),
+        };
+
+        let mut server_nonce = [0u8; 24];
+        rand::thread_rng().fill(&mut server_nonce[..]);
+        let nonce = format!("{}{}", client_nonce, base64::encode(server_nonce));
+        let server_first = format!(
+            "r={},s={},i={}",
+            nonce,
+            base64::encode(&credentials.salt),
+            credentials.iteration_count
+        );
+
+        let conversation = ScramConversation {
+            mechanism,
+            user,
+            credentials,
+            step: Step::ClientFinal {
+                client_first_bare,
+                server_first: server_first.clone(),
+                nonce,
+                gs2_header,
+            },
+        };
+        Ok((conversation, server_first.into_bytes()))
+    }
+
+    /// Verifies the client proof on the `client-final-message` and returns
+    /// the `server-final-message` with the server signature.
+    pub fn finish(&mut self, payload: &[u8]) -> Result<Vec<u8>, ScramError> {
+        let (client_first_bare, server_first, nonce, gs2_header) = match &self.step {
+            Step::ClientFinal {
+                client_first_bare,
+                server_first,
+                nonce,
+                gs2_header,
+            } => (client_first_bare, server_first, nonce, gs2_header),
+            Step::Done => return Err(ScramError::new("conversation already finished")),
+        };
+
+        let client_final = std::str::from_utf8(payload)
+            .map_err(|_| ScramError::new("client final message is not valid UTF-8"))?;
+        let proof_start = match client_final.rfind(",p=") {
+            Some(pos) => pos,
+            None => return Err(ScramError::new("missing proof in client final message")),
+        };
+        let without_proof = &client_final[..proof_start];
+        let attrs = parse_attributes(client_final)?;
+        let get = |key: char| {
+            attrs
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| v.to_string())
+        };
+
+        if get('c') != Some(base64::encode(gs2_header)) {
+            return Err(ScramError::new("channel binding mismatch"));
+        }
+        if get('r').as_ref() != Some(nonce) {
+            return Err(ScramError::new("nonce mismatch"));
+        }
+        let proof = get('p')
+            .and_then(|p| base64::decode(p).ok())
+            .ok_or_else(|| ScramError::new("invalid proof in client final message"))?;
+
+        let auth_message = format!("{},{},{}", client_first_bare, server_first, without_proof);
+        let client_signature = self
+            .mechanism
+            .hmac(&self.credentials.stored_key, auth_message.as_bytes());
+        if proof.len() != client_signature.len() {
+            return Err(ScramError::new("Authentication failed."));
+        }
+        let client_key: Vec<u8> = proof
+            .iter()
+            .zip(client_signature.iter())
+            .map(|(a, b)| a ^ b)
+            .collect();
+        // compared in constant time, so the time it takes doesn't tell how
+        // much of the stored key a forged proof got right
+        let stored_key = self.mechanism.hash(&client_key);
+        if !bool::from(stored_key.ct_eq(&self.credentials.stored_key)) {
+            return Err(ScramError::new("Authentication failed."));
+        }
+
+        let server_signature = self
+            .mechanism
+            .hmac(&self.credentials.server_key, auth_message.as_bytes());
+        self.step = Step::Done;
+        Ok(format!("v={}", base64::encode(server_signature)).into_bytes())
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(self.step, Step::Done)
+    }
+}
+
+fn split_client_first(payload: &[u8]) -> Result<(String, String), ScramError> {
+    let message = std::str::from_utf8(payload)
+        .map_err(|_| ScramError::new("client first message is not valid UTF-8"))?;
+
+    // gs2-header is "n,," or "y,," followed by the bare message, channel
+    // binding and authzid aren't supported
+    if !message.starts_with("n,") && !message.starts_with("y,") {
+        return Err(ScramError::new("channel binding is not supported"));
+    }
+    let mut parts = message.splitn(3, ',');
+    let cbind = parts.next().unwrap_or_default();
+    let authzid = parts.next().unwrap_or_default();
+    let bare = match parts.next() {
+        Some(bare) => bare,
+        None => return Err(ScramError::new("invalid client first message")),
+    };
+    if !authzid.is_empty() {
+        return Err(ScramError::new("authorization identity is not supported"));
+    }
+    Ok((format!("{},{},", cbind, authzid), bare.to_string()))
+}
+
+fn parse_attributes(message: &str) -> Result<Vec<(char, &str)>, ScramError> {
+    message
+        .split(',')
+        .map(|attr| {
+            let mut chars = attr.chars();
+            match (chars.next(), chars.next()) {
+                (Some(key), Some('=')) => Ok((key, &attr[2..])),
+                _ => Err(ScramError::new(&format!(
+                    "invalid SCRAM attribute: {}",
+                    attr
+                ))),
+            }
+        })
+        .collect()
+}
+
+fn decode_username(user: &str) -> String {
+    user.replace("=2C", ",").replace("=3D", "=")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // runs the client side of the conversation, as a driver would
+    fn client_proof(
+        mechanism: ScramMechanism,
+        user: &str,
+        password: &str,
+        client_first_bare: &str,
+        server_first: &str,
+    ) -> (String, Vec<u8>) {
+        let attrs = parse_attributes(server_first).unwrap();
+        let nonce = attrs.iter().find(|(k, _)| *k == 'r').unwrap().1;
+        let salt = base64::decode(attrs.iter().find(|(k, _)| *k == 's').unwrap().1).unwrap();
+        let iterations: u32 = attrs
+            .iter()
+            .find(|(k, _)| *k == 'i')
+            .unwrap()
+            .1
+            .parse()
+            .unwrap();
+
+        let password = mechanism.prepare_password(user, password).unwrap();
+        let salted = mechanism.salted_password(password.as_bytes(), &salt, iterations);
+        let client_key = mechanism.hmac(&salted, b"Client Key");
+        let stored_key = mechanism.hash(&client_key);
+        let without_proof = format!("c=biws,r={}", nonce);
+        let auth_message = format!("{},{},{}", client_first_bare, server_first, without_proof);
+        let signature = mechanism.hmac(&stored_key, auth_message.as_bytes());
+        let proof: Vec<u8> = client_key
+            .iter()
+            .zip(signature.iter())
+            .map(|(a, b)| a ^ b)
+            .collect();
+        let server_key = mechanism.hmac(&salted, b"Server Key");
+        let server_signature = mechanism.hmac(&server_key, auth_message.as_bytes());
+
+        (
+            format!("{},p={}", without_proof, base64::encode(proof)),
+            format!("v={}", base64::encode(server_signature)).into_bytes(),
+        )
+    }
+
+    fn authenticate(mechanism: ScramMechanism, password: &str, attempt: &str) -> bool {
+        let credentials = ScramCredentials::generate(mechanism, "user", password).unwrap();
+        let client_first_bare = "n=user,r=fyko+d2lbbFgONRv9qkxdawL";
+        let client_first = format!("n,,{}", client_first_bare);
+        assert_eq!(
+            ScramConversation::parse_user(client_first.as_bytes()).unwrap(),
+            "user"
+        );
+
+        let (mut conversation, server_first) =
+            ScramConversation::start(mechanism, client_first.as_bytes(), credentials).unwrap();
+        let server_first = String::from_utf8(server_first).unwrap();
+        assert!(server_first.starts_with("r=fyko+d2lbbFgONRv9qkxdawL"));
+
+        let (client_final, expected) =
+            client_proof(mechanism, "user", attempt, client_first_bare, &server_first);
+        match conversation.finish(client_final.as_bytes()) {
+            Ok(server_final) => {
+                assert_eq!(server_final, expected);
+                assert!(conversation.is_done());
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    #[test]
+    fn test_scram_sha_256() {
+        assert!(authenticate(ScramMechanism::Sha256, "pencil", "pencil"));
+        assert!(!authenticate(ScramMechanism::Sha256, "pencil", "pen"));
+    }
+
+    #[test]
+    fn test_scram_sha_1() {
+        assert!(authenticate(ScramMechanism::Sha1, "pencil", "pencil"));
+        assert!(!authenticate(ScramMechanism::Sha1, "pencil", "pen"));
+    }
+
+    #[test]
+    fn test_credentials_round_trip() {
+        let credentials =
+            ScramCredentials::generate(ScramMechanism::Sha256, "user", "pencil").unwrap();
+        assert_eq!(
+            ScramCredentials::from_doc(&credentials.to_doc()).unwrap(),
+            credentials
+        );
+    }
+
+    #[test]
+    fn test_decode_username() {
+        assert_eq!(decode_username("a=2Cb=3Dc"), "a,b=c");
+    }
+}
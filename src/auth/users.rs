@@ -0,0 +1,175 @@
+use super::{ScramCredentials, ScramMechanism};
+use bson::{doc, Bson, Document};
+
+// User documents, stored with the same layout as mongod's `admin.system.users`:
+//
+// { _id: "db.user", userId, user, db, credentials: { "SCRAM-SHA-256": {...} },
+//   roles: [{ role, db }], customData }
+
+pub const DEFAULT_MECHANISMS: &[&str] = &["SCRAM-SHA-1", "SCRAM-SHA-256"];
+
+#[derive(Debug, Clone)]
+pub struct InvalidUserError {
+    pub message: String,
+}
+
+impl InvalidUserError {
+    fn new(message: String) -> Self {
+        InvalidUserError { message }
+    }
+}
+
+pub fn user_id(db: &str, user: &str) -> String {
+    format!("{}.{}", db, user)
+}
+
+/// Builds the credentials of every requested mechanism for a password.
+pub fn build_credentials(
+    user: &str,
+    password: &str,
+    mechanisms: Option<&Bson>,
+) -> Result<Document, InvalidUserError> {
+    if password.is_empty() {
+        return Err(InvalidUserError::new(
+            "Password cannot be empty".to_string(),
+        ));
+    }
+
+    let mechanisms = match mechanisms {
+        Some(Bson::Array(mechanisms)) if !mechanisms.is_empty() => mechanisms
+            .iter()
+            .map(|m| m.as_str().unwrap_or_default().to_string())
+            .collect(),
+        Some(_) => {
+            return Err(InvalidUserError::new(
+                "mechanisms field must be a non-empty array of strings".to_string(),
+            ))
+        }
+        None => DEFAULT_MECHANISMS
+            .iter()
+            .map(|m| m.to_string())
+            .collect::<Vec<_>>(),
+    };
+
+    let mut credentials = Document::new();
+    for name in mechanisms {
+        let mechanism = match ScramMechanism::from_name(&name) {
+            Some(mechanism) => mechanism,
+            None => {
+                return Err(InvalidUserError::new(format!(
+                    "Unknown auth mechanism '{}'",
+                    name
+                )))
+            }
+        };
+        let generated = ScramCredentials::generate(mechanism, user, password)
+            .map_err(|e| InvalidUserError::new(e.message))?;
+        credentials.insert(mechanism.name(), generated.to_doc());
+    }
+    Ok(credentials)
+}
+
+/// Normalizes the roles of a user to `{ role, db }` documents, role names
+/// given as strings refer to the user's database.
+pub fn parse_roles(roles: &Bson, db: &str) -> Result<Vec<Bson>, InvalidUserError> {
+    let roles = match roles {
+        Bson::Array(roles) => roles,
+        _ => {
+            return Err(InvalidUserError::new(
+                "\"roles\" field must be an array".to_string(),
+            ))
+        }
+    };
+
+    roles
+        .iter()
+        .map(|role| match role {
+            Bson::String(role) => Ok(doc! { "role": role, "db": db }.into()),
+            Bson::Document(role) => match (role.get_str("role"), role.get_str("db")) {
+                (Ok(name), Ok(db)) => Ok(doc! { "role": name, "db": db }.into()),
+                _ => Err(InvalidUserError::new(format!(
+                    "Role must have \"role\" and \"db\" fields: {}",
+                    role
+                ))),
+            },
+            _ => Err(InvalidUserError::new(format!(
+                "Role must be either a string or an object: {}",
+                role
+            ))),
+        })
+        .collect()
+}
+
+pub fn mechanisms(user: &Document) -> Vec<Bson> {
+    match user.get_document("credentials") {
+        Ok(credentials) => credentials
+            .keys()
+            .map(|k| Bson::String(k.clone()))
+            .collect(),
+        Err(_) => vec![],
+    }
+}
+
+pub fn get_credentials(user: &Document, mechanism: ScramMechanism) -> Option<ScramCredentials> {
+    let credentials = user.get_document("credentials").ok()?;
+    ScramCredentials::from_doc(credentials.get_document(mechanism.name()).ok()?)
+}
+
+/// The user as returned by `usersInfo`.
+pub fn user_info(user: &Document, show_credentials: bool) -> Document {
+    let mut info = doc! {
+        "_id": user.get("_id").cloned().unwrap_or(Bson::Null),
+        "userId": user.get("userId").cloned().unwrap_or(Bson::Null),
+        "user": user.get("user").cloned().unwrap_or(Bson::Null),
+        "db": user.get("db").cloned().unwrap_or(Bson::Null),
+    };
+    if let Some(custom_data) = user.get("customData") {
+        info.insert("customData", custom_data.clone());
+    }
+    info.insert(
+        "roles",
+        user.get("roles").cloned().unwrap_or(Bson::Array(vec![])),
+    );
+    if show_credentials {
+        if let Some(credentials) = user.get("credentials") {
+            info.insert("credentials", credentials.clone());
+        }
+    }
+    info.insert("mechanisms", mechanisms(user));
+    info
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_roles() {
+        let roles = bson::bson!(["read", { "role": "readWrite", "db": "other" }]);
+        assert_eq!(
+            parse_roles(&roles, "test").unwrap(),
+            vec![
+                Bson::Document(doc! { "role": "read", "db": "test" }),
+                Bson::Document(doc! { "role": "readWrite", "db": "other" }),
+            ]
+        );
+        assert!(parse_roles(&bson::bson!([1]), "test").is_err());
+    }
+
+    #[test]
+    fn test_build_credentials() {
+        let credentials = build_credentials("ana", "secret", None).unwrap();
+        assert!(credentials.contains_key("SCRAM-SHA-1"));
+        assert!(credentials.contains_key("SCRAM-SHA-256"));
+
+        let mechanisms = bson::bson!(["SCRAM-SHA-256"]);
+        let credentials = build_credentials("ana", "secret", Some(&mechanisms)).unwrap();
+        assert_eq!(
+            credentials.keys().collect::<Vec<_>>(),
+            vec!["SCRAM-SHA-256"]
+        );
+
+        let mechanisms = bson::bson!(["PLAIN"]);
+        assert!(build_credentials("ana", "secret", Some(&mechanisms)).is_err());
+    }
+}
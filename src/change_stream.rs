@@ -0,0 +1,432 @@
+use crate::deserializer::PostgresJsonDeserializer;
+use crate::pg::{PgDb, SqlParam, CHANGES_CHANNEL, CHANGES_SCHEMA, CHANGES_TABLE};
+use crate::tls::MakeRustlsConnect;
+use crate::wire::MAX_DOCUMENT_LEN;
+use bson::{doc, ser, Bson, Document};
+use r2d2_postgres::PostgresConnectionManager;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Change streams.
+//
+// Every collection table has a trigger that logs its inserts, updates and
+// deletes to a table of changes and notifies a channel. A change stream is a
+// tailable cursor over that table: it remembers the resume token of the last
+// change it went through, and `getMore` waits for a notification when there's
+// nothing new. A single connection listens to the channel on behalf of every
+// waiting stream, and the streams only take a connection from the pool to
+// read the changes.
+//
+// Changes are read in the order of the transactions that made them, and only
+// once every transaction before theirs has finished. A transaction that
+// commits late can't leave behind a change that a stream already moved past.
+
+/// Time a `getMore` on a change stream waits for changes when it doesn't
+/// give a `maxTimeMS`, same default as MongoDB.
+pub const DEFAULT_AWAIT_TIME: Duration = Duration::from_secs(1);
+
+/// SQL expression of the resume token of a logged change, the same string
+/// `ResumeToken::data` gives.
+pub const RESUME_TOKEN_SQL: &str = "upper(lpad(to_hex(xid), 16, '0') || lpad(to_hex(id), 16, '0'))";
+
+// leaves room for the rest of the reply document around the batch
+const MAX_BATCH_LEN: usize = MAX_DOCUMENT_LEN as usize - 16 * 1024;
+
+// how often the listening connection checks if streams are still waiting
+const LISTENER_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Position of a change stream: the transaction and id of a change, or the
+/// first change of a transaction when `id` is 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ResumeToken {
+    pub xid: i64,
+    pub id: i64,
+}
+
+impl ResumeToken {
+    pub fn new(xid: i64, id: i64) -> Self {
+        ResumeToken { xid, id }
+    }
+
+    /// Reads a token given as `{ _data: "..." }`.
+    pub fn parse(doc: &Document) -> Option<Self> {
+        let data = doc.get_str("_data").ok()?;
+        if data.len() != 32 || !data.is_ascii() {
+            return None;
+        }
+        let xid = i64::from_str_radix(&data[..16], 16).ok()?;
+        let id = i64::from_str_radix(&data[16..], 16).ok()?;
+        Some(ResumeToken::new(xid, id))
+    }
+
+    pub fn data(&self) -> String {
+        format!("{:016X}{:016X}", self.xid, self.id)
+    }
+
+    pub fn to_doc(&self) -> Document {
+        doc! { "_data": self.data() }
+    }
+}
+
+#[derive(Debug)]
+pub enum ChangeStreamError {
+    HistoryLost(ResumeToken),
+    TokenModified,
+    Postgres(eyre::Report),
+}
+
+impl ChangeStreamError {
+    pub fn code(&self) -> i32 {
+        match self {
+            ChangeStreamError::HistoryLost(_) => 286,
+            ChangeStreamError::TokenModified => 280,
+            ChangeStreamError::Postgres(_) => 1,
+        }
+    }
+
+    pub fn code_name(&self) -> &'static str {
+        match self {
+            ChangeStreamError::HistoryLost(_) => "ChangeStreamHistoryLost",
+            ChangeStreamError::TokenModified => "ChangeStreamFatalError",
+            ChangeStreamError::Postgres(_) => "InternalError",
+        }
+    }
+
+    pub fn to_doc(&self) -> Document {
+        doc! {
+            "ok": Bson::Double(0.0),
+            "errmsg": self.to_string(),
+            "code": Bson::Int32(self.code()),
+            "codeName": self.code_name(),
+        }
+    }
+}
+
+impl std::fmt::Display for ChangeStreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ChangeStreamError::HistoryLost(token) => write!(
+                f,
+                "Resume of change stream was not possible, as the resume point {} may no longer be in the log of changes",
+                token.data()
+            ),
+            ChangeStreamError::TokenModified => write!(
+                f,
+                "Encountered an event whose _id field, which contains the resume token, was modified by the pipeline"
+            ),
+            ChangeStreamError::Postgres(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ChangeStreamError {}
+
+impl From<eyre::Report> for ChangeStreamError {
+    fn from(e: eyre::Report) -> Self {
+        ChangeStreamError::Postgres(e)
+    }
+}
+
+/// Where a change stream starts reading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeStreamStart {
+    Now,
+    After(ResumeToken),
+    AtOperationTime(u32),
+}
+
+impl ChangeStreamStart {
+    /// The token a new change stream starts from, checking that a token to
+    /// resume after is still in the log.
+    pub fn token(&self, client: &mut PgDb) -> Result<ResumeToken, ChangeStreamError> {
+        let table = SqlParam::new(CHANGES_SCHEMA, CHANGES_TABLE);
+        match self {
+            ChangeStreamStart::Now => Ok(ResumeToken::new(client.oldest_running_transaction()?, 0)),
+            ChangeStreamStart::After(token) if token.id == 0 => Ok(*token),
+            ChangeStreamStart::After(token) => {
+                let sql = format!(
+                    "SELECT EXISTS (SELECT 1 FROM {} WHERE xid = $1 AND id = $2)",
+                    table
+                );
+                let row = client.query_one(&sql, &[&token.xid, &token.id])?.unwrap();
+                if !row.get::<_, bool>(0) {
+                    return Err(ChangeStreamError::HistoryLost(*token));
+                }
+                Ok(*token)
+            }
+            ChangeStreamStart::AtOperationTime(time) => {
+                let sql = format!(
+                    "SELECT MIN(xid) FROM {} WHERE cluster_time >= to_timestamp($1)",
+                    table
+                );
+                let row = client.query_one(&sql, &[&(*time as f64)])?.unwrap();
+                let xid = match row.get::<_, Option<i64>>(0) {
+                    Some(xid) => xid,
+                    None => client.oldest_running_transaction()?,
+                };
+                Ok(ResumeToken::new(xid, 0))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Changes {
+    pub docs: Vec<Bson>,
+    /// Where the next batch starts, also sent to drivers as the
+    /// `postBatchResumeToken`.
+    pub token: ResumeToken,
+}
+
+/// Reads up to `batch_size` changes after `token` out of `sql`, the statement
+/// of a change stream pipeline.
+pub fn fetch_changes(
+    client: &mut PgDb,
+    sql: &str,
+    token: ResumeToken,
+    batch_size: Option<i64>,
+) -> Result<Changes, ChangeStreamError> {
+    // changes of transactions older than this one are final
+    let until = client.oldest_running_transaction()?;
+
+    // asks for one extra row to know if there are more changes
+    let limit = match batch_size {
+        Some(n) if n > 0 => format!(" LIMIT {}", n + 1),
+        _ => "".to_string(),
+    };
+    let sql = format!(
+        r#"SELECT * FROM ({}) AS s_cursor ORDER BY _jsonb->'_id'->>'_data' COLLATE "C"{}"#,
+        sql, limit
+    );
+    let rows = client.raw_query(&sql, &[&token.xid, &token.id, &until])?;
+
+    let max_docs = match batch_size {
+        Some(n) if n > 0 => n as usize,
+        _ => usize::MAX,
+    };
+    let mut docs = vec![];
+    let mut last = token;
+    let mut size = 0;
+    for row in rows {
+        if docs.len() >= max_docs {
+            return Ok(Changes { docs, token: last });
+        }
+
+        let json: serde_json::Value = row.get(0);
+        let doc = json.from_psql_json()?;
+        let change_token = doc
+            .as_document()
+            .and_then(|doc| doc.get_document("_id").ok())
+            .and_then(ResumeToken::parse)
+            .ok_or(ChangeStreamError::TokenModified)?;
+        size += ser::to_vec(&doc).map(|v| v.len()).unwrap_or(0);
+        if size > MAX_BATCH_LEN && !docs.is_empty() {
+            return Ok(Changes { docs, token: last });
+        }
+        last = change_token;
+        docs.push(doc);
+    }
+
+    // every change before the running transactions was looked at, including
+    // the ones the pipeline filtered out
+    Ok(Changes {
+        docs,
+        token: last.max(ResumeToken::new(until, 0)),
+    })
+}
+
+/// Like `fetch_changes`, but waits up to `wait` for new changes when there
+/// are none yet. A connection is only taken from the pool to read the
+/// changes, not while waiting for them.
+pub fn next_changes(
+    listener: &ChangeListener,
+    sql: &str,
+    token: ResumeToken,
+    batch_size: Option<i64>,
+    wait: Duration,
+) -> Result<Changes, ChangeStreamError> {
+    let fetch = |token| {
+        let mut client = PgDb::new_from_pool(listener.pool.clone());
+        fetch_changes(&mut client, sql, token, batch_size)
+    };
+
+    let changes = fetch(token)?;
+    if !changes.docs.is_empty() {
+        return Ok(changes);
+    }
+
+    let start = Instant::now();
+    let mut subscription = listener.subscribe()?;
+    // changes logged before listening don't wake anybody up
+    let mut changes = fetch(changes.token)?;
+    while changes.docs.is_empty() {
+        let remaining = match wait.checked_sub(start.elapsed()) {
+            Some(remaining) if !remaining.is_zero() => remaining,
+            _ => break,
+        };
+        if !subscription.wait(remaining) {
+            break;
+        }
+        changes = fetch(changes.token)?;
+    }
+    Ok(changes)
+}
+
+/// Wakes up the change streams waiting for changes. One connection listens
+/// to the channel of changes for all of them, and only while one is waiting.
+#[derive(Clone)]
+pub struct ChangeListener {
+    pool: r2d2::Pool<PostgresConnectionManager<MakeRustlsConnect>>,
+    state: Arc<(Mutex<ListenerState>, Condvar)>,
+}
+
+#[derive(Default)]
+struct ListenerState {
+    waiting: usize,
+    running: bool,
+    listening: bool,
+    // bumped on every notification
+    notifications: u64,
+}
+
+impl ChangeListener {
+    pub fn new(pool: r2d2::Pool<PostgresConnectionManager<MakeRustlsConnect>>) -> Self {
+        ChangeListener {
+            pool,
+            state: Arc::new((Mutex::new(ListenerState::default()), Condvar::new())),
+        }
+    }
+
+    /// Starts waiting for changes, returning once they're being listened to.
+    fn subscribe(&self) -> Result<Subscription<'_>, ChangeStreamError> {
+        let (lock, condvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        state.waiting += 1;
+        if !state.running {
+            state.running = true;
+            let listener = self.clone();
+            thread::spawn(move || listener.listen());
+        }
+        let mut state = condvar
+            .wait_while(state, |state| state.running && !state.listening)
+            .unwrap();
+        if !state.listening {
+            state.waiting -= 1;
+            return Err(ChangeStreamError::Postgres(eyre::eyre!(
+                "could not listen for changes"
+            )));
+        }
+        Ok(Subscription {
+            listener: self,
+            seen: state.notifications,
+        })
+    }
+
+    // runs on its own thread until nobody is waiting anymore
+    fn listen(&self) {
+        let (lock, condvar) = &*self.state;
+        let mut failure = ListenerFailure {
+            listener: self,
+            armed: true,
+        };
+
+        let mut client = PgDb::new_from_pool(self.pool.clone());
+        if let Err(e) = client.listen(CHANGES_CHANNEL) {
+            log::error!("Could not listen for changes: {}", e);
+            return;
+        }
+        lock.lock().unwrap().listening = true;
+        condvar.notify_all();
+
+        loop {
+            let notified = match client.wait_for_notification(LISTENER_POLL_INTERVAL) {
+                Ok(notified) => notified,
+                Err(e) => {
+                    log::error!("Error while listening for changes: {}", e);
+                    return;
+                }
+            };
+            let mut state = lock.lock().unwrap();
+            if notified {
+                state.notifications += 1;
+                condvar.notify_all();
+            }
+            if state.waiting == 0 {
+                state.running = false;
+                state.listening = false;
+                failure.armed = false;
+                break;
+            }
+        }
+
+        if let Err(e) = client.unlisten(CHANGES_CHANNEL) {
+            log::error!("Could not stop listening for changes: {}", e);
+        }
+    }
+}
+
+// wakes up the waiting streams when listening stops on an error, including a
+// panic while taking the connection, so they don't wait for nothing
+struct ListenerFailure<'a> {
+    listener: &'a ChangeListener,
+    armed: bool,
+}
+
+impl Drop for ListenerFailure<'_> {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        let (lock, condvar) = &*self.listener.state;
+        let mut state = lock.lock().unwrap_or_else(|e| e.into_inner());
+        state.running = false;
+        state.listening = false;
+        condvar.notify_all();
+    }
+}
+
+struct Subscription<'a> {
+    listener: &'a ChangeListener,
+    seen: u64,
+}
+
+impl Subscription<'_> {
+    /// Waits up to `timeout` for a notification, returning whether one
+    /// arrived.
+    fn wait(&mut self, timeout: Duration) -> bool {
+        let (lock, condvar) = &*self.listener.state;
+        let seen = self.seen;
+        let (state, _) = condvar
+            .wait_timeout_while(lock.lock().unwrap(), timeout, |state| {
+                state.listening && state.notifications == seen
+            })
+            .unwrap();
+        self.seen = state.notifications;
+        self.seen != seen
+    }
+}
+
+impl Drop for Subscription<'_> {
+    fn drop(&mut self) {
+        let (lock, _) = &*self.listener.state;
+        lock.lock().unwrap().waiting -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resume_token() {
+        let token = ResumeToken::new(1234, 56);
+        assert_eq!(token.data(), "00000000000004D20000000000000038");
+        assert_eq!(ResumeToken::parse(&token.to_doc()), Some(token));
+        assert!(ResumeToken::new(1234, 0) < token);
+        assert!(token < ResumeToken::new(1235, 1));
+
+        assert_eq!(ResumeToken::parse(&doc! { "_data": "abc" }), None);
+        assert_eq!(ResumeToken::parse(&doc! { "x": 1 }), None);
+    }
+}
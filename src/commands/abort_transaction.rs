@@ -0,0 +1,30 @@
+use crate::commands::commit_transaction::transaction_id;
+use crate::commands::Handler;
+use crate::handler::{CommandExecutionError, Request};
+use bson::{doc, Bson, Document};
+
+pub struct AbortTransaction {}
+
+impl Handler for AbortTransaction {
+    fn new() -> Self {
+        AbortTransaction {}
+    }
+
+    fn handle(
+        &self,
+        request: &Request,
+        docs: &Vec<Document>,
+    ) -> Result<Document, CommandExecutionError> {
+        let (id, number) = match transaction_id(&docs[0], "abortTransaction") {
+            Ok(transaction) => transaction,
+            Err(e) => return Ok(e.to_doc()),
+        };
+        request.get_sessions().refresh(&id);
+        match request.get_sessions().abort(&id, number) {
+            Ok(()) => Ok(doc! {
+                "ok": Bson::Double(1.0),
+            }),
+            Err(e) => Ok(e.to_doc()),
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use crate::change_stream::{ChangeStreamStart, ResumeToken, RESUME_TOKEN_SQL};
+use crate::pg::{SqlParam, CHANGES_SCHEMA, CHANGES_TABLE};
+use bson::{Bson, Document};
+use eyre::{eyre, Result};
+
+use super::sql_statement::SqlStatement;
+
+/// What a change stream watches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeStreamScope {
+    Collection(String, String),
+    Database(String),
+    Cluster,
+}
+
+impl ChangeStreamScope {
+    /// The database and collection watched, empty when all of them are.
+    pub fn db_and_collection(&self) -> (&str, &str) {
+        match self {
+            ChangeStreamScope::Collection(db, collection) => (db, collection),
+            ChangeStreamScope::Database(db) => (db, ""),
+            ChangeStreamScope::Cluster => ("", ""),
+        }
+    }
+}
+
+/// Builds the statement that turns logged changes into change events. Its
+/// parameters are the resume token to read after and the oldest running
+/// transaction, see `change_stream::fetch_changes`.
+pub fn process_change_stream(
+    doc: &Document,
+    scope: &ChangeStreamScope,
+) -> Result<(SqlStatement, ChangeStreamStart)> {
+    let mut start = ChangeStreamStart::Now;
+    let mut full_document = "default";
+    let mut full_document_before_change = "off";
+    for (key, value) in doc {
+        match key.as_str() {
+            "fullDocument" => {
+                full_document = value.as_str().unwrap_or_default();
+                if !["default", "updateLookup", "whenAvailable", "required"]
+                    .contains(&full_document)
+                {
+                    return Err(eyre!("'{}' is not a valid value for fullDocument", value));
+                }
+            }
+            "fullDocumentBeforeChange" => {
+                full_document_before_change = value.as_str().unwrap_or_default();
+                if !["off", "whenAvailable", "required"].contains(&full_document_before_change) {
+                    return Err(eyre!(
+                        "'{}' is not a valid value for fullDocumentBeforeChange",
+                        value
+                    ));
+                }
+            }
+            "resumeAfter" | "startAfter" => {
+                let token = value
+                    .as_document()
+                    .and_then(ResumeToken::parse)
+                    .ok_or_else(|| eyre!("invalid resume token: {}", value))?;
+                start = ChangeStreamStart::After(token);
+            }
+            "startAtOperationTime" => match value {
+                Bson::Timestamp(timestamp) => {
+                    start = ChangeStreamStart::AtOperationTime(timestamp.time)
+                }
+                _ => return Err(eyre!("startAtOperationTime must be a timestamp")),
+            },
+            // checked by the caller, which knows the database
+            "allChangesForCluster" | "showExpandedEvents" => {}
+            _ => {
+                return Err(eyre!(
+                    "BSON field '$changeStream.{}' is an unknown field.",
+                    key
+                ))
+            }
+        }
+    }
+
+    let mut sql = SqlStatement::builder()
+        .field(&format!(
+            "{} AS _jsonb",
+            event_sql(full_document, full_document_before_change)
+        ))
+        .from_table(SqlParam::new(CHANGES_SCHEMA, CHANGES_TABLE))
+        .filter("(xid, id) > ($1, $2)")
+        .filter("xid < $3")
+        .build();
+    match scope {
+        ChangeStreamScope::Collection(db, collection) => {
+            sql.add_filter(&format!("db = {}", quote(db)));
+            sql.add_filter(&format!("coll = {}", quote(collection)));
+        }
+        ChangeStreamScope::Database(db) => sql.add_filter(&format!("db = {}", quote(db))),
+        ChangeStreamScope::Cluster => {}
+    }
+    Ok((sql, start))
+}
+
+// the change event of a logged change
+fn event_sql(full_document: &str, full_document_before_change: &str) -> String {
+    let updated_document = match full_document {
+        "updateLookup" => format!(
+            " || jsonb_build_object('fullDocument', \"{}\".current_document(db, coll, document_key))",
+            CHANGES_SCHEMA
+        ),
+        "whenAvailable" | "required" => " || jsonb_build_object('fullDocument', full_document)".to_string(),
+        _ => "".to_string(),
+    };
+    let document_before_change = match full_document_before_change {
+        "off" => "".to_string(),
+        _ => " || CASE WHEN operation = 'insert' THEN '{}'::jsonb
+            ELSE jsonb_build_object('fullDocumentBeforeChange', full_document_before) END"
+            .to_string(),
+    };
+
+    format!(
+        "jsonb_build_object(
+            '_id', jsonb_build_object('_data', {}),
+            'operationType', operation,
+            'clusterTime', jsonb_build_object('$timestamp', jsonb_build_object(
+                't', extract(epoch FROM cluster_time)::bigint, 'i', 1
+            )),
+            'wallTime', jsonb_build_object('$d', (extract(epoch FROM cluster_time) * 1000)::bigint),
+            'ns', jsonb_build_object('db', db, 'coll', coll),
+            'documentKey', jsonb_build_object('_id', document_key)
+        ) || CASE
+            WHEN operation IN ('insert', 'replace') THEN jsonb_build_object('fullDocument', full_document)
+            WHEN operation = 'update' THEN jsonb_build_object('updateDescription', update_description){}
+            ELSE '{{}}'::jsonb
+        END{}",
+        RESUME_TOKEN_SQL, updated_document, document_before_change
+    )
+}
+
+fn quote(name: &str) -> String {
+    format!("'{}'", name.replace('\'', "''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bson::doc;
+
+    #[test]
+    fn test_process_change_stream() {
+        let scope = ChangeStreamScope::Collection("db".to_string(), "col".to_string());
+        let (sql, start) = process_change_stream(&doc! {}, &scope).unwrap();
+        assert_eq!(start, ChangeStreamStart::Now);
+        assert_eq!(
+            &sql.filters,
+            &vec![
+                "(xid, id) > ($1, $2)".to_string(),
+                "xid < $3".to_string(),
+                "db = 'db'".to_string(),
+                "coll = 'col'".to_string(),
+            ]
+        );
+        assert!(!sql.fields[0].contains("current_document"));
+
+        let (sql, start) = process_change_stream(
+            &doc! {
+                "fullDocument": "updateLookup",
+                "resumeAfter": ResumeToken::new(10, 2).to_doc(),
+            },
+            &ChangeStreamScope::Cluster,
+        )
+        .unwrap();
+        assert_eq!(start, ChangeStreamStart::After(ResumeToken::new(10, 2)));
+        assert_eq!(sql.filters.len(), 2);
+        assert!(sql.fields[0].contains("current_document"));
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        let scope = ChangeStreamScope::Database("db".to_string());
+        for options in [
+            doc! { "fullDocument": "always" },
+            doc! { "fullDocumentBeforeChange": true },
+            doc! { "resumeAfter": { "_data": "xyz" } },
+            doc! { "startAtOperationTime": 1 },
+            doc! { "unknown": 1 },
+        ] {
+            assert!(process_change_stream(&options, &scope).is_err());
+        }
+    }
+}
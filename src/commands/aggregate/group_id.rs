@@ -1,3 +1,4 @@
+use crate::parser::expression_to_sql;
 use crate::utils::field_to_jsonb;
 
 use super::sql_statement::SqlStatement;
@@ -20,8 +21,8 @@ pub fn process_id(doc: &mut Document) -> Result<SqlStatement> {
 }
 
 fn process_id_str(field: String) -> Result<SqlStatement> {
-    if let Some(field) = field.strip_prefix("$") {
-        let field = field_to_jsonb(field);
+    if field.starts_with("$") {
+        let field = expression_to_sql(&Bson::String(field))?;
         Ok(SqlStatement::builder()
             .field(&format!("{} AS _id", field))
             .group(&"_id")
@@ -1,5 +1,5 @@
 use super::{group_id::process_id, sql_statement::SqlStatement};
-use crate::utils::{collapse_fields, convert_if_numeric, field_to_jsonb};
+use crate::parser::number_to_sql;
 use bson::{Bson, Document};
 
 #[derive(Debug)]
@@ -15,111 +15,40 @@ pub fn process_group(doc: &Document) -> eyre::Result<SqlStatement> {
         sql.append(&mut process_id(&mut doc)?);
     }
 
-    let doc = collapse_fields(&doc);
-
-    for (raw_key, value) in doc.iter() {
-        let mut value: Bson = value.to_owned();
-        let keys = raw_key.split(".").collect::<Vec<&str>>();
-        for key in keys.iter().skip(1).rev() {
-            match *key {
-                "$sum" | "$avg" => {
-                    let oper = key.strip_prefix("$").unwrap();
-                    let sql_func = oper.to_ascii_uppercase();
-                    match value {
-                        Bson::String(str_val) => {
-                            value = Bson::String(process(&sql_func, &str_val));
-                        }
-                        Bson::Int32(i32val) => {
-                            value = Bson::String(process(&sql_func, &i32val.to_string()));
-                        }
-                        Bson::Int64(i64val) => {
-                            value = Bson::String(process(&sql_func, &i64val.to_string()));
-                        }
-                        _ => {
-                            return Err(eyre::eyre!(
-                                "Cannot currently use {} on non-numeric field",
-                                oper
-                            ));
-                        }
-                    }
-                }
-                "$add" | "$multiply" | "$subtract" | "$divide" => {
-                    let oper = match *key {
-                        "$add" => "+",
-                        "$multiply" => "*",
-                        "$subtract" => "-",
-                        "$divide" => "/",
-                        _ => {
-                            return Err(eyre::eyre!(
-                                "Operation invalid or not yet implemented: {}",
-                                key
-                            ));
-                        }
-                    };
-                    if let Some(values) = value.as_array() {
-                        // checks if values all start with $
-                        let items = parse_math_oper_params(values)?;
-                        value = Bson::String(format!("{}", items.join(&format!(" {} ", oper))));
-                    } else {
-                        return Err(eyre::eyre!(
-                            "Cannot {} can only take an array, got {:?}",
-                            oper,
-                            value
-                        ));
-                    }
-                }
-                _ => {
-                    return Err(eyre::eyre!("Operation missing or not implemented: {}", key));
-                }
-            }
-        }
-        match value {
-            Bson::String(str_val) => {
-                sql.add_field(&format!("{} AS {}", str_val, keys[0]));
+    for (key, value) in doc.iter() {
+        let (accumulator, expr) = match value {
+            Bson::Document(accumulator) if accumulator.len() == 1 => {
+                accumulator.iter().next().unwrap()
             }
             _ => {
                 return Err(eyre::eyre!(
                     r#"The field '{}' must be an accumulator object. Try wrapping it on an object like {{ "field": {{ "{}": {} }} }}."#,
-                    raw_key,
-                    raw_key,
+                    key,
+                    key,
                     value
                 ));
             }
-        }
-    }
-
-    Ok(sql)
-}
-
-fn parse_math_oper_params(attributes: &bson::Array) -> eyre::Result<Vec<String>> {
-    let mut items: Vec<String> = vec![];
-    for attr in attributes.into_iter() {
-        match attr {
-            Bson::String(str_val) => {
-                if !str_val.starts_with("$") {
-                    return Err(eyre::eyre!("Prefixing fields with $ is mandatory. Use ${} if you want to use a field as attribute.", str_val));
-                }
-                items.push(convert_if_numeric(&field_to_jsonb(
-                    str_val.strip_prefix("$").unwrap(),
-                )));
+        };
+        match accumulator.as_str() {
+            "$sum" | "$avg" => {
+                let sql_func = accumulator.strip_prefix("$").unwrap().to_ascii_uppercase();
+                sql.add_field(&format!(
+                    "{}({}) AS {}",
+                    sql_func,
+                    number_to_sql(expr)?,
+                    key
+                ));
             }
             _ => {
-                return Err(eyre::eyre!("Cannot use {:?} as a parameter", attr));
+                return Err(eyre::eyre!(
+                    "Operation missing or not implemented: {}",
+                    accumulator
+                ));
             }
         }
     }
-    Ok(items)
-}
-
-fn process(sql_func: &str, value: &str) -> String {
-    // if it's a string starting with $ we take it as a field name
-    let value = if let Some(field_name) = value.strip_prefix("$") {
-        convert_if_numeric(&field_to_jsonb(field_name))
-    } else {
-        value.to_owned()
-    };
 
-    format!("{}({})", sql_func, value)
+    Ok(sql)
 }
 
 #[cfg(test)]
@@ -142,7 +71,10 @@ mod tests {
         let doc = doc! { "_id": "$other", "qty": { "$sum": "$qty" } };
         let sql = process_group(&doc).unwrap();
         assert_eq!(sql.fields[0], "_jsonb->'other' AS _id");
-        assert_eq!(sql.fields[1], "SUM(CASE WHEN (_jsonb->'qty' ? '$f') THEN (_jsonb->'qty'->>'$f')::numeric ELSE (_jsonb->'qty')::numeric END) AS qty");
+        assert_eq!(
+            sql.fields[1],
+            "SUM(oxide_functions.bson_number(_jsonb->'qty')) AS qty"
+        );
         assert_eq!(sql.groups[0], "_id");
     }
 
@@ -160,7 +92,10 @@ mod tests {
         let doc = doc! { "_id": "$other", "qty": { "$avg": "$qty" } };
         let sql = process_group(&doc).unwrap();
         assert_eq!(sql.fields[0], "_jsonb->'other' AS _id");
-        assert_eq!(sql.fields[1], "AVG(CASE WHEN (_jsonb->'qty' ? '$f') THEN (_jsonb->'qty'->>'$f')::numeric ELSE (_jsonb->'qty')::numeric END) AS qty");
+        assert_eq!(
+            sql.fields[1],
+            "AVG(oxide_functions.bson_number(_jsonb->'qty')) AS qty"
+        );
         assert_eq!(sql.groups[0], "_id");
     }
 
@@ -177,7 +112,10 @@ mod tests {
             sql.fields[0],
             "TO_CHAR(TO_TIMESTAMP((_jsonb->'date'->>'$d')::numeric / 1000), 'YYYY-MM-DD') AS _id"
         );
-        assert_eq!(sql.fields[1], "AVG(CASE WHEN (_jsonb->'qty' ? '$f') THEN (_jsonb->'qty'->>'$f')::numeric ELSE (_jsonb->'qty')::numeric END) AS qty");
+        assert_eq!(
+            sql.fields[1],
+            "AVG(oxide_functions.bson_number(_jsonb->'qty')) AS qty"
+        );
         assert_eq!(sql.groups[0], "_id");
     }
 
@@ -186,7 +124,7 @@ mod tests {
         let doc = doc! { "_id": "$field", "total": { "$sum": { "$multiply": ["$a", "$b"] } } };
         let sql = process_group(&doc).unwrap();
         assert_eq!(sql.fields[0], "_jsonb->'field' AS _id");
-        assert_eq!(sql.fields[1], "SUM(CASE WHEN (_jsonb->'a' ? '$f') THEN (_jsonb->'a'->>'$f')::numeric ELSE (_jsonb->'a')::numeric END * CASE WHEN (_jsonb->'b' ? '$f') THEN (_jsonb->'b'->>'$f')::numeric ELSE (_jsonb->'b')::numeric END) AS total");
+        assert_eq!(sql.fields[1], "SUM((oxide_functions.bson_number(_jsonb->'a') * oxide_functions.bson_number(_jsonb->'b'))) AS total");
         assert_eq!(sql.groups[0], "_id");
     }
 
@@ -195,7 +133,7 @@ mod tests {
         let doc = doc! { "_id": "$field", "total": { "$sum": { "$add": ["$a", "$b"] } } };
         let sql = process_group(&doc).unwrap();
         assert_eq!(sql.fields[0], "_jsonb->'field' AS _id");
-        assert_eq!(sql.fields[1], "SUM(CASE WHEN (_jsonb->'a' ? '$f') THEN (_jsonb->'a'->>'$f')::numeric ELSE (_jsonb->'a')::numeric END + CASE WHEN (_jsonb->'b' ? '$f') THEN (_jsonb->'b'->>'$f')::numeric ELSE (_jsonb->'b')::numeric END) AS total");
+        assert_eq!(sql.fields[1], "SUM((oxide_functions.bson_number(_jsonb->'a') + oxide_functions.bson_number(_jsonb->'b'))) AS total");
         assert_eq!(sql.groups[0], "_id");
     }
 
@@ -204,7 +142,7 @@ mod tests {
         let doc = doc! { "_id": "$field", "total": { "$sum": { "$subtract": ["$a", "$b"] } } };
         let sql = process_group(&doc).unwrap();
         assert_eq!(sql.fields[0], "_jsonb->'field' AS _id");
-        assert_eq!(sql.fields[1], "SUM(CASE WHEN (_jsonb->'a' ? '$f') THEN (_jsonb->'a'->>'$f')::numeric ELSE (_jsonb->'a')::numeric END - CASE WHEN (_jsonb->'b' ? '$f') THEN (_jsonb->'b'->>'$f')::numeric ELSE (_jsonb->'b')::numeric END) AS total");
+        assert_eq!(sql.fields[1], "SUM((oxide_functions.bson_number(_jsonb->'a') - oxide_functions.bson_number(_jsonb->'b'))) AS total");
         assert_eq!(sql.groups[0], "_id");
     }
 
@@ -213,7 +151,7 @@ mod tests {
         let doc = doc! { "_id": "$field", "total": { "$sum": { "$divide": ["$a", "$b"] } } };
         let sql = process_group(&doc).unwrap();
         assert_eq!(sql.fields[0], "_jsonb->'field' AS _id");
-        assert_eq!(sql.fields[1], "SUM(CASE WHEN (_jsonb->'a' ? '$f') THEN (_jsonb->'a'->>'$f')::numeric ELSE (_jsonb->'a')::numeric END / CASE WHEN (_jsonb->'b' ? '$f') THEN (_jsonb->'b'->>'$f')::numeric ELSE (_jsonb->'b')::numeric END) AS total");
+        assert_eq!(sql.fields[1], "SUM((oxide_functions.bson_number(_jsonb->'a') / oxide_functions.bson_number(_jsonb->'b'))) AS total");
         assert_eq!(sql.groups[0], "_id");
     }
 }
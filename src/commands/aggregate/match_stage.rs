@@ -30,7 +30,7 @@ mod tests {
 
         assert_eq!(
             sql.filters[0],
-            r#"(jsonb_typeof(_jsonb->'age') = 'number' OR jsonb_typeof(_jsonb->'age'->'$f') = 'number') AND CASE WHEN (_jsonb->'age' ? '$f') THEN (_jsonb->'age'->>'$f')::numeric ELSE (_jsonb->'age')::numeric END > '20'"#
+            r#"EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['age']) AS p(v) WHERE oxide_functions.bson_number(v) > 20)"#
         );
     }
 }
@@ -1,16 +1,23 @@
 #![allow(dead_code)]
+use crate::change_stream::fetch_changes;
 use crate::commands::Handler;
+use crate::cursor::{get_batch_size, Cursor};
 use crate::handler::{CommandExecutionError, Request};
+use crate::parser::escape;
 use crate::pg::SqlParam;
-use crate::utils::{field_to_jsonb, pg_rows_to_bson};
+use crate::utils::field_to_jsonb;
 use bson::{doc, Bson, Document};
+use change_stream_stage::{process_change_stream, ChangeStreamScope};
 use group_stage::process_group;
 use match_stage::process_match;
 use project_stage::process_project;
+
+pub use project_stage::process_find_projection;
 use sql_statement::SqlStatement;
 
 use self::count_stage::process_count;
 
+mod change_stream_stage;
 mod count_stage;
 mod group_id;
 mod group_stage;
@@ -32,24 +39,38 @@ impl Handler for Aggregate {
     ) -> Result<Document, CommandExecutionError> {
         let doc = &docs[0];
         let db = doc.get_str("$db").unwrap();
-        let collection = doc.get_str("aggregate").unwrap();
         let pipeline = doc.get_array("pipeline").unwrap();
+        if let Some(options) = pipeline
+            .first()
+            .and_then(|stage| stage.as_document())
+            .and_then(|stage| stage.get_document("$changeStream").ok())
+        {
+            return watch(request, doc, options);
+        }
+        let collection = doc.get_str("aggregate").unwrap();
         let sp = SqlParam::new(db, collection);
 
         let mut client = request.get_client();
 
+        let ns = format!("{}.{}", db, collection);
+        let users = request.get_auth().authenticated_users().clone();
+        let batch_size = doc.get_document("cursor").ok().and_then(get_batch_size);
+
         let sql = build_sql(&sp, pipeline);
         match sql {
             Ok(sql) => {
                 log::debug!("SQL: {}", sql);
 
-                match client.raw_query(&sql, &[]) {
-                    Ok(rows) => {
+                match request
+                    .get_cursors()
+                    .open(&mut client, &ns, &users, &sql, batch_size)
+                {
+                    Ok((id, rows)) => {
                         let res_doc = doc![
                             "cursor": doc! {
-                                "firstBatch": pg_rows_to_bson(rows),
-                                "id": Bson::Int64(0),
-                                "ns": format!("{}.{}", db, collection),
+                                "firstBatch": rows,
+                                "id": Bson::Int64(id),
+                                "ns": ns,
                             },
                             "ok": Bson::Double(1.0),
                         ];
@@ -64,7 +85,100 @@ impl Handler for Aggregate {
     }
 }
 
+// opens a change stream, a tailable cursor over the changes made from now on
+// or after a resume token, with the rest of the pipeline applied to them
+fn watch(
+    request: &Request,
+    doc: &Document,
+    options: &Document,
+) -> Result<Document, CommandExecutionError> {
+    let db = doc.get_str("$db").unwrap();
+    let all_changes = options.get_bool("allChangesForCluster").unwrap_or(false);
+    let (scope, ns) = match doc.get("aggregate") {
+        Some(Bson::String(collection)) if !all_changes => (
+            ChangeStreamScope::Collection(db.to_string(), collection.to_string()),
+            format!("{}.{}", db, collection),
+        ),
+        Some(Bson::Int32(1)) | Some(Bson::Int64(1)) => {
+            let scope = match (db, all_changes) {
+                ("admin", true) => ChangeStreamScope::Cluster,
+                ("admin", false) => {
+                    return Ok(invalid_namespace(
+                        "$changeStream on the admin database requires allChangesForCluster",
+                    ))
+                }
+                (_, true) => {
+                    return Ok(invalid_namespace(
+                        "A $changeStream with 'allChangesForCluster:true' may only be opened on the 'admin' database",
+                    ))
+                }
+                (db, false) => ChangeStreamScope::Database(db.to_string()),
+            };
+            (scope, format!("{}.$cmd.aggregate", db))
+        }
+        _ => {
+            return Ok(invalid_namespace(
+                "A $changeStream with 'allChangesForCluster:true' must be run with {aggregate: 1}",
+            ))
+        }
+    };
+
+    let (statement, start) = process_change_stream(options, &scope)
+        .map_err(|e| CommandExecutionError::new(e.to_string()))?;
+    let pipeline = doc.get_array("pipeline").unwrap();
+    let sql = build_sql_from(Some(statement), None, &pipeline[1..].to_vec())?;
+    log::debug!("SQL: {}", sql);
+
+    let mut client = request.get_client();
+    // writes only keep the document before the change once a stream asks for it
+    if matches!(options.get_str("fullDocumentBeforeChange"), Ok(before) if before != "off") {
+        let (db, collection) = scope.db_and_collection();
+        client
+            .keep_pre_images(db, collection)
+            .map_err(|e| CommandExecutionError::new(e.to_string()))?;
+    }
+    let batch_size = doc.get_document("cursor").ok().and_then(get_batch_size);
+    let changes = match start
+        .token(&mut client)
+        .and_then(|token| fetch_changes(&mut client, &sql, token, batch_size))
+    {
+        Ok(changes) => changes,
+        Err(e) => return Ok(e.to_doc()),
+    };
+
+    let users = request.get_auth().authenticated_users().clone();
+    let cursor = Cursor::change_stream(ns.clone(), &users, sql, changes.token);
+    let id = request.get_cursors().register(cursor);
+    Ok(doc! {
+        "cursor": doc! {
+            "firstBatch": changes.docs,
+            "postBatchResumeToken": changes.token.to_doc(),
+            "id": Bson::Int64(id),
+            "ns": ns,
+        },
+        "ok": Bson::Double(1.0),
+    })
+}
+
+fn invalid_namespace(errmsg: &str) -> Document {
+    doc! {
+        "ok": Bson::Double(0.0),
+        "errmsg": errmsg,
+        "code": Bson::Int32(73),
+        "codeName": "InvalidNamespace",
+    }
+}
+
 pub fn build_sql(sp: &SqlParam, pipeline: &Vec<Bson>) -> Result<String, CommandExecutionError> {
+    build_sql_from(None, Some(sp), pipeline)
+}
+
+// builds the pipeline on top of `base` when given, or of the table
+fn build_sql_from(
+    base: Option<SqlStatement>,
+    sp: Option<&SqlParam>,
+    pipeline: &Vec<Bson>,
+) -> Result<String, CommandExecutionError> {
     let mut stages: Vec<(String, SqlStatement)> = vec![];
     for stage in pipeline {
         let stage_doc = stage.as_document().unwrap();
@@ -100,7 +214,7 @@ pub fn build_sql(sp: &SqlParam, pipeline: &Vec<Bson>) -> Result<String, CommandE
                 if let Some(last_stage) = stages.last_mut() {
                     for (field, value) in stage_doc.get_document("$sort").unwrap() {
                         let field = if last_stage.0 == "$wrap" {
-                            format!("row_to_json(s_wrap)::jsonb->'{}'", field)
+                            format!("row_to_json(s_wrap)::jsonb->'{}'", escape(field))
                         } else {
                             field_to_jsonb(field)
                         };
@@ -157,6 +271,11 @@ pub fn build_sql(sp: &SqlParam, pipeline: &Vec<Bson>) -> Result<String, CommandE
                         Some(stage_doc.get_i32("$limit").unwrap().try_into().unwrap());
                 }
             }
+            "$changeStream" => {
+                return Err(CommandExecutionError::new(
+                    "$changeStream is only valid as the first stage in a pipeline".to_string(),
+                ))
+            }
             _ => {
                 return Err(CommandExecutionError::new(format!(
                     "Unrecognized pipeline stage name: '{}'",
@@ -166,13 +285,13 @@ pub fn build_sql(sp: &SqlParam, pipeline: &Vec<Bson>) -> Result<String, CommandE
         };
     }
 
-    let mut sql: Option<SqlStatement> = None;
+    let mut sql: Option<SqlStatement> = base;
     for (name, mut stage_sql) in stages {
         if stage_sql.from.is_none() {
             if let Some(mut sql) = sql {
                 let alias = format!("s_{}", name.strip_prefix("$").unwrap());
                 stage_sql.add_subquery_with_alias(&mut sql, &alias);
-            } else {
+            } else if let Some(sp) = sp {
                 stage_sql.set_table(sp.clone());
             }
         }
@@ -210,7 +329,7 @@ mod tests {
         let sql = build_sql(&sp, doc.get_array("pipeline").unwrap()).unwrap();
         assert_eq!(
             sql,
-            r#"SELECT row_to_json(s_wrap)::jsonb AS _jsonb FROM (SELECT _jsonb->'name' AS _id, SUM(1) AS count FROM (SELECT * FROM "schema"."table" WHERE _jsonb->'name' = '"Alice"') AS s_group GROUP BY _id) AS s_wrap"#
+            r#"SELECT row_to_json(s_wrap)::jsonb AS _jsonb FROM (SELECT _jsonb->'name' AS _id, SUM(1) AS count FROM (SELECT * FROM "schema"."table" WHERE EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['name']) AS p(v) WHERE v = '"Alice"')) AS s_group GROUP BY _id) AS s_wrap"#
         );
     }
 
@@ -266,7 +385,7 @@ mod tests {
         let sql = build_sql(&sp, doc.get_array("pipeline").unwrap()).unwrap();
         assert_eq!(
             sql,
-            r#"SELECT row_to_json(s_wrap)::jsonb AS _jsonb FROM (SELECT _jsonb->'item' AS _id, SUM(CASE WHEN (_jsonb->'quantity' ? '$f') THEN (_jsonb->'quantity'->>'$f')::numeric ELSE (_jsonb->'quantity')::numeric END * CASE WHEN (_jsonb->'price' ? '$f') THEN (_jsonb->'price'->>'$f')::numeric ELSE (_jsonb->'price')::numeric END) AS total_sum FROM "schema"."table" GROUP BY _id) AS s_wrap"#
+            r#"SELECT row_to_json(s_wrap)::jsonb AS _jsonb FROM (SELECT _jsonb->'item' AS _id, SUM((oxide_functions.bson_number(_jsonb->'quantity') * oxide_functions.bson_number(_jsonb->'price'))) AS total_sum FROM "schema"."table" GROUP BY _id) AS s_wrap"#
         );
     }
 }
@@ -1,4 +1,5 @@
-use crate::utils::{collapse_fields, expand_doc};
+use crate::parser::{escape, expression_to_sql, parse};
+use crate::utils::{collapse_fields, expand_doc, expand_fields};
 use bson::{doc, Bson, Document};
 
 use super::sql_statement::SqlStatement;
@@ -14,29 +15,23 @@ pub fn handle_oper(doc: &Document) -> Result<Option<String>, InvalidProjectionEr
     if opers.len() < 1 {
         return Ok(None);
     }
-    let oper = opers[0];
-    match oper.as_str() {
-        "$literal" => {
-            let value = doc.get(oper).unwrap();
-            match value {
-                Bson::String(str) => Ok(Some(format!("'{}'", str))),
-                _ => Ok(Some(value.to_string())),
-            }
-        }
-        _ => Err(InvalidProjectionError {
-            message: format!("Unsupported operator: {}", oper),
+    match expression_to_sql(&Bson::Document(doc.clone())) {
+        Ok(sql) => Ok(Some(sql)),
+        Err(e) => Err(InvalidProjectionError {
+            message: e.to_string(),
         }),
     }
 }
 
 pub fn handle_field(key: String, value: &Bson) -> Option<String> {
     match value {
-        Bson::String(str) => match str.strip_prefix("$") {
-            Some(str) => Some(format!("'{}', _jsonb->'{}'", key, str)),
-            None => Some(format!("'{}', '{}'", key, str)),
+        Bson::String(str) if str.starts_with("$") => match expression_to_sql(value) {
+            Ok(sql) => Some(format!("'{}', {}", escape(&key), sql)),
+            Err(_) => None,
         },
-        Bson::Int32(_) => Some(format!("'{}', _jsonb->'{}'", key, key)),
-        _ => Some(format!("'{}', {}", key, value.to_string())),
+        Bson::String(str) => Some(format!("'{}', '{}'", escape(&key), escape(str))),
+        Bson::Int32(_) => Some(format!("'{0}', _jsonb->'{0}'", escape(&key))),
+        _ => Some(format!("'{}', {}", escape(&key), value.to_string())),
     }
 }
 
@@ -45,8 +40,8 @@ pub fn arr_to_json_build_array(arr: &Vec<Bson>) -> Result<String, InvalidProject
         .iter()
         .map(|v| match v {
             Bson::String(str) => match str.strip_prefix("$") {
-                Some(str) => format!("_jsonb->'{}'", str),
-                None => format!("'{}'", str),
+                Some(str) => format!("_jsonb->'{}'", escape(str)),
+                None => format!("'{}'", escape(str)),
             },
             Bson::Int32(i) => format!("{}", i),
             _ => format!("{}", v.to_string()),
@@ -65,13 +60,13 @@ pub fn doc_to_json_build_object(doc: &Document) -> Result<String, InvalidProject
                     Ok(value) => {
                         match value {
                             // is an operation, got the value back
-                            Some(value) => fields.push(format!("'{}', {}", key, value)),
+                            Some(value) => fields.push(format!("'{}', {}", escape(&key), value)),
 
                             // no operation, let's parse as document
                             None => {
                                 // if no operation, just insert the field
                                 let doc = doc_to_json_build_object(&doc)?;
-                                fields.push(format!("'{}', {}", key, doc))
+                                fields.push(format!("'{}', {}", escape(&key), doc))
                             }
                         }
                     }
@@ -79,7 +74,11 @@ pub fn doc_to_json_build_object(doc: &Document) -> Result<String, InvalidProject
                 }
             }
             Bson::Array(arr) => {
-                fields.push(format!("'{}', {}", key, arr_to_json_build_array(&arr)?));
+                fields.push(format!(
+                    "'{}', {}",
+                    escape(&key),
+                    arr_to_json_build_array(&arr)?
+                ));
             }
             _ => match handle_field(key.clone(), &value) {
                 Some(str) => fields.push(str),
@@ -95,9 +94,13 @@ pub fn doc_to_json_build_object(doc: &Document) -> Result<String, InvalidProject
 }
 
 pub fn process_project(doc: &Document) -> Result<SqlStatement, InvalidProjectionError> {
-    let doc = &collapse_fields(doc);
-
     let mut sql = SqlStatement::new();
+    sql.add_field(&format!("{} AS _jsonb", project_to_sql(doc)?));
+    Ok(sql)
+}
+
+fn project_to_sql(doc: &Document) -> Result<String, InvalidProjectionError> {
+    let doc = &collapse_fields(doc);
 
     let mut doc = doc.clone();
     if is_inclusion(&doc)? {
@@ -110,7 +113,7 @@ pub fn process_project(doc: &Document) -> Result<SqlStatement, InvalidProjection
                 if !keep_id {
                     doc.remove("_id");
                 }
-                sql.add_field(&format!("{} AS _jsonb", &doc_to_json_build_object(&doc)?));
+                doc_to_json_build_object(&doc)
             }
             None => {
                 let mut new_doc = doc! {
@@ -119,10 +122,7 @@ pub fn process_project(doc: &Document) -> Result<SqlStatement, InvalidProjection
                 for (key, value) in doc {
                     new_doc.insert(key.clone(), value.clone());
                 }
-                sql.add_field(&format!(
-                    "{} AS _jsonb",
-                    &doc_to_json_build_object(&new_doc)?
-                ));
+                doc_to_json_build_object(&new_doc)
             }
         }
     } else {
@@ -136,32 +136,280 @@ pub fn process_project(doc: &Document) -> Result<SqlStatement, InvalidProjection
         let fields = doc
             .iter()
             .filter(|(key, _)| !include_id || key.as_str() != "_id")
-            .map(|(key, _)| format!("'{}'", key))
+            .map(|(key, _)| format!("'{}'", escape(key)))
             .collect::<Vec<String>>()
             .join(" - ");
 
-        sql.add_field(&format!("_jsonb - {} AS _jsonb", fields));
+        Ok(format!("_jsonb - {}", fields))
+    }
+}
+
+/// Translates the projection of a `find` command into a jsonb expression over
+/// `_jsonb`. Unlike `$project`, fields missing from the document are left out
+/// of the result, dotted paths traverse arrays of subdocuments and the
+/// `$slice` and `$elemMatch` operators are supported.
+pub fn process_find_projection(doc: &Document) -> Result<String, InvalidProjectionError> {
+    let mut fields = Document::new();
+    let mut slices = vec![];
+    let mut elem_matches = vec![];
+    for (key, value) in doc {
+        if let Bson::Document(oper) = value {
+            if let Some(slice) = oper.get("$slice") {
+                slices.push((key.clone(), slice.clone()));
+                continue;
+            }
+            if let Some(elem_match) = oper.get("$elemMatch") {
+                match elem_match.as_document() {
+                    Some(query) => elem_matches.push((key.clone(), query.clone())),
+                    None => {
+                        return Err(InvalidProjectionError {
+                            message: "elemMatch: Invalid argument, object required".to_string(),
+                        })
+                    }
+                }
+                continue;
+            }
+        }
+        fields.insert(key, value);
+    }
+    let fields = collapse_fields(&fields);
+
+    let has_expressions = fields
+        .iter()
+        .any(|(key, value)| key.contains('$') || matches!(value, Bson::String(_)));
+
+    let mut sql = if has_expressions {
+        // renames and literals work the same way they do on $project
+        format!("({})::jsonb", project_to_sql(&fields)?)
+    } else if fields.is_empty() && elem_matches.is_empty() {
+        "_jsonb".to_string()
+    } else {
+        let include_id = match fields.get("_id") {
+            Some(id) => val_as_bool("_id".to_string(), id)?
+                .as_bool()
+                .unwrap_or(true),
+            None => true,
+        };
+        let has_other_fields = fields.keys().any(|k| k != "_id");
+        let inclusion = if has_other_fields {
+            is_inclusion(&fields)?
+        } else {
+            // a lone `_id: 1` includes only the _id
+            fields.contains_key("_id") && include_id
+        };
+
+        if inclusion || !elem_matches.is_empty() {
+            if has_other_fields && !inclusion {
+                let (key, _) = fields.iter().find(|(k, _)| *k != "_id").unwrap();
+                return Err(InvalidProjectionError {
+                    message: format!(
+                        "Cannot do exclusion on field {} in inclusion projection",
+                        key
+                    ),
+                });
+            }
+
+            let mut paths = vec![];
+            if include_id {
+                paths.push("_id".to_string());
+            }
+            paths.extend(fields.keys().filter(|k| *k != "_id").cloned());
+            paths.extend(slices.iter().map(|(path, _)| path.clone()));
+            paths.extend(elem_matches.iter().map(|(path, _)| path.clone()));
+            inclusion_to_sql("_jsonb", &paths_to_tree(&paths)?, 1)
+        } else {
+            let mut sql = "_jsonb".to_string();
+            for (key, value) in fields.iter() {
+                if key == "_id" && include_id {
+                    continue;
+                }
+                if let Ok(Bson::Boolean(true)) = val_as_bool(key.clone(), value) {
+                    continue;
+                }
+                if key.contains('.') {
+                    sql = format!("{} #- {}", sql, path_literal(key));
+                } else {
+                    sql = format!("{} - '{}'", sql, escape(key));
+                }
+            }
+            sql
+        }
+    };
+
+    for (path, value) in slices {
+        sql = slice_to_sql(&sql, &path, &value)?;
+    }
+
+    for (path, query) in elem_matches {
+        sql = elem_match_to_sql(&sql, &path, &query)?;
     }
 
     Ok(sql)
 }
 
-fn val_as_bool(key: String, value: &Bson) -> Result<Bson, InvalidProjectionError> {
-    // handling special operators
-    let last = key.split(".").last().unwrap();
+fn paths_to_tree(paths: &Vec<String>) -> Result<Document, InvalidProjectionError> {
+    fn insert(
+        tree: &mut Document,
+        parts: &[&str],
+        path: &str,
+    ) -> Result<(), InvalidProjectionError> {
+        let collision = || InvalidProjectionError {
+            message: format!("Path collision at {}", path),
+        };
+        let key = parts[0];
+        if parts.len() == 1 {
+            if tree.contains_key(key) {
+                return Err(collision());
+            }
+            tree.insert(key, true);
+            return Ok(());
+        }
 
-    // $literal
-    if last == "$literal" {
-        return Ok(Bson::Boolean(true));
+        if !tree.contains_key(key) {
+            tree.insert(key, Document::new());
+        }
+        match tree.get_mut(key) {
+            Some(Bson::Document(subtree)) => insert(subtree, &parts[1..], path),
+            _ => Err(collision()),
+        }
     }
 
-    // unsupported operators
-    if last.starts_with("$") {
+    let mut tree = Document::new();
+    for path in paths {
+        insert(&mut tree, &path.split('.').collect::<Vec<_>>(), path)?;
+    }
+    Ok(tree)
+}
+
+fn inclusion_to_sql(source: &str, tree: &Document, depth: usize) -> String {
+    let mut parts = vec!["'{}'::jsonb".to_string()];
+    for (key, value) in tree {
+        let key = escape(key);
+        let field = format!("{}->'{}'", source, key);
+        match value {
+            Bson::Document(subtree) => {
+                let elem = format!("e{}", depth);
+                let idx = format!("i{}", depth);
+                parts.push(format!(
+                    "CASE jsonb_typeof({field}) \
+                    WHEN 'object' THEN jsonb_build_object('{key}', {object}) \
+                    WHEN 'array' THEN jsonb_build_object('{key}', (SELECT COALESCE(jsonb_agg({array} ORDER BY {idx}), '[]'::jsonb) \
+                    FROM jsonb_array_elements({field}) WITH ORDINALITY AS t{depth}({elem}, {idx}) WHERE jsonb_typeof({elem}) = 'object')) \
+                    ELSE '{{}}'::jsonb END",
+                    object = inclusion_to_sql(&field, subtree, depth + 1),
+                    array = inclusion_to_sql(&elem, subtree, depth + 1),
+                ));
+            }
+            _ => parts.push(format!(
+                "CASE WHEN {source} ? '{key}' THEN jsonb_build_object('{key}', {field}) ELSE '{{}}'::jsonb END"
+            )),
+        }
+    }
+    parts.join(" || ")
+}
+
+fn slice_to_sql(sql: &str, path: &str, value: &Bson) -> Result<String, InvalidProjectionError> {
+    let path = path_literal(path);
+    let field = format!("_jsonb #> {}", path);
+    let invalid = || InvalidProjectionError {
+        message: format!("Invalid $slice syntax: {}", value),
+    };
+    let as_i64 = |value: &Bson| match value {
+        Bson::Int32(n) => Some(*n as i64),
+        Bson::Int64(n) => Some(*n),
+        Bson::Double(n) => Some(*n as i64),
+        _ => None,
+    };
+
+    let condition = match value {
+        Bson::Array(args) => {
+            if args.len() != 2 {
+                return Err(invalid());
+            }
+            let skip = as_i64(&args[0]).ok_or_else(invalid)?;
+            let limit = as_i64(&args[1]).ok_or_else(invalid)?;
+            if limit <= 0 {
+                return Err(InvalidProjectionError {
+                    message: format!("$slice limit must be positive, got {}", limit),
+                });
+            }
+            let start = if skip >= 0 {
+                skip.to_string()
+            } else {
+                format!("GREATEST(jsonb_array_length({}) + {}, 0)", field, skip)
+            };
+            format!("i > {start} AND i <= {start} + {limit}")
+        }
+        value => match as_i64(value).ok_or_else(invalid)? {
+            n if n >= 0 => format!("i <= {}", n),
+            n => format!("i > jsonb_array_length({}) + {}", field, n),
+        },
+    };
+
+    Ok(format!(
+        "CASE WHEN jsonb_typeof({field}) = 'array' THEN jsonb_set({sql}, {path}, \
+        (SELECT COALESCE(jsonb_agg(e ORDER BY i), '[]'::jsonb) FROM jsonb_array_elements({field}) WITH ORDINALITY AS t(e, i) WHERE {condition})) \
+        ELSE {sql} END"
+    ))
+}
+
+fn elem_match_to_sql(
+    sql: &str,
+    path: &str,
+    query: &Document,
+) -> Result<String, InvalidProjectionError> {
+    if query
+        .keys()
+        .any(|k| k.starts_with('$') && k != "$and" && k != "$or")
+    {
         return Err(InvalidProjectionError {
-            message: format!(r#"Unrecognized expression "{}""#, last),
+            message: format!(
+                "$elemMatch projection on {} requires a query document",
+                path
+            ),
         });
     }
 
+    let filter = expand_fields(query)
+        .map_err(|e| InvalidProjectionError {
+            message: e.to_string(),
+        })
+        .and_then(|filter| {
+            parse(filter).map_err(|e| InvalidProjectionError {
+                message: e.to_string(),
+            })
+        })?;
+    let filter = if filter.is_empty() {
+        "TRUE".to_string()
+    } else {
+        filter
+    };
+
+    // each element is aliased as _jsonb so the filter applies to it
+    let path = path_literal(path);
+    let field = format!("_jsonb #> {}", path);
+    let matched = format!(
+        "(SELECT jsonb_build_array(_jsonb) FROM jsonb_array_elements(CASE WHEN jsonb_typeof({field}) = 'array' THEN {field} ELSE '[]'::jsonb END) \
+        WITH ORDINALITY AS t(_jsonb, i) WHERE {filter} ORDER BY i LIMIT 1)"
+    );
+
+    Ok(format!(
+        "CASE WHEN {matched} IS NULL THEN {sql} #- {path} ELSE jsonb_set({sql}, {path}, {matched}) END"
+    ))
+}
+
+// `'{a,b}'` for `a.b`
+fn path_literal(path: &str) -> String {
+    format!("'{{{}}}'", escape(&path.replace('.', ",")))
+}
+
+fn val_as_bool(key: String, value: &Bson) -> Result<Bson, InvalidProjectionError> {
+    // expressions are computed fields, they get validated when compiled
+    let last = key.split(".").last().unwrap();
+    if last.starts_with("$") {
+        return Ok(Bson::Boolean(true));
+    }
+
     match value {
         Bson::Int32(v) => Ok(Bson::Boolean(*v != 0)),
         Bson::Int64(v) => Ok(Bson::Boolean(*v != 0)),
@@ -178,7 +426,7 @@ fn val_as_bool(key: String, value: &Bson) -> Result<Bson, InvalidProjectionError
                     )
                 });
             }
-            if keys[0].ends_with("$literal") {
+            if keys[0].split(".").last().unwrap().starts_with("$") {
                 Ok(Bson::Boolean(true))
             } else {
                 Err(InvalidProjectionError {
@@ -338,7 +586,7 @@ mod tests {
         let fields = doc_to_json_build_object(&flat).unwrap();
         assert_eq!(
             fields,
-            "json_build_object('pick', _jsonb->'pick', 'num', 1, 'bool', true, 'str', 'value')"
+            "json_build_object('pick', _jsonb->'pick', 'num', '1'::jsonb, 'bool', 'true'::jsonb, 'str', '\"value\"'::jsonb)"
         );
     }
 
@@ -355,7 +603,7 @@ mod tests {
         let fields = process_project(&flat).unwrap();
         assert_eq!(
             fields.to_string(),
-            "SELECT json_build_object('pick', _jsonb->'pick', 'num', 1, 'bool', true, 'str', 'value') AS _jsonb "
+            "SELECT json_build_object('pick', _jsonb->'pick', 'num', '1'::jsonb, 'bool', 'true'::jsonb, 'str', '\"value\"'::jsonb) AS _jsonb "
         );
     }
 
@@ -386,7 +634,7 @@ mod tests {
         let fields = doc_to_json_build_object(&flat).unwrap();
         assert_eq!(
             fields,
-            "json_build_object('field', json_build_object('one', _jsonb->'from_field', 'two', _jsonb->'number', 'three', '$number'), 'non_field', 'str value')"
+            "json_build_object('field', json_build_object('one', _jsonb->'from_field', 'two', _jsonb->'number', 'three', '\"$number\"'::jsonb), 'non_field', 'str value')"
         );
     }
 
@@ -399,7 +647,10 @@ mod tests {
             "str": { "$literal": "value" },
         };
         let sql = process_project(&doc).unwrap();
-        assert_eq!(sql.to_string(), "SELECT json_build_object('_id', _jsonb->'_id', 'pick', _jsonb->'pick', 'num', 1, 'bool', true, 'str', 'value') AS _jsonb ");
+        assert_eq!(
+            sql.to_string(),
+            r#"SELECT json_build_object('_id', _jsonb->'_id', 'pick', _jsonb->'pick', 'num', '1'::jsonb, 'bool', 'true'::jsonb, 'str', '"value"'::jsonb) AS _jsonb "#
+        );
     }
 
     #[test]
@@ -426,14 +677,17 @@ mod tests {
             "age.$literal": 30,
         };
         let str = doc_to_json_build_object(&doc).unwrap();
-        assert_eq!(str, "json_build_object('include', _jsonb->'include', 'complete_name', _jsonb->'name', 'place', _jsonb->'city', 'attr', json_build_object('hair_color', _jsonb->'hair', 'eyes_color', _jsonb->'eyes'), 'name', 'Felipe', 'age', 30)");
+        assert_eq!(str, "json_build_object('include', _jsonb->'include', 'complete_name', _jsonb->'name', 'place', _jsonb->'city', 'attr', json_build_object('hair_color', _jsonb->'hair', 'eyes_color', _jsonb->'eyes'), 'name', '\"Felipe\"'::jsonb, 'age', '30'::jsonb)");
     }
 
     #[test]
     pub fn test_handle_oper() {
-        assert_eq!("1", handle_oper(&doc! { "$literal": 1 }).unwrap().unwrap());
         assert_eq!(
-            "'Felipe'",
+            "'1'::jsonb",
+            handle_oper(&doc! { "$literal": 1 }).unwrap().unwrap()
+        );
+        assert_eq!(
+            r#"'"Felipe"'::jsonb"#,
             handle_oper(&doc! { "$literal": "Felipe" })
                 .unwrap()
                 .unwrap()
@@ -474,4 +728,28 @@ mod tests {
         let str = arr_to_json_build_array(&arr).unwrap();
         assert_eq!(str, "json_build_array(1, 2, 'Felipe')");
     }
+
+    #[test]
+    fn test_find_projection_exclusion() {
+        let doc = doc! { "a": 0, "b.c": 0 };
+        assert_eq!(
+            process_find_projection(&doc).unwrap(),
+            "_jsonb - 'a' #- '{b,c}'"
+        );
+    }
+
+    #[test]
+    fn test_find_projection_path_collision() {
+        let doc = doc! { "a": 1, "a.b": 1 };
+        assert_eq!(
+            process_find_projection(&doc).unwrap_err().message,
+            "Path collision at a.b"
+        );
+    }
+
+    #[test]
+    fn test_find_projection_invalid_slice() {
+        let doc = doc! { "a": { "$slice": [1, 0] } };
+        assert!(process_find_projection(&doc).is_err());
+    }
 }
@@ -0,0 +1,48 @@
+use crate::commands::Handler;
+use crate::handler::{CommandExecutionError, Request};
+use crate::session::{session_id, txn_number, TransactionError};
+use bson::{doc, Bson, Document};
+
+pub struct CommitTransaction {}
+
+impl Handler for CommitTransaction {
+    fn new() -> Self {
+        CommitTransaction {}
+    }
+
+    fn handle(
+        &self,
+        request: &Request,
+        docs: &Vec<Document>,
+    ) -> Result<Document, CommandExecutionError> {
+        let (id, number) = match transaction_id(&docs[0], "commitTransaction") {
+            Ok(transaction) => transaction,
+            Err(e) => return Ok(e.to_doc()),
+        };
+        request.get_sessions().refresh(&id);
+        match request.get_sessions().commit(&id, number) {
+            Ok(()) => Ok(doc! {
+                "ok": Bson::Double(1.0),
+            }),
+            Err(e) => Ok(e.to_doc()),
+        }
+    }
+}
+
+/// Reads the session and number of the transaction `commitTransaction` and
+/// `abortTransaction` act on.
+pub fn transaction_id(doc: &Document, command: &str) -> Result<(Vec<u8>, i64), TransactionError> {
+    let id = session_id(doc).ok_or_else(|| {
+        TransactionError::InvalidOptions(format!("{} must be run within a session", command))
+    })?;
+    let number = txn_number(doc).ok_or_else(|| {
+        TransactionError::InvalidOptions(format!("{} requires a txnNumber", command))
+    })?;
+    if doc.get_bool("autocommit") != Ok(false) {
+        return Err(TransactionError::InvalidOptions(format!(
+            "{} must be run with autocommit: false",
+            command
+        )));
+    }
+    Ok((id, number))
+}
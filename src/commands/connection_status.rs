@@ -1,3 +1,4 @@
+use crate::auth::roles::role_names;
 use crate::commands::Handler;
 use crate::handler::{CommandExecutionError, Request};
 use bson::{doc, Bson, Document};
@@ -11,13 +12,34 @@ impl Handler for ConnectionStatus {
 
     fn handle(
         &self,
-        _request: &Request,
+        request: &Request,
         _msg: &Vec<Document>,
     ) -> Result<Document, CommandExecutionError> {
+        let (users, authenticated) = {
+            let auth = request.get_auth();
+            (
+                auth.authenticated_users_bson(),
+                auth.authenticated_users().clone(),
+            )
+        };
+
+        let mut roles: Vec<Bson> = vec![];
+        if !authenticated.is_empty() {
+            let mut client = request.get_client();
+            for user in authenticated {
+                let user = client
+                    .get_user(&user.db, &user.user)
+                    .map_err(|e| CommandExecutionError::new(e.to_string()))?;
+                if let Some(user) = user {
+                    roles.extend(role_names(&user).iter().map(|r| Bson::from(r.to_doc())));
+                }
+            }
+        }
+
         Ok(doc! {
           "authInfo": {
-            "authenticatedUsers": [],
-            "authenticatedUserRoles": [],
+            "authenticatedUsers": users,
+            "authenticatedUserRoles": roles,
             "authenticatedUserPrivileges": [],
           },
           "ok": Bson::Double(1.into())
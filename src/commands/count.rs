@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 use crate::commands::Handler;
 use crate::handler::{CommandExecutionError, Request};
+use crate::parser::InvalidQueryError;
 use crate::pg::SqlParam;
 use bson::{doc, Bson, Document};
 
@@ -48,6 +49,9 @@ impl Handler for Count {
                 })
             }
             Err(error) => {
+                if let Some(e) = error.downcast_ref::<InvalidQueryError>() {
+                    return Ok(e.to_doc());
+                }
                 log::error!("Error during count: {:?} - doc: {}", error, &doc);
                 Err(CommandExecutionError::new(format!(
                     "error during count: {:?}",
@@ -0,0 +1,108 @@
+use super::create_user::bad_value;
+use crate::auth::roles::{parse_privileges, role_id, RoleName};
+use crate::auth::users::parse_roles;
+use crate::commands::Handler;
+use crate::handler::{CommandExecutionError, Request};
+use crate::pg::PgDb;
+use bson::{doc, Bson, Document};
+
+pub struct CreateRole {}
+
+impl Handler for CreateRole {
+    fn new() -> Self {
+        CreateRole {}
+    }
+
+    fn handle(
+        &self,
+        request: &Request,
+        docs: &Vec<Document>,
+    ) -> Result<Document, CommandExecutionError> {
+        let doc = &docs[0];
+        let db = doc.get_str("$db").unwrap();
+        let name = match doc.get_str("createRole") {
+            Ok(name) if !name.is_empty() => name,
+            _ => return Ok(bad_value("Role name must be a non-empty string")),
+        };
+        if RoleName::new(name, db).is_builtin() {
+            return Ok(bad_value(
+                "Cannot create roles with the same name as a built-in role",
+            ));
+        }
+
+        let privileges = match doc.get("privileges") {
+            Some(privileges) => match parse_privileges(privileges, db) {
+                Ok(privileges) => privileges,
+                Err(e) => return Ok(bad_value(&e.message)),
+            },
+            None => {
+                return Ok(bad_value(
+                    "\"createRole\" command requires a \"privileges\" array",
+                ))
+            }
+        };
+        let roles = match doc.get("roles") {
+            Some(roles) => match parse_roles(roles, db) {
+                Ok(roles) => roles,
+                Err(e) => return Ok(bad_value(&e.message)),
+            },
+            None => {
+                return Ok(bad_value(
+                    "\"createRole\" command requires a \"roles\" array",
+                ))
+            }
+        };
+
+        let mut client = request.get_client();
+        if let Some(err) = check_roles_exist(&mut client, &roles)? {
+            return Ok(err);
+        }
+
+        let role = doc! {
+            "_id": role_id(db, name),
+            "role": name,
+            "db": db,
+            "privileges": privileges,
+            "roles": roles,
+        };
+        match client.insert_role(&role) {
+            Ok(true) => Ok(doc! {
+                "ok": Bson::Double(1.0),
+            }),
+            Ok(false) => Ok(doc! {
+                "ok": Bson::Double(0.0),
+                "errmsg": format!("Role \"{}@{}\" already exists", name, db),
+                "code": Bson::Int32(51002),
+                "codeName": "Location51002",
+            }),
+            Err(e) => Err(CommandExecutionError::new(e.to_string())),
+        }
+    }
+}
+
+/// Returns the error reply for the first of `roles` that doesn't exist.
+pub fn check_roles_exist(
+    client: &mut PgDb,
+    roles: &[Bson],
+) -> Result<Option<Document>, CommandExecutionError> {
+    for role in roles.iter().filter_map(RoleName::from_bson) {
+        if role.is_builtin() {
+            continue;
+        }
+        match client.get_role(&role.db, &role.role) {
+            Ok(Some(_)) => {}
+            Ok(None) => return Ok(Some(role_not_found(&role))),
+            Err(e) => return Err(CommandExecutionError::new(e.to_string())),
+        }
+    }
+    Ok(None)
+}
+
+pub fn role_not_found(role: &RoleName) -> Document {
+    doc! {
+        "ok": Bson::Double(0.0),
+        "errmsg": format!("Could not find role: {}", role),
+        "code": Bson::Int32(31),
+        "codeName": "RoleNotFound",
+    }
+}
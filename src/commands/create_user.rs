@@ -0,0 +1,85 @@
+use super::create_role::check_roles_exist;
+use crate::auth::users::{build_credentials, parse_roles, user_id};
+use crate::commands::Handler;
+use crate::handler::{CommandExecutionError, Request};
+use bson::{doc, spec::BinarySubtype, Binary, Bson, Document};
+
+pub struct CreateUser {}
+
+impl Handler for CreateUser {
+    fn new() -> Self {
+        CreateUser {}
+    }
+
+    fn handle(
+        &self,
+        request: &Request,
+        docs: &Vec<Document>,
+    ) -> Result<Document, CommandExecutionError> {
+        let doc = &docs[0];
+        let db = doc.get_str("$db").unwrap();
+        let name = match doc.get_str("createUser") {
+            Ok(name) if !name.is_empty() => name,
+            _ => return Ok(bad_value("User name must be a non-empty string")),
+        };
+        let password = match doc.get_str("pwd") {
+            Ok(password) => password,
+            Err(_) => {
+                return Ok(bad_value(
+                    "Must provide a 'pwd' field for all user documents",
+                ))
+            }
+        };
+
+        let roles = match parse_roles(doc.get("roles").unwrap_or(&Bson::Array(vec![])), db) {
+            Ok(roles) => roles,
+            Err(e) => return Ok(bad_value(&e.message)),
+        };
+        let credentials = match build_credentials(name, password, doc.get("mechanisms")) {
+            Ok(credentials) => credentials,
+            Err(e) => return Ok(bad_value(&e.message)),
+        };
+
+        let mut user = doc! {
+            "_id": user_id(db, name),
+            "userId": Binary { subtype: BinarySubtype::Uuid, bytes: uuid::Uuid::new_v4().as_bytes().to_vec() },
+            "user": name,
+            "db": db,
+            "credentials": credentials,
+            "roles": roles.clone(),
+        };
+        match doc.get("customData") {
+            Some(Bson::Document(custom_data)) => {
+                user.insert("customData", custom_data.clone());
+            }
+            Some(_) => return Ok(bad_value("\"customData\" must be an object")),
+            None => {}
+        }
+
+        let mut client = request.get_client();
+        if let Some(err) = check_roles_exist(&mut client, &roles)? {
+            return Ok(err);
+        }
+        match client.insert_user(&user) {
+            Ok(true) => Ok(doc! {
+                "ok": Bson::Double(1.0),
+            }),
+            Ok(false) => Ok(doc! {
+                "ok": Bson::Double(0.0),
+                "errmsg": format!("User \"{}@{}\" already exists", name, db),
+                "code": Bson::Int32(51003),
+                "codeName": "Location51003",
+            }),
+            Err(e) => Err(CommandExecutionError::new(e.to_string())),
+        }
+    }
+}
+
+pub fn bad_value(errmsg: &str) -> Document {
+    doc! {
+        "ok": Bson::Double(0.0),
+        "errmsg": errmsg,
+        "code": Bson::Int32(2),
+        "codeName": "BadValue",
+    }
+}
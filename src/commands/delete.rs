@@ -1,4 +1,5 @@
 use crate::handler::{CommandExecutionError, Request};
+use crate::pg::PgDb;
 use crate::{commands::Handler, pg::SqlParam};
 use bson::{doc, Bson, Document};
 
@@ -18,7 +19,14 @@ impl Handler for Delete {
         let db = doc.get_str("$db").unwrap();
         let collection = doc.get_str("delete").unwrap();
         let deletes = doc.get_array("deletes").unwrap();
+        let ordered = doc.get_bool("ordered").unwrap_or(true);
         let sp = SqlParam::new(db, collection);
+
+        let statements = match parse_statements(deletes) {
+            Ok(statements) => statements,
+            Err(err) => return Ok(err),
+        };
+
         let mut client = request.get_client();
 
         let exists = sp.exists(&mut client);
@@ -37,21 +45,114 @@ impl Handler for Delete {
             }
         };
 
-        if deletes.len() > 1 {
-            return Err(CommandExecutionError::new(
-                "Only one delete operation is supported".to_string(),
-            ));
+        let mut n = 0;
+        let mut write_errors = vec![];
+        let mut run = |client: &mut PgDb| -> eyre::Result<()> {
+            for (index, (filter, limit)) in statements.iter().enumerate() {
+                // a failing statement is undone without losing the ones before it
+                match client.atomically(|client| client.delete(&sp, Some(filter), Some(*limit))) {
+                    Ok(deleted) => n += deleted,
+                    Err(e) => {
+                        log::error!("Error deleting from {}: {}", sp, e);
+                        write_errors.push(write_error(index, &e));
+                        if ordered {
+                            break;
+                        }
+                    }
+                }
+            }
+            Ok(())
+        };
+
+        // ordered batches stop at the first error, keeping what was deleted
+        // before it, and are applied at once
+        let res = if ordered {
+            client.transaction(run)
+        } else {
+            run(&mut client)
+        };
+        if let Err(e) = res {
+            return Err(CommandExecutionError::new(e.to_string()));
         }
 
-        let delete_doc = deletes[0].as_document().unwrap();
-        let filter = delete_doc.get_document("q").unwrap();
-        let limit: Option<i32> = delete_doc.get_i32("limit").ok();
+        let mut res = doc! {
+            "n": Bson::Int64(n as i64),
+        };
+        if !write_errors.is_empty() {
+            res.insert("writeErrors", write_errors);
+        }
+        res.insert("ok", Bson::Double(1.0));
+        Ok(res)
+    }
+}
 
-        let n = client.delete(&sp, Some(filter), limit).unwrap();
+/// Describes a failed statement of a write batch for `writeErrors`. Errors
+/// coming from Postgres are internal, others come from invalid input.
+pub fn write_error(index: usize, error: &eyre::Report) -> Document {
+    let code = if error.downcast_ref::<postgres::Error>().is_some() {
+        1
+    } else {
+        2
+    };
+    doc! {
+        "index": Bson::Int32(index as i32),
+        "code": Bson::Int32(code),
+        "errmsg": error.to_string(),
+    }
+}
 
-        Ok(doc! {
-            "n": Bson::Int64(n as i64),
-            "ok": Bson::Double(1.0),
-        })
+// reads the filter and limit of every statement, rejecting the whole command
+// if any of them is malformed
+fn parse_statements(deletes: &[Bson]) -> Result<Vec<(Document, i32)>, Document> {
+    let mut statements = vec![];
+    for delete in deletes {
+        let delete = match delete.as_document() {
+            Some(delete) => delete,
+            None => {
+                return Err(doc! {
+                    "ok": Bson::Double(0.0),
+                    "errmsg": "BSON field 'delete.deletes' must be an array of objects",
+                    "code": Bson::Int32(14),
+                    "codeName": "TypeMismatch",
+                })
+            }
+        };
+
+        let filter = match delete.get_document("q") {
+            Ok(filter) => filter.clone(),
+            Err(_) => {
+                return Err(doc! {
+                    "ok": Bson::Double(0.0),
+                    "errmsg": "BSON field 'delete.deletes.q' is missing but a required field",
+                    "code": Bson::Int32(40414),
+                    "codeName": "Location40414",
+                })
+            }
+        };
+
+        let limit = match delete.get("limit") {
+            None => 0,
+            Some(Bson::Int32(n)) => *n as i64,
+            Some(Bson::Int64(n)) => *n,
+            Some(Bson::Double(n)) if n.fract() == 0.0 => *n as i64,
+            Some(other) => {
+                return Err(limit_error(other));
+            }
+        };
+        if limit != 0 && limit != 1 {
+            return Err(limit_error(&Bson::Int64(limit)));
+        }
+
+        statements.push((filter, limit as i32));
+    }
+    Ok(statements)
+}
+
+fn limit_error(limit: &Bson) -> Document {
+    doc! {
+        "ok": Bson::Double(0.0),
+        "errmsg": format!("The limit field in delete objects must be 0 or 1. Got {}", limit),
+        "code": Bson::Int32(9),
+        "codeName": "FailedToParse",
     }
 }
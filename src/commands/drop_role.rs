@@ -0,0 +1,84 @@
+use super::create_role::role_not_found;
+use super::create_user::bad_value;
+use crate::auth::roles::{role_names, RoleName};
+use crate::commands::Handler;
+use crate::handler::{CommandExecutionError, Request};
+use bson::{doc, Bson, Document};
+
+pub struct DropRole {}
+
+impl Handler for DropRole {
+    fn new() -> Self {
+        DropRole {}
+    }
+
+    fn handle(
+        &self,
+        request: &Request,
+        docs: &Vec<Document>,
+    ) -> Result<Document, CommandExecutionError> {
+        let doc = &docs[0];
+        let db = doc.get_str("$db").unwrap();
+        let name = match doc.get_str("dropRole") {
+            Ok(name) => name,
+            Err(_) => return Ok(bad_value("Role name must be a string")),
+        };
+        let role = RoleName::new(name, db);
+        if role.is_builtin() {
+            return Ok(bad_value(&format!(
+                "{} is a built-in role and cannot be modified",
+                role
+            )));
+        }
+
+        let mut client = request.get_client();
+        match client.delete_role(db, name) {
+            Ok(0) => return Ok(role_not_found(&role)),
+            Ok(_) => {}
+            Err(e) => return Err(CommandExecutionError::new(e.to_string())),
+        }
+
+        // like mongod, the role is also removed from the users and roles that
+        // were granted it
+        let users = client
+            .get_users(None)
+            .map_err(|e| CommandExecutionError::new(e.to_string()))?;
+        for mut user in users {
+            if without_role(&mut user, &role) {
+                client
+                    .replace_user(&user)
+                    .map_err(|e| CommandExecutionError::new(e.to_string()))?;
+            }
+        }
+        let roles = client
+            .get_roles(None)
+            .map_err(|e| CommandExecutionError::new(e.to_string()))?;
+        for mut other in roles {
+            if without_role(&mut other, &role) {
+                client
+                    .replace_role(&other)
+                    .map_err(|e| CommandExecutionError::new(e.to_string()))?;
+            }
+        }
+
+        Ok(doc! {
+            "ok": Bson::Double(1.0),
+        })
+    }
+}
+
+/// Removes `role` from the roles of a user or role document, returning
+/// whether it had it.
+fn without_role(doc: &mut Document, role: &RoleName) -> bool {
+    let roles = role_names(doc);
+    if !roles.contains(role) {
+        return false;
+    }
+    let remaining: Vec<Bson> = roles
+        .iter()
+        .filter(|r| *r != role)
+        .map(|r| r.to_doc().into())
+        .collect();
+    doc.insert("roles", remaining);
+    true
+}
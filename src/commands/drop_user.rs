@@ -0,0 +1,43 @@
+use super::create_user::bad_value;
+use crate::commands::Handler;
+use crate::handler::{CommandExecutionError, Request};
+use bson::{doc, Bson, Document};
+
+pub struct DropUser {}
+
+impl Handler for DropUser {
+    fn new() -> Self {
+        DropUser {}
+    }
+
+    fn handle(
+        &self,
+        request: &Request,
+        docs: &Vec<Document>,
+    ) -> Result<Document, CommandExecutionError> {
+        let doc = &docs[0];
+        let db = doc.get_str("$db").unwrap();
+        let name = match doc.get_str("dropUser") {
+            Ok(name) => name,
+            Err(_) => return Ok(bad_value("User name must be a string")),
+        };
+
+        let mut client = request.get_client();
+        match client.delete_user(db, name) {
+            Ok(0) => Ok(user_not_found(name, db)),
+            Ok(_) => Ok(doc! {
+                "ok": Bson::Double(1.0),
+            }),
+            Err(e) => Err(CommandExecutionError::new(e.to_string())),
+        }
+    }
+}
+
+pub fn user_not_found(name: &str, db: &str) -> Document {
+    doc! {
+        "ok": Bson::Double(0.0),
+        "errmsg": format!("User '{}@{}' not found", name, db),
+        "code": Bson::Int32(11),
+        "codeName": "UserNotFound",
+    }
+}
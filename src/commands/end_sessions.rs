@@ -0,0 +1,50 @@
+use crate::commands::Handler;
+use crate::handler::{CommandExecutionError, Request};
+use crate::session::session_id;
+use bson::{doc, Bson, Document};
+
+pub struct EndSessions {}
+
+impl Handler for EndSessions {
+    fn new() -> Self {
+        EndSessions {}
+    }
+
+    fn handle(
+        &self,
+        request: &Request,
+        docs: &Vec<Document>,
+    ) -> Result<Document, CommandExecutionError> {
+        let lsids = match session_ids(&docs[0], "endSessions") {
+            Ok(lsids) => lsids,
+            Err(err) => return Ok(err),
+        };
+        for id in lsids {
+            request.get_sessions().end(&id);
+        }
+        Ok(doc! {
+            "ok": Bson::Double(1.0),
+        })
+    }
+}
+
+/// Reads the ids of the sessions listed by `endSessions` and
+/// `refreshSessions`.
+pub fn session_ids(doc: &Document, command: &str) -> Result<Vec<Vec<u8>>, Document> {
+    let invalid = || {
+        doc! {
+            "ok": Bson::Double(0.0),
+            "errmsg": format!("{} must be an array of session ids", command),
+            "code": Bson::Int32(14),
+            "codeName": "TypeMismatch",
+        }
+    };
+    let lsids = doc.get_array(command).map_err(|_| invalid())?;
+    lsids
+        .iter()
+        .map(|lsid| {
+            let lsid = lsid.as_document().ok_or_else(invalid)?;
+            session_id(&doc! { "lsid": lsid }).ok_or_else(invalid)
+        })
+        .collect()
+}
@@ -1,6 +1,9 @@
 #![allow(dead_code)]
-use crate::deserializer::PostgresJsonDeserializer;
+use super::aggregate::process_find_projection;
+use crate::cursor::{fetch_batch, get_batch_size, KeysetQuery, DEFAULT_BATCH_SIZE};
 use crate::handler::{CommandExecutionError, Request};
+use crate::parser::InvalidQueryError;
+use crate::pg::{get_order_by, get_where_clause};
 use crate::{commands::Handler, pg::SqlParam};
 use bson::{doc, Bson, Document};
 
@@ -41,26 +44,95 @@ impl Handler for Find {
             None
         };
 
-        let r = client.query("SELECT * FROM %table%", sp, filter, &[]);
-        match r {
-            Ok(rows) => {
-                let mut res: Vec<Bson> = vec![];
-                for row in rows.iter() {
-                    let json_value: serde_json::Value = row.get(0);
-                    let bson_value = json_value.from_psql_json();
-                    res.push(bson_value);
-                }
+        let skip = get_i64(doc, "skip").unwrap_or(0);
+        if skip < 0 {
+            return Ok(bad_value(format!(
+                "skip value must be non-negative, but received: {}",
+                skip
+            )));
+        }
 
-                Ok(doc! {
-                    "cursor": doc! {
-                        "firstBatch": res,
-                        "id": Bson::Int64(0),
-                        "ns": format!("{}.{}", db, collection),
-                    },
-                    "ok": Bson::Double(1.0),
-                })
+        let batch_size = get_batch_size(doc);
+        if let Some(n) = batch_size {
+            if n < 0 {
+                return Ok(bad_value(format!(
+                    "batchSize value must be non-negative, but received: {}",
+                    n
+                )));
             }
+        }
+
+        // a negative limit asks for a single batch of that many documents
+        let limit = get_i64(doc, "limit").unwrap_or(0);
+        let single_batch = limit < 0 || doc.get_bool("singleBatch").unwrap_or(false);
+        let limit = limit.abs();
+
+        let projection = match doc.get_document("projection") {
+            Ok(projection) if !projection.is_empty() => match process_find_projection(projection) {
+                Ok(projection) => projection,
+                Err(e) => return Err(CommandExecutionError::new(e.message)),
+            },
+            _ => "_jsonb".to_string(),
+        };
+        let order_by = get_order_by(doc.get_document("sort").ok());
+        let limit_str = if limit > 0 {
+            format!(" LIMIT {}", limit)
+        } else {
+            "".to_string()
+        };
+        let offset_str = if skip > 0 {
+            format!(" OFFSET {}", skip)
+        } else {
+            "".to_string()
+        };
+
+        let ns = format!("{}.{}", db, collection);
+        let users = request.get_auth().authenticated_users().clone();
+        let r = get_where_clause(filter.as_ref()).and_then(|where_str| {
+            let sql = format!(
+                "SELECT {} AS _jsonb FROM {}{}{}{}{}",
+                projection,
+                sp.sanitize(),
+                where_str,
+                order_by,
+                limit_str,
+                offset_str
+            );
+            log::debug!("SQL: {}", sql);
+
+            if single_batch {
+                let size = batch_size
+                    .filter(|n| *n > 0)
+                    .or(Some(limit).filter(|n| *n > 0))
+                    .unwrap_or(DEFAULT_BATCH_SIZE);
+                fetch_batch(&mut client, &sql, Some(size)).map(|batch| (0, batch.docs))
+            } else {
+                let query = KeysetQuery::new(
+                    &projection,
+                    &sp.sanitize(),
+                    where_str.strip_prefix(" WHERE ").map(str::to_string),
+                    doc.get_document("sort").ok(),
+                    skip,
+                    limit,
+                );
+                request
+                    .get_cursors()
+                    .open_find(&mut client, &ns, &users, query, batch_size)
+            }
+        });
+        match r {
+            Ok((id, res)) => Ok(doc! {
+                "cursor": doc! {
+                    "firstBatch": res,
+                    "id": Bson::Int64(id),
+                    "ns": ns,
+                },
+                "ok": Bson::Double(1.0),
+            }),
             Err(error) => {
+                if let Some(e) = error.downcast_ref::<InvalidQueryError>() {
+                    return Ok(e.to_doc());
+                }
                 log::error!("Error during find: {:?} - doc: {}", error, &doc);
                 Err(CommandExecutionError::new(format!(
                     "error during find: {:?}",
@@ -70,3 +142,21 @@ impl Handler for Find {
         }
     }
 }
+
+fn get_i64(doc: &Document, key: &str) -> Option<i64> {
+    match doc.get(key) {
+        Some(Bson::Int32(n)) => Some(*n as i64),
+        Some(Bson::Int64(n)) => Some(*n),
+        Some(Bson::Double(n)) => Some(*n as i64),
+        _ => None,
+    }
+}
+
+fn bad_value(errmsg: String) -> Document {
+    doc! {
+        "ok": Bson::Double(0.0),
+        "errmsg": errmsg,
+        "code": Bson::Int32(2),
+        "codeName": "BadValue",
+    }
+}
@@ -1,5 +1,5 @@
 use crate::handler::{CommandExecutionError, Request};
-use crate::parser::parse_update;
+use crate::parser::{parse_update, InvalidQueryError};
 use crate::pg::UpdateResult;
 use crate::{commands::Handler, pg::SqlParam};
 use bson::{doc, Bson, Document};
@@ -25,8 +25,7 @@ impl Handler for FindAndModify {
             Ok(sort_doc) => Some(sort_doc),
             _ => None,
         };
-        let raw_update = doc.get_document("update").unwrap();
-        let update_doc = parse_update(raw_update);
+        let update_doc = parse_update(doc.get_document("update").unwrap());
         let upsert = doc.get_bool("upsert").unwrap_or(false);
         let new = doc.get_bool("new").unwrap_or(false);
 
@@ -35,46 +34,29 @@ impl Handler for FindAndModify {
         let mut client = request.get_client();
         client.create_table_if_not_exists(db, collection).unwrap();
 
-        let res = client
-            .update(
-                &sp,
-                Some(query),
-                sort,
-                update_doc.unwrap(),
-                false,
-                false,
-                true,
-            )
-            .unwrap();
+        let res = match client.update(
+            &sp,
+            Some(query),
+            sort,
+            update_doc.unwrap(),
+            upsert,
+            false,
+            true,
+        ) {
+            Ok(res) => res,
+            Err(err) => match err.downcast_ref::<InvalidQueryError>() {
+                Some(e) => return Ok(e.to_doc()),
+                None => return Err(CommandExecutionError::new(err.to_string())),
+            },
+        };
 
         match res {
-            UpdateResult::Count(total) => {
-                if total == 0 {
-                    if upsert {
-                        let mut obj = query.clone();
-                        obj.extend(extract_operator_values(&raw_update));
-
-                        let res = client.insert_doc(sp, &obj).unwrap();
-                        let mut doc = doc! {
-                            "value": null,
-                            "lastErrorObject": {
-                                "updatedExisting": false,
-                                "upserted": res.get_object_id("_id").unwrap().to_string(),
-                                "n": 1,
-                            },
-                            "ok": 1.0,
-                        };
-                        if new {
-                            doc.insert("value", res.clone());
-                        }
-
-                        return Ok(doc);
-                    } else {
-                        return Ok(doc! {
-                            "value": null,
-                            "ok": Bson::Double(1.0),
-                        });
-                    }
+            UpdateResult::Count { matched, .. } => {
+                if matched == 0 {
+                    return Ok(doc! {
+                        "value": null,
+                        "ok": Bson::Double(1.0),
+                    });
                 } else {
                     unreachable!(
                         "Unexpected numeric result for a findAndUpdate command: {:#?}",
@@ -82,6 +64,15 @@ impl Handler for FindAndModify {
                     );
                 }
             }
+            UpdateResult::Upserted(value) => Ok(doc! {
+                "value": if new { Bson::Document(value.clone()) } else { Bson::Null },
+                "lastErrorObject": {
+                    "updatedExisting": false,
+                    "upserted": value.get("_id").unwrap(),
+                    "n": 1,
+                },
+                "ok": Bson::Double(1.0),
+            }),
             UpdateResult::Document(value) => Ok(doc! {
                 "n": Bson::Int64(1),
                 "value": value,
@@ -90,28 +81,3 @@ impl Handler for FindAndModify {
         }
     }
 }
-
-fn extract_operator_values(doc: &Document) -> Document {
-    let mut res = Document::new();
-    for (key, value) in doc {
-        if key.starts_with("$") {
-            if let Some(value) = value.as_document() {
-                res.extend(value.clone());
-            }
-        }
-    }
-    res
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_extract_operator_values() {
-        assert_eq!(
-            extract_operator_values(&doc! { "$inc": { "score": 1 }, "$set": { "name": "abc" } }),
-            doc! { "score": 1, "name": "abc" }
-        );
-    }
-}
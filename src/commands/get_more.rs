@@ -0,0 +1,138 @@
+use crate::change_stream::{fetch_changes, next_changes, DEFAULT_AWAIT_TIME};
+use crate::commands::Handler;
+use crate::cursor::{get_batch_size, CursorSource};
+use crate::handler::{CommandExecutionError, Request};
+use bson::{doc, Bson, Document};
+use std::time::Duration;
+
+pub struct GetMore {}
+
+impl Handler for GetMore {
+    fn new() -> Self {
+        GetMore {}
+    }
+
+    fn handle(
+        &self,
+        request: &Request,
+        docs: &Vec<Document>,
+    ) -> Result<Document, CommandExecutionError> {
+        let doc = &docs[0];
+        let db = doc.get_str("$db").unwrap();
+        let collection = doc.get_str("collection").unwrap_or_default();
+        let ns = format!("{}.{}", db, collection);
+        let cursor_id = match doc.get("getMore") {
+            Some(Bson::Int64(id)) => *id,
+            Some(Bson::Int32(id)) => *id as i64,
+            _ => {
+                return Ok(doc! {
+                    "ok": Bson::Double(0.0),
+                    "errmsg": "getMore must be a 64-bit integer",
+                    "code": Bson::Int32(14),
+                    "codeName": "TypeMismatch",
+                })
+            }
+        };
+        let batch_size = get_batch_size(doc);
+
+        let cursors = request.get_cursors();
+        let mut cursor = match cursors.checkout(cursor_id) {
+            Some(cursor) => cursor,
+            None => {
+                return Ok(doc! {
+                    "ok": Bson::Double(0.0),
+                    "errmsg": format!("cursor id {} not found", cursor_id),
+                    "code": Bson::Int32(43),
+                    "codeName": "CursorNotFound",
+                })
+            }
+        };
+
+        if !cursor.is_usable_by(&request.get_auth()) {
+            cursors.restore(cursor_id, cursor);
+            return Ok(doc! {
+                "ok": Bson::Double(0.0),
+                "errmsg": format!("cursor id {} was not created by the authenticated user", cursor_id),
+                "code": Bson::Int32(13),
+                "codeName": "Unauthorized",
+            });
+        }
+
+        if cursor.ns != ns {
+            let errmsg = format!(
+                "Requested getMore on namespace '{}', but cursor belongs to a different namespace {}",
+                ns, cursor.ns
+            );
+            cursors.restore(cursor_id, cursor);
+            return Ok(doc! {
+                "ok": Bson::Double(0.0),
+                "errmsg": errmsg,
+                "code": Bson::Int32(13),
+                "codeName": "Unauthorized",
+            });
+        }
+
+        // change streams wait for changes instead of running out of them
+        if let CursorSource::ChangeStream { sql, token } = &mut cursor.source {
+            let wait = match doc.get("maxTimeMS") {
+                Some(Bson::Int32(ms)) => Duration::from_millis(*ms as u64),
+                Some(Bson::Int64(ms)) => Duration::from_millis(*ms as u64),
+                _ => DEFAULT_AWAIT_TIME,
+            };
+            // notifications aren't delivered inside a transaction
+            let changes = if request.in_transaction() {
+                fetch_changes(&mut request.get_client(), sql, *token, batch_size)
+            } else {
+                next_changes(request.get_changes(), sql, *token, batch_size, wait)
+            };
+            let changes = match changes {
+                Ok(changes) => changes,
+                Err(e) => {
+                    log::error!("Error during getMore on a change stream: {}", e);
+                    return Ok(e.to_doc());
+                }
+            };
+            *token = changes.token;
+            cursors.restore(cursor_id, cursor);
+            return Ok(doc! {
+                "cursor": doc! {
+                    "nextBatch": changes.docs,
+                    "postBatchResumeToken": changes.token.to_doc(),
+                    "id": Bson::Int64(cursor_id),
+                    "ns": ns,
+                },
+                "ok": Bson::Double(1.0),
+            });
+        }
+
+        let mut client = request.get_client();
+        cursors.drop_released(&mut client);
+        let batch = match cursor.next_batch(&mut client, batch_size) {
+            Ok(batch) => batch,
+            Err(e) => {
+                log::error!("Error during getMore: {:?} - doc: {}", e, &doc);
+                return Err(CommandExecutionError::new(format!(
+                    "error during getMore: {:?}",
+                    e
+                )));
+            }
+        };
+
+        let id = if batch.exhausted {
+            cursor.close(&mut client);
+            0
+        } else {
+            cursors.restore(cursor_id, cursor);
+            cursor_id
+        };
+
+        Ok(doc! {
+            "cursor": doc! {
+                "nextBatch": batch.docs,
+                "id": Bson::Int64(id),
+                "ns": ns,
+            },
+            "ok": Bson::Double(1.0),
+        })
+    }
+}
@@ -0,0 +1,86 @@
+use super::create_role::check_roles_exist;
+use super::create_user::bad_value;
+use super::drop_user::user_not_found;
+use crate::auth::roles::{role_names, RoleName};
+use crate::auth::users::parse_roles;
+use crate::commands::Handler;
+use crate::handler::{CommandExecutionError, Request};
+use bson::{doc, Bson, Document};
+
+pub struct GrantRolesToUser {}
+
+impl Handler for GrantRolesToUser {
+    fn new() -> Self {
+        GrantRolesToUser {}
+    }
+
+    fn handle(
+        &self,
+        request: &Request,
+        docs: &Vec<Document>,
+    ) -> Result<Document, CommandExecutionError> {
+        update_roles(request, &docs[0], "grantRolesToUser")
+    }
+}
+
+/// Adds or removes roles of a user, shared with `revokeRolesFromUser`.
+pub fn update_roles(
+    request: &Request,
+    doc: &Document,
+    command: &str,
+) -> Result<Document, CommandExecutionError> {
+    let db = doc.get_str("$db").unwrap();
+    let name = match doc.get_str(command) {
+        Ok(name) => name,
+        Err(_) => return Ok(bad_value("User name must be a string")),
+    };
+    let roles = match doc.get("roles") {
+        Some(roles) => match parse_roles(roles, db) {
+            Ok(roles) if !roles.is_empty() => roles,
+            Ok(_) => {
+                return Ok(bad_value(&format!(
+                    "\"{}\" command requires a non-empty \"roles\" array",
+                    command
+                )))
+            }
+            Err(e) => return Ok(bad_value(&e.message)),
+        },
+        None => {
+            return Ok(bad_value(&format!(
+                "\"{}\" command requires a non-empty \"roles\" array",
+                command
+            )))
+        }
+    };
+
+    let mut client = request.get_client();
+    let mut user = match client.get_user(db, name) {
+        Ok(Some(user)) => user,
+        Ok(None) => return Ok(user_not_found(name, db)),
+        Err(e) => return Err(CommandExecutionError::new(e.to_string())),
+    };
+    if let Some(err) = check_roles_exist(&mut client, &roles)? {
+        return Ok(err);
+    }
+
+    let changed: Vec<RoleName> = roles.iter().filter_map(RoleName::from_bson).collect();
+    let mut current = role_names(&user);
+    if command == "grantRolesToUser" {
+        for role in changed {
+            if !current.contains(&role) {
+                current.push(role);
+            }
+        }
+    } else {
+        current.retain(|role| !changed.contains(role));
+    }
+    let current: Vec<Bson> = current.iter().map(|r| r.to_doc().into()).collect();
+    user.insert("roles", current);
+
+    match client.replace_user(&user) {
+        Ok(_) => Ok(doc! {
+            "ok": Bson::Double(1.0),
+        }),
+        Err(e) => Err(CommandExecutionError::new(e.to_string())),
+    }
+}
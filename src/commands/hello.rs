@@ -1,6 +1,8 @@
+use crate::auth::users::mechanisms;
 use crate::commands::Handler;
 use crate::handler::{CommandExecutionError, Request};
-use crate::wire::{MAX_DOCUMENT_LEN, MAX_MSG_LEN};
+use crate::session::LOGICAL_SESSION_TIMEOUT_MINUTES;
+use crate::wire::{Compressor, MAX_DOCUMENT_LEN, MAX_MSG_LEN};
 use bson::{doc, Bson, Document};
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -13,14 +15,14 @@ impl Handler for Hello {
 
     fn handle(
         &self,
-        _request: &Request,
-        _msg: &Vec<Document>,
+        request: &Request,
+        docs: &Vec<Document>,
     ) -> Result<Document, CommandExecutionError> {
         let local_time = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_millis();
-        Ok(doc! {
+        let mut reply = doc! {
             "isWritablePrimary": Bson::Boolean(true),
             "maxBsonObjectSize": MAX_DOCUMENT_LEN,
             "maxMessageSizeBytes": MAX_MSG_LEN,
@@ -29,7 +31,45 @@ impl Handler for Hello {
             "minWireVersion": 0,
             "maxWireVersion": 13,
             "readOnly": Bson::Boolean(false),
+            "logicalSessionTimeoutMinutes": LOGICAL_SESSION_TIMEOUT_MINUTES,
             "ok": Bson::Double(1.into())
-        })
+        };
+        if let Some(mechs) = sasl_supported_mechs(request, &docs[0]) {
+            reply.insert("saslSupportedMechs", mechs);
+        }
+        if let Some(compressors) = compression(&docs[0]) {
+            reply.insert("compression", compressors);
+        }
+        Ok(reply)
+    }
+}
+
+/// Lists the mechanisms a user can authenticate with, asked for by drivers
+/// during the handshake with `saslSupportedMechs: "db.user"`.
+pub fn sasl_supported_mechs(request: &Request, doc: &Document) -> Option<Vec<Bson>> {
+    let name = doc.get_str("saslSupportedMechs").ok()?;
+    let (db, user) = name.split_once('.')?;
+    match request.get_client().get_user(db, user) {
+        Ok(user) => Some(user.map(|u| mechanisms(&u)).unwrap_or_default()),
+        Err(e) => {
+            log::error!("Error looking up user {}: {}", name, e);
+            None
+        }
+    }
+}
+
+/// Picks the compressors the client asked for with `compression` that are
+/// also supported here, keeping the client's order of preference.
+pub fn compression(doc: &Document) -> Option<Vec<Bson>> {
+    let requested = doc.get_array("compression").ok()?;
+    let compressors: Vec<Bson> = requested
+        .iter()
+        .filter_map(|name| Compressor::from_name(name.as_str()?))
+        .map(|compressor| Bson::String(compressor.name().to_string()))
+        .collect();
+    if compressors.is_empty() {
+        None
+    } else {
+        Some(compressors)
     }
 }
@@ -1,9 +1,17 @@
+use crate::commands::delete::write_error;
+use crate::commands::list_indexes::{index_name, parse_index_definition};
 use crate::handler::{CommandExecutionError, Request};
+use crate::pg::PgDb;
 use crate::{commands::Handler, pg::SqlParam};
 use bson::{doc, Bson, Document};
+use postgres::error::SqlState;
 
 pub struct Insert {}
 
+// documents written by each multi-row INSERT, well below the limit of
+// parameters Postgres takes on a statement
+const INSERT_BATCH_SIZE: usize = 1000;
+
 impl Handler for Insert {
     fn new() -> Self {
         Insert {}
@@ -17,17 +25,183 @@ impl Handler for Insert {
         let doc = &docs[0];
         let db = doc.get_str("$db").unwrap();
         let collection = doc.get_str("insert").unwrap();
-        let mut docs: Vec<Document> = doc.get_array("documents").unwrap().iter().map(|d| d.as_document().unwrap().clone()).collect();
+        let ordered = doc.get_bool("ordered").unwrap_or(true);
+
+        let mut docs = vec![];
+        for document in doc.get_array("documents").unwrap() {
+            match document.as_document() {
+                Some(document) => docs.push(document.clone()),
+                None => {
+                    return Ok(doc! {
+                        "ok": Bson::Double(0.0),
+                        "errmsg": "BSON field 'insert.documents' must be an array of objects",
+                        "code": Bson::Int32(14),
+                        "codeName": "TypeMismatch",
+                    })
+                }
+            }
+        }
+        for doc in docs.iter_mut() {
+            if !doc.contains_key("_id") {
+                doc.insert("_id", bson::oid::ObjectId::new());
+            }
+        }
 
         let mut client = request.get_client();
-        client.create_table_if_not_exists(db, collection).unwrap();
+        client
+            .create_table_if_not_exists(db, collection)
+            .map_err(|e| CommandExecutionError::new(e.to_string()))?;
 
         let sp = SqlParam::new(db, collection);
-        let inserted = client.insert_docs(sp, &mut docs).unwrap();
+        let mut n = 0;
+        let mut write_errors = vec![];
+
+        // valid documents are written in batches, stopping at the first
+        // invalid one when the insert is ordered
+        let mut batch: Vec<(usize, &Document)> = vec![];
+        for (index, doc) in docs.iter().enumerate() {
+            match validate_id(doc) {
+                Ok(()) => batch.push((index, doc)),
+                Err(errmsg) => {
+                    write_errors.push(doc! {
+                        "index": Bson::Int32(index as i32),
+                        "code": Bson::Int32(53),
+                        "errmsg": errmsg,
+                    });
+                    if ordered {
+                        break;
+                    }
+                }
+            }
+        }
+
+        for chunk in batch.chunks(INSERT_BATCH_SIZE) {
+            let docs: Vec<Document> = chunk.iter().map(|(_, doc)| (*doc).clone()).collect();
+            if let Ok(inserted) = client.atomically(|client| client.insert_docs(&sp, &docs)) {
+                n += inserted;
+                continue;
+            }
+
+            // the batch was rejected as a whole, so find out which documents
+            // are at fault by writing them one at a time
+            let mut failed = false;
+            for (index, doc) in chunk {
+                match client.atomically(|client| client.insert_doc(sp.clone(), doc)) {
+                    Ok(_) => n += 1,
+                    Err(e) => {
+                        log::error!("Error inserting into {}: {}", sp, e);
+                        let error = duplicate_key_error(&mut client, &sp, *index, doc, &e)
+                            .unwrap_or_else(|| write_error(*index, &e));
+                        write_errors.push(error);
+                        if ordered {
+                            failed = true;
+                            break;
+                        }
+                    }
+                }
+            }
+            if failed {
+                break;
+            }
+        }
+
+        // an ordered insert reports only its first error, wherever it came from
+        write_errors.sort_by_key(|e| e.get_i32("index").unwrap());
+        if ordered {
+            write_errors.truncate(1);
+        }
+
+        let mut res = doc! {
+            "n": Bson::Int64(n as i64),
+        };
+        if !write_errors.is_empty() {
+            res.insert("writeErrors", write_errors);
+        }
+        res.insert("ok", Bson::Double(1.0));
+        Ok(res)
+    }
+}
+
+/// Builds the `E11000` write error for a document that violates a unique
+/// index, or returns `None` if `error` is of some other kind.
+pub fn duplicate_key_error(
+    client: &mut PgDb,
+    sp: &SqlParam,
+    index: usize,
+    doc: &Document,
+    error: &eyre::Report,
+) -> Option<Document> {
+    let db_error = error.downcast_ref::<postgres::Error>()?.as_db_error()?;
+    if db_error.code() != &SqlState::UNIQUE_VIOLATION {
+        return None;
+    }
+    let pg_name = db_error.constraint().unwrap_or_default();
+
+    let fields = client
+        .get_table_indexes(&sp.db, &sp.collection)
+        .unwrap_or_default()
+        .iter()
+        .find(|row| row.get::<_, &str>("indexname") == pg_name)
+        .map(|row| parse_index_definition(row.get("indexdef")))
+        .unwrap_or_default();
+
+    let name = index_name(sp, pg_name);
+    let mut key_pattern = doc! {};
+    let mut key_value = doc! {};
+    for field in fields {
+        key_pattern.insert(&field, 1);
+        key_value.insert(&field, get_path(doc, &field).unwrap_or(Bson::Null));
+    }
+
+    Some(doc! {
+        "index": Bson::Int32(index as i32),
+        "code": Bson::Int32(11000),
+        "keyPattern": key_pattern,
+        "keyValue": key_value.clone(),
+        "errmsg": format!(
+            "E11000 duplicate key error collection: {}.{} index: {} dup key: {}",
+            sp.db, sp.collection, name, key_value
+        ),
+    })
+}
+
+fn validate_id(doc: &Document) -> Result<(), String> {
+    match doc.get("_id") {
+        Some(Bson::Array(_)) => Err("The '_id' value cannot be of type array".to_string()),
+        Some(Bson::RegularExpression(_)) => {
+            Err("The '_id' value cannot be of type regex".to_string())
+        }
+        Some(Bson::Undefined) => Err("The '_id' value cannot be of type undefined".to_string()),
+        _ => Ok(()),
+    }
+}
+
+// value at a dotted path of `doc`
+fn get_path(doc: &Document, path: &str) -> Option<Bson> {
+    let mut value = doc.get(path.split('.').next()?)?;
+    for key in path.split('.').skip(1) {
+        value = value.as_document()?.get(key)?;
+    }
+    Some(value.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_path() {
+        let doc = doc! { "a": { "b": { "c": 1 } }, "x": 2 };
+        assert_eq!(get_path(&doc, "x"), Some(Bson::Int32(2)));
+        assert_eq!(get_path(&doc, "a.b.c"), Some(Bson::Int32(1)));
+        assert_eq!(get_path(&doc, "a.z"), None);
+        assert_eq!(get_path(&doc, "x.y"), None);
+    }
 
-        Ok(doc! {
-          "n": Bson::Int64(inserted.try_into().unwrap()),
-          "ok": Bson::Double(1.0),
-        })
+    #[test]
+    fn test_validate_id() {
+        assert!(validate_id(&doc! { "_id": 1 }).is_ok());
+        assert!(validate_id(&doc! { "x": 1 }).is_ok());
+        assert!(validate_id(&doc! { "_id": [1, 2] }).is_err());
     }
 }
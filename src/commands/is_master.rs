@@ -1,5 +1,7 @@
+use super::hello::{compression, sasl_supported_mechs};
 use crate::commands::Handler;
 use crate::handler::{CommandExecutionError, Request};
+use crate::session::LOGICAL_SESSION_TIMEOUT_MINUTES;
 use crate::wire::{MAX_DOCUMENT_LEN, MAX_MSG_LEN};
 use bson::{doc, Bson, Document};
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -13,14 +15,14 @@ impl Handler for IsMaster {
 
     fn handle(
         &self,
-        _request: &Request,
-        _msg: &Vec<Document>,
+        request: &Request,
+        docs: &Vec<Document>,
     ) -> Result<Document, CommandExecutionError> {
         let local_time = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_millis();
-        Ok(doc! {
+        let mut reply = doc! {
           "ismaster": Bson::Boolean(true),
           "maxBsonObjectSize": MAX_DOCUMENT_LEN,
           "maxMessageSizeBytes": MAX_MSG_LEN,
@@ -29,7 +31,15 @@ impl Handler for IsMaster {
           "minWireVersion": 0,
           "maxWireVersion": 13,
           "readOnly": Bson::Boolean(false),
+          "logicalSessionTimeoutMinutes": LOGICAL_SESSION_TIMEOUT_MINUTES,
           "ok": Bson::Double(1.into())
-        })
+        };
+        if let Some(mechs) = sasl_supported_mechs(request, &docs[0]) {
+            reply.insert("saslSupportedMechs", mechs);
+        }
+        if let Some(compressors) = compression(&docs[0]) {
+            reply.insert("compression", compressors);
+        }
+        Ok(reply)
     }
 }
@@ -0,0 +1,73 @@
+use crate::commands::Handler;
+use crate::cursor::Kill;
+use crate::handler::{CommandExecutionError, Request};
+use bson::{doc, Bson, Document};
+
+pub struct KillCursors {}
+
+impl Handler for KillCursors {
+    fn new() -> Self {
+        KillCursors {}
+    }
+
+    fn handle(
+        &self,
+        request: &Request,
+        docs: &Vec<Document>,
+    ) -> Result<Document, CommandExecutionError> {
+        let doc = &docs[0];
+        let ns = format!(
+            "{}.{}",
+            doc.get_str("$db").unwrap_or_default(),
+            doc.get_str("killCursors").unwrap_or_default()
+        );
+        let ids = match doc.get_array("cursors") {
+            Ok(ids) => ids,
+            Err(_) => {
+                return Ok(doc! {
+                    "ok": Bson::Double(0.0),
+                    "errmsg": "killCursors requires a 'cursors' array",
+                    "code": Bson::Int32(9),
+                    "codeName": "FailedToParse",
+                })
+            }
+        };
+
+        let cursors = request.get_cursors();
+        let auth = request.get_auth();
+        let mut killed = vec![];
+        let mut not_found = vec![];
+        for id in ids {
+            let id = match id {
+                Bson::Int64(id) => *id,
+                Bson::Int32(id) => *id as i64,
+                _ => continue,
+            };
+            // cursors of other namespaces weren't covered by the
+            // authorization of the command, so they aren't found here
+            match cursors.kill(id, &ns, &auth) {
+                Kill::Killed => killed.push(Bson::Int64(id)),
+                Kill::NotFound => not_found.push(Bson::Int64(id)),
+                Kill::Unauthorized => {
+                    return Ok(doc! {
+                        "ok": Bson::Double(0.0),
+                        "errmsg": format!("not authorized to kill cursor {} on {}", id, ns),
+                        "code": Bson::Int32(13),
+                        "codeName": "Unauthorized",
+                    })
+                }
+            }
+        }
+        drop(auth);
+
+        cursors.drop_released(&mut request.get_client());
+
+        Ok(doc! {
+            "cursorsKilled": killed,
+            "cursorsNotFound": not_found,
+            "cursorsAlive": Bson::Array(vec![]),
+            "cursorsUnknown": Bson::Array(vec![]),
+            "ok": Bson::Double(1.0),
+        })
+    }
+}
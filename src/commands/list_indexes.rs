@@ -31,22 +31,28 @@ impl Handler for ListIndexes {
         }
 
         let mut indexes: Vec<Bson> = vec![];
-        for table in tables {
-            for row in &mut client.get_table_indexes(&sp.db, &table).unwrap() {
-                let name: String = row.get("indexname");
-                let def: String = row.get("indexdef");
+        for row in &mut client.get_table_indexes(&sp.db, collection).unwrap() {
+            let name: String = row.get("indexname");
+            let def: String = row.get("indexdef");
+            // the numeric one is part of the _id index for clients
+            if name == sp.id_number_index_name() {
+                continue;
+            }
 
-                let mut keys: Document = doc! {};
-                for field in parse_index_definition(def.as_str()) {
-                    keys.insert(field, 1);
-                }
+            let mut keys: Document = doc! {};
+            for field in parse_index_definition(def.as_str()) {
+                keys.insert(field, 1);
+            }
 
-                indexes.push(Bson::Document(doc! {
-                    "v": 2,
-                    "key": keys,
-                    "name": name,
-                }));
+            let mut index = doc! {
+                "v": 2,
+                "key": keys,
+                "name": index_name(&sp, &name),
+            };
+            if def.starts_with("CREATE UNIQUE INDEX") && name != sp.id_index_name() {
+                index.insert("unique", true);
             }
+            indexes.push(Bson::Document(index));
         }
 
         return Ok(doc! {
@@ -60,7 +66,16 @@ impl Handler for ListIndexes {
     }
 }
 
-fn parse_index_definition(def: &str) -> Vec<String> {
+/// Name an index is known by to clients, given its name on Postgres.
+pub fn index_name(sp: &SqlParam, name: &str) -> String {
+    if name == sp.id_index_name() || name == sp.id_number_index_name() {
+        "_id_".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+pub fn parse_index_definition(def: &str) -> Vec<String> {
     let regex = Regex::new(r"\s->\s'(.*?)'").unwrap();
     def.split("USING btree ")
         .nth(1)
@@ -0,0 +1,23 @@
+use crate::commands::Handler;
+use crate::handler::{CommandExecutionError, Request};
+use bson::{doc, Bson, Document};
+
+pub struct Logout {}
+
+impl Handler for Logout {
+    fn new() -> Self {
+        Logout {}
+    }
+
+    fn handle(
+        &self,
+        request: &Request,
+        docs: &Vec<Document>,
+    ) -> Result<Document, CommandExecutionError> {
+        let db = docs[0].get_str("$db").unwrap();
+        request.get_auth().logout(db);
+        Ok(doc! {
+            "ok": Bson::Double(1.0),
+        })
+    }
+}
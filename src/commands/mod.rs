@@ -1,55 +1,91 @@
 use crate::handler::{CommandExecutionError, Request};
 use bson::Document;
 
+mod abort_transaction;
 mod aggregate;
 mod build_info;
 mod coll_stats;
+mod commit_transaction;
 mod connection_status;
 mod count;
 mod create;
 mod create_indexes;
+mod create_role;
+mod create_user;
 mod db_stats;
 mod delete;
 mod drop;
 mod drop_database;
+mod drop_role;
+mod drop_user;
+mod end_sessions;
 mod find;
 mod find_and_modify;
 mod get_cmd_line_opts;
+mod get_more;
 mod get_parameter;
+mod grant_roles_to_user;
 mod hello;
 mod insert;
 mod is_master;
+mod kill_cursors;
 mod list_collections;
 mod list_databases;
 mod list_indexes;
+mod logout;
 mod ping;
+mod refresh_sessions;
+mod revoke_roles_from_user;
+mod sasl_continue;
+mod sasl_start;
+mod start_session;
 mod update;
+mod update_user;
+mod users_info;
 mod whats_my_uri;
 
+pub use self::abort_transaction::AbortTransaction;
 pub use self::aggregate::build_sql;
 pub use self::aggregate::Aggregate;
 pub use self::build_info::BuildInfo;
 pub use self::coll_stats::CollStats;
+pub use self::commit_transaction::CommitTransaction;
 pub use self::connection_status::ConnectionStatus;
 pub use self::count::Count;
 pub use self::create::Create;
 pub use self::create_indexes::CreateIndexes;
+pub use self::create_role::CreateRole;
+pub use self::create_user::CreateUser;
 pub use self::db_stats::DbStats;
 pub use self::delete::Delete;
 pub use self::drop::Drop;
 pub use self::drop_database::DropDatabase;
+pub use self::drop_role::DropRole;
+pub use self::drop_user::DropUser;
+pub use self::end_sessions::EndSessions;
 pub use self::find::Find;
 pub use self::find_and_modify::FindAndModify;
 pub use self::get_cmd_line_opts::GetCmdLineOpts;
+pub use self::get_more::GetMore;
 pub use self::get_parameter::GetParameter;
+pub use self::grant_roles_to_user::GrantRolesToUser;
 pub use self::hello::Hello;
 pub use self::insert::Insert;
 pub use self::is_master::IsMaster;
+pub use self::kill_cursors::KillCursors;
 pub use self::list_collections::ListCollections;
 pub use self::list_databases::ListDatabases;
 pub use self::list_indexes::ListIndexes;
+pub use self::logout::Logout;
 pub use self::ping::Ping;
+pub use self::refresh_sessions::RefreshSessions;
+pub use self::revoke_roles_from_user::RevokeRolesFromUser;
+pub use self::sasl_continue::SaslContinue;
+pub use self::sasl_start::SaslStart;
+pub use self::start_session::StartSession;
 pub use self::update::Update;
+pub use self::update_user::UpdateUser;
+pub use self::users_info::UsersInfo;
 pub use self::whats_my_uri::WhatsMyUri;
 
 pub trait Handler {
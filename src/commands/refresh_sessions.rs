@@ -0,0 +1,29 @@
+use crate::commands::end_sessions::session_ids;
+use crate::commands::Handler;
+use crate::handler::{CommandExecutionError, Request};
+use bson::{doc, Bson, Document};
+
+pub struct RefreshSessions {}
+
+impl Handler for RefreshSessions {
+    fn new() -> Self {
+        RefreshSessions {}
+    }
+
+    fn handle(
+        &self,
+        request: &Request,
+        docs: &Vec<Document>,
+    ) -> Result<Document, CommandExecutionError> {
+        let lsids = match session_ids(&docs[0], "refreshSessions") {
+            Ok(lsids) => lsids,
+            Err(err) => return Ok(err),
+        };
+        for id in lsids {
+            request.get_sessions().refresh(&id);
+        }
+        Ok(doc! {
+            "ok": Bson::Double(1.0),
+        })
+    }
+}
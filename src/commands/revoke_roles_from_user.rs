@@ -0,0 +1,20 @@
+use super::grant_roles_to_user::update_roles;
+use crate::commands::Handler;
+use crate::handler::{CommandExecutionError, Request};
+use bson::Document;
+
+pub struct RevokeRolesFromUser {}
+
+impl Handler for RevokeRolesFromUser {
+    fn new() -> Self {
+        RevokeRolesFromUser {}
+    }
+
+    fn handle(
+        &self,
+        request: &Request,
+        docs: &Vec<Document>,
+    ) -> Result<Document, CommandExecutionError> {
+        update_roles(request, &docs[0], "revokeRolesFromUser")
+    }
+}
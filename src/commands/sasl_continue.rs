@@ -0,0 +1,78 @@
+use super::sasl_start::{authentication_failed, get_payload};
+use crate::commands::Handler;
+use crate::handler::{CommandExecutionError, Request};
+use bson::{doc, spec::BinarySubtype, Binary, Bson, Document};
+
+pub struct SaslContinue {}
+
+impl Handler for SaslContinue {
+    fn new() -> Self {
+        SaslContinue {}
+    }
+
+    fn handle(
+        &self,
+        request: &Request,
+        docs: &Vec<Document>,
+    ) -> Result<Document, CommandExecutionError> {
+        let doc = &docs[0];
+        let payload = get_payload(doc).unwrap_or_default();
+
+        let mut auth = request.get_auth();
+        let mut session = match auth.take_session() {
+            Some(session) => session,
+            None => {
+                return Ok(doc! {
+                    "ok": Bson::Double(0.0),
+                    "errmsg": "No SASL session state found",
+                    "code": Bson::Int32(17),
+                    "codeName": "ProtocolError",
+                })
+            }
+        };
+
+        // the client acknowledges the server signature with an empty message
+        if session.conversation.is_done() {
+            return Ok(doc! {
+                "conversationId": Bson::Int32(1),
+                "done": true,
+                "payload": Binary { subtype: BinarySubtype::Generic, bytes: vec![] },
+                "ok": Bson::Double(1.0),
+            });
+        }
+
+        match session.conversation.finish(&payload) {
+            Ok(server_final) => {
+                let user = session.conversation.user.clone();
+                log::info!(
+                    "Successfully authenticated as {}@{} using {}",
+                    user,
+                    session.db,
+                    session.conversation.mechanism.name()
+                );
+                auth.authenticate(&user, &session.db);
+
+                let done = session.skip_empty_exchange;
+                if !done {
+                    auth.start_session(session);
+                }
+                Ok(doc! {
+                    "conversationId": Bson::Int32(1),
+                    "done": done,
+                    "payload": Binary { subtype: BinarySubtype::Generic, bytes: server_final },
+                    "ok": Bson::Double(1.0),
+                })
+            }
+            Err(e) => {
+                log::info!(
+                    "Authentication with {} failed for {}@{}: {}",
+                    session.conversation.mechanism.name(),
+                    session.conversation.user,
+                    session.db,
+                    e
+                );
+                Ok(authentication_failed())
+            }
+        }
+    }
+}
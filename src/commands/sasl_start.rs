@@ -0,0 +1,113 @@
+use crate::auth::users::get_credentials;
+use crate::auth::{SaslSession, ScramConversation, ScramMechanism};
+use crate::commands::Handler;
+use crate::handler::{CommandExecutionError, Request};
+use bson::{doc, spec::BinarySubtype, Binary, Bson, Document};
+
+pub struct SaslStart {}
+
+impl Handler for SaslStart {
+    fn new() -> Self {
+        SaslStart {}
+    }
+
+    fn handle(
+        &self,
+        request: &Request,
+        docs: &Vec<Document>,
+    ) -> Result<Document, CommandExecutionError> {
+        let doc = &docs[0];
+        let db = doc.get_str("$db").unwrap();
+        let mechanism = doc.get_str("mechanism").unwrap_or_default();
+        let mechanism = match ScramMechanism::from_name(mechanism) {
+            Some(mechanism) => mechanism,
+            None => {
+                return Ok(doc! {
+                    "ok": Bson::Double(0.0),
+                    "errmsg": format!("Received authentication for mechanism {} which is not enabled", mechanism),
+                    "code": Bson::Int32(334),
+                    "codeName": "MechanismUnavailable",
+                })
+            }
+        };
+        let payload = match get_payload(doc) {
+            Some(payload) => payload,
+            None => {
+                return Ok(doc! {
+                    "ok": Bson::Double(0.0),
+                    "errmsg": "saslStart payload must be binary data",
+                    "code": Bson::Int32(14),
+                    "codeName": "TypeMismatch",
+                })
+            }
+        };
+        let skip_empty_exchange = doc
+            .get_document("options")
+            .and_then(|o| o.get_bool("skipEmptyExchange"))
+            .unwrap_or(false);
+
+        let user = match ScramConversation::parse_user(&payload) {
+            Ok(user) => user,
+            Err(e) => {
+                log::debug!("Invalid SCRAM client first message: {}", e);
+                return Ok(authentication_failed());
+            }
+        };
+
+        let mut client = request.get_client();
+        let credentials = match client.get_user(db, &user) {
+            Ok(Some(user)) => get_credentials(&user, mechanism),
+            Ok(None) => None,
+            Err(e) => return Err(CommandExecutionError::new(e.to_string())),
+        };
+        let credentials = match credentials {
+            Some(credentials) => credentials,
+            None => {
+                log::info!(
+                    "Authentication with {} failed for {}@{}: user not found",
+                    mechanism.name(),
+                    user,
+                    db
+                );
+                return Ok(authentication_failed());
+            }
+        };
+
+        match ScramConversation::start(mechanism, &payload, credentials) {
+            Ok((conversation, server_first)) => {
+                let id = request.get_auth().start_session(SaslSession {
+                    db: db.to_string(),
+                    conversation,
+                    skip_empty_exchange,
+                });
+                Ok(doc! {
+                    "conversationId": Bson::Int32(id),
+                    "done": false,
+                    "payload": Binary { subtype: BinarySubtype::Generic, bytes: server_first },
+                    "ok": Bson::Double(1.0),
+                })
+            }
+            Err(e) => {
+                log::debug!("Invalid SCRAM client first message: {}", e);
+                Ok(authentication_failed())
+            }
+        }
+    }
+}
+
+pub fn get_payload(doc: &Document) -> Option<Vec<u8>> {
+    match doc.get("payload") {
+        Some(Bson::Binary(payload)) => Some(payload.bytes.clone()),
+        Some(Bson::String(payload)) => Some(payload.as_bytes().to_vec()),
+        _ => None,
+    }
+}
+
+pub fn authentication_failed() -> Document {
+    doc! {
+        "ok": Bson::Double(0.0),
+        "errmsg": "Authentication failed.",
+        "code": Bson::Int32(18),
+        "codeName": "AuthenticationFailed",
+    }
+}
@@ -0,0 +1,25 @@
+use crate::commands::Handler;
+use crate::handler::{CommandExecutionError, Request};
+use crate::session::LOGICAL_SESSION_TIMEOUT_MINUTES;
+use bson::{doc, Bson, Document};
+
+pub struct StartSession {}
+
+impl Handler for StartSession {
+    fn new() -> Self {
+        StartSession {}
+    }
+
+    fn handle(
+        &self,
+        request: &Request,
+        _docs: &Vec<Document>,
+    ) -> Result<Document, CommandExecutionError> {
+        let lsid = request.get_sessions().start();
+        Ok(doc! {
+            "id": lsid,
+            "timeoutMinutes": LOGICAL_SESSION_TIMEOUT_MINUTES,
+            "ok": Bson::Double(1.0),
+        })
+    }
+}
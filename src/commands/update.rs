@@ -1,6 +1,6 @@
 #![allow(dead_code)]
 use crate::handler::{CommandExecutionError, Request};
-use crate::parser::parse_update;
+use crate::parser::{parse_update, InvalidQueryError};
 use crate::{commands::Handler, pg::SqlParam, pg::UpdateResult};
 use bson::{doc, Bson, Document};
 
@@ -26,7 +26,9 @@ impl Handler for Update {
         client.create_table_if_not_exists(db, collection).unwrap();
 
         let mut n = 0;
-        for update in updates {
+        let mut n_modified = 0;
+        let mut upserted = vec![];
+        for (index, update) in updates.iter().enumerate() {
             let doc = update.as_document().unwrap();
             let q = doc.get_document("q").unwrap();
             let update_doc = parse_update(doc.get_document("u").unwrap());
@@ -48,16 +50,36 @@ impl Handler for Update {
             );
 
             match result {
-                Ok(UpdateResult::Count(total)) => n += total,
-                Ok(UpdateResult::Document(_)) => n += 1,
-                Err(err) => return Err(CommandExecutionError::new(format!("{:?}", err))),
+                Ok(UpdateResult::Count { matched, modified }) => {
+                    n += matched;
+                    n_modified += modified;
+                }
+                Ok(UpdateResult::Document(_)) => {
+                    n += 1;
+                    n_modified += 1;
+                }
+                Ok(UpdateResult::Upserted(doc)) => {
+                    n += 1;
+                    upserted.push(doc! {
+                        "index": index as i32,
+                        "_id": doc.get("_id").unwrap(),
+                    });
+                }
+                Err(err) => match err.downcast_ref::<InvalidQueryError>() {
+                    Some(e) => return Ok(e.to_doc()),
+                    None => return Err(CommandExecutionError::new(format!("{:?}", err))),
+                },
             }
         }
 
-        Ok(doc! {
+        let mut res = doc! {
             "n": Bson::Int64(n.try_into().unwrap()),
-            "nModified": Bson::Int64(n as i64),
+            "nModified": Bson::Int64(n_modified as i64),
             "ok": Bson::Double(1.0),
-        })
+        };
+        if !upserted.is_empty() {
+            res.insert("upserted", upserted);
+        }
+        Ok(res)
     }
 }
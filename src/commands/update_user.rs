@@ -0,0 +1,81 @@
+use super::create_role::check_roles_exist;
+use super::create_user::bad_value;
+use super::drop_user::user_not_found;
+use crate::auth::users::{build_credentials, mechanisms, parse_roles};
+use crate::commands::Handler;
+use crate::handler::{CommandExecutionError, Request};
+use bson::{doc, Bson, Document};
+
+pub struct UpdateUser {}
+
+impl Handler for UpdateUser {
+    fn new() -> Self {
+        UpdateUser {}
+    }
+
+    fn handle(
+        &self,
+        request: &Request,
+        docs: &Vec<Document>,
+    ) -> Result<Document, CommandExecutionError> {
+        let doc = &docs[0];
+        let db = doc.get_str("$db").unwrap();
+        let name = match doc.get_str("updateUser") {
+            Ok(name) => name,
+            Err(_) => return Ok(bad_value("User name must be a string")),
+        };
+        if !doc.contains_key("pwd") && !doc.contains_key("roles") && !doc.contains_key("customData")
+        {
+            return Ok(bad_value(
+                "Must specify at least one field to update in updateUser",
+            ));
+        }
+
+        let mut client = request.get_client();
+        let mut user = match client.get_user(db, name) {
+            Ok(Some(user)) => user,
+            Ok(None) => return Ok(user_not_found(name, db)),
+            Err(e) => return Err(CommandExecutionError::new(e.to_string())),
+        };
+
+        if let Some(password) = doc.get("pwd") {
+            let password = match password.as_str() {
+                Some(password) => password,
+                None => return Ok(bad_value("\"pwd\" must be a string")),
+            };
+            // keeps the mechanisms the user had unless new ones are given
+            let current = Bson::Array(mechanisms(&user));
+            let requested = doc.get("mechanisms").or(Some(&current));
+            match build_credentials(name, password, requested) {
+                Ok(credentials) => user.insert("credentials", credentials),
+                Err(e) => return Ok(bad_value(&e.message)),
+            };
+        }
+
+        if let Some(roles) = doc.get("roles") {
+            let roles = match parse_roles(roles, db) {
+                Ok(roles) => roles,
+                Err(e) => return Ok(bad_value(&e.message)),
+            };
+            if let Some(err) = check_roles_exist(&mut client, &roles)? {
+                return Ok(err);
+            }
+            user.insert("roles", roles);
+        }
+
+        match doc.get("customData") {
+            Some(Bson::Document(custom_data)) => {
+                user.insert("customData", custom_data.clone());
+            }
+            Some(_) => return Ok(bad_value("\"customData\" must be an object")),
+            None => {}
+        }
+
+        match client.replace_user(&user) {
+            Ok(_) => Ok(doc! {
+                "ok": Bson::Double(1.0),
+            }),
+            Err(e) => Err(CommandExecutionError::new(e.to_string())),
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use super::create_user::bad_value;
+use crate::auth::users::user_info;
+use crate::commands::Handler;
+use crate::handler::{CommandExecutionError, Request};
+use crate::pg::PgDb;
+use bson::{doc, Bson, Document};
+
+pub struct UsersInfo {}
+
+impl Handler for UsersInfo {
+    fn new() -> Self {
+        UsersInfo {}
+    }
+
+    fn handle(
+        &self,
+        request: &Request,
+        docs: &Vec<Document>,
+    ) -> Result<Document, CommandExecutionError> {
+        let doc = &docs[0];
+        let db = doc.get_str("$db").unwrap();
+        let show_credentials = doc.get_bool("showCredentials").unwrap_or(false);
+        let mut client = request.get_client();
+
+        let users = match doc.get("usersInfo") {
+            Some(Bson::Document(spec)) if spec.get_bool("forAllDBs").unwrap_or(false) => {
+                client.get_users(None)
+            }
+            Some(Bson::Array(specs)) => {
+                let mut users = vec![];
+                for spec in specs {
+                    match find_user(&mut client, spec, db) {
+                        Ok(Some(found)) => users.extend(found),
+                        Ok(None) => return Ok(invalid_spec(spec)),
+                        Err(e) => return Err(CommandExecutionError::new(e.to_string())),
+                    }
+                }
+                Ok(users)
+            }
+            Some(Bson::Int32(_)) | Some(Bson::Int64(_)) | Some(Bson::Double(_)) => {
+                client.get_users(Some(db))
+            }
+            Some(spec) => match find_user(&mut client, spec, db) {
+                Ok(Some(users)) => Ok(users),
+                Ok(None) => return Ok(invalid_spec(spec)),
+                Err(e) => Err(e),
+            },
+            None => return Ok(bad_value("usersInfo is required")),
+        };
+
+        match users {
+            Ok(users) => Ok(doc! {
+                "users": users
+                    .iter()
+                    .map(|u| Bson::Document(user_info(u, show_credentials)))
+                    .collect::<Vec<_>>(),
+                "ok": Bson::Double(1.0),
+            }),
+            Err(e) => Err(CommandExecutionError::new(e.to_string())),
+        }
+    }
+}
+
+/// Finds the user of a `"name"` or `{ user, db }` spec, returning `None` if the
+/// spec itself is invalid.
+fn find_user(client: &mut PgDb, spec: &Bson, db: &str) -> eyre::Result<Option<Vec<Document>>> {
+    let (name, db) = match spec {
+        Bson::String(name) => (name.as_str(), db),
+        Bson::Document(spec) => match (spec.get_str("user"), spec.get_str("db")) {
+            (Ok(name), Ok(db)) => (name, db),
+            _ => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+    Ok(Some(client.get_user(db, name)?.into_iter().collect()))
+}
+
+fn invalid_spec(spec: &Bson) -> Document {
+    bad_value(&format!(
+        "User name must be a string or an object with user and db fields, got {}",
+        spec
+    ))
+}
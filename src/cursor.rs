@@ -0,0 +1,706 @@
+use crate::auth::{AuthState, AuthenticatedUser};
+use crate::change_stream::ResumeToken;
+use crate::deserializer::PostgresJsonDeserializer;
+use crate::pg::{PgDb, SqlParam, SESSIONS_SCHEMA};
+use crate::wire::MAX_DOCUMENT_LEN;
+use bson::{ser, Bson, Document};
+use eyre::Result;
+use postgres::fallible_iterator::FallibleIterator;
+use postgres::Row;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Server-side cursors.
+//
+// Instead of keeping a Postgres portal (and therefore a pooled connection)
+// pinned for every open cursor, each cursor keeps enough state to ask for
+// the rows after the last one it handed out, so cursors can be served by any
+// connection in the pool and idle ones cost nothing but a map entry.
+//
+// Finds are resumed after the sort key of their last document, with the `_id`
+// breaking ties, so each `getMore` is a single index-friendly query that
+// can't skip or repeat documents when rows move around in between. The
+// results of an aggregation that don't fit in the first batch are copied to
+// a table with their position, which later batches read in order.
+
+/// Number of documents returned on the first batch when `batchSize` is absent.
+pub const DEFAULT_BATCH_SIZE: i64 = 101;
+
+/// Idle time after which a cursor is discarded, same default as MongoDB.
+pub const DEFAULT_CURSOR_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+// results of cursors older than this are dropped on startup, more recent ones
+// could still be read by another server on the same database
+const STALE_RESULTS_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+// leaves room for the rest of the reply document around the batch
+const MAX_BATCH_LEN: usize = MAX_DOCUMENT_LEN as usize - 16 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct Cursor {
+    pub ns: String,
+    pub source: CursorSource,
+    // users authenticated on the connection that opened it
+    users: Vec<AuthenticatedUser>,
+    last_used: Instant,
+}
+
+/// Where the next batch of a cursor comes from.
+#[derive(Debug, Clone)]
+pub enum CursorSource {
+    Keyset(KeysetQuery),
+    /// A table of `oxide_sessions` holding the results and the position of
+    /// the last one returned.
+    Materialized {
+        table: String,
+        position: i64,
+    },
+    /// The changes after `token`, which are never exhausted.
+    ChangeStream {
+        sql: String,
+        token: ResumeToken,
+    },
+}
+
+impl Cursor {
+    pub fn new(ns: String, users: &[AuthenticatedUser], source: CursorSource) -> Self {
+        Cursor {
+            ns,
+            source,
+            users: users.to_vec(),
+            last_used: Instant::now(),
+        }
+    }
+
+    /// A tailable cursor over the changes after `token`.
+    pub fn change_stream(
+        ns: String,
+        users: &[AuthenticatedUser],
+        sql: String,
+        token: ResumeToken,
+    ) -> Self {
+        Cursor::new(ns, users, CursorSource::ChangeStream { sql, token })
+    }
+
+    /// Whether the cursor can be used on a connection, which takes one of
+    /// the users that opened it.
+    pub fn is_usable_by(&self, auth: &AuthState) -> bool {
+        auth.is_coauthorized_with(&self.users)
+    }
+
+    /// Reads the next batch and moves the cursor past it. Change streams are
+    /// read with `change_stream::next_changes` instead.
+    pub fn next_batch(&mut self, client: &mut PgDb, batch_size: Option<i64>) -> Result<Batch> {
+        match &mut self.source {
+            CursorSource::Keyset(query) => query.next_batch(client, batch_size),
+            CursorSource::Materialized { table, position } => {
+                let sql = format!(
+                    "SELECT _jsonb, n FROM {} WHERE n > {} ORDER BY n{}",
+                    table,
+                    position,
+                    limit_clause(batch_size, None)
+                );
+                read_batch(client, &sql, batch_size, |row| *position = row.get(1))
+            }
+            CursorSource::ChangeStream { .. } => {
+                Err(eyre::eyre!("change streams are read with next_changes"))
+            }
+        }
+    }
+
+    /// Drops what the cursor keeps in Postgres once it's exhausted or killed.
+    pub fn close(&self, client: &mut PgDb) {
+        if let CursorSource::Materialized { table, .. } = &self.source {
+            drop_results(client, table);
+        }
+    }
+}
+
+/// A find resumed after the sort key of the last document it returned.
+#[derive(Debug, Clone)]
+pub struct KeysetQuery {
+    projection: String,
+    table: String,
+    filter: Option<String>,
+    // sort expressions and if they are descending, ending with the `_id`
+    keys: Vec<(String, bool)>,
+    skip: i64,
+    // documents left to return when the find has a limit
+    remaining: Option<i64>,
+    // sort key of the last document returned, None where it was missing
+    after: Option<Vec<Option<serde_json::Value>>>,
+}
+
+impl KeysetQuery {
+    /// `filter` is the condition of the where clause, if any.
+    pub fn new(
+        projection: &str,
+        table: &str,
+        filter: Option<String>,
+        sort: Option<&Document>,
+        skip: i64,
+        limit: i64,
+    ) -> Self {
+        let mut keys = vec![];
+        for (field, direction) in sort.into_iter().flatten() {
+            let descending = match direction {
+                Bson::Int32(v) => *v < 0,
+                Bson::Int64(v) => *v < 0,
+                Bson::Double(v) => *v < 0.0,
+                _ => false,
+            };
+            let path = field
+                .split('.')
+                .map(|f| format!("'{}'", f.replace('\'', "''")))
+                .collect::<Vec<_>>()
+                .join("->");
+            keys.push((format!("_jsonb->{}", path), descending));
+        }
+        keys.push(("_jsonb->'_id'".to_string(), false));
+
+        KeysetQuery {
+            projection: projection.to_string(),
+            table: table.to_string(),
+            filter,
+            keys,
+            skip,
+            remaining: Some(limit).filter(|n| *n > 0),
+            after: None,
+        }
+    }
+
+    fn sql(&self, batch_size: Option<i64>) -> String {
+        let mut conditions = vec![];
+        if let Some(filter) = &self.filter {
+            conditions.push(format!("({})", filter));
+        }
+        if let Some(after) = &self.after {
+            conditions.push(self.after_condition(after));
+        }
+        let where_str = if conditions.is_empty() {
+            "".to_string()
+        } else {
+            format!(" WHERE {}", conditions.join(" AND "))
+        };
+        let columns = self
+            .keys
+            .iter()
+            .enumerate()
+            .map(|(i, (key, _))| format!(", {} AS _key{}", key, i))
+            .collect::<String>();
+        let order_by = self
+            .keys
+            .iter()
+            .map(|(key, descending)| {
+                format!("{} {}", key, if *descending { "DESC" } else { "ASC" })
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let offset = match self.after {
+            None if self.skip > 0 => format!(" OFFSET {}", self.skip),
+            _ => "".to_string(),
+        };
+        format!(
+            "SELECT {} AS _jsonb{} FROM {}{} ORDER BY {}{}{}",
+            self.projection,
+            columns,
+            self.table,
+            where_str,
+            order_by,
+            limit_clause(batch_size, self.remaining),
+            offset
+        )
+    }
+
+    // Rows sorting after `after`: same keys up to some point, then a later
+    // one. Missing keys sort last ascending and first descending, as they do
+    // in the ORDER BY.
+    fn after_condition(&self, after: &[Option<serde_json::Value>]) -> String {
+        let value =
+            |v: &serde_json::Value| format!("'{}'::jsonb", v.to_string().replace('\'', "''"));
+        let mut alternatives = vec![];
+        for (i, ((key, descending), last)) in self.keys.iter().zip(after).enumerate() {
+            let later = match (last, descending) {
+                // the _id is never missing, which lets it use its index
+                (Some(v), false) if i == self.keys.len() - 1 => format!("{} > {}", key, value(v)),
+                (Some(v), false) => format!("({} > {} OR {} IS NULL)", key, value(v), key),
+                (None, false) => continue,
+                (Some(v), true) => format!("{} < {}", key, value(v)),
+                (None, true) => format!("{} IS NOT NULL", key),
+            };
+            let mut terms = self.keys[..i]
+                .iter()
+                .zip(after)
+                .map(|((key, _), last)| match last {
+                    Some(v) => format!("{} = {}", key, value(v)),
+                    None => format!("{} IS NULL", key),
+                })
+                .collect::<Vec<_>>();
+            terms.push(later);
+            alternatives.push(terms.join(" AND "));
+        }
+        if alternatives.is_empty() {
+            return "FALSE".to_string();
+        }
+        format!("(({}))", alternatives.join(") OR ("))
+    }
+
+    fn next_batch(&mut self, client: &mut PgDb, batch_size: Option<i64>) -> Result<Batch> {
+        let sql = self.sql(batch_size);
+        let keys = self.keys.len();
+        let mut last = None;
+        let batch = read_batch(client, &sql, batch_size, |row| {
+            last = Some((1..=keys).map(|i| row.get(i)).collect::<Vec<_>>());
+        })?;
+        if let Some(last) = last {
+            self.after = Some(last);
+        }
+        if let Some(remaining) = &mut self.remaining {
+            *remaining -= batch.docs.len() as i64;
+        }
+        Ok(batch)
+    }
+}
+
+/// Outcome of `CursorRegistry::kill`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Kill {
+    Killed,
+    NotFound,
+    Unauthorized,
+}
+
+#[derive(Debug)]
+pub struct Batch {
+    pub docs: Vec<Bson>,
+    pub exhausted: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct CursorRegistry {
+    cursors: Arc<Mutex<HashMap<i64, Cursor>>>,
+    // tables of the cursors removed since the last `drop_released`
+    released: Arc<Mutex<Vec<String>>>,
+    timeout: Duration,
+}
+
+impl Default for CursorRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CursorRegistry {
+    pub fn new() -> Self {
+        Self::with_timeout(DEFAULT_CURSOR_TIMEOUT)
+    }
+
+    pub fn with_timeout(timeout: Duration) -> Self {
+        CursorRegistry {
+            cursors: Arc::new(Mutex::new(HashMap::new())),
+            released: Arc::new(Mutex::new(vec![])),
+            timeout,
+        }
+    }
+
+    /// Runs the find and returns the first batch, registering a cursor for
+    /// the remaining documents if there are any. The returned id is 0 when
+    /// the results were exhausted on the first batch.
+    pub fn open_find(
+        &self,
+        client: &mut PgDb,
+        ns: &str,
+        users: &[AuthenticatedUser],
+        mut query: KeysetQuery,
+        batch_size: Option<i64>,
+    ) -> Result<(i64, Vec<Bson>)> {
+        self.drop_released(client);
+        let batch_size = batch_size.unwrap_or(DEFAULT_BATCH_SIZE);
+        if batch_size == 0 {
+            // an empty first batch still establishes the cursor
+            let cursor = Cursor::new(ns.to_string(), users, CursorSource::Keyset(query));
+            return Ok((self.register(cursor), vec![]));
+        }
+
+        let batch = query.next_batch(client, Some(batch_size))?;
+        if batch.exhausted {
+            return Ok((0, batch.docs));
+        }
+        let cursor = Cursor::new(ns.to_string(), users, CursorSource::Keyset(query));
+        Ok((self.register(cursor), batch.docs))
+    }
+
+    /// Runs the statement and returns the first batch. When there are more
+    /// results, all of them are copied to a table the cursor reads from, so
+    /// the statement only runs again to take that snapshot.
+    pub fn open(
+        &self,
+        client: &mut PgDb,
+        ns: &str,
+        users: &[AuthenticatedUser],
+        sql: &str,
+        batch_size: Option<i64>,
+    ) -> Result<(i64, Vec<Bson>)> {
+        self.drop_released(client);
+        let batch_size = batch_size.unwrap_or(DEFAULT_BATCH_SIZE);
+        if batch_size > 0 {
+            let batch = fetch_batch(client, sql, Some(batch_size))?;
+            if batch.exhausted {
+                return Ok((0, batch.docs));
+            }
+        }
+
+        // named after when they're created, see `drop_stale_results`
+        let table = SqlParam::new(
+            SESSIONS_SCHEMA,
+            &format!("cursor_{}_{}", unix_time(), uuid::Uuid::new_v4().simple()),
+        )
+        .sanitize();
+        client.materialize(&table, sql)?;
+        let mut cursor = Cursor::new(
+            ns.to_string(),
+            users,
+            CursorSource::Materialized { table, position: 0 },
+        );
+        let docs = if batch_size > 0 {
+            let batch = cursor.next_batch(client, Some(batch_size))?;
+            if batch.exhausted {
+                cursor.close(client);
+                return Ok((0, batch.docs));
+            }
+            batch.docs
+        } else {
+            vec![]
+        };
+        Ok((self.register(cursor), docs))
+    }
+
+    pub fn register(&self, cursor: Cursor) -> i64 {
+        let mut cursors = self.cursors.lock().unwrap();
+        self.reap(&mut cursors);
+
+        let mut id = new_cursor_id();
+        while cursors.contains_key(&id) {
+            id = new_cursor_id();
+        }
+        cursors.insert(id, cursor);
+        id
+    }
+
+    /// Removes the cursor from the registry so it can be advanced without
+    /// holding the lock while Postgres is queried. Callers put it back with
+    /// `restore` unless it was exhausted.
+    pub fn checkout(&self, id: i64) -> Option<Cursor> {
+        let mut cursors = self.cursors.lock().unwrap();
+        self.reap(&mut cursors);
+        cursors.remove(&id)
+    }
+
+    pub fn restore(&self, id: i64, mut cursor: Cursor) {
+        cursor.last_used = Instant::now();
+        self.cursors.lock().unwrap().insert(id, cursor);
+    }
+
+    /// Removes the cursor if it belongs to `ns` and can be used on the
+    /// connection, leaving what it keeps in Postgres to `drop_released`.
+    pub fn kill(&self, id: i64, ns: &str, auth: &AuthState) -> Kill {
+        let mut cursors = self.cursors.lock().unwrap();
+        self.reap(&mut cursors);
+        match cursors.get(&id) {
+            Some(cursor) if cursor.ns == ns => {
+                if !cursor.is_usable_by(auth) {
+                    return Kill::Unauthorized;
+                }
+            }
+            _ => return Kill::NotFound,
+        }
+        let cursor = cursors.remove(&id).unwrap();
+        self.release(&cursor);
+        Kill::Killed
+    }
+
+    /// Drops the results kept for cursors that were killed or timed out.
+    /// It waits for a connection outside of a transaction, which could still
+    /// roll the drop back.
+    pub fn drop_released(&self, client: &mut PgDb) {
+        if client.in_transaction() {
+            return;
+        }
+        let tables = std::mem::take(&mut *self.released.lock().unwrap());
+        for table in tables {
+            drop_results(client, &table);
+        }
+    }
+
+    fn release(&self, cursor: &Cursor) {
+        if let CursorSource::Materialized { table, .. } = &cursor.source {
+            self.released.lock().unwrap().push(table.clone());
+        }
+    }
+
+    pub fn contains(&self, id: i64) -> bool {
+        let mut cursors = self.cursors.lock().unwrap();
+        self.reap(&mut cursors);
+        cursors.contains_key(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        let mut cursors = self.cursors.lock().unwrap();
+        self.reap(&mut cursors);
+        cursors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn reap(&self, cursors: &mut HashMap<i64, Cursor>) {
+        let timeout = self.timeout;
+        cursors.retain(|id, cursor| {
+            let alive = cursor.last_used.elapsed() < timeout;
+            if !alive {
+                log::debug!("Cursor {} on {} timed out", id, cursor.ns);
+                self.release(cursor);
+            }
+            alive
+        });
+    }
+}
+
+/// Reads up to `batch_size` rows of `sql`, stopping early if the batch would
+/// not fit in a single reply.
+pub fn fetch_batch(client: &mut PgDb, sql: &str, batch_size: Option<i64>) -> Result<Batch> {
+    let sql = format!(
+        "SELECT * FROM ({}) AS s_cursor{}",
+        sql,
+        limit_clause(batch_size, None)
+    );
+    read_batch(client, &sql, batch_size, |_| {})
+}
+
+// asks for one extra row to know if the results are exhausted, unless
+// there's no more than `remaining` of them anyway
+fn limit_clause(batch_size: Option<i64>, remaining: Option<i64>) -> String {
+    let limit = match (batch_size.filter(|n| *n > 0), remaining) {
+        (Some(n), Some(remaining)) => Some((n + 1).min(remaining)),
+        (Some(n), None) => Some(n + 1),
+        (None, remaining) => remaining,
+    };
+    match limit {
+        Some(n) => format!(" LIMIT {}", n),
+        None => "".to_string(),
+    }
+}
+
+// Reads the documents in the first column of `sql`, calling `returned` with
+// every row that makes it into the batch.
+fn read_batch(
+    client: &mut PgDb,
+    sql: &str,
+    batch_size: Option<i64>,
+    mut returned: impl FnMut(&Row),
+) -> Result<Batch> {
+    let mut rows = client.raw_query_iter(sql)?;
+
+    let max_docs = match batch_size {
+        Some(n) if n > 0 => n as usize,
+        _ => usize::MAX,
+    };
+    let mut docs = vec![];
+    let mut size = 0;
+    while let Some(row) = rows.next()? {
+        if docs.len() >= max_docs {
+            return Ok(Batch {
+                docs,
+                exhausted: false,
+            });
+        }
+
+        let json: serde_json::Value = row.get(0);
+        let doc = json.from_psql_json()?;
+        size += ser::to_vec(&doc).map(|v| v.len()).unwrap_or(0);
+        if size > MAX_BATCH_LEN && !docs.is_empty() {
+            return Ok(Batch {
+                docs,
+                exhausted: false,
+            });
+        }
+        returned(&row);
+        docs.push(doc);
+    }
+
+    Ok(Batch {
+        docs,
+        exhausted: true,
+    })
+}
+
+fn drop_results(client: &mut PgDb, table: &str) {
+    if let Err(e) = client.exec(&format!("DROP TABLE IF EXISTS {}", table), &[]) {
+        log::warn!("Could not drop the results of a cursor in {}: {}", table, e);
+    }
+}
+
+/// Drops the results of cursors left behind by servers that stopped before
+/// they were exhausted or killed, returning how many there were.
+pub fn drop_stale_results(client: &mut PgDb) -> usize {
+    let now = unix_time();
+    let mut dropped = 0;
+    for table in client.get_tables(SESSIONS_SCHEMA) {
+        let created = match table.strip_prefix("cursor_") {
+            Some(rest) => rest.split('_').next().and_then(|s| s.parse().ok()),
+            None => continue,
+        };
+        if let Some(created) = created {
+            if now.saturating_sub(created) < STALE_RESULTS_AGE.as_secs() {
+                continue;
+            }
+        }
+        drop_results(client, &SqlParam::new(SESSIONS_SCHEMA, &table).sanitize());
+        dropped += 1;
+    }
+    dropped
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Reads `batchSize` from a command (or its `cursor` option document).
+pub fn get_batch_size(doc: &Document) -> Option<i64> {
+    match doc.get("batchSize") {
+        Some(Bson::Int32(n)) => Some(*n as i64),
+        Some(Bson::Int64(n)) => Some(*n),
+        Some(Bson::Double(n)) => Some(*n as i64),
+        _ => None,
+    }
+}
+
+fn new_cursor_id() -> i64 {
+    // cursor ids are positive and never 0, which means "no cursor"
+    let id = (uuid::Uuid::new_v4().as_u128() as i64) & i64::MAX;
+    if id == 0 {
+        1
+    } else {
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn materialized(position: i64) -> Cursor {
+        let table = r#""oxide_sessions"."cursor_1""#.to_string();
+        Cursor::new(
+            "db.col".to_string(),
+            &[],
+            CursorSource::Materialized { table, position },
+        )
+    }
+
+    #[test]
+    fn test_register_and_kill() {
+        let registry = CursorRegistry::new();
+        let id = registry.register(materialized(0));
+        assert!(id > 0);
+        assert!(registry.contains(id));
+        let auth = AuthState::new(false);
+        // only from the namespace of the cursor
+        assert_eq!(registry.kill(id, "db.other", &auth), Kill::NotFound);
+        assert!(registry.contains(id));
+        assert_eq!(registry.kill(id, "db.col", &auth), Kill::Killed);
+        assert_eq!(registry.kill(id, "db.col", &auth), Kill::NotFound);
+        assert!(registry.is_empty());
+        assert_eq!(registry.released.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_kill_other_users_cursor() {
+        let registry = CursorRegistry::new();
+        let ana = AuthenticatedUser {
+            user: "ana".to_string(),
+            db: "admin".to_string(),
+        };
+        let id = registry.register(Cursor::new(
+            "db.col".to_string(),
+            &[ana],
+            CursorSource::Materialized {
+                table: r#""oxide_sessions"."cursor_1""#.to_string(),
+                position: 0,
+            },
+        ));
+
+        let mut auth = AuthState::new(true);
+        assert_eq!(registry.kill(id, "db.col", &auth), Kill::Unauthorized);
+        auth.authenticate("bruno", "admin");
+        assert_eq!(registry.kill(id, "db.col", &auth), Kill::Unauthorized);
+        auth.authenticate("ana", "admin");
+        assert_eq!(registry.kill(id, "db.col", &auth), Kill::Killed);
+    }
+
+    #[test]
+    fn test_checkout_and_restore() {
+        let registry = CursorRegistry::new();
+        let id = registry.register(materialized(2));
+        let mut cursor = registry.checkout(id).unwrap();
+        assert!(!registry.contains(id));
+
+        if let CursorSource::Materialized { position, .. } = &mut cursor.source {
+            *position += 2;
+        }
+        registry.restore(id, cursor);
+        assert!(matches!(
+            registry.checkout(id).unwrap().source,
+            CursorSource::Materialized { position: 4, .. }
+        ));
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let registry = CursorRegistry::with_timeout(Duration::from_millis(10));
+        let id = registry.register(materialized(0));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!registry.contains(id));
+        assert!(registry.checkout(id).is_none());
+        assert_eq!(registry.released.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_keyset_query() {
+        let mut query = KeysetQuery::new(
+            "_jsonb",
+            r#""db"."col""#,
+            Some("a = 1 OR b = 2".to_string()),
+            Some(&bson::doc! { "x": -1, "it's": 1 }),
+            5,
+            0,
+        );
+        assert_eq!(
+            query.sql(Some(2)),
+            r#"SELECT _jsonb AS _jsonb, _jsonb->'x' AS _key0, _jsonb->'it''s' AS _key1, _jsonb->'_id' AS _key2 FROM "db"."col" WHERE (a = 1 OR b = 2) ORDER BY _jsonb->'x' DESC, _jsonb->'it''s' ASC, _jsonb->'_id' ASC LIMIT 3 OFFSET 5"#
+        );
+
+        query.after = Some(vec![
+            Some(serde_json::json!(3)),
+            None,
+            Some(serde_json::json!("o'b")),
+        ]);
+        assert_eq!(
+            query.after_condition(query.after.as_ref().unwrap()),
+            "((_jsonb->'x' < '3'::jsonb) OR (_jsonb->'x' = '3'::jsonb AND _jsonb->'it''s' IS NULL AND _jsonb->'_id' > '\"o''b\"'::jsonb))"
+        );
+    }
+
+    #[test]
+    fn test_limit_clause() {
+        assert_eq!(limit_clause(Some(2), None), " LIMIT 3");
+        assert_eq!(limit_clause(Some(2), Some(2)), " LIMIT 2");
+        assert_eq!(limit_clause(None, Some(4)), " LIMIT 4");
+        assert_eq!(limit_clause(Some(0), None), "");
+    }
+}
@@ -1,73 +1,120 @@
+use bson::oid::ObjectId;
 use bson::{ser, Bson};
 use chrono::{TimeZone, Utc};
+use eyre::{eyre, Result};
 use serde_json::Value;
 
 pub trait PostgresJsonDeserializer {
-    fn from_psql_json(&self) -> Bson;
+    /// Converts a value stored by `PostgresSerializer` back to BSON, failing
+    /// when one of the types it encodes is malformed.
+    fn from_psql_json(&self) -> Result<Bson>;
 }
 
 impl PostgresJsonDeserializer for Value {
-    fn from_psql_json(&self) -> Bson {
+    fn from_psql_json(&self) -> Result<Bson> {
         match self {
-            serde_json::Value::String(s) => Bson::String(s.to_string()),
+            serde_json::Value::String(s) => Ok(Bson::String(s.to_string())),
             serde_json::Value::Number(n) => {
                 let s = n.to_string();
                 if s.contains(".") {
-                    Bson::Double(n.as_f64().unwrap())
+                    n.as_f64()
+                        .map(Bson::Double)
+                        .ok_or_else(|| eyre!("Unsupported number {}", n))
                 } else {
                     if let Some(n) = n.as_i64() {
-                        Bson::Int32(n.try_into().unwrap())
+                        match i32::try_from(n) {
+                            Ok(n) => Ok(Bson::Int32(n)),
+                            Err(_) => Ok(Bson::Int64(n)),
+                        }
                     } else if let Some(n) = n.as_f64() {
-                        Bson::Double(n)
+                        Ok(Bson::Double(n))
                     } else {
-                        panic!("Unsupported number type while attempting to deserialize Value::Number for {}", n);
+                        Err(eyre!("Unsupported number {}", n))
                     }
                 }
             }
-            serde_json::Value::Bool(b) => Bson::Boolean(b.to_owned()),
-            serde_json::Value::Null => Bson::Null,
-            serde_json::Value::Array(a) => {
-                Bson::Array(a.into_iter().map(|v| v.from_psql_json()).collect())
-            }
+            serde_json::Value::Bool(b) => Ok(Bson::Boolean(b.to_owned())),
+            serde_json::Value::Null => Ok(Bson::Null),
+            serde_json::Value::Array(a) => Ok(Bson::Array(
+                a.iter()
+                    .map(|v| v.from_psql_json())
+                    .collect::<Result<_>>()?,
+            )),
             serde_json::Value::Object(o) => {
                 if o.contains_key("$o") {
-                    return Bson::ObjectId(
-                        bson::oid::ObjectId::parse_str(o["$o"].as_str().unwrap().to_string())
-                            .unwrap(),
-                    );
+                    let oid = o["$o"]
+                        .as_str()
+                        .and_then(|oid| ObjectId::parse_str(oid).ok())
+                        .ok_or_else(|| malformed("$o", self))?;
+                    return Ok(Bson::ObjectId(oid));
                 }
                 if o.contains_key("$d") {
-                    return Bson::DateTime(Utc.timestamp_millis(o["$d"].as_i64().unwrap()).into());
+                    let date = o["$d"]
+                        .as_i64()
+                        .and_then(|millis| Utc.timestamp_millis_opt(millis).single())
+                        .ok_or_else(|| malformed("$d", self))?;
+                    return Ok(Bson::DateTime(date.into()));
+                }
+                if o.contains_key("$timestamp") {
+                    let timestamp = &o["$timestamp"];
+                    let part = |key: &str| {
+                        timestamp[key]
+                            .as_u64()
+                            .and_then(|n| u32::try_from(n).ok())
+                            .ok_or_else(|| malformed("$timestamp", self))
+                    };
+                    return Ok(Bson::Timestamp(bson::Timestamp {
+                        time: part("t")?,
+                        increment: part("i")?,
+                    }));
                 }
                 if o.contains_key("$f") {
-                    return Bson::Double(o["$f"].as_f64().unwrap());
+                    let n = o["$f"].as_f64().ok_or_else(|| malformed("$f", self))?;
+                    return Ok(Bson::Double(n));
                 }
                 if o.contains_key("$j") {
+                    let code = o["$j"]
+                        .as_str()
+                        .ok_or_else(|| malformed("$j", self))?
+                        .to_string();
                     if o.contains_key("s") {
-                        return Bson::JavaScriptCodeWithScope(bson::JavaScriptCodeWithScope {
-                            code: o["$j"].as_str().unwrap().to_string(),
-                            scope: ser::to_document(&o["s"]).unwrap(),
-                        });
+                        return Ok(Bson::JavaScriptCodeWithScope(
+                            bson::JavaScriptCodeWithScope {
+                                code,
+                                scope: ser::to_document(&o["s"])
+                                    .map_err(|_| malformed("$j", self))?,
+                            },
+                        ));
                     } else {
-                        return Bson::JavaScriptCode(o["$j"].as_str().unwrap().to_string());
+                        return Ok(Bson::JavaScriptCode(code));
                     }
                 }
                 if o.contains_key("$r") {
-                    return Bson::RegularExpression(bson::Regex {
-                        pattern: o["$r"].as_str().unwrap().to_string(),
-                        options: o["o"].as_str().unwrap().to_string(),
-                    });
+                    let part = |key: &str| {
+                        o[key]
+                            .as_str()
+                            .map(str::to_string)
+                            .ok_or_else(|| malformed("$r", self))
+                    };
+                    return Ok(Bson::RegularExpression(bson::Regex {
+                        pattern: part("$r")?,
+                        options: part("o")?,
+                    }));
                 }
                 let mut m = bson::Document::new();
                 for (k, v) in o {
-                    m.insert(k, v.from_psql_json());
+                    m.insert(k, v.from_psql_json()?);
                 }
-                Bson::Document(m)
+                Ok(Bson::Document(m))
             }
         }
     }
 }
 
+fn malformed(kind: &str, value: &Value) -> eyre::Report {
+    eyre!("Malformed {} value: {}", kind, value)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -76,8 +123,39 @@ mod tests {
     fn test_deserialize_date() {
         let json = r#"{"$d":1546300800000}"#;
         let bson: serde_json::Value = serde_json::from_str(json).unwrap();
-        let bson = bson.from_psql_json();
+        let bson = bson.from_psql_json().unwrap();
         println!("{:?}", bson);
         assert_eq!(bson, Bson::DateTime(Utc.timestamp(1546300800, 0).into()));
     }
+
+    #[test]
+    fn test_deserialize_long() {
+        let json = r#"{"a":1,"b":5000000000}"#;
+        let bson: serde_json::Value = serde_json::from_str(json).unwrap();
+        assert_eq!(bson["a"].from_psql_json().unwrap(), Bson::Int32(1));
+        assert_eq!(bson["b"].from_psql_json().unwrap(), Bson::Int64(5000000000));
+    }
+
+    #[test]
+    fn test_deserialize_timestamp() {
+        let json = r#"{"$timestamp":{"t":1546300800,"i":1}}"#;
+        let bson: serde_json::Value = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            bson.from_psql_json().unwrap(),
+            Bson::Timestamp(bson::Timestamp {
+                time: 1546300800,
+                increment: 1,
+            })
+        );
+
+        for json in [
+            r#"{"$timestamp":{"t":"1546300800","i":1}}"#,
+            r#"{"$timestamp":{"t":1546300800}}"#,
+            r#"{"$timestamp":{"t":-1,"i":1}}"#,
+            r#"{"$timestamp":{"t":5000000000,"i":1}}"#,
+        ] {
+            let bson: serde_json::Value = serde_json::from_str(json).unwrap();
+            assert!(bson.from_psql_json().is_err(), "{}", json);
+        }
+    }
 }
@@ -1,32 +1,48 @@
 #![allow(dead_code)]
+use crate::auth::roles::{is_authorized, required_privileges, resolve_privileges, role_names};
+use crate::auth::{requires_authentication, AuthState};
+use crate::change_stream::ChangeListener;
 use crate::commands::{
-    Aggregate, BuildInfo, CollStats, ConnectionStatus, Count, Create, CreateIndexes, DbStats,
-    Delete, Drop, DropDatabase, Find, FindAndModify, GetCmdLineOpts, GetParameter, Handler, Hello,
-    Insert, IsMaster, ListCollections, ListDatabases, ListIndexes, Ping, Update, WhatsMyUri,
+    AbortTransaction, Aggregate, BuildInfo, CollStats, CommitTransaction, ConnectionStatus, Count,
+    Create, CreateIndexes, CreateRole, CreateUser, DbStats, Delete, Drop, DropDatabase, DropRole,
+    DropUser, EndSessions, Find, FindAndModify, GetCmdLineOpts, GetMore, GetParameter,
+    GrantRolesToUser, Handler, Hello, Insert, IsMaster, KillCursors, ListCollections,
+    ListDatabases, ListIndexes, Logout, Ping, RefreshSessions, RevokeRolesFromUser, SaslContinue,
+    SaslStart, StartSession, Update, UpdateUser, UsersInfo, WhatsMyUri,
 };
-use crate::pg::PgDb;
-use crate::wire::{OpCode, OpMsg};
+use crate::cursor::CursorRegistry;
+use crate::pg::{is_internal_schema, PgDb};
+use crate::server::SharedState;
+use crate::session::{
+    session_id, txn_number, SessionRegistry, TransactionError, RETRYABLE_WRITE_COMMANDS,
+};
+use crate::wire::OpCode;
 use bson::{doc, Bson, Document};
-use postgres::NoTls;
-use r2d2_postgres::PostgresConnectionManager;
 use std::net::SocketAddr;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard};
 
 pub struct Request<'a> {
-    pool: &'a r2d2::Pool<PostgresConnectionManager<NoTls>>,
+    state: &'a SharedState,
+    auth: &'a Mutex<AuthState>,
     peer_addr: SocketAddr,
     op_code: &'a OpCode,
+    transaction: Option<Arc<Mutex<PgDb>>>,
 }
 
 impl<'a> Request<'a> {
     pub fn new(
-        pool: &'a r2d2::Pool<PostgresConnectionManager<NoTls>>,
+        state: &'a SharedState,
+        auth: &'a Mutex<AuthState>,
         peer_addr: SocketAddr,
         op_code: &'a OpCode,
     ) -> Self {
         Request {
-            pool,
+            state,
+            auth,
             peer_addr,
             op_code,
+            transaction: None,
         }
     }
 
@@ -38,8 +54,58 @@ impl<'a> Request<'a> {
         self.op_code
     }
 
-    pub fn get_client(&self) -> PgDb {
-        PgDb::new_from_pool(self.pool.clone())
+    /// Returns the connection to run the command on, which is the one pinned
+    /// to its transaction when it's part of one.
+    pub fn get_client(&self) -> Connection<'_> {
+        match &self.transaction {
+            Some(client) => Connection::Pinned(client.lock().unwrap()),
+            None => Connection::Pooled(Box::new(PgDb::new_from_pool(self.state.pool.clone()))),
+        }
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.transaction.is_some()
+    }
+
+    pub fn get_changes(&self) -> &ChangeListener {
+        &self.state.changes
+    }
+
+    pub fn get_cursors(&self) -> &CursorRegistry {
+        &self.state.cursors
+    }
+
+    pub fn get_sessions(&self) -> &SessionRegistry {
+        &self.state.sessions
+    }
+
+    pub fn get_auth(&self) -> MutexGuard<'_, AuthState> {
+        self.auth.lock().unwrap()
+    }
+}
+
+pub enum Connection<'a> {
+    Pooled(Box<PgDb>),
+    Pinned(MutexGuard<'a, PgDb>),
+}
+
+impl Deref for Connection<'_> {
+    type Target = PgDb;
+
+    fn deref(&self) -> &PgDb {
+        match self {
+            Connection::Pooled(client) => client,
+            Connection::Pinned(client) => client,
+        }
+    }
+}
+
+impl DerefMut for Connection<'_> {
+    fn deref_mut(&mut self) -> &mut PgDb {
+        match self {
+            Connection::Pooled(client) => client,
+            Connection::Pinned(client) => client,
+        }
     }
 }
 
@@ -48,11 +114,28 @@ pub struct Response<'a> {
     id: u32,
     op_code: &'a OpCode,
     docs: Vec<Document>,
+    more_to_come: bool,
 }
 
 impl<'a> Response<'a> {
     pub fn new(id: u32, op_code: &'a OpCode, docs: Vec<Document>) -> Self {
-        Response { id, op_code, docs }
+        Response {
+            id,
+            op_code,
+            docs,
+            more_to_come: false,
+        }
+    }
+
+    /// Flags the reply with moreToCome, telling the client that more replies
+    /// will follow without further requests.
+    pub fn with_more_to_come(mut self, more_to_come: bool) -> Self {
+        self.more_to_come = more_to_come;
+        self
+    }
+
+    pub fn more_to_come(&self) -> bool {
+        self.more_to_come
     }
 
     pub fn get_doc(&self) -> &Document {
@@ -87,39 +170,192 @@ impl CommandExecutionError {
     }
 }
 
+/// A serialized reply. `more_to_come` is set when the request was a `getMore`
+/// on an exhaust cursor with batches left, which are streamed by handling the
+/// same request again.
+pub struct Reply {
+    pub bytes: Vec<u8>,
+    pub more_to_come: bool,
+}
+
 pub fn handle(
     id: u32,
-    pool: &r2d2::Pool<PostgresConnectionManager<NoTls>>,
+    state: &SharedState,
+    auth: &Mutex<AuthState>,
     peer_addr: SocketAddr,
     op_code: &OpCode,
-) -> Result<Vec<u8>, CommandExecutionError> {
-    let request = Request {
-        pool,
-        peer_addr,
-        op_code: &op_code,
-    };
+) -> Result<Reply, CommandExecutionError> {
+    let request = Request::new(state, auth, peer_addr, op_code);
     match route(&request) {
         Ok(doc) => {
             log::trace!("Sending response: {:#?}", doc);
-            let response = Response {
-                id,
-                op_code: &op_code,
-                docs: vec![doc],
-            };
-            Ok(op_code.reply(response).unwrap())
+            let more_to_come = op_code.exhaust_allowed() && has_next_batch(&doc);
+            let response = Response::new(id, op_code, vec![doc]).with_more_to_come(more_to_come);
+            Ok(Reply {
+                bytes: op_code.reply(response).unwrap(),
+                more_to_come,
+            })
         }
         Err(e) => Err(e),
     }
 }
 
+// whether the document is a successful getMore reply on a cursor that isn't
+// exhausted yet
+fn has_next_batch(doc: &Document) -> bool {
+    match doc.get_document("cursor") {
+        Ok(cursor) => cursor.contains_key("nextBatch") && cursor.get_i64("id").unwrap_or(0) != 0,
+        Err(_) => false,
+    }
+}
+
 fn run(request: &Request, docs: &Vec<Document>) -> Result<Document, CommandExecutionError> {
-    let command = docs[0].keys().next().unwrap();
+    let command = match docs[0].keys().next() {
+        Some(command) => command,
+        None => {
+            return Ok(doc! {
+                "ok": Bson::Double(0.0),
+                "errmsg": "no command given",
+                "code": Bson::Int32(59),
+                "codeName": "CommandNotFound",
+            })
+        }
+    };
 
     log::debug!("OP_MSG command: {}", command);
     log::trace!("Received document: {:#?}", docs);
 
+    if let Some(err) = check_authentication(request, command) {
+        return Ok(err);
+    }
+    if let Some(err) = check_internal_schema(command, &docs[0]) {
+        return Ok(err);
+    }
+    if let Some(err) = check_authorization(request, command, &docs[0]) {
+        return Ok(err);
+    }
+
+    // commands that are part of a transaction run on its connection, and a
+    // failing one aborts it
+    let transaction = if command == "commitTransaction" || command == "abortTransaction" {
+        None
+    } else {
+        match request
+            .get_sessions()
+            .transaction(&request.state.pool, command, &docs[0])
+        {
+            Ok(transaction) => transaction,
+            Err(e) => return Ok(e.to_doc()),
+        }
+    };
+    let in_transaction = transaction.is_some();
+    if !in_transaction && RETRYABLE_WRITE_COMMANDS.contains(&command.as_str()) {
+        if let (Some(id), Some(number)) = (session_id(&docs[0]), txn_number(&docs[0])) {
+            return run_retryable_write(request, command, docs, &id, number);
+        }
+    }
+    let request = &Request {
+        transaction,
+        ..*request
+    };
+
+    let res = dispatch(request, command, docs);
+    if in_transaction && !succeeded(&res) {
+        let doc = &docs[0];
+        if let (Some(id), Some(number)) = (session_id(doc), txn_number(doc)) {
+            let _ = request.get_sessions().abort(&id, number);
+        }
+    }
+    res
+}
+
+// runs a write that drivers may send again, recording its reply in the same
+// SQL transaction as its changes, or answers a retry with the recorded reply
+fn run_retryable_write(
+    request: &Request,
+    command: &str,
+    docs: &Vec<Document>,
+    id: &[u8],
+    number: i64,
+) -> Result<Document, CommandExecutionError> {
+    let error = |e: eyre::Report| CommandExecutionError::new(e.to_string());
+
+    let mut client = PgDb::new_from_pool(request.state.pool.clone());
+    if let Some(reply) = previous_write(&mut client, id, number).map_err(error)? {
+        log::debug!("Replying to retried write {} with its first reply", number);
+        return Ok(reply);
+    }
+    client.begin().map_err(error)?;
+    let client = Arc::new(Mutex::new(client));
+
+    let res = dispatch(
+        &Request {
+            transaction: Some(client.clone()),
+            ..*request
+        },
+        command,
+        docs,
+    );
+    let mut client = client.lock().unwrap();
+    let reply = match res {
+        Ok(reply) if is_ok(&reply) => reply,
+        res => {
+            let _ = client.rollback();
+            return res;
+        }
+    };
+
+    if !client
+        .save_retryable_write(id, number, &reply)
+        .map_err(error)?
+    {
+        // a concurrent retry finished first, so its writes are the ones kept
+        client.rollback().map_err(error)?;
+        let previous = previous_write(&mut client, id, number).map_err(error)?;
+        return Ok(previous.unwrap_or(reply));
+    }
+    client.commit().map_err(error)?;
+    Ok(reply)
+}
+
+// the reply to a retryable write that already ran, or an error if the session
+// moved on to a newer one
+fn previous_write(client: &mut PgDb, id: &[u8], number: i64) -> eyre::Result<Option<Document>> {
+    match client.get_retryable_write(id)? {
+        Some((latest, reply)) if latest == number => Ok(Some(reply)),
+        Some((latest, _)) if latest > number => {
+            Ok(Some(TransactionError::RetryTooOld(number, latest).to_doc()))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn is_ok(doc: &Document) -> bool {
+    match doc.get("ok") {
+        Some(Bson::Double(ok)) => *ok != 0.0,
+        Some(Bson::Int32(ok)) => *ok != 0,
+        _ => true,
+    }
+}
+
+fn succeeded(res: &Result<Document, CommandExecutionError>) -> bool {
+    match res {
+        Ok(doc) => is_ok(doc) && !doc.contains_key("writeErrors"),
+        Err(_) => false,
+    }
+}
+
+fn dispatch(
+    request: &Request,
+    command: &str,
+    docs: &Vec<Document>,
+) -> Result<Document, CommandExecutionError> {
     if command == "find" {
         Find::new().handle(request, docs)
+    } else if command == "getMore" {
+        GetMore::new().handle(request, docs)
+    } else if command == "killCursors" {
+        KillCursors::new().handle(request, docs)
     } else if command == "findAndModify" {
         FindAndModify::new().handle(request, docs)
     } else if command == "count" {
@@ -166,6 +402,38 @@ fn run(request: &Request, docs: &Vec<Document>) -> Result<Document, CommandExecu
         GetParameter::new().handle(request, docs)
     } else if command == "connectionStatus" {
         ConnectionStatus::new().handle(request, docs)
+    } else if command == "saslStart" {
+        SaslStart::new().handle(request, docs)
+    } else if command == "saslContinue" {
+        SaslContinue::new().handle(request, docs)
+    } else if command == "logout" {
+        Logout::new().handle(request, docs)
+    } else if command == "createUser" {
+        CreateUser::new().handle(request, docs)
+    } else if command == "dropUser" {
+        DropUser::new().handle(request, docs)
+    } else if command == "updateUser" {
+        UpdateUser::new().handle(request, docs)
+    } else if command == "usersInfo" {
+        UsersInfo::new().handle(request, docs)
+    } else if command == "createRole" {
+        CreateRole::new().handle(request, docs)
+    } else if command == "dropRole" {
+        DropRole::new().handle(request, docs)
+    } else if command == "grantRolesToUser" {
+        GrantRolesToUser::new().handle(request, docs)
+    } else if command == "revokeRolesFromUser" {
+        RevokeRolesFromUser::new().handle(request, docs)
+    } else if command == "startSession" {
+        StartSession::new().handle(request, docs)
+    } else if command == "endSessions" {
+        EndSessions::new().handle(request, docs)
+    } else if command == "refreshSessions" {
+        RefreshSessions::new().handle(request, docs)
+    } else if command == "commitTransaction" {
+        CommitTransaction::new().handle(request, docs)
+    } else if command == "abortTransaction" {
+        AbortTransaction::new().handle(request, docs)
     } else {
         log::error!("Got unknown OP_MSG command: {}\n{:?}", command, docs);
         Ok(doc! {
@@ -177,10 +445,107 @@ fn run(request: &Request, docs: &Vec<Document>) -> Result<Document, CommandExecu
     }
 }
 
+/// Returns the error reply for commands that need an authenticated connection
+/// when authentication is enabled and the client hasn't authenticated yet.
+fn check_authentication(request: &Request, command: &str) -> Option<Document> {
+    {
+        let auth = request.get_auth();
+        if !auth.is_enabled() || auth.is_authenticated() || !requires_authentication(command) {
+            return None;
+        }
+    }
+
+    // localhost exception: the first user can be created from the server's
+    // own host while there are none
+    if command == "createUser" && request.peer_addr().ip().is_loopback() {
+        match request.get_client().has_users() {
+            Ok(false) => return None,
+            Ok(true) => {}
+            Err(e) => log::error!("Error checking for existing users: {}", e),
+        }
+    }
+
+    Some(doc! {
+        "ok": Bson::Double(0.0),
+        "errmsg": format!("command {} requires authentication", command),
+        "code": Bson::Int32(13),
+        "codeName": "Unauthorized",
+    })
+}
+
+/// Returns the error reply for commands on the schemas that hold users,
+/// sessions and changes, which no client is allowed to reach.
+fn check_internal_schema(command: &str, doc: &Document) -> Option<Document> {
+    let db = doc.get_str("$db").unwrap_or_default();
+    if !is_internal_schema(db) {
+        return None;
+    }
+
+    Some(doc! {
+        "ok": Bson::Double(0.0),
+        "errmsg": format!("not authorized on {} to execute command {{ {}: {} }}", db, command, doc.get(command).unwrap_or(&Bson::Null)),
+        "code": Bson::Int32(13),
+        "codeName": "Unauthorized",
+    })
+}
+
+/// Returns the error reply for commands the authenticated users aren't
+/// allowed to run, according to the privileges granted by their roles.
+fn check_authorization(request: &Request, command: &str, doc: &Document) -> Option<Document> {
+    let users = {
+        let auth = request.get_auth();
+        // unauthenticated commands already went through check_authentication
+        if !auth.is_enabled() || !auth.is_authenticated() {
+            return None;
+        }
+        auth.authenticated_users().clone()
+    };
+    let required = required_privileges(command, doc);
+    if required.is_empty() {
+        return None;
+    }
+
+    // roles are read on every command so grants and revokes apply right away
+    let mut client = request.get_client();
+    let mut roles = vec![];
+    for user in users {
+        match client.get_user(&user.db, &user.user) {
+            Ok(Some(user)) => roles.extend(role_names(&user)),
+            Ok(None) => {}
+            Err(e) => log::error!("Error reading user {}@{}: {}", user.user, user.db, e),
+        }
+    }
+    let privileges = resolve_privileges(roles, |role| client.get_role(&role.db, &role.role))
+        .unwrap_or_else(|e| {
+            log::error!("Error reading roles: {}", e);
+            vec![]
+        });
+    if is_authorized(&privileges, &required) {
+        return None;
+    }
+
+    Some(doc! {
+        "ok": Bson::Double(0.0),
+        "errmsg": format!(
+            "not authorized on {} to execute command {{ {}: {} }}",
+            doc.get_str("$db").unwrap_or_default(),
+            command,
+            doc.get(command).unwrap_or(&Bson::Null)
+        ),
+        "code": Bson::Int32(13),
+        "codeName": "Unauthorized",
+    })
+}
+
 fn run_op_query(
     request: &Request,
     docs: &Vec<Document>,
 ) -> Result<Document, CommandExecutionError> {
+    // legacy drivers wrap the command when they add a read preference
+    if let Ok(query) = docs[0].get_document("$query") {
+        return run_op_query(request, &vec![query.clone()]);
+    }
+
     let empty = "".to_string();
     let command = docs[0].keys().next().unwrap_or(&empty);
 
@@ -199,91 +564,15 @@ fn run_op_query(
     }
 }
 
-fn handle_op_msg(request: &Request, msg: OpMsg) -> Result<Document, CommandExecutionError> {
-    if msg.sections.len() < 1 {
-        log::error!("Received OP_MSG with no sections:\n{:#?}", msg);
-        return Err(CommandExecutionError::new(
-            "OP_MSG must have at least one section, received none".to_string(),
-        ));
-    }
-
-    let section = msg.sections[0].clone();
-    if section.kind == 0 {
-        let mut documents = section.documents.clone();
-        if msg.sections.len() > 1 {
-            for section in msg.sections[1..].iter() {
-                if let Some(identifier) = section.identifier.clone() {
-                    if identifier == "documents\0" {
-                        let new_doc = section.documents[0].clone();
-                        documents[0].insert("documents", Bson::Array(vec![new_doc.into()]));
-                    }
-                }
-            }
-        }
-        return run(request, &documents);
-    }
-
-    if section.kind == 1 {
-        if section.identifier.is_none() {
-            log::error!(
-                "Received a kind 1 section from OP_MSG with no identifier:\n{:#?}",
-                msg
-            );
-            return Err(CommandExecutionError::new(
-                "all kind 1 sections on OP_MSG must have an identifier, received none".to_string(),
-            ));
-        }
-
-        let mut identifier = section.identifier.unwrap();
-        identifier.pop();
-
-        if identifier == "documents" {
-            if msg.sections.len() < 2 {
-                log::error!(
-                    "Received a document kind 1 section with no matching kind 0:\n{:#?}",
-                    msg
-                );
-                return Err(CommandExecutionError::new(
-                    "OP_MSG with a kind 1 documents section must also have at least one kind 0 section, received none".to_string(),
-                ));
-            }
-
-            let mut doc = msg.sections[1].documents[0].clone();
-            doc.insert(identifier, section.documents.clone());
-            return run(request, &vec![doc]);
-        }
-
-        log::error!(
-            "Received unknown kind 1 section identifier from OP_MSG:\n{:#?}",
-            msg
-        );
-        return Err(CommandExecutionError::new(
-            format!(
-                "received unknown kind 1 section identifier from OP_MSG: {}",
-                identifier
-            )
-            .to_string(),
-        ));
-    }
-
-    log::error!(
-        "Received unknown section from OP_MSG: {}\n{:#?}",
-        section.kind,
-        msg
-    );
-    Err(CommandExecutionError::new(
-        format!(
-            "received unknown section kind from OP_MSG: {}",
-            section.kind
-        )
-        .to_string(),
-    ))
-}
-
 fn route(request: &Request) -> Result<Document, CommandExecutionError> {
     match request.op_code {
-        OpCode::OpMsg(msg) => handle_op_msg(request, msg.clone()),
+        OpCode::OpMsg(msg) => run(request, &vec![msg.to_command()]),
         OpCode::OpQuery(query) => run_op_query(request, &vec![query.query.clone()]),
+        OpCode::OpCompressed(compressed) => route(&Request {
+            op_code: &compressed.message,
+            transaction: request.transaction.clone(),
+            ..*request
+        }),
         _ => {
             log::error!("Unroutable opcode received: {:?}", request.op_code);
             Err(CommandExecutionError::new(format!(
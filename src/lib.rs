@@ -1,11 +1,15 @@
+pub mod auth;
+pub mod change_stream;
 pub mod commands;
+pub mod cursor;
 pub mod deserializer;
 pub mod handler;
 pub mod parser;
 pub mod pg;
 pub mod serializer;
 pub mod server;
+pub mod session;
 pub mod shell;
-pub mod threadpool;
+pub mod tls;
 pub mod utils;
 pub mod wire;
@@ -3,19 +3,24 @@ use indoc::indoc;
 use server::Server;
 use std::env;
 use std::thread;
+use tls::TlsOptions;
 
 #[macro_use]
 extern crate nickel;
 
+pub mod auth;
+pub mod change_stream;
 pub mod commands;
+pub mod cursor;
 pub mod deserializer;
 pub mod handler;
 pub mod parser;
 pub mod pg;
 pub mod serializer;
 pub mod server;
+pub mod session;
 pub mod shell;
-pub mod threadpool;
+pub mod tls;
 pub mod ui;
 pub mod utils;
 pub mod wire;
@@ -68,6 +73,26 @@ struct Cli {
     #[clap(short = 'u', long)]
     postgres_url: Option<String>,
 
+    /// Maximum number of client connections, defaults to 1000
+    #[clap(long, value_parser = parse_max_connections)]
+    max_connections: Option<usize>,
+
+    /// Requires clients to authenticate
+    #[clap(long)]
+    auth: bool,
+
+    /// PEM file with the TLS certificate, enables TLS along with --tls-key
+    #[clap(long)]
+    tls_cert: Option<String>,
+
+    /// PEM file with the private key of the TLS certificate
+    #[clap(long)]
+    tls_key: Option<String>,
+
+    /// PEM file with the CA that client certificates must be signed by
+    #[clap(long)]
+    tls_ca: Option<String>,
+
     /// Starts web interface
     #[clap(short, long)]
     web: bool,
@@ -81,6 +106,15 @@ struct Cli {
     debug: bool,
 }
 
+// at least one client has to be able to connect
+fn parse_max_connections(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(0) => Err("must be at least 1".to_string()),
+        Ok(max_connections) => Ok(max_connections),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
 fn main() {
     dotenv::dotenv().ok();
 
@@ -115,10 +149,31 @@ fn main() {
             shell.start();
         }
         None => {
+            let tls_cert = cli.tls_cert.or_else(|| env::var("OXIDE_TLS_CERT").ok());
+            let tls_key = cli.tls_key.or_else(|| env::var("OXIDE_TLS_KEY").ok());
+            let tls_ca = cli.tls_ca.or_else(|| env::var("OXIDE_TLS_CA").ok());
+            let tls = match (tls_cert, tls_key) {
+                (Some(cert), Some(key)) => {
+                    let options = TlsOptions::new(cert, key);
+                    Some(match tls_ca {
+                        Some(ca) => options.with_ca(ca),
+                        None => options,
+                    })
+                }
+                (None, None) if tls_ca.is_none() => None,
+                _ => {
+                    log::error!("Both --tls-cert and --tls-key are required to enable TLS.");
+                    return;
+                }
+            };
+
             start(
                 cli.listen_addr,
                 cli.port,
                 cli.postgres_url,
+                cli.max_connections,
+                cli.auth,
+                tls,
                 cli.web,
                 cli.web_addr,
             );
@@ -129,6 +184,9 @@ fn main() {
         listen_addr: Option<String>,
         port: Option<u16>,
         postgres_url: Option<String>,
+        max_connections: Option<usize>,
+        auth: bool,
+        tls: Option<TlsOptions>,
         web: bool,
         web_addr: Option<String>,
     ) {
@@ -140,6 +198,16 @@ fn main() {
                 .parse()
                 .unwrap(),
         );
+        let max_connections = max_connections.unwrap_or(
+            env::var("OXIDE_MAX_CONNECTIONS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(server::DEFAULT_MAX_CONNECTIONS),
+        );
+        let auth = auth
+            || env::var("OXIDE_AUTH")
+                .map(|v| v == "1" || v == "true")
+                .unwrap_or(false);
         let mut pg_url = postgres_url;
         if pg_url.is_none() {
             pg_url = env::var("DATABASE_URL").ok();
@@ -158,7 +226,19 @@ fn main() {
                 });
             }
 
-            Server::new_with_pgurl(ip_addr, port, pg_url).start();
+            let mut server = match Server::new_with_pgurl(ip_addr, port, pg_url)
+                .with_max_connections(max_connections)
+            {
+                Ok(server) => server.with_auth(auth),
+                Err(e) => {
+                    log::error!("Invalid OXIDE_MAX_CONNECTIONS: {}", e);
+                    return;
+                }
+            };
+            if let Some(tls) = tls {
+                server = server.with_tls(tls);
+            }
+            server.start();
         } else {
             log::error!(indoc! {"
                     No PostgreSQL URL specified.
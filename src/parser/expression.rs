@@ -0,0 +1,368 @@
+use bson::Bson;
+use eyre::Result;
+
+use super::{escape, invalid_query};
+use crate::pg::FUNCTIONS_SCHEMA;
+use crate::serializer::PostgresSerializer;
+
+/// Compiles an aggregation expression, like `{ "$gt": ["$spent", "$budget"] }`,
+/// into SQL giving its value as jsonb, encoded like stored documents, or null
+/// when the value is missing.
+pub fn expression_to_sql(expr: &Bson) -> Result<String> {
+    match expr {
+        Bson::String(s) if s.starts_with('$') => field_path(s),
+        Bson::Document(doc) => match doc.keys().next() {
+            Some(key) if key.starts_with('$') => {
+                if doc.len() > 1 {
+                    return Err(invalid_query(format!(
+                        "an expression specification must contain exactly one field, the name of the expression. Found {} fields",
+                        doc.len()
+                    )));
+                }
+                operator_to_sql(key, doc.get(key).unwrap())
+            }
+            _ => {
+                let fields = doc
+                    .iter()
+                    .map(|(key, value)| {
+                        Ok(format!("'{}', {}", escape(key), expression_to_sql(value)?))
+                    })
+                    .collect::<Result<Vec<String>>>()?;
+                Ok(format!("jsonb_build_object({})", fields.join(", ")))
+            }
+        },
+        Bson::Array(values) => {
+            let values = values
+                .iter()
+                .map(expression_to_sql)
+                .collect::<Result<Vec<String>>>()?;
+            Ok(format!("jsonb_build_array({})", values.join(", ")))
+        }
+        value => Ok(literal(value)),
+    }
+}
+
+/// Compiles an expression into a condition that holds when its value is
+/// anything but false, null, zero or missing.
+pub fn condition_to_sql(expr: &Bson) -> Result<String> {
+    Ok(format!(
+        "{}.bson_truthy({})",
+        FUNCTIONS_SCHEMA,
+        expression_to_sql(expr)?
+    ))
+}
+
+/// Compiles an expression into SQL giving its value as a numeric, or null when
+/// it isn't a number.
+pub fn number_to_sql(expr: &Bson) -> Result<String> {
+    match expr {
+        Bson::Int32(n) => return Ok(n.to_string()),
+        Bson::Int64(n) => return Ok(n.to_string()),
+        Bson::Double(n) if n.is_finite() => return Ok(n.to_string()),
+        Bson::Document(doc) if doc.len() == 1 => {
+            let (key, args) = doc.iter().next().unwrap();
+            if let Some(arithmetic) = arithmetic_to_sql(key, args) {
+                return Ok(arithmetic?.0);
+            }
+        }
+        _ => {}
+    }
+    Ok(format!(
+        "{}.bson_number({})",
+        FUNCTIONS_SCHEMA,
+        expression_to_sql(expr)?
+    ))
+}
+
+fn operator_to_sql(name: &str, args: &Bson) -> Result<String> {
+    let schema = FUNCTIONS_SCHEMA;
+    if let Some(arithmetic) = arithmetic_to_sql(name, args) {
+        let (sql, double) = arithmetic?;
+        return Ok(format!("{}.bson_from_number({}, {})", schema, sql, double));
+    }
+
+    match name {
+        "$literal" => Ok(literal(args)),
+        "$eq" | "$ne" | "$gt" | "$gte" | "$lt" | "$lte" | "$cmp" => {
+            let args = arguments(name, args, Some(2))?;
+            // values of any type compare in BSON order, missing ones as null
+            let cmp = format!(
+                "{}.bson_compare(COALESCE({}, 'null'), COALESCE({}, 'null'))",
+                schema, args[0], args[1]
+            );
+            let oper = match name {
+                "$eq" => "=",
+                "$ne" => "<>",
+                "$gt" => ">",
+                "$gte" => ">=",
+                "$lt" => "<",
+                "$lte" => "<=",
+                _ => return Ok(format!("to_jsonb({})", cmp)),
+            };
+            Ok(format!("to_jsonb({} {} 0)", cmp, oper))
+        }
+        "$and" | "$or" => {
+            let conditions = argument_list(args)
+                .iter()
+                .map(condition_to_sql)
+                .collect::<Result<Vec<String>>>()?;
+            if conditions.is_empty() {
+                return Ok(literal(&Bson::Boolean(name == "$and")));
+            }
+            let oper = if name == "$and" { " AND " } else { " OR " };
+            Ok(format!("to_jsonb({})", conditions.join(oper)))
+        }
+        "$not" => {
+            let args = argument_list(args);
+            if args.len() != 1 {
+                return Err(wrong_arguments(name, 1, args.len()));
+            }
+            Ok(format!("to_jsonb(NOT {})", condition_to_sql(&args[0])?))
+        }
+        "$cond" => {
+            let (condition, then, otherwise) = match args {
+                Bson::Array(args) if args.len() == 3 => (&args[0], &args[1], &args[2]),
+                Bson::Array(args) => return Err(wrong_arguments(name, 3, args.len())),
+                Bson::Document(args) => {
+                    match (args.get("if"), args.get("then"), args.get("else")) {
+                        (Some(condition), Some(then), Some(otherwise)) => {
+                            (condition, then, otherwise)
+                        }
+                        (None, _, _) => {
+                            return Err(invalid_query("Missing 'if' parameter to $cond"))
+                        }
+                        (_, None, _) => {
+                            return Err(invalid_query("Missing 'then' parameter to $cond"))
+                        }
+                        (_, _, None) => {
+                            return Err(invalid_query("Missing 'else' parameter to $cond"))
+                        }
+                    }
+                }
+                _ => return Err(wrong_arguments(name, 3, 1)),
+            };
+            Ok(format!(
+                "CASE WHEN {} THEN {} ELSE {} END",
+                condition_to_sql(condition)?,
+                expression_to_sql(then)?,
+                expression_to_sql(otherwise)?
+            ))
+        }
+        "$ifNull" => {
+            let mut args = arguments(name, args, None)?;
+            if args.len() < 2 {
+                return Err(invalid_query("$ifNull needs at least two arguments"));
+            }
+            // the last value is the replacement, even when it's null
+            let replacement = args.pop().unwrap();
+            let values = args
+                .iter()
+                .map(|value| format!("NULLIF({}, 'null')", value))
+                .collect::<Vec<String>>();
+            Ok(format!("COALESCE({}, {})", values.join(", "), replacement))
+        }
+        "$concat" => {
+            let args = arguments(name, args, None)?;
+            if args.is_empty() {
+                return Ok(literal(&Bson::String("".to_string())));
+            }
+            let strings = args
+                .iter()
+                .map(|value| format!("({} #>> '{{}}')", value))
+                .collect::<Vec<String>>();
+            Ok(format!("to_jsonb({})", strings.join(" || ")))
+        }
+        "$toLower" | "$toUpper" => {
+            let args = arguments(name, args, Some(1))?;
+            let func = if name == "$toLower" { "lower" } else { "upper" };
+            Ok(format!(
+                "to_jsonb({}(COALESCE({} #>> '{{}}', '')))",
+                func, args[0]
+            ))
+        }
+        "$size" => {
+            let args = arguments(name, args, Some(1))?;
+            Ok(format!("to_jsonb(jsonb_array_length({}))", args[0]))
+        }
+        "$in" => {
+            let args = arguments(name, args, Some(2))?;
+            Ok(format!(
+                "to_jsonb(EXISTS (SELECT 1 FROM jsonb_array_elements({}) AS e(v) WHERE {}.bson_compare(v, COALESCE({}, 'null')) = 0))",
+                args[1], schema, args[0]
+            ))
+        }
+        _ => Err(invalid_query(format!("Unrecognized expression '{}'", name))),
+    }
+}
+
+// The numeric SQL of an arithmetic expression and whether its result is a
+// double, which it is when any operand is one, or None for other expressions.
+fn arithmetic_to_sql(name: &str, args: &Bson) -> Option<Result<(String, String)>> {
+    let (oper, count) = match name {
+        "$add" => (" + ", None),
+        "$multiply" => (" * ", None),
+        "$subtract" => (" - ", Some(2)),
+        "$divide" => (" / ", Some(2)),
+        "$mod" => ("mod", Some(2)),
+        "$abs" => ("abs", Some(1)),
+        _ => return None,
+    };
+
+    let build = || {
+        let args = arguments(name, args, count)?;
+        if args.is_empty() {
+            return Ok(("0".to_string(), "FALSE".to_string()));
+        }
+        let numbers = args
+            .iter()
+            .map(|arg| format!("{}.bson_number({})", FUNCTIONS_SCHEMA, arg))
+            .collect::<Vec<String>>();
+        let sql = match oper {
+            "mod" | "abs" => format!("{}({})", oper, numbers.join(", ")),
+            oper => format!("({})", numbers.join(oper)),
+        };
+        let double = if name == "$divide" {
+            "TRUE".to_string()
+        } else {
+            let doubles = args
+                .iter()
+                .map(|arg| {
+                    format!(
+                        "{}.bson_type({}) IN ('double', 'decimal')",
+                        FUNCTIONS_SCHEMA, arg
+                    )
+                })
+                .collect::<Vec<String>>();
+            format!("({})", doubles.join(" OR "))
+        };
+        Ok((sql, double))
+    };
+    Some(build())
+}
+
+// `$$ROOT`, `$$CURRENT` and `$a.b` paths, which don't go into arrays
+fn field_path(path: &str) -> Result<String> {
+    let path = match path.strip_prefix("$$") {
+        Some(var) => {
+            let (name, rest) = var.split_once('.').unwrap_or((var, ""));
+            if name != "ROOT" && name != "CURRENT" {
+                return Err(invalid_query(format!(
+                    "Use of undefined variable: {}",
+                    name
+                )));
+            }
+            rest
+        }
+        None => &path[1..],
+    };
+    if path.is_empty() {
+        return Ok("_jsonb".to_string());
+    }
+    let fields = path
+        .split('.')
+        .map(|field| format!("->'{}'", escape(field)))
+        .collect::<Vec<String>>();
+    Ok(format!("_jsonb{}", fields.join("")))
+}
+
+fn literal(value: &Bson) -> String {
+    let json = value.clone().into_psql_json().to_string();
+    format!("'{}'::jsonb", escape(&json))
+}
+
+// expressions take an array of arguments, or a single argument on its own
+fn argument_list(args: &Bson) -> Vec<Bson> {
+    match args {
+        Bson::Array(args) => args.clone(),
+        arg => vec![arg.clone()],
+    }
+}
+
+fn arguments(name: &str, args: &Bson, count: Option<usize>) -> Result<Vec<String>> {
+    let args = argument_list(args);
+    if let Some(count) = count {
+        if args.len() != count {
+            return Err(wrong_arguments(name, count, args.len()));
+        }
+    }
+    args.iter().map(expression_to_sql).collect()
+}
+
+fn wrong_arguments(name: &str, expected: usize, passed: usize) -> eyre::Report {
+    invalid_query(format!(
+        "Expression {} takes exactly {} arguments. {} were passed in.",
+        name, expected, passed
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::{bson, doc};
+
+    #[test]
+    fn test_field_path() {
+        assert_eq!(
+            expression_to_sql(&bson!("$a.b")).unwrap(),
+            "_jsonb->'a'->'b'"
+        );
+        assert_eq!(expression_to_sql(&bson!("$$ROOT")).unwrap(), "_jsonb");
+        assert!(expression_to_sql(&bson!("$$foo")).is_err());
+    }
+
+    #[test]
+    fn test_literal() {
+        assert_eq!(
+            expression_to_sql(&bson!({ "$literal": "$it's" })).unwrap(),
+            r#"'"$it''s"'::jsonb"#
+        );
+        assert_eq!(
+            expression_to_sql(&bson!({ "a": 1.5, "b": ["$c"] })).unwrap(),
+            r#"jsonb_build_object('a', '{"$f":1.5}'::jsonb, 'b', jsonb_build_array(_jsonb->'c'))"#
+        );
+    }
+
+    #[test]
+    fn test_comparison() {
+        assert_eq!(
+            condition_to_sql(&bson!({ "$gt": ["$spent", "$budget"] })).unwrap(),
+            "oxide_functions.bson_truthy(to_jsonb(oxide_functions.bson_compare(COALESCE(_jsonb->'spent', 'null'), COALESCE(_jsonb->'budget', 'null')) > 0))"
+        );
+        let err = expression_to_sql(&bson!({ "$gt": ["$spent"] })).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Expression $gt takes exactly 2 arguments. 1 were passed in."
+        );
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(
+            number_to_sql(&bson!({ "$multiply": ["$a", 2] })).unwrap(),
+            "(oxide_functions.bson_number(_jsonb->'a') * oxide_functions.bson_number('2'::jsonb))"
+        );
+        assert_eq!(
+            expression_to_sql(&bson!({ "$divide": ["$a", "$b"] })).unwrap(),
+            "oxide_functions.bson_from_number((oxide_functions.bson_number(_jsonb->'a') / oxide_functions.bson_number(_jsonb->'b')), TRUE)"
+        );
+        assert_eq!(number_to_sql(&bson!(1)).unwrap(), "1");
+    }
+
+    #[test]
+    fn test_cond() {
+        assert_eq!(
+            expression_to_sql(&Bson::Document(doc! {
+                "$cond": { "if": "$a", "then": "yes", "else": "$b" }
+            }))
+            .unwrap(),
+            r#"CASE WHEN oxide_functions.bson_truthy(_jsonb->'a') THEN '"yes"'::jsonb ELSE _jsonb->'b' END"#
+        );
+        assert!(expression_to_sql(&bson!({ "$cond": { "if": true } })).is_err());
+    }
+
+    #[test]
+    fn test_unknown_expression() {
+        let err = expression_to_sql(&bson!({ "$foo": 1 })).unwrap_err();
+        assert_eq!(err.to_string(), "Unrecognized expression '$foo'");
+    }
+}
@@ -1,20 +1,25 @@
 #![allow(dead_code)]
-use crate::utils::{field_to_jsonb, flatten_object};
+use crate::deserializer::PostgresJsonDeserializer;
+use crate::pg::FUNCTIONS_SCHEMA;
 use crate::{serializer::PostgresSerializer, utils::expand_object};
-use bson::{Bson, Document};
-use eyre::{eyre, Result};
+use bson::{doc, Bson, Document};
+use eyre::Result;
 use mongodb_language_model::{
     Clause, Expression, ExpressionTreeClause, LeafClause, LeafValue, ListOperator, Operator,
     OperatorExpressionOperator, Value, ValueOperator,
 };
 use serde_json::Map;
-use std::fmt;
 
+pub use self::expression::{condition_to_sql, expression_to_sql, number_to_sql};
+use self::regex::regex_to_sql;
 pub use self::update_parser::parse_update;
 pub use self::update_parser::InvalidUpdateError;
 pub use self::update_parser::UpdateDoc;
 pub use self::update_parser::UpdateOper;
+pub use self::update_parser::{as_integer, each_modifiers, upsert_seed};
 
+mod expression;
+mod regex;
 mod update_parser;
 
 #[derive(Debug)]
@@ -40,6 +45,39 @@ impl std::fmt::Display for UnimplementedError {
 
 impl std::error::Error for UnimplementedError {}
 
+/// A query that can't be translated, answered with a `BadValue` error.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidQueryError {
+    pub message: String,
+}
+
+impl InvalidQueryError {
+    pub fn new(message: String) -> Self {
+        InvalidQueryError { message }
+    }
+
+    pub fn to_doc(&self) -> Document {
+        doc! {
+            "ok": Bson::Double(0.0),
+            "errmsg": &self.message,
+            "code": Bson::Int32(2),
+            "codeName": "BadValue",
+        }
+    }
+}
+
+impl std::fmt::Display for InvalidQueryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for InvalidQueryError {}
+
+fn invalid_query(message: impl Into<String>) -> eyre::Report {
+    InvalidQueryError::new(message.into()).into()
+}
+
 pub fn parse(doc: Document) -> Result<String> {
     if doc.is_empty() {
         return Ok("".to_string());
@@ -48,12 +86,113 @@ pub fn parse(doc: Document) -> Result<String> {
     let json = serde_json::Value::Object(
         expand_object(bson.into_psql_json().as_object().unwrap()).unwrap(),
     );
-    let str = serde_json::to_string(&json)?;
-    let expression = mongodb_language_model::parse(&str)?;
+    parse_json(json)
+}
+
+fn parse_json(json: serde_json::Value) -> Result<String> {
+    let expression = match json.as_object() {
+        Some(obj) => to_expression(obj)?,
+        None => return Err(invalid_query("query filter must be an object")),
+    };
     log::trace!("{:#?}", expression);
     parse_expression(expression)
 }
 
+// Reads a filter into the syntax tree of mongodb_language_model. Its grammar
+// can't hold arrays of values or `$elemMatch`, so it isn't used to parse them.
+// Objects made only of known operators are operators, any other object is a
+// value.
+fn to_expression(obj: &Map<String, serde_json::Value>) -> Result<Expression> {
+    let mut clauses = vec![];
+    for (key, value) in obj {
+        let clause = match key.as_str() {
+            "$and" | "$or" | "$nor" => {
+                let values = value
+                    .as_array()
+                    .ok_or_else(|| invalid_query(format!("{} must be an array", key)))?;
+                let expressions = values
+                    .iter()
+                    .map(|value| match value.as_object() {
+                        Some(obj) => to_expression(obj),
+                        None => Err(invalid_query(
+                            "$or/$and/$nor entries need to be full objects",
+                        )),
+                    })
+                    .collect::<Result<Vec<Expression>>>()?;
+                Clause::ExpressionTree(ExpressionTreeClause {
+                    operator: key.to_string(),
+                    expressions,
+                })
+            }
+            // an aggregation expression, compiled on its own
+            "$expr" => Clause::Leaf(LeafClause {
+                key: key.to_string(),
+                value: Value::Leaf(LeafValue {
+                    value: value.clone(),
+                }),
+            }),
+            _ => Clause::Leaf(LeafClause {
+                key: key.to_string(),
+                value: to_value(value),
+            }),
+        };
+        clauses.push(clause);
+    }
+    Ok(Expression { clauses })
+}
+
+fn to_value(value: &serde_json::Value) -> Value {
+    if let Some(obj) = value.as_object().filter(|obj| !obj.is_empty()) {
+        let operators: Option<Vec<Operator>> = obj
+            .iter()
+            .map(|(key, value)| to_operator(key, value))
+            .collect();
+        if let Some(operators) = operators {
+            return Value::Operators(operators);
+        }
+    }
+    Value::Leaf(LeafValue {
+        value: value.clone(),
+    })
+}
+
+fn to_operator(key: &str, value: &serde_json::Value) -> Option<Operator> {
+    match key {
+        "$gte" | "$gt" | "$lte" | "$lt" | "$eq" | "$ne" | "$type" | "$size" | "$exists"
+        | "$elemMatch" | "$bitsAllClear" | "$bitsAllSet" | "$bitsAnyClear" | "$bitsAnySet" => {
+            Some(Operator::Value(ValueOperator {
+                operator: key.to_string(),
+                value: LeafValue {
+                    value: value.clone(),
+                },
+            }))
+        }
+        "$in" | "$nin" | "$all" | "$mod" => value.as_array().map(|values| {
+            Operator::List(ListOperator {
+                operator: key.to_string(),
+                values: values
+                    .iter()
+                    .map(|value| LeafValue {
+                        value: value.clone(),
+                    })
+                    .collect(),
+            })
+        }),
+        "$not" => {
+            let obj = value.as_object().filter(|obj| !obj.is_empty())?;
+            let operators: Option<Vec<Operator>> = obj
+                .iter()
+                .map(|(key, value)| to_operator(key, value))
+                .collect();
+            Some(Operator::ExpressionOperator(OperatorExpressionOperator {
+                operator: key.to_string(),
+                operators: operators?,
+            }))
+        }
+        _ => None,
+    }
+}
+
 fn parse_expression(expression: Expression) -> Result<String> {
     parse_clauses(expression.clauses)
 }
@@ -84,6 +223,15 @@ fn parse_clause(clause: Clause) -> Result<String> {
 }
 
 fn parse_leaf(leaf: LeafClause) -> Result<String> {
+    if let ("$expr", Value::Leaf(leaf_value)) = (leaf.key.as_str(), &leaf.value) {
+        return condition_to_sql(&leaf_value.value.from_psql_json()?);
+    }
+    if leaf.key.starts_with('$') {
+        return Err(invalid_query(format!(
+            "unknown top level operator: {}",
+            leaf.key
+        )));
+    }
     match leaf.value {
         Value::Leaf(leaf_value) => parse_leaf_value(leaf_value, leaf.key, None),
         Value::Operators(val_operators) => parse_value_operators(val_operators, leaf.key),
@@ -97,7 +245,7 @@ fn parse_value_operators(val_operators: Vec<Operator>, field: String) -> Result<
         .collect();
 
     if let Ok(values) = values {
-        Ok(values.join(""))
+        Ok(values.join(" AND "))
     } else {
         Err(values.unwrap_err())
     }
@@ -112,43 +260,18 @@ fn parse_operator(oper: Operator, field: String) -> Result<String> {
 }
 
 fn parse_list_operator(list_oper: ListOperator, field: String) -> Result<String> {
+    let values: Vec<serde_json::Value> = list_oper
+        .values
+        .into_iter()
+        .map(|leaf_value| leaf_value.value)
+        .collect();
+    let parts = field.split('.').collect::<Vec<&str>>();
     match list_oper.operator.as_str() {
-        "$in" | "$nin" => {
-            let values: Vec<serde_json::Value> = list_oper
-                .values
-                .into_iter()
-                .map(|leaf_value| leaf_value.value)
-                .collect();
-            let jsonb_field = if field.contains(".") {
-                let fields = field
-                    .split(".")
-                    .collect::<Vec<&str>>()
-                    .iter()
-                    .map(|f| format!("'{}'", f))
-                    .collect::<Vec<String>>()
-                    .join("->>");
-                format!("_jsonb->{}", fields)
-            } else {
-                format!("_jsonb->>'{}'", field)
-            };
-
-            let clause = format!(
-                "{} = ANY('{{{}}}')",
-                jsonb_field,
-                values
-                    .iter()
-                    .map(|v| v.to_string())
-                    .collect::<Vec<String>>()
-                    .join(", ")
-            );
-
-            if list_oper.operator.as_str() == "$in" {
-                Ok(clause)
-            } else {
-                Ok(format!("NOT ({})", clause))
-            }
-        }
-        t => return Err(eyre!("list operator in parse_list_operator: {}", t)),
+        "$in" => parse_in(&parts, &values, false),
+        "$nin" => parse_in(&parts, &values, true),
+        "$all" => Ok(parse_all(&parts, &values)),
+        "$mod" => parse_mod(&field_to_path(&parts), &values),
+        t => Err(invalid_query(format!("unsupported operator: {}", t))),
     }
 }
 
@@ -165,12 +288,7 @@ fn parse_expression_operator(
         let operators_str = operators.join("");
         match expr_oper.operator.as_str() {
             "$not" => Ok(format!("NOT ({})", operators_str)),
-            t => {
-                return Err(eyre!(
-                    "parse_expression_operator - unimplemented operator {:?}",
-                    t
-                ))
-            }
+            t => Err(invalid_query(format!("unsupported operator: {}", t))),
         }
     } else {
         return Err(operators.unwrap_err());
@@ -202,13 +320,13 @@ fn parse_value_operator(value_oper: ValueOperator, field: String) -> Result<Stri
             let (field, target) = if field.contains(".") {
                 let parts = field.split(".").collect::<Vec<&str>>();
                 let field = parts[parts.len() - 1].to_string();
-                let target = format!("_jsonb->'{}'", parts[0..parts.len() - 1].join("'->'"));
+                let target = field_to_path(&parts[0..parts.len() - 1]);
                 (field, target)
             } else {
                 (field, source)
             };
 
-            let stmt = format!("{} ? '{}'", target, field);
+            let stmt = format!("{} ? '{}'", target, escape(&field));
             if (value.is_boolean() && !value.as_bool().unwrap())
                 || value.is_number() && value.as_i64().unwrap() == 0
             {
@@ -217,7 +335,16 @@ fn parse_value_operator(value_oper: ValueOperator, field: String) -> Result<Stri
                 return Ok(stmt);
             }
         }
-        t => unimplemented!("parse_value_operator - operator unimplemented {:?}", t),
+        "$size" | "$type" | "$elemMatch" => {
+            let field = field_to_path(&field.split('.').collect::<Vec<&str>>());
+            let value = &value_oper.value.value;
+            return match value_oper.operator.as_str() {
+                "$size" => parse_size(&field, value),
+                "$type" => parse_type(&field, value),
+                _ => parse_elem_match(&field, value),
+            };
+        }
+        t => return Err(invalid_query(format!("unsupported operator: {}", t))),
     };
 
     parse_leaf_value(value_oper.value, field, Some(operator))
@@ -226,56 +353,415 @@ fn parse_value_operator(value_oper: ValueOperator, field: String) -> Result<Stri
 fn parse_expression_tree(exp_tree: ExpressionTreeClause) -> Result<String> {
     let operator = match exp_tree.operator.as_str() {
         "$and" => "AND",
-        "$or" => "OR",
-        // FIXME handle $nor expression
-        // #17 - https://github.com/fcoury/oxide/issues/17
-        // "$nor" => "NOR", FIXME
-        t => {
-            return Err(eyre!(
-                "parse_expression_tree operator unimplemented = {:?}",
-                t
-            ))
-        }
+        "$or" | "$nor" => "OR",
+        t => return Err(invalid_query(format!("unknown top level operator: {}", t))),
     };
-    let sql: Vec<String> = exp_tree
+    let sql = exp_tree
         .expressions
         .into_iter()
-        .map(|exp| parse_expression(exp).unwrap())
-        .collect();
+        .map(parse_expression)
+        .collect::<Result<Vec<String>>>()?;
+    if sql.is_empty() {
+        return Err(invalid_query("$and/$or/$nor must be a nonempty array"));
+    }
 
-    if sql.len() > 1 {
-        Ok(format!("({})", sql.join(&format!(" {} ", operator))))
+    let sql = if sql.len() > 1 {
+        format!("({})", sql.join(&format!(" {} ", operator)))
     } else {
-        Ok(sql[0].to_string())
+        sql[0].to_string()
+    };
+    if exp_tree.operator == "$nor" {
+        // a missing field makes the clauses null, which $nor still matches
+        Ok(format!("NOT COALESCE({}, FALSE)", sql))
+    } else {
+        Ok(sql)
     }
 }
 
-pub fn str_to_jsonb(value: String) -> String {
-    format!("'{}'", value)
+// `{ "$all": [a, b] }` matches arrays holding every value, or a field equal
+// to the only one
+fn parse_all(parts: &[&str], values: &[serde_json::Value]) -> String {
+    if values.is_empty() {
+        return "FALSE".to_string();
+    }
+    let clauses = values
+        .iter()
+        .map(|value| compare_path(parts, "=", value))
+        .collect::<Vec<String>>();
+    format!("({})", clauses.join(" AND "))
 }
 
-pub fn value_to_jsonb(value: &Bson) -> String {
-    let new_value = value.clone().into_psql_json();
-    new_value.to_string()
+fn parse_mod(field: &str, values: &[serde_json::Value]) -> Result<String> {
+    let (divisor, remainder) = match values {
+        [divisor, remainder] => (divisor, remainder),
+        [] | [_] => return Err(invalid_query("malformed mod, not enough elements")),
+        _ => return Err(invalid_query("malformed mod, too many elements")),
+    };
+    let divisor = number_value(divisor)
+        .ok_or_else(|| invalid_query("malformed mod, divisor not a number"))?
+        .trunc() as i64;
+    let remainder = number_value(remainder)
+        .ok_or_else(|| invalid_query("malformed mod, remainder not a number"))?
+        .trunc() as i64;
+    if divisor == 0 {
+        return Err(invalid_query("divisor cannot be 0"));
+    }
+
+    Ok(format!(
+        "mod(trunc({}.bson_number({})), {}) = {}",
+        FUNCTIONS_SCHEMA, field, divisor, remainder
+    ))
 }
 
-enum OperatorValueType {
-    Json(serde_json::Value),
-    Field(String),
+fn parse_size(field: &str, value: &serde_json::Value) -> Result<String> {
+    let size = match number_value(value) {
+        Some(n) if n < 0.0 => return Err(invalid_query("$size may not be negative")),
+        Some(n) if n.fract() != 0.0 => return Err(invalid_query("$size must be a whole number")),
+        Some(n) => n as i64,
+        None => return Err(invalid_query("$size needs a number")),
+    };
+    Ok(format!(
+        "(jsonb_typeof({0}) = 'array' AND jsonb_array_length({0}) = {1})",
+        field, size
+    ))
 }
 
-impl fmt::Display for OperatorValueType {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            OperatorValueType::Json(json) => write!(f, "{}", json),
-            OperatorValueType::Field(field) => write!(f, "{}", field),
+// `$elemMatch` either has conditions on the elements themselves, like
+// `{ "$gt": 1 }`, or is a query on the elements that are documents
+fn parse_elem_match(field: &str, query: &serde_json::Value) -> Result<String> {
+    let query = match query {
+        serde_json::Value::Object(query) => query.clone(),
+        _ => return Err(invalid_query("$elemMatch needs an Object")),
+    };
+    let elements = format!(
+        "jsonb_array_elements(CASE WHEN jsonb_typeof({0}) = 'array' THEN {0} END)",
+        field
+    );
+    let on_values = match query.keys().next() {
+        Some(key) => key.starts_with('$') && !["$and", "$or", "$nor"].contains(&key.as_str()),
+        None => false,
+    };
+
+    // the query is parsed against the elements, which are what `_jsonb`
+    // refers to in the subquery
+    let (source, filter) = if on_values {
+        let mut wrapped = Map::new();
+        wrapped.insert("v".to_string(), serde_json::Value::Object(query));
+        (
+            format!(
+                "SELECT jsonb_build_object('v', value) AS _jsonb FROM {}",
+                elements
+            ),
+            parse_json(serde_json::Value::Object(wrapped))?,
+        )
+    } else {
+        let filter = if query.is_empty() {
+            "TRUE".to_string()
+        } else {
+            parse_json(serde_json::Value::Object(query))?
+        };
+        (
+            format!(
+                "SELECT value AS _jsonb FROM {} WHERE jsonb_typeof(value) = 'object'",
+                elements
+            ),
+            filter,
+        )
+    };
+    Ok(format!(
+        "EXISTS (SELECT 1 FROM ({}) AS elem WHERE {})",
+        source, filter
+    ))
+}
+
+// `{ "$type": ... }` takes a type alias, a type code or a list of them
+fn parse_type(field: &str, value: &serde_json::Value) -> Result<String> {
+    let values = match value {
+        serde_json::Value::Array(values) => values.as_slice(),
+        value => std::slice::from_ref(value),
+    };
+    let mut types = vec![];
+    for value in values {
+        types.extend(type_names(value)?);
+    }
+    if types.is_empty() {
+        return Ok("FALSE".to_string());
+    }
+
+    Ok(format!(
+        "{}.bson_type({}) IN ({})",
+        FUNCTIONS_SCHEMA,
+        field,
+        types
+            .iter()
+            .map(|t| format!("'{}'", t))
+            .collect::<Vec<String>>()
+            .join(", ")
+    ))
+}
+
+// the type codes and aliases of BSON, with the names `bson_type` returns
+const BSON_TYPES: [(i64, &str); 21] = [
+    (1, "double"),
+    (2, "string"),
+    (3, "object"),
+    (4, "array"),
+    (5, "binData"),
+    (6, "undefined"),
+    (7, "objectId"),
+    (8, "bool"),
+    (9, "date"),
+    (10, "null"),
+    (11, "regex"),
+    (12, "dbPointer"),
+    (13, "javascript"),
+    (14, "symbol"),
+    (15, "javascriptWithScope"),
+    (16, "int"),
+    (17, "timestamp"),
+    (18, "long"),
+    (19, "decimal"),
+    (-1, "minKey"),
+    (127, "maxKey"),
+];
+
+fn type_names(value: &serde_json::Value) -> Result<Vec<&'static str>> {
+    match value {
+        serde_json::Value::String(alias) if alias == "number" => {
+            Ok(vec!["double", "int", "long", "decimal"])
+        }
+        serde_json::Value::String(alias) => BSON_TYPES
+            .iter()
+            .find(|(_, name)| name == alias)
+            .map(|(_, name)| vec![*name])
+            .ok_or_else(|| invalid_query(format!("Unknown type name alias: {}", alias))),
+        value => match number_value(value) {
+            Some(code) => BSON_TYPES
+                .iter()
+                .find(|(c, _)| *c as f64 == code)
+                .map(|(_, name)| vec![*name])
+                .ok_or_else(|| invalid_query(format!("Invalid numerical type code: {}", code))),
+            None => Err(invalid_query(
+                "type must be represented as a number or a string",
+            )),
+        },
+    }
+}
+
+fn parse_in(parts: &[&str], values: &[serde_json::Value], negate: bool) -> Result<String> {
+    let clause = if values.is_empty() {
+        "FALSE".to_string()
+    } else {
+        any_value_result(parts, |field| {
+            let clauses = values
+                .iter()
+                .map(|value| match as_regex(value) {
+                    Some((pattern, options)) => regex_to_sql(field, pattern, options),
+                    None => Ok(compare_sql(field, "=", value)),
+                })
+                .collect::<Result<Vec<String>>>()?;
+            Ok(format!("({})", clauses.join(" OR ")))
+        })?
+    };
+    if negate {
+        Ok(format!("NOT COALESCE({}, FALSE)", clause))
+    } else {
+        Ok(clause)
+    }
+}
+
+// the pattern and options of a regex value, `/pattern/options`
+fn as_regex(value: &serde_json::Value) -> Option<(&str, &str)> {
+    let obj = value.as_object().filter(|obj| obj.len() == 2)?;
+    match (obj.get("$regex"), obj.get("$options")) {
+        (Some(serde_json::Value::String(pattern)), Some(serde_json::Value::String(options))) => {
+            Some((pattern, options))
+        }
+        _ => None,
+    }
+}
+
+// Matches when any of the values a path leads to satisfies the condition,
+// going into arrays and the documents they hold like MongoDB does.
+fn any_value(parts: &[&str], condition: impl Fn(&str) -> String) -> String {
+    if parts == ["_id"] {
+        return condition(&any_value_field(parts));
+    }
+    let path = parts
+        .iter()
+        .map(|p| format!("'{}'", escape(p)))
+        .collect::<Vec<String>>()
+        .join(", ");
+    format!(
+        "EXISTS (SELECT 1 FROM {}.bson_path_values(_jsonb, ARRAY[{}]) AS p(v) WHERE {})",
+        FUNCTIONS_SCHEMA,
+        path,
+        condition("v")
+    )
+}
+
+// what the condition of `any_value` is applied to: ids can't be arrays, and
+// comparing them directly keeps the _id index usable
+fn any_value_field(parts: &[&str]) -> String {
+    if parts == ["_id"] {
+        field_to_path(parts)
+    } else {
+        "v".to_string()
+    }
+}
+
+// like `any_value`, for conditions that can fail to compile
+fn any_value_result(parts: &[&str], condition: impl Fn(&str) -> Result<String>) -> Result<String> {
+    let clause = condition(&any_value_field(parts))?;
+    Ok(any_value(parts, |_| clause.clone()))
+}
+
+fn compare_path(parts: &[&str], oper: &str, value: &serde_json::Value) -> String {
+    if value.is_null() && (oper == ">" || oper == "<") {
+        return "FALSE".to_string();
+    }
+    if oper == "!=" {
+        // a missing field is not equal to anything but null
+        return format!("NOT COALESCE({}, FALSE)", compare_path(parts, "=", value));
+    }
+    any_value(parts, |field| compare_sql(field, oper, value))
+}
+
+// Compares the way MongoDB does: numbers by value whether they're ints, longs,
+// doubles or decimals, and other values only against values of the same type,
+// in BSON order.
+fn compare_sql(field: &str, oper: &str, value: &serde_json::Value) -> String {
+    if let Some(number) = number_literal(value) {
+        return format!(
+            "{}.bson_number({}) {} {}",
+            FUNCTIONS_SCHEMA, field, oper, number
+        );
+    }
+    if let Some(date) = value
+        .as_object()
+        .filter(|obj| obj.len() == 1)
+        .and_then(|obj| obj.get("$d"))
+    {
+        return format!(
+            "{}->'$d' {} {}",
+            field,
+            oper,
+            str_to_jsonb(date.to_string())
+        );
+    }
+
+    let json = str_to_jsonb(value.to_string());
+    match (oper, value) {
+        ("=", serde_json::Value::Null) => format!("({0} IS NULL OR {0} = 'null')", field),
+        (">=" | "<=", serde_json::Value::Null) => compare_sql(field, "=", value),
+        (_, serde_json::Value::Null) => "FALSE".to_string(),
+        ("=", serde_json::Value::Object(obj)) if is_bson_value(obj) => {
+            format!("{} = {}", field, json)
+        }
+        ("=", serde_json::Value::Object(_) | serde_json::Value::Array(_)) => {
+            format!("{}.bson_compare({}, {}) = 0", FUNCTIONS_SCHEMA, field, json)
+        }
+        // comparing the whole value lets Postgres use indexes, like the _id one
+        ("=", _) => format!("{} = {}", field, json),
+        _ => format!(
+            "({0}.bson_type_order({1}) = {0}.bson_type_order({2}) AND {0}.bson_compare({1}, {2}) {3} 0)",
+            FUNCTIONS_SCHEMA, field, json, oper
+        ),
+    }
+}
+
+// a number as a numeric literal, whether it's stored as an int, a double or a
+// decimal
+fn number_literal(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::Number(n) => Some(n.to_string()),
+        serde_json::Value::Object(obj) if obj.len() == 1 => match obj.iter().next() {
+            Some((key, serde_json::Value::Number(n))) if key == "$f" => Some(n.to_string()),
+            Some((key, serde_json::Value::String(s)))
+                if key == "$numberDecimal" && s.parse::<f64>().is_ok() =>
+            {
+                Some(format!("'{}'::numeric", s))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// whether an object is how the serializer stores a BSON value other than a
+// document
+fn is_bson_value(obj: &Map<String, serde_json::Value>) -> bool {
+    const MARKERS: [&str; 12] = [
+        "$f",
+        "$o",
+        "$d",
+        "$j",
+        "$timestamp",
+        "$numberDecimal",
+        "$binary",
+        "$symbol",
+        "$dbPointer",
+        "$undefined",
+        "$minKey",
+        "$maxKey",
+    ];
+    obj.keys()
+        .next()
+        .map_or(false, |key| MARKERS.contains(&key.as_str()))
+}
+
+// Flattens nested conditions into dotted keys, stopping at operators and at
+// BSON values, which are compared as a whole.
+fn flatten_query(obj: &Map<String, serde_json::Value>) -> Map<String, serde_json::Value> {
+    let mut collapsed = Map::new();
+    for (key, value) in obj {
+        match value.as_object() {
+            Some(inner) if !key.starts_with('$') && !inner.is_empty() && !is_bson_value(inner) => {
+                for (k, v) in flatten_query(inner) {
+                    collapsed.insert(format!("{}.{}", key, k), v);
+                }
+            }
+            _ => {
+                collapsed.insert(key.clone(), value.clone());
+            }
         }
     }
+    collapsed
+}
+
+fn number_value(value: &serde_json::Value) -> Option<f64> {
+    match value.get("$f") {
+        Some(n) => n.as_f64(),
+        None => value.as_f64(),
+    }
+}
+
+pub fn str_to_jsonb(value: String) -> String {
+    format!("'{}'", escape(&value))
+}
+
+/// Doubles the quotes of a string going into a SQL literal.
+pub fn escape(s: &str) -> String {
+    s.replace('\'', "''")
+}
+
+pub fn value_to_jsonb(value: &Bson) -> String {
+    let new_value = value.clone().into_psql_json();
+    new_value.to_string()
 }
 
 pub fn parse_object(field: &str, object: &Map<String, serde_json::Value>) -> Result<String> {
+    if is_bson_value(object) {
+        let parts = field.split('.').collect::<Vec<&str>>();
+        return Ok(compare_path(
+            &parts,
+            "=",
+            &serde_json::Value::Object(object.clone()),
+        ));
+    }
+
     let mut res = vec![];
-    let source_flat_obj = flatten_object(object);
+    let source_flat_obj = flatten_query(object);
     let mut flat_obj: Map<String, serde_json::Value> = Map::new();
     for (key, value) in source_flat_obj {
         flat_obj.insert(format!("{}.{}", field, key), value);
@@ -283,170 +769,121 @@ pub fn parse_object(field: &str, object: &Map<String, serde_json::Value>) -> Res
 
     for (key, v) in &flat_obj {
         let mut parts = key.split(".").collect::<Vec<&str>>();
-        let mut value = OperatorValueType::Json(v.to_owned());
-        let mut alternate = "".to_string();
-
-        let oper = if parts[parts.len() - 1].starts_with("$") {
-            let operator = parts.pop().unwrap();
-            match operator {
-                "$lt" | "$lte" | "$gt" | "$gte" | "$ne" | "$eq" => translate_operator(operator),
-                "$regex" => {
-                    let last = parts.pop().unwrap();
-                    let mut field = parts
-                        .iter()
-                        .map(|f| format!("'{}'", f))
-                        .collect::<Vec<String>>();
-                    field.insert(0, "_jsonb".to_string());
-                    let field = field.join("->");
-                    let field = format!("{}->>'{}'", field, last);
-
-                    let options_key = key.replace("$regex", "$options");
-                    let options = flat_obj.get(&options_key);
-
-                    let mut oper = "~";
-                    if let Some(options) = options {
-                        if options.as_str().unwrap().contains('i') {
-                            oper = "~*";
-                        }
-                    }
-
-                    let value = match v {
-                        serde_json::Value::String(s) => s,
-                        _ => return Err(eyre!("$regex has to be a string")),
-                    };
+        if !parts[parts.len() - 1].starts_with("$") {
+            res.push(compare_path(&parts, "=", v));
+            continue;
+        }
 
-                    return Ok(format!("{} {} '{}'", field, oper, value));
-                }
-                "$exists" => {
-                    let negative = v.is_boolean() && !v.as_bool().unwrap()
-                        || v.is_number() && v.as_i64().unwrap() == 0;
-
-                    if negative {
-                        let mut clauses = vec![];
-                        let mut acc_parts = vec![];
-                        for p in &parts {
-                            let mut fields = acc_parts
-                                .iter()
-                                .map(|f| format!("'{}'", f))
-                                .collect::<Vec<String>>()
-                                .join("->");
-                            if !fields.is_empty() {
-                                fields = format!("->{}", fields);
-                            }
-                            clauses.push(format!("NOT _jsonb{} ? '{}'", fields, p));
-                            acc_parts.push(p.clone());
-                        }
-                        res.push(format!("({})", clauses.join(" OR ")));
-                        continue;
+        let operator = parts.pop().unwrap();
+        match operator {
+            "$lt" | "$lte" | "$gt" | "$gte" | "$ne" | "$eq" => {
+                res.push(compare_path(&parts, translate_operator(operator), v));
+            }
+            "$regex" => {
+                let options = match flat_obj.get(&key.replace("$regex", "$options")) {
+                    Some(serde_json::Value::String(options)) => Some(options.as_str()),
+                    Some(_) => return Err(invalid_query("$options has to be a string")),
+                    None => None,
+                };
+                let (pattern, options) = match (v, as_regex(v)) {
+                    (serde_json::Value::String(pattern), _) => {
+                        (pattern.as_str(), options.unwrap_or(""))
                     }
-
-                    value = OperatorValueType::Field(parts.pop().unwrap().to_string());
-                    "?"
-                }
-                "$in" | "$nin" => {
-                    if !v.is_array() {
-                        // FIXME error this out
-                        todo!("Return an error when exists doesn't have an array");
+                    (_, Some((_, regex_options)))
+                        if options.is_some() && !regex_options.is_empty() =>
+                    {
+                        return Err(invalid_query("options set in both $regex and $options"))
                     }
-
-                    let field = parts
-                        .iter()
-                        .map(|f| format!("'{}'", f))
-                        .collect::<Vec<String>>()
-                        .join("->>");
-                    let field = if parts.len() > 1 {
-                        format!("_jsonb->{}", field)
-                    } else {
-                        format!("_jsonb->>{}", field)
-                    };
-                    let values = v
-                        .as_array()
-                        .unwrap()
-                        .iter()
-                        .map(|v| v.to_string())
-                        .collect::<Vec<String>>()
-                        .join(", ");
-
-                    let str = format!("{} = ANY('{{{}}}')", field, values);
-
-                    if operator == "$nin" {
-                        return Ok(format!("NOT ({})", str));
+                    (_, Some((pattern, regex_options))) => {
+                        (pattern, options.unwrap_or(regex_options))
                     }
-
-                    return Ok(str);
-                }
-                "$o" => {
-                    return Ok(format!("_jsonb->'{}'->'$o' = '{}'", field, v));
+                    _ => return Err(invalid_query("$regex has to be a string")),
+                };
+                res.push(any_value_result(&parts, |field| {
+                    regex_to_sql(field, pattern, options)
+                })?);
+            }
+            "$not" => match as_regex(v) {
+                Some((pattern, options)) => res.push(format!(
+                    "NOT COALESCE({}, FALSE)",
+                    any_value_result(&parts, |field| regex_to_sql(field, pattern, options))?
+                )),
+                None => return Err(invalid_query("$not needs a regex or a document")),
+            },
+            "$options" => {
+                if !flat_obj.contains_key(&key.replace("$options", "$regex")) {
+                    return Err(invalid_query("$options needs a $regex"));
                 }
-                "$d" => {
-                    return Ok(format!("_jsonb->'{}'->'$d' = '{}'", field, v));
+            }
+            "$exists" => {
+                let negative = v.is_boolean() && !v.as_bool().unwrap()
+                    || v.is_number() && v.as_i64().unwrap() == 0;
+
+                if negative {
+                    let mut clauses = vec![];
+                    let mut acc_parts: Vec<&str> = vec![];
+                    for p in &parts {
+                        let mut fields = acc_parts
+                            .iter()
+                            .map(|f| format!("'{}'", escape(f)))
+                            .collect::<Vec<String>>()
+                            .join("->");
+                        if !fields.is_empty() {
+                            fields = format!("->{}", fields);
+                        }
+                        clauses.push(format!("NOT _jsonb{} ? '{}'", fields, escape(p)));
+                        acc_parts.push(*p);
+                    }
+                    res.push(format!("({})", clauses.join(" OR ")));
+                } else {
+                    let last = parts.pop().unwrap();
+                    res.push(format!("({} ? '{}')", field_to_path(&parts), escape(last)));
                 }
-                t => unimplemented!("parse_object - unimplemented {:?} in ${:?}", t, object),
             }
-        } else {
-            let mut alt_parts = parts.clone();
-            let field = alt_parts.pop().unwrap();
-            let str_value = value.to_string();
-            let fields_expr = alt_parts
-                .iter()
-                .map(|p| format!("{p}[*]."))
-                .collect::<Vec<String>>()
-                .join("");
-            alternate = format!(
-                " OR jsonb_path_exists(_jsonb, '$[*].{fields_expr}{field} ? (@ == {str_value})')"
-            );
-
-            "="
-        };
-
-        let field = parts
-            .iter()
-            .map(|f| format!("'{}'", f))
-            .collect::<Vec<String>>()
-            .join("->");
-        let field = format!("_jsonb->{}", field);
-        let value = str_to_jsonb(value.to_string());
-        let str = format!("({} {} {}{})", field, oper, value, alternate);
-        res.push(str);
+            "$in" | "$nin" | "$all" => match v.as_array() {
+                Some(values) if operator == "$all" => res.push(parse_all(&parts, values)),
+                Some(values) => res.push(parse_in(&parts, values, operator == "$nin")?),
+                None => return Err(invalid_query(format!("{} needs an array", operator))),
+            },
+            "$size" | "$mod" | "$type" | "$elemMatch" => {
+                let field = field_to_path(&parts);
+                let values = v.as_array().map(|values| values.as_slice());
+                res.push(match (operator, values) {
+                    ("$size", _) => parse_size(&field, v)?,
+                    ("$mod", Some(values)) => parse_mod(&field, values)?,
+                    ("$type", _) => parse_type(&field, v)?,
+                    ("$elemMatch", _) => parse_elem_match(&field, v)?,
+                    _ => return Err(invalid_query(format!("{} needs an array", operator))),
+                });
+            }
+            t => return Err(invalid_query(format!("unsupported operator: {}", t))),
+        }
     }
 
     Ok(res.join(" AND "))
 }
 
+// `_jsonb->'a'->'b'` for the parts of `a.b`
+fn field_to_path(parts: &[&str]) -> String {
+    let field = parts
+        .iter()
+        .map(|f| format!("->'{}'", escape(f)))
+        .collect::<Vec<String>>()
+        .join("");
+    format!("_jsonb{}", field)
+}
+
 fn parse_leaf_value(leaf_value: LeafValue, f: String, operator: Option<&str>) -> Result<String> {
     let json = leaf_value.value;
-    let mut field = field_to_jsonb(&f);
-
-    if json.is_object() {
-        let obj = json.as_object().unwrap();
 
-        if obj.contains_key("$d") {
-            let oper = operator.unwrap_or("=");
-            return Ok(format!(
-                "{}->'$d' {} {}",
-                field,
-                oper,
-                str_to_jsonb(obj["$d"].to_string())
-            ));
-        } else {
+    if let Some(obj) = json.as_object() {
+        if !obj.is_empty() && !is_bson_value(obj) && operator.is_none() {
             return parse_object(&f, obj);
         }
     }
 
-    if json.is_number() {
-        field = format!(
-            "(jsonb_typeof({}) = 'number' OR jsonb_typeof({}->'$f') = 'number') AND CASE WHEN ({} ? '$f') THEN ({}->>'$f')::numeric ELSE ({})::numeric END",
-            field, field, field, field, field
-        );
-    }
-
-    let oper = operator.unwrap_or("=");
-    Ok(format!(
-        "{} {} {}",
-        field,
-        oper,
-        str_to_jsonb(json.to_string())
-    ))
+    let parts = f.split('.').collect::<Vec<&str>>();
+    Ok(compare_path(&parts, operator.unwrap_or("="), &json))
 }
 
 #[cfg(test)]
@@ -463,14 +900,31 @@ mod tests {
     #[test]
     fn test_simple_str() {
         let doc = doc! {"name": "test"};
-        assert_eq!(r#"_jsonb->'name' = '"test"'"#, parse(doc).unwrap())
+        assert_eq!(
+            r#"EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['name']) AS p(v) WHERE v = '"test"')"#,
+            parse(doc).unwrap()
+        )
+    }
+
+    #[test]
+    fn test_quotes() {
+        let doc = doc! {"it's": "O'Brien"};
+        assert_eq!(
+            r#"EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['it''s']) AS p(v) WHERE v = '"O''Brien"')"#,
+            parse(doc).unwrap()
+        );
+        let doc = doc! {"a'.b": {"$exists": false}};
+        assert_eq!(
+            r#"(NOT _jsonb ? 'a''' OR NOT _jsonb->'a''' ? 'b')"#,
+            parse(doc).unwrap()
+        );
     }
 
     #[test]
     fn test_simple_int() {
         let doc = doc! {"age": 12};
         assert_eq!(
-            r#"(jsonb_typeof(_jsonb->'age') = 'number' OR jsonb_typeof(_jsonb->'age'->'$f') = 'number') AND CASE WHEN (_jsonb->'age' ? '$f') THEN (_jsonb->'age'->>'$f')::numeric ELSE (_jsonb->'age')::numeric END = '12'"#,
+            r#"EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['age']) AS p(v) WHERE oxide_functions.bson_number(v) = 12)"#,
             parse(doc).unwrap()
         )
     }
@@ -479,7 +933,7 @@ mod tests {
     fn test_simple_double() {
         let doc = doc! {"age": Bson::Double(1.2)};
         assert_eq!(
-            r#"(jsonb_typeof(_jsonb->'age') = 'number' OR jsonb_typeof(_jsonb->'age'->'$f') = 'number') AND CASE WHEN (_jsonb->'age' ? '$f') THEN (_jsonb->'age'->>'$f')::numeric ELSE (_jsonb->'age')::numeric END = '1.2'"#,
+            r#"EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['age']) AS p(v) WHERE oxide_functions.bson_number(v) = 1.2)"#,
             parse(doc).unwrap()
         )
     }
@@ -488,7 +942,7 @@ mod tests {
     fn test_and() {
         let doc = doc! {"a": "name", "b": 212};
         assert_eq!(
-            r#"(_jsonb->'a' = '"name"' AND (jsonb_typeof(_jsonb->'b') = 'number' OR jsonb_typeof(_jsonb->'b'->'$f') = 'number') AND CASE WHEN (_jsonb->'b' ? '$f') THEN (_jsonb->'b'->>'$f')::numeric ELSE (_jsonb->'b')::numeric END = '212')"#,
+            r#"(EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a']) AS p(v) WHERE v = '"name"') AND EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['b']) AS p(v) WHERE oxide_functions.bson_number(v) = 212))"#,
             parse(doc).unwrap()
         )
     }
@@ -499,7 +953,7 @@ mod tests {
             doc! { "a": "name", "b": 212 },
         ]};
         assert_eq!(
-            r#"(_jsonb->'a' = '"name"' AND (jsonb_typeof(_jsonb->'b') = 'number' OR jsonb_typeof(_jsonb->'b'->'$f') = 'number') AND CASE WHEN (_jsonb->'b' ? '$f') THEN (_jsonb->'b'->>'$f')::numeric ELSE (_jsonb->'b')::numeric END = '212')"#,
+            r#"(EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a']) AS p(v) WHERE v = '"name"') AND EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['b']) AS p(v) WHERE oxide_functions.bson_number(v) = 212))"#,
             parse(doc).unwrap()
         )
     }
@@ -510,7 +964,7 @@ mod tests {
             doc! { "a": 1, "b": 2, "c": 3 },
         ]};
         assert_eq!(
-            r#"((jsonb_typeof(_jsonb->'a') = 'number' OR jsonb_typeof(_jsonb->'a'->'$f') = 'number') AND CASE WHEN (_jsonb->'a' ? '$f') THEN (_jsonb->'a'->>'$f')::numeric ELSE (_jsonb->'a')::numeric END = '1' AND (jsonb_typeof(_jsonb->'b') = 'number' OR jsonb_typeof(_jsonb->'b'->'$f') = 'number') AND CASE WHEN (_jsonb->'b' ? '$f') THEN (_jsonb->'b'->>'$f')::numeric ELSE (_jsonb->'b')::numeric END = '2' AND (jsonb_typeof(_jsonb->'c') = 'number' OR jsonb_typeof(_jsonb->'c'->'$f') = 'number') AND CASE WHEN (_jsonb->'c' ? '$f') THEN (_jsonb->'c'->>'$f')::numeric ELSE (_jsonb->'c')::numeric END = '3')"#,
+            r#"(EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a']) AS p(v) WHERE oxide_functions.bson_number(v) = 1) AND EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['b']) AS p(v) WHERE oxide_functions.bson_number(v) = 2) AND EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['c']) AS p(v) WHERE oxide_functions.bson_number(v) = 3))"#,
             parse(doc).unwrap()
         )
     }
@@ -522,7 +976,7 @@ mod tests {
             doc! { "c": "name", "d": 212 },
         ]};
         assert_eq!(
-            r#"((_jsonb->'a' = '"name"' AND (jsonb_typeof(_jsonb->'b') = 'number' OR jsonb_typeof(_jsonb->'b'->'$f') = 'number') AND CASE WHEN (_jsonb->'b' ? '$f') THEN (_jsonb->'b'->>'$f')::numeric ELSE (_jsonb->'b')::numeric END = '212') OR (_jsonb->'c' = '"name"' AND (jsonb_typeof(_jsonb->'d') = 'number' OR jsonb_typeof(_jsonb->'d'->'$f') = 'number') AND CASE WHEN (_jsonb->'d' ? '$f') THEN (_jsonb->'d'->>'$f')::numeric ELSE (_jsonb->'d')::numeric END = '212'))"#,
+            r#"((EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a']) AS p(v) WHERE v = '"name"') AND EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['b']) AS p(v) WHERE oxide_functions.bson_number(v) = 212)) OR (EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['c']) AS p(v) WHERE v = '"name"') AND EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['d']) AS p(v) WHERE oxide_functions.bson_number(v) = 212)))"#,
             parse(doc).unwrap()
         )
     }
@@ -531,7 +985,7 @@ mod tests {
     fn test_with_gt_oper() {
         let doc = doc! {"age": {"$gt": 12}};
         assert_eq!(
-            r#"(jsonb_typeof(_jsonb->'age') = 'number' OR jsonb_typeof(_jsonb->'age'->'$f') = 'number') AND CASE WHEN (_jsonb->'age' ? '$f') THEN (_jsonb->'age'->>'$f')::numeric ELSE (_jsonb->'age')::numeric END > '12'"#,
+            r#"EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['age']) AS p(v) WHERE oxide_functions.bson_number(v) > 12)"#,
             parse(doc).unwrap()
         )
     }
@@ -540,7 +994,7 @@ mod tests {
     fn test_with_simple_unary_not() {
         let doc = doc! { "age": {"$not": {"$gt": 12 } } };
         assert_eq!(
-            r#"NOT ((jsonb_typeof(_jsonb->'age') = 'number' OR jsonb_typeof(_jsonb->'age'->'$f') = 'number') AND CASE WHEN (_jsonb->'age' ? '$f') THEN (_jsonb->'age'->>'$f')::numeric ELSE (_jsonb->'age')::numeric END > '12')"#,
+            r#"NOT (EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['age']) AS p(v) WHERE oxide_functions.bson_number(v) > 12))"#,
             parse(doc).unwrap()
         )
     }
@@ -549,7 +1003,7 @@ mod tests {
     fn test_with_unary_not() {
         let doc = doc! { "x": 1, "age": {"$not": {"$gt": 12} }, "y": 2 };
         assert_eq!(
-            r#"((jsonb_typeof(_jsonb->'x') = 'number' OR jsonb_typeof(_jsonb->'x'->'$f') = 'number') AND CASE WHEN (_jsonb->'x' ? '$f') THEN (_jsonb->'x'->>'$f')::numeric ELSE (_jsonb->'x')::numeric END = '1' AND NOT ((jsonb_typeof(_jsonb->'age') = 'number' OR jsonb_typeof(_jsonb->'age'->'$f') = 'number') AND CASE WHEN (_jsonb->'age' ? '$f') THEN (_jsonb->'age'->>'$f')::numeric ELSE (_jsonb->'age')::numeric END > '12') AND (jsonb_typeof(_jsonb->'y') = 'number' OR jsonb_typeof(_jsonb->'y'->'$f') = 'number') AND CASE WHEN (_jsonb->'y' ? '$f') THEN (_jsonb->'y'->>'$f')::numeric ELSE (_jsonb->'y')::numeric END = '2')"#,
+            r#"(EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['x']) AS p(v) WHERE oxide_functions.bson_number(v) = 1) AND NOT (EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['age']) AS p(v) WHERE oxide_functions.bson_number(v) > 12)) AND EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['y']) AS p(v) WHERE oxide_functions.bson_number(v) = 2))"#,
             parse(doc).unwrap()
         )
     }
@@ -559,7 +1013,7 @@ mod tests {
         let doc =
             doc! { "age": {"$not": {"$gt": 12} }, "$or": vec! [ doc!{ "y": 2 }, doc!{ "x": 1 } ]};
         assert_eq!(
-            r#"(NOT ((jsonb_typeof(_jsonb->'age') = 'number' OR jsonb_typeof(_jsonb->'age'->'$f') = 'number') AND CASE WHEN (_jsonb->'age' ? '$f') THEN (_jsonb->'age'->>'$f')::numeric ELSE (_jsonb->'age')::numeric END > '12') AND ((jsonb_typeof(_jsonb->'y') = 'number' OR jsonb_typeof(_jsonb->'y'->'$f') = 'number') AND CASE WHEN (_jsonb->'y' ? '$f') THEN (_jsonb->'y'->>'$f')::numeric ELSE (_jsonb->'y')::numeric END = '2' OR (jsonb_typeof(_jsonb->'x') = 'number' OR jsonb_typeof(_jsonb->'x'->'$f') = 'number') AND CASE WHEN (_jsonb->'x' ? '$f') THEN (_jsonb->'x'->>'$f')::numeric ELSE (_jsonb->'x')::numeric END = '1'))"#,
+            r#"(NOT (EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['age']) AS p(v) WHERE oxide_functions.bson_number(v) > 12)) AND (EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['y']) AS p(v) WHERE oxide_functions.bson_number(v) = 2) OR EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['x']) AS p(v) WHERE oxide_functions.bson_number(v) = 1)))"#,
             parse(doc).unwrap()
         )
     }
@@ -570,7 +1024,10 @@ mod tests {
             "type": false,
         };
 
-        assert_eq!(r#"_jsonb->'type' = 'false'"#, parse(doc).unwrap(),);
+        assert_eq!(
+            r#"EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['type']) AS p(v) WHERE v = 'false')"#,
+            parse(doc).unwrap(),
+        );
     }
 
     #[test]
@@ -620,7 +1077,7 @@ mod tests {
     fn test_dot_nested() {
         assert_eq!(
             parse(doc! { "config.get.method": "GET" }).unwrap(),
-            r#"(_jsonb->'config'->'get'->'method' = '"GET"' OR jsonb_path_exists(_jsonb, '$[*].config[*].get[*].method ? (@ == "GET")'))"#
+            r#"EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['config', 'get', 'method']) AS p(v) WHERE v = '"GET"')"#
         )
     }
 
@@ -628,7 +1085,7 @@ mod tests {
     fn test_nested_find() {
         assert_eq!(
             parse(doc! { "a": { "b": { "c": 1, "d": 2 }, "e": 2 } }).unwrap(),
-            r#"(_jsonb->'a'->'b'->'c' = '1' OR jsonb_path_exists(_jsonb, '$[*].a[*].b[*].c ? (@ == 1)')) AND (_jsonb->'a'->'b'->'d' = '2' OR jsonb_path_exists(_jsonb, '$[*].a[*].b[*].d ? (@ == 2)')) AND (_jsonb->'a'->'e' = '2' OR jsonb_path_exists(_jsonb, '$[*].a[*].e ? (@ == 2)'))"#
+            r#"EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a', 'b', 'c']) AS p(v) WHERE oxide_functions.bson_number(v) = 1) AND EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a', 'b', 'd']) AS p(v) WHERE oxide_functions.bson_number(v) = 2) AND EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a', 'e']) AS p(v) WHERE oxide_functions.bson_number(v) = 2)"#
         )
     }
 
@@ -637,7 +1094,7 @@ mod tests {
         assert_eq!(
             parse(doc! { "a": { "b": { "$exists": 1 }, "c": { "$gt": 1 }, "e": "Felipe" } })
                 .unwrap(),
-            r#"(_jsonb->'a' ? 'b') AND (_jsonb->'a'->'c' > '1') AND (_jsonb->'a'->'e' = '"Felipe"' OR jsonb_path_exists(_jsonb, '$[*].a[*].e ? (@ == "Felipe")'))"#
+            r#"(_jsonb->'a' ? 'b') AND EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a', 'c']) AS p(v) WHERE oxide_functions.bson_number(v) > 1) AND EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a', 'e']) AS p(v) WHERE v = '"Felipe"')"#
         )
     }
 
@@ -653,53 +1110,189 @@ mod tests {
     fn test_in() {
         assert_eq!(
             parse(doc! { "a": { "$in": [1, 2] } }).unwrap(),
-            r#"_jsonb->>'a' = ANY('{1, 2}')"#
+            r#"EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a']) AS p(v) WHERE (oxide_functions.bson_number(v) = 1 OR oxide_functions.bson_number(v) = 2))"#
         );
 
         assert_eq!(
             parse(doc! { "a": { "$in": ["a", "b"] } }).unwrap(),
-            r#"_jsonb->>'a' = ANY('{"a", "b"}')"#
+            r#"EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a']) AS p(v) WHERE (v = '"a"' OR v = '"b"'))"#
         );
 
         assert_eq!(
-            parse(doc! { "a.b": { "$in": ["a", "b"] } }).unwrap(),
-            r#"_jsonb->'a'->>'b' = ANY('{"a", "b"}')"#
+            parse(doc! { "a.b": { "$in": ["a", null] } }).unwrap(),
+            r#"EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a', 'b']) AS p(v) WHERE (v = '"a"' OR (v IS NULL OR v = 'null')))"#
         );
 
         assert_eq!(
             parse(doc! { "a": { "b": { "$in": ["a", "b"] } } }).unwrap(),
-            r#"_jsonb->'a'->>'b' = ANY('{"a", "b"}')"#
+            r#"EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a', 'b']) AS p(v) WHERE (v = '"a"' OR v = '"b"'))"#
         );
+
+        assert_eq!(parse(doc! { "a": { "$in": [] } }).unwrap(), "FALSE");
     }
 
     #[test]
     fn test_nin() {
         assert_eq!(
             parse(doc! { "a": { "$nin": [1, 2] } }).unwrap(),
-            r#"NOT (_jsonb->>'a' = ANY('{1, 2}'))"#
+            r#"NOT COALESCE(EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a']) AS p(v) WHERE (oxide_functions.bson_number(v) = 1 OR oxide_functions.bson_number(v) = 2)), FALSE)"#
         );
 
         assert_eq!(
             parse(doc! { "a": { "$nin": ["a", "b"] } }).unwrap(),
-            r#"NOT (_jsonb->>'a' = ANY('{"a", "b"}'))"#
+            r#"NOT COALESCE(EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a']) AS p(v) WHERE (v = '"a"' OR v = '"b"')), FALSE)"#
         );
 
         assert_eq!(
-            parse(doc! { "a.b": { "$nin": ["a", "b"] } }).unwrap(),
-            r#"NOT (_jsonb->'a'->>'b' = ANY('{"a", "b"}'))"#
+            parse(doc! { "a": { "b": { "$nin": ["a", "b"] } } }).unwrap(),
+            r#"NOT COALESCE(EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a', 'b']) AS p(v) WHERE (v = '"a"' OR v = '"b"')), FALSE)"#
         );
+    }
 
+    #[test]
+    fn test_array_path() {
         assert_eq!(
-            parse(doc! { "a": { "b": { "$nin": ["a", "b"] } } }).unwrap(),
-            r#"NOT (_jsonb->'a'->>'b' = ANY('{"a", "b"}'))"#
+            parse(doc! { "items.0.sku": "a" }).unwrap(),
+            r#"EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['items', '0', 'sku']) AS p(v) WHERE v = '"a"')"#
+        );
+        assert_eq!(
+            parse(doc! { "_id": { "$in": [1, 2] } }).unwrap(),
+            r#"(oxide_functions.bson_number(_jsonb->'_id') = 1 OR oxide_functions.bson_number(_jsonb->'_id') = 2)"#
+        );
+    }
+
+    #[test]
+    fn test_type() {
+        assert_eq!(
+            parse(doc! { "a": { "$type": "string" } }).unwrap(),
+            r#"oxide_functions.bson_type(_jsonb->'a') IN ('string')"#
+        );
+        assert_eq!(
+            parse(doc! { "a.b": { "$type": [9, "number"] } }).unwrap(),
+            r#"oxide_functions.bson_type(_jsonb->'a'->'b') IN ('date', 'double', 'int', 'long', 'decimal')"#
+        );
+        assert_eq!(parse(doc! { "a": { "$type": [] } }).unwrap(), "FALSE");
+        for (value, message) in [
+            (
+                Bson::String("foo".to_string()),
+                "Unknown type name alias: foo",
+            ),
+            (Bson::Int32(42), "Invalid numerical type code: 42"),
+            (
+                Bson::Boolean(true),
+                "type must be represented as a number or a string",
+            ),
+        ] {
+            let err = parse(doc! { "a": { "$type": value } }).unwrap_err();
+            assert_eq!(err.to_string(), message);
+        }
+    }
+
+    #[test]
+    fn test_compare_across_types() {
+        assert_eq!(
+            parse(doc! { "a": { "$gt": "m" } }).unwrap(),
+            r#"EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a']) AS p(v) WHERE (oxide_functions.bson_type_order(v) = oxide_functions.bson_type_order('"m"') AND oxide_functions.bson_compare(v, '"m"') > 0))"#
+        );
+        assert_eq!(
+            parse_json(serde_json::json!({ "a": { "$lte": { "$numberDecimal": "1.5" } } }))
+                .unwrap(),
+            r#"EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a']) AS p(v) WHERE oxide_functions.bson_number(v) <= '1.5'::numeric)"#
+        );
+        assert_eq!(
+            parse(doc! { "a": { "$ne": null } }).unwrap(),
+            r#"NOT COALESCE(EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a']) AS p(v) WHERE (v IS NULL OR v = 'null')), FALSE)"#
+        );
+        assert_eq!(parse(doc! { "a": { "$gt": null } }).unwrap(), "FALSE");
+        assert_eq!(
+            parse(doc! { "a": [1, 2] }).unwrap(),
+            r#"EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a']) AS p(v) WHERE oxide_functions.bson_compare(v, '[1,2]') = 0)"#
+        );
+    }
+
+    #[test]
+    fn test_nor() {
+        assert_eq!(
+            parse(doc! { "$nor": [{ "a": "x" }, { "b": "y" }] }).unwrap(),
+            r#"NOT COALESCE((EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a']) AS p(v) WHERE v = '"x"') OR EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['b']) AS p(v) WHERE v = '"y"')), FALSE)"#
+        );
+    }
+
+    #[test]
+    fn test_size() {
+        assert_eq!(
+            parse(doc! { "a": { "$size": 2 } }).unwrap(),
+            r#"(jsonb_typeof(_jsonb->'a') = 'array' AND jsonb_array_length(_jsonb->'a') = 2)"#
+        );
+        assert_eq!(
+            parse(doc! { "a.b": { "$size": 0 } }).unwrap(),
+            r#"(jsonb_typeof(_jsonb->'a'->'b') = 'array' AND jsonb_array_length(_jsonb->'a'->'b') = 0)"#
+        );
+        assert!(parse(doc! { "a": { "$size": 1.5 } }).is_err());
+        assert!(parse(doc! { "a": { "$size": "1" } }).is_err());
+    }
+
+    #[test]
+    fn test_all() {
+        assert_eq!(
+            parse(doc! { "a": { "$all": ["x"] } }).unwrap(),
+            r#"(EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a']) AS p(v) WHERE v = '"x"'))"#
+        );
+        assert_eq!(parse(doc! { "a": { "$all": [] } }).unwrap(), "FALSE");
+    }
+
+    #[test]
+    fn test_mod() {
+        assert_eq!(
+            parse(doc! { "a": { "$mod": [4, 1] } }).unwrap(),
+            r#"mod(trunc(oxide_functions.bson_number(_jsonb->'a')), 4) = 1"#
         );
+        for (value, message) in [
+            (bson::bson!([4]), "malformed mod, not enough elements"),
+            (bson::bson!([4, 1, 2]), "malformed mod, too many elements"),
+            (bson::bson!([0, 1]), "divisor cannot be 0"),
+            (bson::bson!(["4", 1]), "malformed mod, divisor not a number"),
+        ] {
+            let err = parse(doc! { "a": { "$mod": value } }).unwrap_err();
+            assert_eq!(err.to_string(), message);
+        }
+    }
+
+    #[test]
+    fn test_elem_match() {
+        assert_eq!(
+            parse(doc! { "a": { "$elemMatch": { "b": "x" } } }).unwrap(),
+            r#"EXISTS (SELECT 1 FROM (SELECT value AS _jsonb FROM jsonb_array_elements(CASE WHEN jsonb_typeof(_jsonb->'a') = 'array' THEN _jsonb->'a' END) WHERE jsonb_typeof(value) = 'object') AS elem WHERE EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['b']) AS p(v) WHERE v = '"x"'))"#
+        );
+        assert_eq!(
+            parse(doc! { "a": { "$elemMatch": { "$gt": 1 } } }).unwrap(),
+            r#"EXISTS (SELECT 1 FROM (SELECT jsonb_build_object('v', value) AS _jsonb FROM jsonb_array_elements(CASE WHEN jsonb_typeof(_jsonb->'a') = 'array' THEN _jsonb->'a' END)) AS elem WHERE EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['v']) AS p(v) WHERE oxide_functions.bson_number(v) > 1))"#
+        );
+        assert!(parse(doc! { "a.b": { "$elemMatch": { "c": 1 } } })
+            .unwrap()
+            .contains("jsonb_typeof(_jsonb->'a'->'b') = 'array'"));
+        assert!(parse(doc! { "a": { "$elemMatch": 1 } }).is_err());
+    }
+
+    #[test]
+    fn test_unsupported_operator() {
+        for doc in [
+            doc! { "a": { "$bitsAllSet": 1 } },
+            doc! { "a": { "$foo": 1 } },
+            doc! { "a": { "b": { "$foo": 1 } } },
+            doc! { "$foo": 1 },
+            doc! { "$or": [] },
+        ] {
+            let err = parse(doc).unwrap_err();
+            assert!(err.downcast_ref::<InvalidQueryError>().is_some());
+        }
     }
 
     #[test]
     fn test_date() {
         assert_eq!(
             parse(doc! { "a": { "$gt": { "$d": Bson::Int64(1659448486285) } } }).unwrap(),
-            r#"_jsonb->'a'->'$d' > '1659448486285'"#
+            r#"EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a']) AS p(v) WHERE v->'$d' > '1659448486285')"#
         )
     }
 
@@ -707,7 +1300,7 @@ mod tests {
     fn test_regex() {
         assert_eq!(
             parse(doc! { "a": { "$regex": "^j" } }).unwrap(),
-            r#"_jsonb->>'a' ~ '^j'"#
+            r#"EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a']) AS p(v) WHERE (jsonb_typeof(v) = 'string' AND v #>> '{}' ~ '(?p)^j'))"#
         )
     }
 
@@ -715,7 +1308,7 @@ mod tests {
     fn test_regex_nested() {
         assert_eq!(
             parse(doc! { "a": { "b": { "$regex": "^j", "$options": "i" } } }).unwrap(),
-            r#"_jsonb->'a'->>'b' ~* '^j'"#
+            r#"EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a', 'b']) AS p(v) WHERE (jsonb_typeof(v) = 'string' AND v #>> '{}' ~* '(?p)^j'))"#
         )
     }
 
@@ -723,7 +1316,7 @@ mod tests {
     fn test_regex_ignore_case() {
         assert_eq!(
             parse(doc! { "a": { "$regex": "^j", "$options": "i" } }).unwrap(),
-            r#"_jsonb->>'a' ~* '^j'"#
+            r#"EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a']) AS p(v) WHERE (jsonb_typeof(v) = 'string' AND v #>> '{}' ~* '(?p)^j'))"#
         )
     }
 
@@ -731,10 +1324,42 @@ mod tests {
     fn test_regex_nested_ignore_case() {
         assert_eq!(
             parse(doc! { "a.b": { "$regex": "^j", "$options": "i" } }).unwrap(),
-            r#"_jsonb->'a'->>'b' ~* '^j'"#
+            r#"EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a', 'b']) AS p(v) WHERE (jsonb_typeof(v) = 'string' AND v #>> '{}' ~* '(?p)^j'))"#
         )
     }
 
+    #[test]
+    fn test_regex_value() {
+        let regex = |pattern: &str, options: &str| {
+            Bson::RegularExpression(bson::Regex {
+                pattern: pattern.to_string(),
+                options: options.to_string(),
+            })
+        };
+        assert_eq!(
+            parse(doc! { "a": regex("^j", "im") }).unwrap(),
+            r#"EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a']) AS p(v) WHERE (jsonb_typeof(v) = 'string' AND v #>> '{}' ~* '(?n)^j'))"#
+        );
+        assert_eq!(
+            parse(doc! { "a": { "$regex": regex("^j", ""), "$options": "s" } }).unwrap(),
+            r#"EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a']) AS p(v) WHERE (jsonb_typeof(v) = 'string' AND v #>> '{}' ~ '^j'))"#
+        );
+        assert_eq!(
+            parse(doc! { "a": { "$in": [regex("^j", "i"), "x"] } }).unwrap(),
+            r#"EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a']) AS p(v) WHERE ((jsonb_typeof(v) = 'string' AND v #>> '{}' ~* '(?p)^j') OR v = '"x"'))"#
+        );
+        assert_eq!(
+            parse(doc! { "a": { "$not": regex("^j", "") } }).unwrap(),
+            r#"NOT COALESCE(EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a']) AS p(v) WHERE (jsonb_typeof(v) = 'string' AND v #>> '{}' ~ '(?p)^j')), FALSE)"#
+        );
+        assert_eq!(
+            parse(doc! { "a": { "$regex": regex("^j", "i"), "$options": "m" } })
+                .unwrap_err()
+                .to_string(),
+            "options set in both $regex and $options"
+        );
+    }
+
     #[test]
     fn test_regex_invalid() {
         assert_eq!(
@@ -749,7 +1374,16 @@ mod tests {
     fn test_oid() {
         assert_eq!(
             parse(doc! { "a": { "$o": "62e27ae37d8474ae4ce87c14" } }).unwrap(),
-            r#"_jsonb->'a'->'$o' = '"62e27ae37d8474ae4ce87c14"'"#
+            r#"EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a']) AS p(v) WHERE v = '{"$o":"62e27ae37d8474ae4ce87c14"}')"#
+        )
+    }
+
+    #[test]
+    fn test_id_oid() {
+        let id = bson::oid::ObjectId::parse_str("62e27ae37d8474ae4ce87c14").unwrap();
+        assert_eq!(
+            parse(doc! { "_id": id }).unwrap(),
+            r#"_jsonb->'_id' = '{"$o":"62e27ae37d8474ae4ce87c14"}'"#
         )
     }
 
@@ -760,7 +1394,7 @@ mod tests {
         let obj = json.as_object().unwrap();
         assert_eq!(
             parse_object("a", &obj).unwrap(),
-            r#"_jsonb->'a'->'$d' = '1659448486285'"#
+            r#"EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a']) AS p(v) WHERE v->'$d' = '1659448486285')"#
         )
     }
 
@@ -0,0 +1,237 @@
+use eyre::Result;
+
+use super::invalid_query;
+
+/// Matches a jsonb string value against a MongoDB (PCRE) regular expression
+/// using a Postgres regex. Options are translated to `~*` and embedded
+/// options: by default `.` doesn't match newlines (`p`), `m` makes `^` and `$`
+/// match at line breaks (`n`), `s` lets `.` match newlines and `x` ignores
+/// whitespace and `#` comments in the pattern.
+pub fn regex_to_sql(field: &str, pattern: &str, options: &str) -> Result<String> {
+    let (pattern, options) = leading_options(pattern, options);
+
+    let (mut case_insensitive, mut multiline, mut dotall) = (false, false, false);
+    for c in options.chars() {
+        match c {
+            'i' => case_insensitive = true,
+            'm' => multiline = true,
+            's' => dotall = true,
+            'x' | 'u' => {}
+            c => {
+                return Err(invalid_query(format!(
+                    "invalid flag in regex options: {}",
+                    c
+                )))
+            }
+        }
+    }
+    let mut embedded = match (multiline, dotall) {
+        (false, false) => "p",
+        (true, false) => "n",
+        (false, true) => "",
+        (true, true) => "w",
+    }
+    .to_string();
+    if options.contains('x') {
+        embedded.push('x');
+    }
+    if !embedded.is_empty() {
+        embedded = format!("(?{})", embedded);
+    }
+
+    Ok(format!(
+        "(jsonb_typeof({0}) = 'string' AND {0} #>> '{{}}' {1} '{2}{3}')",
+        field,
+        if case_insensitive { "~*" } else { "~" },
+        embedded,
+        translate(pattern)?.replace('\'', "''")
+    ))
+}
+
+// PCRE allows flags like `(?i)` at the start of the pattern
+fn leading_options(pattern: &str, options: &str) -> (String, String) {
+    if let Some(rest) = pattern.strip_prefix("(?") {
+        if let Some(end) = rest.find(')') {
+            let flags = &rest[..end];
+            if !flags.is_empty() && flags.chars().all(|c| "imsx".contains(c)) {
+                return (rest[end + 1..].to_string(), format!("{}{}", options, flags));
+            }
+        }
+    }
+    (pattern.to_string(), options.to_string())
+}
+
+// Rewrites the PCRE constructs that mean something else to Postgres and
+// rejects the ones it doesn't have.
+fn translate(pattern: String) -> Result<String> {
+    let unsupported = |what: &str| {
+        invalid_query(format!(
+            "Regular expression {} is not supported: {}",
+            what, pattern
+        ))
+    };
+
+    let chars = pattern.chars().collect::<Vec<char>>();
+    let mut res = String::new();
+    let mut in_bracket = false;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '\\' if i + 1 < chars.len() => {
+                let next = chars[i + 1];
+                i += 1;
+                match next {
+                    'b' if !in_bracket => res.push_str("\\y"),
+                    'B' if !in_bracket => res.push_str("\\Y"),
+                    'z' => res.push_str("\\Z"),
+                    'Z' => res.push_str("(?=\\n?\\Z)"),
+                    'Q' => {
+                        // everything up to \E is literal
+                        let rest = chars[i + 1..].iter().collect::<String>();
+                        let (literal, len) = match rest.find("\\E") {
+                            Some(end) => (&rest[..end], end + 2),
+                            None => (rest.as_str(), rest.len()),
+                        };
+                        for l in literal.chars() {
+                            if !l.is_alphanumeric() && !l.is_whitespace() {
+                                res.push('\\');
+                            }
+                            res.push(l);
+                        }
+                        i += literal.chars().count() + (len - literal.len());
+                    }
+                    'p' | 'P' | 'X' | 'K' | 'G' | 'R' | 'h' | 'H' | 'N' | 'g' | 'k' | 'C' => {
+                        return Err(unsupported(&format!("escape \\{}", next)))
+                    }
+                    next => {
+                        res.push('\\');
+                        res.push(next);
+                    }
+                }
+            }
+            '[' if !in_bracket => {
+                in_bracket = true;
+                res.push(c);
+                // a leading ] (or ^]) is a literal
+                for _ in 0..2 {
+                    match chars.get(i + 1) {
+                        Some('^') if res.ends_with('[') => {
+                            res.push('^');
+                            i += 1;
+                        }
+                        Some(']') => {
+                            res.push(']');
+                            i += 1;
+                            break;
+                        }
+                        _ => break,
+                    }
+                }
+            }
+            ']' if in_bracket => {
+                in_bracket = false;
+                res.push(c);
+            }
+            '(' if !in_bracket && chars.get(i + 1) == Some(&'?') => {
+                let rest = chars[i + 2..].iter().collect::<String>();
+                if [":", "=", "!", "<=", "<!", "#"]
+                    .iter()
+                    .any(|p| rest.starts_with(p))
+                {
+                    res.push_str("(?");
+                    i += 1;
+                } else if rest.starts_with('<') || rest.starts_with("P<") || rest.starts_with('\'')
+                {
+                    return Err(unsupported("named group"));
+                } else if rest.starts_with('>') {
+                    return Err(unsupported("atomic group"));
+                } else if rest.starts_with(|c: char| "imsx-".contains(c)) {
+                    return Err(unsupported("inline flag group"));
+                } else {
+                    return Err(unsupported("group"));
+                }
+            }
+            '*' | '+' | '?' | '}' if !in_bracket && chars.get(i + 1) == Some(&'+') => {
+                return Err(unsupported("possessive quantifier"));
+            }
+            c => res.push(c),
+        }
+        i += 1;
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sql(pattern: &str, options: &str) -> String {
+        regex_to_sql("v", pattern, options).unwrap()
+    }
+
+    #[test]
+    fn test_options() {
+        assert_eq!(
+            sql("^a.b", ""),
+            "(jsonb_typeof(v) = 'string' AND v #>> '{}' ~ '(?p)^a.b')"
+        );
+        assert_eq!(
+            sql("^a", "i"),
+            "(jsonb_typeof(v) = 'string' AND v #>> '{}' ~* '(?p)^a')"
+        );
+        assert_eq!(
+            sql("^a", "m"),
+            "(jsonb_typeof(v) = 'string' AND v #>> '{}' ~ '(?n)^a')"
+        );
+        assert_eq!(
+            sql("^a", "s"),
+            "(jsonb_typeof(v) = 'string' AND v #>> '{}' ~ '^a')"
+        );
+        assert_eq!(
+            sql("^a # comment", "msx"),
+            "(jsonb_typeof(v) = 'string' AND v #>> '{}' ~ '(?wx)^a # comment')"
+        );
+        assert_eq!(
+            sql("(?i)^a", ""),
+            "(jsonb_typeof(v) = 'string' AND v #>> '{}' ~* '(?p)^a')"
+        );
+        assert_eq!(
+            regex_to_sql("v", "a", "q").unwrap_err().to_string(),
+            "invalid flag in regex options: q"
+        );
+    }
+
+    #[test]
+    fn test_translate() {
+        let translate = |p: &str| translate(p.to_string()).unwrap();
+        assert_eq!(translate(r"\bfoo\B"), r"\yfoo\Y");
+        assert_eq!(translate(r"[\b]"), r"[\b]");
+        assert_eq!(translate(r"a\z"), r"a\Z");
+        assert_eq!(translate(r"\Qa.b*\Ec"), r"a\.b\*c");
+        assert_eq!(translate(r"[]a]+(?:b|c)(?=d)"), r"[]a]+(?:b|c)(?=d)");
+        assert_eq!(translate("it's"), "it's");
+        assert_eq!(
+            sql("it's", "s"),
+            "(jsonb_typeof(v) = 'string' AND v #>> '{}' ~ 'it''s')"
+        );
+    }
+
+    #[test]
+    fn test_unsupported() {
+        for (pattern, message) in [
+            (r"(?<year>\d+)", "named group"),
+            (r"(?>a+)b", "atomic group"),
+            (r"a(?i)b", "inline flag group"),
+            (r"a++", "possessive quantifier"),
+            (r"\p{L}", r"escape \p"),
+        ] {
+            let err = translate(pattern.to_string()).unwrap_err().to_string();
+            assert!(
+                err.starts_with(&format!("Regular expression {} is not supported", message)),
+                "{}",
+                err
+            );
+        }
+    }
+}
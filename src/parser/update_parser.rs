@@ -1,6 +1,6 @@
-use bson::Document;
+use bson::{Bson, Document};
 
-use crate::utils::expand_fields;
+use crate::utils::{expand_fields, KeyConflictError};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum UpdateOper {
@@ -14,6 +14,16 @@ pub enum UpdateDoc {
     Set(Document),
     Unset(Document),
     AddToSet(Document),
+    Push(Document),
+    Pull(Document),
+    PullAll(Document),
+    Pop(Document),
+    Rename(Document),
+    Min(Document),
+    Max(Document),
+    Mul(Document),
+    CurrentDate(Document),
+    SetOnInsert(Document),
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -39,15 +49,288 @@ impl UpdateDoc {
                     )));
                 }
             },
+            UpdateDoc::SetOnInsert(doc) => match expand_fields(doc) {
+                Ok(u) => Ok(UpdateDoc::SetOnInsert(u)),
+                Err(e) => Err(InvalidUpdateError::new(format!(
+                    "Cannot update '{}' and '{}' at the same time",
+                    e.target, e.source
+                ))),
+            },
             UpdateDoc::Unset(doc) => Ok(UpdateDoc::Unset(doc.clone())),
             UpdateDoc::Inc(u) => Ok(UpdateDoc::Inc(u.clone())),
-            UpdateDoc::AddToSet(doc) => Ok(UpdateDoc::AddToSet(doc.clone())),
-            // _ => {
-            //     return Err(InvalidUpdateError::new(format!(
-            //         "Unhandled update operation: {:?}",
-            //         self
-            //     )));
-            // }
+            UpdateDoc::AddToSet(doc) => {
+                for value in doc.values() {
+                    if let Some(modifiers) = each_modifiers(value) {
+                        validate_each("$addToSet", modifiers.get("$each").unwrap())?;
+                        if let Some(key) = modifiers.keys().find(|k| *k != "$each") {
+                            return Err(InvalidUpdateError::new(format!(
+                                "Found unexpected fields after $each in $addToSet: {}",
+                                key
+                            )));
+                        }
+                    }
+                }
+                Ok(UpdateDoc::AddToSet(doc.clone()))
+            }
+            UpdateDoc::Push(doc) => {
+                for value in doc.values() {
+                    if let Some(modifiers) = each_modifiers(value) {
+                        for (key, value) in modifiers {
+                            match key.as_str() {
+                                "$each" => validate_each("$push", value)?,
+                                "$slice" | "$position" => {
+                                    if as_integer(value).is_none() {
+                                        return Err(InvalidUpdateError::new(format!(
+                                            "The value for {} must be an integer value but was given type: {}",
+                                            key,
+                                            type_name(value)
+                                        )));
+                                    }
+                                }
+                                "$sort" => validate_sort(value)?,
+                                _ => {
+                                    return Err(InvalidUpdateError::new(format!(
+                                        "Unrecognized clause in $push: {}",
+                                        key
+                                    )))
+                                }
+                            }
+                        }
+                    }
+                }
+                Ok(UpdateDoc::Push(doc.clone()))
+            }
+            UpdateDoc::Pull(doc) => Ok(UpdateDoc::Pull(doc.clone())),
+            UpdateDoc::PullAll(doc) => {
+                if let Some(value) = doc.values().find(|v| !matches!(v, Bson::Array(_))) {
+                    return Err(InvalidUpdateError::new(format!(
+                        "$pullAll requires an array argument but was given a {}",
+                        type_name(value)
+                    )));
+                }
+                Ok(UpdateDoc::PullAll(doc.clone()))
+            }
+            UpdateDoc::Pop(doc) => {
+                if let Some(value) = doc
+                    .values()
+                    .find(|v| !matches!(as_integer(v), Some(1) | Some(-1)))
+                {
+                    return Err(InvalidUpdateError::new(format!(
+                        "$pop expects 1 or -1, found: {}",
+                        value
+                    )));
+                }
+                Ok(UpdateDoc::Pop(doc.clone()))
+            }
+            UpdateDoc::Rename(doc) => {
+                for (from, to) in doc {
+                    let to = match to {
+                        Bson::String(to) => to,
+                        _ => {
+                            return Err(InvalidUpdateError::new(format!(
+                                "The 'to' field for $rename must be a string: {}: {}",
+                                from, to
+                            )))
+                        }
+                    };
+                    if from == to {
+                        return Err(InvalidUpdateError::new(format!(
+                            "The source and target field for $rename must differ: {}: \"{}\"",
+                            from, to
+                        )));
+                    }
+                    if to.starts_with(&format!("{}.", from))
+                        || from.starts_with(&format!("{}.", to))
+                    {
+                        return Err(InvalidUpdateError::new(format!(
+                            "The source and target field for $rename must not be on the same path: {}: \"{}\"",
+                            from, to
+                        )));
+                    }
+                }
+                Ok(UpdateDoc::Rename(doc.clone()))
+            }
+            UpdateDoc::Min(doc) => Ok(UpdateDoc::Min(doc.clone())),
+            UpdateDoc::Max(doc) => Ok(UpdateDoc::Max(doc.clone())),
+            UpdateDoc::Mul(doc) => {
+                for (field, value) in doc {
+                    if !matches!(
+                        value,
+                        Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) | Bson::Decimal128(_)
+                    ) {
+                        return Err(InvalidUpdateError::new(format!(
+                            "Cannot multiply with non-numeric argument: {{{}: {}}}",
+                            field, value
+                        )));
+                    }
+                }
+                Ok(UpdateDoc::Mul(doc.clone()))
+            }
+            UpdateDoc::CurrentDate(doc) => {
+                for value in doc.values() {
+                    match value {
+                        Bson::Boolean(_) => {}
+                        Bson::Document(spec) => match spec.get("$type") {
+                            Some(Bson::String(t)) if t == "date" || t == "timestamp" => {}
+                            _ => {
+                                return Err(InvalidUpdateError::new(
+                                    "The '$type' string field is required to be 'date' or 'timestamp': {$currentDate: {field : {$type: 'date'}}}".to_string(),
+                                ))
+                            }
+                        },
+                        _ => {
+                            return Err(InvalidUpdateError::new(format!(
+                                "{} is not valid type for $currentDate. Please use a boolean ('true') or a $type expression ({{$type: 'timestamp/date'}}).",
+                                value
+                            )))
+                        }
+                    }
+                }
+                Ok(UpdateDoc::CurrentDate(doc.clone()))
+            } // _ => {
+              //     return Err(InvalidUpdateError::new(format!(
+              //         "Unhandled update operation: {:?}",
+              //         self
+              //     )));
+              // }
+        }
+    }
+}
+
+/// The modifiers of a `$push` or `$addToSet` value, like `{ "$each": [1, 2] }`.
+pub fn each_modifiers(value: &Bson) -> Option<&Document> {
+    value.as_document().filter(|doc| doc.contains_key("$each"))
+}
+
+/// An integer given as any kind of number, as `$slice` and `$position` take.
+pub fn as_integer(value: &Bson) -> Option<i64> {
+    match value {
+        Bson::Int32(v) => Some(*v as i64),
+        Bson::Int64(v) => Some(*v),
+        Bson::Double(v) if v.fract() == 0.0 => Some(*v as i64),
+        _ => None,
+    }
+}
+
+fn type_name(value: &Bson) -> &'static str {
+    match value {
+        Bson::Double(_) => "double",
+        Bson::String(_) => "string",
+        Bson::Document(_) => "object",
+        Bson::Array(_) => "array",
+        Bson::Boolean(_) => "bool",
+        Bson::Null => "null",
+        Bson::Int32(_) => "int",
+        Bson::Int64(_) => "long",
+        _ => "unsupported",
+    }
+}
+
+fn validate_each(oper: &str, value: &Bson) -> Result<(), InvalidUpdateError> {
+    match value {
+        Bson::Array(_) => Ok(()),
+        _ => Err(InvalidUpdateError::new(format!(
+            "The argument to $each in {} must be an array but it was of type: {}",
+            oper,
+            type_name(value)
+        ))),
+    }
+}
+
+fn validate_sort(value: &Bson) -> Result<(), InvalidUpdateError> {
+    let is_direction = |v: &Bson| matches!(as_integer(v), Some(1) | Some(-1));
+    let valid = match value {
+        Bson::Document(doc) => !doc.is_empty() && doc.values().all(is_direction),
+        v => is_direction(v),
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(InvalidUpdateError::new(
+            "The $sort element value must be either 1 or -1".to_string(),
+        ))
+    }
+}
+
+/// The document an upsert starts from when nothing matches `filter`: its
+/// equality conditions, including those in `$and` and on dotted paths, which
+/// become subdocuments. Other conditions, like `{ "$gt": 5 }`, are left out.
+pub fn upsert_seed(filter: &Document) -> Result<Document, KeyConflictError> {
+    let mut fields = Document::new();
+    add_equality_fields(filter, &mut fields)?;
+    let mut seed = Document::new();
+    for (key, value) in fields.iter() {
+        insert_path(&mut seed, key, value.clone()).map_err(|target| KeyConflictError {
+            source: key.clone(),
+            target,
+        })?;
+    }
+    // the _id goes first, like on inserts
+    let mut doc = Document::new();
+    if let Some(id) = seed.get("_id") {
+        doc.insert("_id", id.clone());
+    }
+    doc.extend(seed.into_iter().filter(|(k, _)| k != "_id"));
+    Ok(doc)
+}
+
+// a field can only be given once, even by different clauses of `$and`
+fn add_equality_fields(filter: &Document, seed: &mut Document) -> Result<(), KeyConflictError> {
+    for (key, value) in filter {
+        if key == "$and" {
+            if let Bson::Array(clauses) = value {
+                for clause in clauses.iter().filter_map(|c| c.as_document()) {
+                    add_equality_fields(clause, seed)?;
+                }
+            }
+            continue;
+        }
+        if key.starts_with('$') {
+            continue;
+        }
+        let value = match value {
+            Bson::Document(doc) if doc.keys().any(|k| k.starts_with('$')) => {
+                match (doc.get("$eq"), doc.get("$in")) {
+                    (Some(value), _) => value,
+                    (None, Some(Bson::Array(values))) if values.len() == 1 => &values[0],
+                    _ => continue,
+                }
+            }
+            value => value,
+        };
+        if matches!(value, Bson::RegularExpression(_)) {
+            continue;
+        }
+        if seed.contains_key(key) {
+            return Err(KeyConflictError {
+                source: key.clone(),
+                target: key.clone(),
+            });
+        }
+        seed.insert(key.clone(), value.clone());
+    }
+    Ok(())
+}
+
+// Sets a dotted path, creating the intermediate documents. Returns the
+// conflicting prefix when part of the path is already taken by a value.
+fn insert_path(doc: &mut Document, path: &str, value: Bson) -> Result<(), String> {
+    match path.split_once('.') {
+        None if doc.contains_key(path) => Err(path.to_string()),
+        None => {
+            doc.insert(path, value);
+            Ok(())
+        }
+        Some((head, rest)) => {
+            let child = doc
+                .entry(head.to_string())
+                .or_insert_with(|| Bson::Document(Document::new()));
+            match child {
+                Bson::Document(child) => {
+                    insert_path(child, rest, value).map_err(|target| format!("{}.{}", head, target))
+                }
+                _ => Err(head.to_string()),
+            }
         }
     }
 }
@@ -114,9 +397,39 @@ pub fn parse_update(doc: &Document) -> Result<UpdateOper, InvalidUpdateError> {
                 let doc = value.as_document().unwrap().clone();
                 match UpdateDoc::AddToSet(doc).validate() {
                     Ok(update_doc) => res.push(update_doc),
-                    Err(e) => {
-                        return Err(InvalidUpdateError::new(format!("{:?}", e)));
-                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            "$push" => {
+                let doc = value.as_document().unwrap().clone();
+                match UpdateDoc::Push(doc).validate() {
+                    Ok(update_doc) => res.push(update_doc),
+                    Err(e) => return Err(e),
+                }
+            }
+            "$setOnInsert" => {
+                let doc = value.as_document().unwrap().clone();
+                match UpdateDoc::SetOnInsert(doc).validate() {
+                    Ok(update_doc) => res.push(update_doc),
+                    Err(e) => return Err(e),
+                }
+            }
+            "$pull" | "$pullAll" | "$pop" | "$rename" | "$min" | "$max" | "$mul"
+            | "$currentDate" => {
+                let doc = value.as_document().unwrap().clone();
+                let update_doc = match key.as_str() {
+                    "$pull" => UpdateDoc::Pull(doc),
+                    "$pullAll" => UpdateDoc::PullAll(doc),
+                    "$pop" => UpdateDoc::Pop(doc),
+                    "$rename" => UpdateDoc::Rename(doc),
+                    "$min" => UpdateDoc::Min(doc),
+                    "$max" => UpdateDoc::Max(doc),
+                    "$mul" => UpdateDoc::Mul(doc),
+                    _ => UpdateDoc::CurrentDate(doc),
+                };
+                match update_doc.validate() {
+                    Ok(update_doc) => res.push(update_doc),
+                    Err(e) => return Err(e),
                 }
             }
             _ => {
@@ -162,4 +475,131 @@ mod tests {
             InvalidUpdateError::new("Unknown update operator: b".to_string())
         );
     }
+
+    #[test]
+    fn test_parse_pull() {
+        assert_eq!(
+            parse_update(&doc! { "$pull": { "a": { "qty": { "$lt": 5 } } }, "$pop": { "b": -1 } })
+                .unwrap(),
+            UpdateOper::Update(vec![
+                UpdateDoc::Pull(doc! { "a": { "qty": { "$lt": 5 } } }),
+                UpdateDoc::Pop(doc! { "b": -1 }),
+            ])
+        );
+        assert_eq!(
+            parse_update(&doc! { "$pullAll": { "a": 1 } }).unwrap_err(),
+            InvalidUpdateError::new(
+                "$pullAll requires an array argument but was given a int".to_string()
+            )
+        );
+        assert_eq!(
+            parse_update(&doc! { "$pop": { "a": 2 } }).unwrap_err(),
+            InvalidUpdateError::new("$pop expects 1 or -1, found: 2".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_field_operators() {
+        assert_eq!(
+            parse_update(&doc! { "$rename": { "a": "b" }, "$mul": { "c": 2.5 } }).unwrap(),
+            UpdateOper::Update(vec![
+                UpdateDoc::Rename(doc! { "a": "b" }),
+                UpdateDoc::Mul(doc! { "c": 2.5 }),
+            ])
+        );
+
+        for (doc, reason) in [
+            (
+                doc! { "$rename": { "a": 1 } },
+                "The 'to' field for $rename must be a string: a: 1",
+            ),
+            (
+                doc! { "$rename": { "a": "a" } },
+                "The source and target field for $rename must differ: a: \"a\"",
+            ),
+            (
+                doc! { "$rename": { "a": "a.b" } },
+                "The source and target field for $rename must not be on the same path: a: \"a.b\"",
+            ),
+            (
+                doc! { "$mul": { "a": "x" } },
+                "Cannot multiply with non-numeric argument: {a: \"x\"}",
+            ),
+            (
+                doc! { "$currentDate": { "a": { "$type": "x" } } },
+                "The '$type' string field is required to be 'date' or 'timestamp': {$currentDate: {field : {$type: 'date'}}}",
+            ),
+        ] {
+            assert_eq!(
+                parse_update(&doc).unwrap_err(),
+                InvalidUpdateError::new(reason.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn test_upsert_seed() {
+        assert_eq!(
+            upsert_seed(&doc! {
+                "name": "a",
+                "qty": { "$gt": 5 },
+                "$and": [{ "tags": { "$eq": ["x"] } }, { "$or": [{ "c": 1 }] }],
+                "info.kind": "k",
+                "info.size": { "$in": [3] },
+                "code": bson::Regex { pattern: "^a".to_string(), options: "".to_string() },
+                "_id": 7,
+            })
+            .unwrap(),
+            doc! {
+                "_id": 7,
+                "name": "a",
+                "tags": ["x"],
+                "info": { "kind": "k", "size": 3 },
+            }
+        );
+
+        let err = upsert_seed(&doc! { "a": 1, "$and": [{ "a": 2 }] }).unwrap_err();
+        assert_eq!(err.to_string(), "Conflicting keys 'a' and 'a'");
+        assert!(upsert_seed(&doc! { "$and": [{ "a": 1 }, { "a": { "$eq": 1 } }] }).is_err());
+    }
+
+    #[test]
+    fn test_parse_push() {
+        let push_doc =
+            doc! { "$push": { "a": { "$each": [1, 2], "$slice": -3, "$sort": { "x": -1 } } } };
+        assert_eq!(
+            parse_update(&push_doc).unwrap(),
+            UpdateOper::Update(vec![UpdateDoc::Push(
+                doc! { "a": { "$each": [1, 2], "$slice": -3, "$sort": { "x": -1 } } }
+            )])
+        );
+
+        for (doc, reason) in [
+            (
+                doc! { "$push": { "a": { "$each": 1 } } },
+                "The argument to $each in $push must be an array but it was of type: int",
+            ),
+            (
+                doc! { "$push": { "a": { "$each": [], "$slice": "1" } } },
+                "The value for $slice must be an integer value but was given type: string",
+            ),
+            (
+                doc! { "$push": { "a": { "$each": [], "$sort": 2 } } },
+                "The $sort element value must be either 1 or -1",
+            ),
+            (
+                doc! { "$push": { "a": { "$each": [], "$foo": 1 } } },
+                "Unrecognized clause in $push: $foo",
+            ),
+            (
+                doc! { "$addToSet": { "a": { "$each": [], "$slice": 1 } } },
+                "Found unexpected fields after $each in $addToSet: $slice",
+            ),
+        ] {
+            assert_eq!(
+                parse_update(&doc).unwrap_err(),
+                InvalidUpdateError::new(reason.to_string())
+            );
+        }
+    }
 }
@@ -1,11 +1,16 @@
 use crate::deserializer::PostgresJsonDeserializer;
-use crate::parser::{value_to_jsonb, InvalidUpdateError, UpdateDoc, UpdateOper};
+use crate::parser::{
+    as_integer, each_modifiers, escape, upsert_seed, value_to_jsonb, InvalidUpdateError, UpdateDoc,
+    UpdateOper,
+};
 use crate::serializer::PostgresSerializer;
+use crate::tls::{connection_manager, MakeRustlsConnect};
 use crate::utils::{collapse_fields, expand_fields};
-use bson::{Bson, Document};
+use bson::{doc, Bson, Document};
 use eyre::{eyre, Result};
 use postgres::error::{Error, SqlState};
-use postgres::{types::ToSql, NoTls, Row};
+use postgres::fallible_iterator::FallibleIterator;
+use postgres::{types::ToSql, Row, RowIter};
 use r2d2::PooledConnection;
 use r2d2_postgres::PostgresConnectionManager;
 use sql_lexer::sanitize_string;
@@ -13,6 +18,45 @@ use std::env;
 use std::error::Error as StdError;
 use std::fmt;
 
+/// Schema that holds users and roles, hidden from the list of databases.
+pub const AUTH_SCHEMA: &str = "oxide_auth";
+const USERS_TABLE: &str = "users";
+const ROLES_TABLE: &str = "roles";
+
+/// Schema that holds the state of sessions, hidden like the auth one.
+pub const SESSIONS_SCHEMA: &str = "oxide_sessions";
+const TRANSACTIONS_TABLE: &str = "transactions";
+
+/// Schema that holds the log of changes read by change streams, hidden like
+/// the auth one.
+pub const CHANGES_SCHEMA: &str = "oxide_changes";
+pub const CHANGES_TABLE: &str = "events";
+/// Channel notified whenever a change is logged.
+pub const CHANGES_CHANNEL: &str = "oxide_changes";
+// name of the trigger that logs the changes of a collection
+const CHANGES_TRIGGER: &str = "oxide_changes";
+// scopes whose changes keep the document before the change, '' matching
+// every database or collection
+const PRE_IMAGES_TABLE: &str = "pre_images";
+// hours a change is kept in the log, enough for change streams to resume
+const CHANGES_RETENTION_HOURS: i32 = 24;
+
+/// Schema of the SQL functions queries use to compare BSON values, hidden
+/// like the auth one.
+pub const FUNCTIONS_SCHEMA: &str = "oxide_functions";
+
+/// Whether a schema holds Oxide's own state rather than a database of the
+/// clients, which can't reach it.
+pub fn is_internal_schema(name: &str) -> bool {
+    [
+        AUTH_SCHEMA,
+        SESSIONS_SCHEMA,
+        CHANGES_SCHEMA,
+        FUNCTIONS_SCHEMA,
+    ]
+    .contains(&name)
+}
+
 #[derive(Debug)]
 pub struct AlreadyExistsError {
     _target: String,
@@ -34,8 +78,14 @@ pub enum CreateTableError {
 
 #[derive(Debug)]
 pub enum UpdateResult {
-    Count(u64),
+    /// How many documents matched, and how many of them the update changed.
+    Count {
+        matched: u64,
+        modified: u64,
+    },
     Document(Document),
+    /// Nothing matched an upsert, so this document was inserted.
+    Upserted(Document),
 }
 
 #[derive(Debug)]
@@ -45,24 +95,99 @@ pub enum UpdateError {
 }
 
 pub struct PgDb {
-    client: PooledConnection<PostgresConnectionManager<NoTls>>,
+    client: PooledConnection<PostgresConnectionManager<MakeRustlsConnect>>,
+    in_transaction: bool,
 }
 
+// savepoint statements are wrapped in by `atomically`
+const STATEMENT_SAVEPOINT: &str = "oxide_statement";
+
 impl PgDb {
     pub fn new() -> Self {
         PgDb::new_with_uri(&env::var("DATABASE_URL").unwrap())
     }
 
-    pub fn new_from_pool(pool: r2d2::Pool<PostgresConnectionManager<NoTls>>) -> Self {
+    pub fn new_from_pool(pool: r2d2::Pool<PostgresConnectionManager<MakeRustlsConnect>>) -> Self {
         let client = pool.get().unwrap();
-        PgDb { client }
+        PgDb {
+            client,
+            in_transaction: false,
+        }
     }
 
     pub fn new_with_uri(uri: &str) -> Self {
-        let manager = PostgresConnectionManager::new(uri.parse().unwrap(), NoTls);
+        let manager = connection_manager(uri).unwrap();
         let pool = r2d2::Pool::new(manager).unwrap();
         let client = pool.get().unwrap();
-        PgDb { client }
+        PgDb {
+            client,
+            in_transaction: false,
+        }
+    }
+
+    /// Starts a transaction on this connection. It's rolled back if the
+    /// connection goes back to the pool before `commit` or `rollback`.
+    pub fn begin(&mut self) -> Result<()> {
+        self.batch_exec("BEGIN")?;
+        self.in_transaction = true;
+        Ok(())
+    }
+
+    pub fn commit(&mut self) -> Result<()> {
+        self.in_transaction = false;
+        self.batch_exec("COMMIT")
+    }
+
+    pub fn rollback(&mut self) -> Result<()> {
+        self.in_transaction = false;
+        self.batch_exec("ROLLBACK")
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.in_transaction
+    }
+
+    /// Runs `f` in a transaction, committed if it succeeds. When the
+    /// connection is already in one, `f` becomes part of it instead.
+    pub fn transaction<T>(&mut self, f: impl FnOnce(&mut PgDb) -> Result<T>) -> Result<T> {
+        if self.in_transaction {
+            return self.atomically(f);
+        }
+        self.begin()?;
+        match f(self) {
+            Ok(value) => {
+                self.commit()?;
+                Ok(value)
+            }
+            Err(e) => {
+                let _ = self.rollback();
+                Err(e)
+            }
+        }
+    }
+
+    /// Runs `f` so that, within a transaction, a failure only undoes what
+    /// `f` did instead of aborting the whole transaction.
+    pub fn atomically<T>(&mut self, f: impl FnOnce(&mut PgDb) -> Result<T>) -> Result<T> {
+        if !self.in_transaction {
+            return f(self);
+        }
+        self.batch_exec(&format!("SAVEPOINT {}", STATEMENT_SAVEPOINT))?;
+        match f(self) {
+            Ok(value) => {
+                self.batch_exec(&format!("RELEASE SAVEPOINT {}", STATEMENT_SAVEPOINT))?;
+                Ok(value)
+            }
+            Err(e) => {
+                self.batch_exec(&format!("ROLLBACK TO SAVEPOINT {}", STATEMENT_SAVEPOINT))?;
+                Err(e)
+            }
+        }
+    }
+
+    fn batch_exec(&mut self, sql: &str) -> Result<()> {
+        log::debug!("SQL: {}", sql);
+        self.client.batch_execute(sql).map_err(|e| eyre! {e})
     }
 
     pub fn exec(&mut self, query: &str, params: &[&(dyn ToSql + Sync)]) -> Result<u64> {
@@ -90,82 +215,18 @@ impl PgDb {
         filter: Option<Document>,
         params: &[&(dyn ToSql + Sync)],
     ) -> Result<Vec<Row>> {
-        let mut sql = self.get_query(query, sp);
-        if let Some(f) = filter {
-            let filter_doc = expand_fields(&f).unwrap();
-            let filter = super::parser::parse(filter_doc)?;
-            if filter != "" {
-                sql = format!("{} WHERE {}", sql, filter);
-            }
-        }
-
-        log::debug!("SQL: {} - {:#?}", sql, params);
+        let sql = self.get_filtered_query(query, sp, filter)?;
         self.raw_query(&sql, params)
     }
 
-    fn get_matching_ids(
-        &mut self,
-        sp: &SqlParam,
-        limit: i32,
-        filter: Option<&Document>,
-    ) -> Result<Option<Vec<String>>> {
-        let where_str = match filter {
-            Some(f) => {
-                let filter = super::parser::parse(f.clone())?;
-                if filter != "" {
-                    format!("WHERE {}", filter)
-                } else {
-                    "".to_string()
-                }
-            }
-            None => "".to_string(),
-        };
-        let sql = format!(
-            "SELECT _jsonb->'_id'->>'$o' FROM {} {} LIMIT {}",
-            sp.sanitize(),
-            where_str,
-            limit,
-        );
-        let ids = self
-            .client
-            .query(&sql, &[])
-            .unwrap()
-            .into_iter()
-            .map(|r| r.get(0))
-            .collect::<Vec<String>>();
-        if ids.is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(ids))
-        }
-    }
-
-    fn add_ids_to_where(
-        &mut self,
-        sp: &SqlParam,
-        limit: i32,
-        filter: Option<&Document>,
-        where_str: &str,
+    pub fn get_filtered_query(
+        &self,
+        query: &str,
+        sp: SqlParam,
+        filter: Option<Document>,
     ) -> Result<String> {
-        let ids = self.get_matching_ids(sp, limit, filter)?;
-        if ids.is_some() {
-            let in_ids = format!(
-                "({})",
-                ids.unwrap()
-                    .into_iter()
-                    .map(|id| format!("'{}'", id))
-                    .collect::<Vec<String>>()
-                    .join(", ")
-            );
-            let where_id = format!("_jsonb->'_id'->>'$o' IN {}", in_ids);
-            if where_str == "" {
-                Ok(format!("WHERE {}", where_id))
-            } else {
-                Ok(format!("{} AND {}", where_str, where_id))
-            }
-        } else {
-            Ok(where_str.to_string())
-        }
+        let sql = self.get_query(query, sp);
+        Ok(format!("{}{}", sql, get_where_clause(filter.as_ref())?))
     }
 
     pub fn delete(
@@ -174,7 +235,7 @@ impl PgDb {
         filter: Option<&Document>,
         limit: Option<i32>,
     ) -> Result<u64> {
-        let mut where_str = if let Some(f) = filter {
+        let where_str = if let Some(f) = filter {
             if f.keys().count() < 1 {
                 "".to_string()
             } else {
@@ -184,14 +245,17 @@ impl PgDb {
             "".to_string()
         };
 
-        // apply limit
-        if let Some(limit) = limit {
-            if limit > 0 {
-                where_str = self.add_ids_to_where(sp, limit, filter, where_str.as_str())?;
-            }
-        }
-
-        let sql = format!("DELETE FROM {}{}", sp.to_string(), where_str);
+        // rows are picked by their physical location, so limits work on
+        // documents of any _id type
+        let sql = match limit {
+            Some(limit) if limit > 0 => format!(
+                "DELETE FROM {table} WHERE ctid IN (SELECT ctid FROM {table}{} LIMIT {})",
+                where_str,
+                limit,
+                table = sp
+            ),
+            _ => format!("DELETE FROM {}{}", sp, where_str),
+        };
         self.exec(&sql, &[])
     }
 
@@ -218,6 +282,14 @@ impl PgDb {
         let return_str = if returning { " RETURNING _jsonb" } else { "" };
 
         let table_name = format!("{}", sp.sanitize());
+        if let (true, UpdateOper::Update(updates)) = (upsert, &update) {
+            let query = format!("SELECT EXISTS(SELECT 1 FROM {}{})", table_name, where_str);
+            let exists: bool = self.client.query_one(&query, &[])?.get(0);
+            if !exists {
+                return self.upsert(sp, filter, updates);
+            }
+        }
+
         if !multi {
             let order_by_str = get_order_by(sort);
             // gets the first id that matches
@@ -227,7 +299,10 @@ impl PgDb {
             );
             let rows = self.raw_query(&sql, &[]).unwrap();
             if !upsert && rows.len() < 1 {
-                return Ok(UpdateResult::Count(0));
+                return Ok(UpdateResult::Count {
+                    matched: 0,
+                    modified: 0,
+                });
             }
             if rows.len() > 0 {
                 let id: serde_json::Value = rows[0].get(0);
@@ -241,25 +316,20 @@ impl PgDb {
         };
 
         if let UpdateOper::Update(updates) = update.clone() {
-            self.check_preconditions(&sp, &updates)?;
+            self.check_preconditions(&sp, &where_str, &updates)?;
         }
 
-        let statements = match update {
-            UpdateOper::Update(updates) => updates
-                .iter()
-                .map(|u| {
-                    format!(
-                        "UPDATE {} SET {}{}{}",
-                        table_name,
-                        update_from_operation(u),
-                        where_str,
-                        return_str
-                    )
-                })
-                .collect::<Vec<String>>(),
+        let updates = match update {
+            UpdateOper::Update(updates) => updates,
             UpdateOper::Replace(mut replace) => {
                 if !replace.contains_key("_id") {
-                    replace.insert("_id", bson::oid::ObjectId::new());
+                    // an upsert keeps the _id the filter asks for
+                    let id = filter
+                        .map(upsert_seed)
+                        .transpose()?
+                        .and_then(|seed| seed.get("_id").cloned())
+                        .unwrap_or_else(|| bson::oid::ObjectId::new().into());
+                    replace.insert("_id", id);
                 }
                 let json = Bson::Document(replace).into_psql_json();
                 let needs_insert = upsert
@@ -272,17 +342,20 @@ impl PgDb {
                         let exists: bool = row.get(0);
                         exists
                     };
-                let sql = if needs_insert {
-                    format!(
-                        "INSERT INTO {} (_jsonb) VALUES ($1){}",
-                        table_name, return_str
-                    )
-                } else {
-                    format!(
-                        "UPDATE {} SET _jsonb = $1 {}{}",
-                        table_name, where_str, return_str
-                    )
-                };
+                if needs_insert {
+                    let sql = format!(
+                        "INSERT INTO {} (_jsonb) VALUES ($1) RETURNING _jsonb",
+                        table_name
+                    );
+                    let rows = self.raw_query(&sql, &[&json])?;
+                    let json: serde_json::Value = rows[0].get(0);
+                    let doc = json.from_psql_json()?;
+                    return Ok(UpdateResult::Upserted(doc.as_document().unwrap().clone()));
+                }
+                let sql = format!(
+                    "UPDATE {} SET _jsonb = $1 {}{}",
+                    table_name, where_str, return_str
+                );
 
                 return if returning {
                     let res = self.raw_query(&sql, &[&json])?;
@@ -290,32 +363,116 @@ impl PgDb {
                     // Ok(UpdateResult::Document(res))
                     todo!("Not ready yet")
                 } else {
-                    let res = self.exec(&sql, &[&json])?;
-                    Ok(UpdateResult::Count(res))
+                    // tells the changes trigger this isn't an update by operators
+                    let res = self.transaction(|client| {
+                        client
+                            .batch_exec("SELECT set_config('oxide.operation', 'replace', true)")?;
+                        let res = client.exec(&sql, &[&json])?;
+                        client.batch_exec("SELECT set_config('oxide.operation', '', true)")?;
+                        Ok(res)
+                    })?;
+                    Ok(UpdateResult::Count {
+                        matched: res,
+                        modified: res,
+                    })
                 };
             }
         };
 
-        if returning {
-            let sql = statements[0].clone();
-            let res = self.raw_query(&sql, &[])?;
-            let row = res.get(0).unwrap();
-            let json: serde_json::Value = row.get(0);
-            let doc = json.from_psql_json();
-
-            Ok(UpdateResult::Document(doc.as_document().unwrap().clone()))
-        } else {
-            // FIXME start a transaction here
-            // #16 - https://github.com/fcoury/oxide/issues/16
-            let mut total = 0;
-            for sql in statements {
-                total += self.exec(&sql, &[])?;
+        // The operators run one after the other on a temporary copy of the
+        // matched documents, so the collection sees a single update of each
+        // document the operators changed. `$setOnInsert` only applies to
+        // upserted documents.
+        let (matched, modified, document) = self.transaction(|client| {
+            client.batch_exec(
+                "CREATE TEMP TABLE IF NOT EXISTS oxide_update (id jsonb, _jsonb jsonb); DELETE FROM oxide_update",
+            )?;
+            let matched = client.exec(
+                &format!(
+                    "INSERT INTO oxide_update SELECT _jsonb->'_id', _jsonb FROM {}{} FOR UPDATE",
+                    table_name, where_str
+                ),
+                &[],
+            )?;
+            for update in updates
+                .iter()
+                .filter(|u| !matches!(u, UpdateDoc::SetOnInsert(_)))
+            {
+                let sql = format!("UPDATE oxide_update SET {}", update_from_operation(update)?);
+                client.exec(&sql, &[])?;
             }
-            Ok(UpdateResult::Count(total))
+            let modified = client.exec(
+                &format!(
+                    "UPDATE {} t SET _jsonb = u._jsonb FROM oxide_update u WHERE t._jsonb->'_id' = u.id AND t._jsonb <> u._jsonb",
+                    table_name
+                ),
+                &[],
+            )?;
+            let document = match returning {
+                true => client
+                    .raw_query("SELECT _jsonb FROM oxide_update LIMIT 1", &[])?
+                    .first()
+                    .map(|row| row.get::<_, serde_json::Value>(0)),
+                false => None,
+            };
+            Ok((matched, modified, document))
+        })?;
+
+        match document {
+            Some(json) => Ok(UpdateResult::Document(
+                json.from_psql_json()?.as_document().unwrap().clone(),
+            )),
+            None => Ok(UpdateResult::Count { matched, modified }),
         }
     }
 
-    pub fn check_preconditions(&mut self, sp: &SqlParam, updates: &Vec<UpdateDoc>) -> Result<()> {
+    /// Inserts the document of an upsert that matched nothing: the equality
+    /// fields of `filter` with the update operators, `$setOnInsert` included,
+    /// applied to them. The operators run on a temporary table, so the
+    /// collection only sees the final insert.
+    fn upsert(
+        &mut self,
+        sp: &SqlParam,
+        filter: Option<&Document>,
+        updates: &[UpdateDoc],
+    ) -> Result<UpdateResult> {
+        let mut seed = upsert_seed(filter.unwrap_or(&Document::new()))?;
+        if !seed.contains_key("_id") {
+            let mut doc = bson::doc! { "_id": bson::oid::ObjectId::new() };
+            doc.extend(seed);
+            seed = doc;
+        }
+        let json = Bson::Document(seed).into_psql_json();
+        let doc = self.transaction(|client| {
+            client.batch_exec(
+                "CREATE TEMP TABLE IF NOT EXISTS oxide_upsert (_jsonb jsonb); DELETE FROM oxide_upsert",
+            )?;
+            client.exec("INSERT INTO oxide_upsert VALUES ($1)", &[&json])?;
+            for update in updates {
+                let update = match update {
+                    UpdateDoc::SetOnInsert(set) => UpdateDoc::Set(set.clone()),
+                    update => update.clone(),
+                };
+                let sql = format!("UPDATE oxide_upsert SET {}", update_from_operation(&update)?);
+                client.exec(&sql, &[])?;
+            }
+            let sql = format!(
+                "INSERT INTO {} SELECT _jsonb FROM oxide_upsert RETURNING _jsonb",
+                sp.sanitize()
+            );
+            let rows = client.raw_query(&sql, &[])?;
+            let json: serde_json::Value = rows[0].get(0);
+            json.from_psql_json()
+        })?;
+        Ok(UpdateResult::Upserted(doc.as_document().unwrap().clone()))
+    }
+
+    pub fn check_preconditions(
+        &mut self,
+        sp: &SqlParam,
+        where_str: &str,
+        updates: &Vec<UpdateDoc>,
+    ) -> Result<()> {
         // field is a.b.c and value is d: 1
         // preconditions are:
         // 1. a is object (or doesn't exist -- ends here with success)
@@ -323,36 +480,58 @@ impl PgDb {
         // 3. c is array  (or doesn't exist -- ends here with success)
 
         for update in updates {
-            match update {
-                UpdateDoc::AddToSet(add_to_set) => {
-                    // check if any of the fields to be set is not an array
-                    let table = sp.sanitize();
-                    for (field, _) in add_to_set.iter() {
-                        let set_field = format!(
-                            "_jsonb{}",
-                            field
-                                .split(".")
-                                .map(|f| format!("['{}']", f))
-                                .collect::<Vec<_>>()
-                                .join("")
-                        );
-
-                        let sql = format!("SELECT _jsonb->'_id' AS _id FROM {table} WHERE jsonb_typeof({set_field}) IS NOT NULL AND jsonb_typeof({set_field}) <> 'array'");
-                        let row = self.query_one(&sql, &[])?;
-                        if let Some(row) = row {
-                            let id: serde_json::Value = row.get(0);
-                            let id = id.from_psql_json();
-                            let field = add_to_set.keys().next().unwrap();
-                            let last_part = field.split(".").last().unwrap();
-                            let err = format!(
-                                "Cannot apply $addToSet to a non-array field. Field named '{}' has a non-array type int in the document _id: {}",
-                                last_part, id
-                            );
-                            return Err(eyre! { err });
+            let (oper, fields) = match update {
+                UpdateDoc::AddToSet(fields) => ("$addToSet", fields),
+                UpdateDoc::Push(fields) => ("$push", fields),
+                UpdateDoc::Pull(fields) => ("$pull", fields),
+                UpdateDoc::PullAll(fields) => ("$pullAll", fields),
+                UpdateDoc::Pop(fields) => ("$pop", fields),
+                UpdateDoc::Mul(fields) => ("$mul", fields),
+                _ => continue,
+            };
+            // check if any of the fields to be set in the matched documents
+            // is not an array, or not a number for $mul
+            let table = sp.sanitize();
+            let filter = match where_str {
+                "" => " WHERE".to_string(),
+                where_str => format!("{} AND", where_str),
+            };
+            for field in fields.keys() {
+                let set_field = subscript_field(field);
+                let invalid = match oper {
+                    "$mul" => format!("{FUNCTIONS_SCHEMA}.bson_number({set_field}) IS NULL"),
+                    _ => format!("jsonb_typeof({set_field}) <> 'array'"),
+                };
+                let sql = format!("SELECT _jsonb->'_id' AS _id, {FUNCTIONS_SCHEMA}.bson_type({set_field}) FROM {table}{filter} jsonb_typeof({set_field}) IS NOT NULL AND {invalid}");
+                let row = self.query_one(&sql, &[])?;
+                if let Some(row) = row {
+                    let id: serde_json::Value = row.get(0);
+                    let id = id.from_psql_json()?;
+                    let type_name: String = row.get(1);
+                    let last_part = field.split(".").last().unwrap();
+                    let err = match oper {
+                        "$pull" | "$pullAll" => {
+                            "Cannot apply $pull to a non-array value".to_string()
                         }
-                    }
+                        "$mul" => format!(
+                            "Cannot apply $mul to a value of non-numeric type. {{_id: {}}} has the field '{}' of non-numeric type {}",
+                            id, last_part, type_name
+                        ),
+                        "$pop" => format!(
+                            "Path '{}' contains an element of non-array type '{}'",
+                            field, type_name
+                        ),
+                        "$push" => format!(
+                            "The field '{}' must be an array but is of type {} in document {{_id: {}}}",
+                            field, type_name, id
+                        ),
+                        _ => format!(
+                            "Cannot apply {} to a non-array field. Field named '{}' has a non-array type {} in the document _id: {}",
+                            oper, last_part, type_name, id
+                        ),
+                    };
+                    return Err(eyre! { err });
                 }
-                _ => {}
             }
         }
         Ok(())
@@ -366,6 +545,14 @@ impl PgDb {
         }
     }
 
+    pub fn raw_query_iter(&mut self, query: &str) -> Result<RowIter<'_>> {
+        log::debug!("SQL: {}", query);
+        match self.client.query_raw(query, std::iter::empty::<i32>()) {
+            Ok(rows) => Ok(rows),
+            Err(e) => Err(eyre! {e}),
+        }
+    }
+
     fn get_query(&self, s: &str, sp: SqlParam) -> String {
         let table = sp.sanitize();
         sanitize_string(s.replace("%table%", &table))
@@ -384,25 +571,31 @@ impl PgDb {
         let res = self.raw_query(&query, &[&json])?;
         let row = res.get(0).unwrap();
         let json: serde_json::Value = row.get(0);
-        let doc = json.from_psql_json();
+        let doc = json.from_psql_json()?;
 
         Ok(doc.as_document().unwrap().clone())
     }
 
-    pub fn insert_docs(&mut self, sp: SqlParam, docs: &mut Vec<Document>) -> Result<u64> {
-        let query = self.get_query("INSERT INTO %table% VALUES ($1)", sp);
-
-        let mut affected = 0;
-        for doc in docs {
-            if !doc.contains_key("_id") {
-                doc.insert("_id", bson::oid::ObjectId::new());
-            }
-            let bson: Bson = Bson::Document(doc.clone()).into();
-            let json = bson.into_psql_json();
-            let n = &self.exec(&query, &[&json]).unwrap();
-            affected += n;
+    /// Inserts all `docs` with a single statement, so either all or none of
+    /// them are written.
+    pub fn insert_docs(&mut self, sp: &SqlParam, docs: &[Document]) -> Result<u64> {
+        if docs.is_empty() {
+            return Ok(0);
         }
-        Ok(affected)
+        let values = (1..=docs.len())
+            .map(|i| format!("(${})", i))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let sql = format!("INSERT INTO {} VALUES {}", sp, values);
+        let json: Vec<serde_json::Value> = docs
+            .iter()
+            .map(|doc| Bson::Document(doc.clone()).into_psql_json())
+            .collect();
+        let params: Vec<&(dyn ToSql + Sync)> = json
+            .iter()
+            .map(|value| value as &(dyn ToSql + Sync))
+            .collect();
+        self.exec(&sql, &params)
     }
 
     pub fn table_exists(&mut self, db: &str, collection: &str) -> Result<bool> {
@@ -436,7 +629,9 @@ impl PgDb {
             .unwrap();
         schemas
             .into_iter()
-            .filter(|s| !s.starts_with("pg_") && !(s == "information_schema"))
+            .filter(|s| {
+                !s.starts_with("pg_") && !(s == "information_schema") && !is_internal_schema(s)
+            })
             .collect()
     }
 
@@ -521,9 +716,15 @@ impl PgDb {
     }
 
     pub fn create_table(&mut self, sp: SqlParam) -> Result<u64, CreateTableError> {
-        let query = format!("CREATE TABLE {} (_jsonb jsonb)", sp.clone());
-        match self.client.execute(&query, &[]) {
-            Ok(u64) => Ok(u64),
+        let query = format!(
+            "CREATE TABLE {} (_jsonb jsonb); {}; {}; {}",
+            sp,
+            create_id_index_sql(&sp),
+            create_id_number_index_sql(&sp),
+            create_changes_trigger_sql(&sp)
+        );
+        match self.client.batch_execute(&query) {
+            Ok(()) => Ok(0),
             Err(err) => {
                 if let Some(sql_state) = err.code() {
                     if sql_state == &SqlState::DUPLICATE_TABLE
@@ -540,19 +741,32 @@ impl PgDb {
     }
 
     pub fn create_table_if_not_exists(&mut self, schema: &str, table: &str) -> Result<u64> {
-        let name = SqlParam::new(schema, table).sanitize();
-        let sql = format!("CREATE TABLE IF NOT EXISTS {} (_jsonb jsonb)", name);
+        let sp = SqlParam::new(schema, table);
+        let sql = format!(
+            "CREATE TABLE IF NOT EXISTS {} (_jsonb jsonb); {}; {}; {}",
+            sp,
+            create_id_index_sql(&sp),
+            create_id_number_index_sql(&sp),
+            create_changes_trigger_sql(&sp)
+        );
 
         self.create_schema_if_not_exists(schema).unwrap();
 
         let mut attempt = 0;
         loop {
-            match self.client.execute(&sql, &[]) {
-                Ok(u64) => return Ok(u64),
+            // creating the index locks the table, so it's only attempted
+            // when the table is missing
+            if self.table_exists(schema, table)? {
+                return Ok(0);
+            }
+            match self.client.batch_execute(&sql) {
+                Ok(()) => return Ok(0),
                 Err(err) => {
                     if let Some(sql_state) = err.code() {
                         log::info!("Error {:?} - attempt {}", sql_state, attempt);
+                        // the indexes of a table created meanwhile already exist
                         if sql_state != &SqlState::DUPLICATE_DATABASE
+                            && sql_state != &SqlState::DUPLICATE_TABLE
                             && sql_state != &SqlState::UNIQUE_VIOLATION
                         {
                             return Err(eyre! {err});
@@ -567,6 +781,634 @@ impl PgDb {
         }
     }
 
+    /// Adds the `_id` indexes to collections created before every collection
+    /// had them. They are found by their definition, whatever their name.
+    /// Collections that already hold duplicate ids are left without them and
+    /// logged as errors.
+    pub fn create_missing_id_indexes(&mut self) -> Result<()> {
+        let rows = self.raw_query(
+            "
+            SELECT * FROM (
+                SELECT t.schemaname, t.tablename,
+                    EXISTS (
+                        SELECT 1 FROM pg_index x WHERE x.indrelid = c.oid
+                        AND x.indisunique AND x.indnatts = 1 AND x.indpred IS NULL
+                        AND pg_get_indexdef(x.indexrelid, 1, false) = '((_jsonb -> ''_id''::text))'
+                    ) AS has_id,
+                    EXISTS (
+                        SELECT 1 FROM pg_index x WHERE x.indrelid = c.oid
+                        AND x.indisunique AND x.indnatts = 1
+                        AND pg_get_indexdef(x.indexrelid, 1, false) = $4 || '.bson_number((_jsonb -> ''_id''::text))'
+                    ) AS has_id_number
+                FROM pg_tables t
+                JOIN pg_class c ON c.oid = format('%I.%I', t.schemaname, t.tablename)::regclass
+                WHERE t.schemaname NOT IN ('pg_catalog', 'information_schema', $1, $2, $3, $4)
+                AND t.schemaname NOT LIKE 'pg\\_temp\\_%'
+            ) AS t WHERE NOT has_id OR NOT has_id_number
+            ",
+            &[
+                &AUTH_SCHEMA,
+                &SESSIONS_SCHEMA,
+                &CHANGES_SCHEMA,
+                &FUNCTIONS_SCHEMA,
+            ],
+        )?;
+        for row in rows {
+            let sp = SqlParam::new(row.get::<_, &str>(0), row.get::<_, &str>(1));
+            let mut missing = vec![];
+            if !row.get::<_, bool>(2) {
+                missing.push(create_id_index_sql(&sp));
+            }
+            if !row.get::<_, bool>(3) {
+                missing.push(create_id_number_index_sql(&sp));
+            }
+            log::info!("Creating the _id indexes of {}...", sp);
+            for sql in missing {
+                if let Err(e) = self.batch_exec(&sql) {
+                    log::error!(
+                        "Could not create the _id index of {}, its ids won't be kept unique: {}",
+                        sp,
+                        e
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates the functions filters use to compare values the way MongoDB
+    /// does: `bson_type` gives the type alias of a value, `bson_type_order`
+    /// the rank of its type in the BSON sort order, `bson_number` the value of
+    /// any kind of number and `bson_compare` orders two values, while
+    /// `bson_path_values` gives the values a dotted path leads to through
+    /// arrays, and null where it's missing. `bson_truthy` and
+    /// `bson_from_number` back aggregation expressions. `bson_set_path` sets a
+    /// dotted path, creating the documents on the way, for the update
+    /// operators, with `bson_mul`, `bson_push`, `bson_add_to_set` and
+    /// `bson_pull_all` computing their new values. Ints and longs are
+    /// both stored as plain numbers, so a long is only told apart from an int
+    /// when it doesn't fit in 32 bits.
+    pub fn create_functions(&mut self) -> Result<()> {
+        self.create_schema_if_not_exists(FUNCTIONS_SCHEMA)?;
+        self.batch_exec(&format!(
+            r#"
+            CREATE OR REPLACE FUNCTION "{schema}".bson_type(v jsonb) RETURNS text AS $$
+                SELECT CASE jsonb_typeof(v)
+                    WHEN 'null' THEN 'null'
+                    WHEN 'string' THEN 'string'
+                    WHEN 'boolean' THEN 'bool'
+                    WHEN 'array' THEN 'array'
+                    WHEN 'number' THEN CASE
+                        WHEN v::numeric <> trunc(v::numeric) THEN 'double'
+                        WHEN v::numeric BETWEEN -2147483648 AND 2147483647 THEN 'int'
+                        ELSE 'long'
+                    END
+                    ELSE CASE
+                        WHEN v ? '$f' THEN 'double'
+                        WHEN v ? '$numberDecimal' THEN 'decimal'
+                        WHEN v ? '$o' THEN 'objectId'
+                        WHEN v ? '$d' THEN 'date'
+                        WHEN v ? '$timestamp' THEN 'timestamp'
+                        WHEN v ? '$regex' AND v ? '$options' THEN 'regex'
+                        WHEN v ? '$j' AND v ? 's' THEN 'javascriptWithScope'
+                        WHEN v ? '$j' THEN 'javascript'
+                        WHEN v ? '$binary' THEN 'binData'
+                        WHEN v ? '$symbol' THEN 'symbol'
+                        WHEN v ? '$dbPointer' THEN 'dbPointer'
+                        WHEN v ? '$undefined' THEN 'undefined'
+                        WHEN v ? '$minKey' THEN 'minKey'
+                        WHEN v ? '$maxKey' THEN 'maxKey'
+                        ELSE 'object'
+                    END
+                END
+            $$ LANGUAGE sql IMMUTABLE STRICT PARALLEL SAFE;
+
+            CREATE OR REPLACE FUNCTION "{schema}".bson_type_order(v jsonb) RETURNS int AS $$
+                SELECT CASE "{schema}".bson_type(v)
+                    WHEN 'minKey' THEN 1
+                    WHEN 'null' THEN 2
+                    WHEN 'undefined' THEN 2
+                    WHEN 'double' THEN 3
+                    WHEN 'int' THEN 3
+                    WHEN 'long' THEN 3
+                    WHEN 'decimal' THEN 3
+                    WHEN 'string' THEN 4
+                    WHEN 'symbol' THEN 4
+                    WHEN 'object' THEN 5
+                    WHEN 'array' THEN 6
+                    WHEN 'binData' THEN 7
+                    WHEN 'objectId' THEN 8
+                    WHEN 'bool' THEN 9
+                    WHEN 'date' THEN 10
+                    WHEN 'timestamp' THEN 11
+                    WHEN 'regex' THEN 12
+                    WHEN 'dbPointer' THEN 13
+                    WHEN 'javascript' THEN 14
+                    WHEN 'javascriptWithScope' THEN 15
+                    WHEN 'maxKey' THEN 16
+                END
+            $$ LANGUAGE sql IMMUTABLE STRICT PARALLEL SAFE;
+
+            CREATE OR REPLACE FUNCTION "{schema}".bson_number(v jsonb) RETURNS numeric AS $$
+                SELECT CASE
+                    WHEN jsonb_typeof(v) = 'number' THEN v::numeric
+                    WHEN jsonb_typeof(v->'$f') = 'number' THEN (v->>'$f')::numeric
+                    WHEN jsonb_typeof(v->'$numberDecimal') = 'string' THEN (v->>'$numberDecimal')::numeric
+                END
+            $$ LANGUAGE sql IMMUTABLE STRICT PARALLEL SAFE;
+
+            CREATE OR REPLACE FUNCTION "{schema}".bson_compare(a jsonb, b jsonb) RETURNS int AS $$
+            DECLARE
+                a_order int := "{schema}".bson_type_order(a);
+                b_order int := "{schema}".bson_type_order(b);
+                a_num numeric;
+                b_num numeric;
+                a_text text;
+                b_text text;
+                a_keys text[];
+                b_keys text[];
+                result int;
+            BEGIN
+                IF a_order <> b_order THEN
+                    RETURN sign(a_order - b_order);
+                END IF;
+                CASE a_order
+                WHEN 3 THEN
+                    a_num := "{schema}".bson_number(a);
+                    b_num := "{schema}".bson_number(b);
+                WHEN 10 THEN
+                    a_num := (a->>'$d')::numeric;
+                    b_num := (b->>'$d')::numeric;
+                WHEN 11 THEN
+                    a_num := (a->'$timestamp'->>'t')::numeric * 4294967296 + (a->'$timestamp'->>'i')::numeric;
+                    b_num := (b->'$timestamp'->>'t')::numeric * 4294967296 + (b->'$timestamp'->>'i')::numeric;
+                WHEN 4 THEN
+                    a_text := COALESCE(a->>'$symbol', a #>> '{{}}');
+                    b_text := COALESCE(b->>'$symbol', b #>> '{{}}');
+                WHEN 8 THEN
+                    a_text := lower(a->>'$o');
+                    b_text := lower(b->>'$o');
+                WHEN 5 THEN
+                    -- field by field, by type, name and then value
+                    a_keys := ARRAY(SELECT jsonb_object_keys(a));
+                    b_keys := ARRAY(SELECT jsonb_object_keys(b));
+                    FOR i IN 1..least(cardinality(a_keys), cardinality(b_keys)) LOOP
+                        result := sign("{schema}".bson_type_order(a->a_keys[i]) - "{schema}".bson_type_order(b->b_keys[i]));
+                        IF result = 0 AND a_keys[i] <> b_keys[i] THEN
+                            result := CASE WHEN a_keys[i] COLLATE "C" < b_keys[i] COLLATE "C" THEN -1 ELSE 1 END;
+                        END IF;
+                        IF result = 0 THEN
+                            result := "{schema}".bson_compare(a->a_keys[i], b->b_keys[i]);
+                        END IF;
+                        IF result <> 0 THEN
+                            RETURN result;
+                        END IF;
+                    END LOOP;
+                    RETURN sign(cardinality(a_keys) - cardinality(b_keys));
+                WHEN 6 THEN
+                    FOR i IN 0..least(jsonb_array_length(a), jsonb_array_length(b)) - 1 LOOP
+                        result := "{schema}".bson_compare(a->i, b->i);
+                        IF result <> 0 THEN
+                            RETURN result;
+                        END IF;
+                    END LOOP;
+                    RETURN sign(jsonb_array_length(a) - jsonb_array_length(b));
+                ELSE
+                    RETURN CASE WHEN a < b THEN -1 WHEN a = b THEN 0 ELSE 1 END;
+                END CASE;
+
+                IF a_num IS NOT NULL OR b_num IS NOT NULL THEN
+                    RETURN CASE WHEN a_num < b_num THEN -1 WHEN a_num = b_num THEN 0 ELSE 1 END;
+                END IF;
+                RETURN CASE
+                    WHEN a_text COLLATE "C" < b_text COLLATE "C" THEN -1
+                    WHEN a_text = b_text THEN 0
+                    ELSE 1
+                END;
+            END
+            $$ LANGUAGE plpgsql IMMUTABLE STRICT PARALLEL SAFE;
+
+            CREATE OR REPLACE FUNCTION "{schema}".bson_path_values(v jsonb, path text[]) RETURNS SETOF jsonb AS $$
+            DECLARE
+                elem jsonb;
+            BEGIN
+                IF cardinality(path) = 0 THEN
+                    RETURN NEXT v;
+                    IF jsonb_typeof(v) = 'array' THEN
+                        RETURN QUERY SELECT jsonb_array_elements(v);
+                    END IF;
+                ELSIF jsonb_typeof(v) = 'array' THEN
+                    -- a position picks an element, and any other field is
+                    -- looked up in the documents of the array
+                    IF path[1] ~ '^[0-9]+$' AND path[1]::numeric < jsonb_array_length(v) THEN
+                        RETURN QUERY SELECT * FROM "{schema}".bson_path_values(v->(path[1]::int), path[2:]);
+                    END IF;
+                    FOR elem IN SELECT jsonb_array_elements(v) LOOP
+                        IF jsonb_typeof(elem) = 'object' AND (path[1] !~ '^[0-9]+$' OR elem ? path[1]) THEN
+                            RETURN QUERY SELECT * FROM "{schema}".bson_path_values(elem, path);
+                        END IF;
+                    END LOOP;
+                ELSIF jsonb_typeof(v) = 'object' AND v ? path[1] THEN
+                    RETURN QUERY SELECT * FROM "{schema}".bson_path_values(v->path[1], path[2:]);
+                ELSE
+                    -- a missing field is matched as null
+                    RETURN NEXT NULL;
+                END IF;
+            END
+            $$ LANGUAGE plpgsql IMMUTABLE STRICT PARALLEL SAFE;
+
+            CREATE OR REPLACE FUNCTION "{schema}".bson_truthy(v jsonb) RETURNS boolean AS $$
+                SELECT v IS NOT NULL AND v <> 'null' AND v <> 'false'
+                    AND "{schema}".bson_type(v) <> 'undefined'
+                    AND COALESCE("{schema}".bson_number(v) <> 0, TRUE)
+            $$ LANGUAGE sql IMMUTABLE PARALLEL SAFE;
+
+            CREATE OR REPLACE FUNCTION "{schema}".bson_from_number(n numeric, as_double boolean) RETURNS jsonb AS $$
+                SELECT CASE WHEN as_double THEN jsonb_build_object('$f', n::float8) ELSE to_jsonb(n) END
+            $$ LANGUAGE sql IMMUTABLE STRICT PARALLEL SAFE;
+
+            CREATE OR REPLACE FUNCTION "{schema}".bson_set_path(doc jsonb, path text[], value jsonb) RETURNS jsonb AS $$
+            DECLARE
+                child jsonb;
+            BEGIN
+                IF value IS NULL THEN
+                    RETURN doc;
+                END IF;
+                IF cardinality(path) = 1 THEN
+                    RETURN jsonb_set(doc, path, value);
+                END IF;
+                child := doc #> path[1:1];
+                IF jsonb_typeof(child) IS DISTINCT FROM 'object' AND jsonb_typeof(child) IS DISTINCT FROM 'array' THEN
+                    child := '{{}}';
+                END IF;
+                RETURN jsonb_set(doc, path[1:1], "{schema}".bson_set_path(child, path[2:], value));
+            END
+            $$ LANGUAGE plpgsql IMMUTABLE PARALLEL SAFE;
+
+            CREATE OR REPLACE FUNCTION "{schema}".bson_mul(v jsonb, factor jsonb) RETURNS jsonb AS $$
+                SELECT CASE
+                    WHEN "{schema}".bson_type(v) = 'decimal' OR "{schema}".bson_type(factor) = 'decimal' THEN
+                        jsonb_build_object('$numberDecimal', (COALESCE("{schema}".bson_number(v), 0) * "{schema}".bson_number(factor))::text)
+                    ELSE "{schema}".bson_from_number(
+                        COALESCE("{schema}".bson_number(v), 0) * "{schema}".bson_number(factor),
+                        COALESCE("{schema}".bson_type(v) = 'double', FALSE) OR "{schema}".bson_type(factor) = 'double'
+                    )
+                END
+            $$ LANGUAGE sql IMMUTABLE PARALLEL SAFE;
+
+            CREATE OR REPLACE FUNCTION "{schema}".bson_sort_compare(a jsonb, b jsonb, sort jsonb) RETURNS int AS $$
+            DECLARE
+                key text;
+                direction int;
+                result int;
+            BEGIN
+                IF jsonb_typeof(sort) = 'number' THEN
+                    RETURN "{schema}".bson_compare(a, b) * (sort #>> '{{}}')::int;
+                END IF;
+                FOR key, direction IN SELECT k, v::int FROM jsonb_each_text(sort) AS s(k, v) LOOP
+                    result := "{schema}".bson_compare(
+                        COALESCE(a #> string_to_array(key, '.'), 'null'),
+                        COALESCE(b #> string_to_array(key, '.'), 'null')
+                    ) * direction;
+                    IF result <> 0 THEN
+                        RETURN result;
+                    END IF;
+                END LOOP;
+                RETURN 0;
+            END
+            $$ LANGUAGE plpgsql IMMUTABLE STRICT PARALLEL SAFE;
+
+            CREATE OR REPLACE FUNCTION "{schema}".bson_push(arr jsonb, items jsonb, pos int, slice int, sort jsonb) RETURNS jsonb AS $$
+            DECLARE
+                len int := jsonb_array_length(arr);
+                result jsonb;
+                sorted jsonb;
+                elem jsonb;
+                i int;
+            BEGIN
+                IF pos IS NULL OR pos >= len THEN
+                    result := arr || items;
+                ELSE
+                    IF pos < 0 THEN
+                        pos := greatest(len + pos, 0);
+                    END IF;
+                    result := COALESCE((SELECT jsonb_agg(e ORDER BY n) FROM jsonb_array_elements(arr) WITH ORDINALITY AS t(e, n) WHERE n <= pos), '[]')
+                        || items
+                        || COALESCE((SELECT jsonb_agg(e ORDER BY n) FROM jsonb_array_elements(arr) WITH ORDINALITY AS t(e, n) WHERE n > pos), '[]');
+                END IF;
+                IF sort IS NOT NULL THEN
+                    -- an insertion sort keeps equal elements in order
+                    sorted := '[]';
+                    FOR elem IN SELECT jsonb_array_elements(result) LOOP
+                        i := 0;
+                        WHILE i < jsonb_array_length(sorted) AND "{schema}".bson_sort_compare(sorted->i, elem, sort) <= 0 LOOP
+                            i := i + 1;
+                        END LOOP;
+                        sorted := jsonb_insert(sorted, ARRAY[i::text], elem);
+                    END LOOP;
+                    result := sorted;
+                END IF;
+                IF slice IS NOT NULL THEN
+                    len := jsonb_array_length(result);
+                    result := COALESCE((
+                        SELECT jsonb_agg(e ORDER BY n) FROM jsonb_array_elements(result) WITH ORDINALITY AS t(e, n)
+                        WHERE CASE WHEN slice >= 0 THEN n <= slice ELSE n > len + slice END
+                    ), '[]');
+                END IF;
+                RETURN result;
+            END
+            $$ LANGUAGE plpgsql IMMUTABLE PARALLEL SAFE;
+
+            CREATE OR REPLACE FUNCTION "{schema}".bson_pull_all(arr jsonb, items jsonb) RETURNS jsonb AS $$
+                SELECT COALESCE(jsonb_agg(e ORDER BY i), '[]')
+                FROM jsonb_array_elements(arr) WITH ORDINALITY AS t(e, i)
+                WHERE NOT EXISTS (
+                    SELECT 1 FROM jsonb_array_elements(items) AS v(item)
+                    WHERE "{schema}".bson_compare(e, item) = 0
+                )
+            $$ LANGUAGE sql IMMUTABLE STRICT PARALLEL SAFE;
+
+            CREATE OR REPLACE FUNCTION "{schema}".bson_add_to_set(arr jsonb, items jsonb) RETURNS jsonb AS $$
+            DECLARE
+                result jsonb := arr;
+                item jsonb;
+            BEGIN
+                FOR item IN SELECT jsonb_array_elements(items) LOOP
+                    IF NOT EXISTS (SELECT 1 FROM jsonb_array_elements(result) AS t(e) WHERE "{schema}".bson_compare(e, item) = 0) THEN
+                        result := result || jsonb_build_array(item);
+                    END IF;
+                END LOOP;
+                RETURN result;
+            END
+            $$ LANGUAGE plpgsql IMMUTABLE STRICT PARALLEL SAFE;
+            "#,
+            schema = FUNCTIONS_SCHEMA,
+        ))
+    }
+
+    /// Creates the log of changes and the function that collection triggers
+    /// call to fill it. Old changes are dropped by `expire_changes`.
+    pub fn create_changes_table(&mut self) -> Result<()> {
+        self.create_schema_if_not_exists(CHANGES_SCHEMA)?;
+        let table = SqlParam::new(CHANGES_SCHEMA, CHANGES_TABLE);
+        let pre_images = SqlParam::new(CHANGES_SCHEMA, PRE_IMAGES_TABLE);
+        // changes are ordered by the transaction that made them, so they can
+        // be read once every transaction before it has finished
+        self.batch_exec(&format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS {table} (
+                id bigserial PRIMARY KEY,
+                xid bigint NOT NULL DEFAULT pg_current_xact_id()::text::bigint,
+                db text NOT NULL,
+                coll text NOT NULL,
+                operation text NOT NULL,
+                document_key jsonb,
+                full_document jsonb,
+                full_document_before jsonb,
+                cluster_time timestamptz NOT NULL DEFAULT now()
+            );
+            ALTER TABLE {table} ADD COLUMN IF NOT EXISTS update_description jsonb;
+            CREATE INDEX IF NOT EXISTS events_xid_id ON {table} (xid, id);
+            CREATE INDEX IF NOT EXISTS events_cluster_time ON {table} (cluster_time);
+
+            CREATE TABLE IF NOT EXISTS {pre_images} (
+                db text NOT NULL,
+                coll text NOT NULL,
+                PRIMARY KEY (db, coll)
+            );
+
+            CREATE OR REPLACE FUNCTION "{schema}".record_change() RETURNS trigger AS $$
+            DECLARE
+                op text;
+                description jsonb;
+                before jsonb;
+            BEGIN
+                op := CASE
+                    WHEN TG_OP = 'INSERT' THEN 'insert'
+                    WHEN TG_OP = 'DELETE' THEN 'delete'
+                    WHEN current_setting('oxide.operation', true) = 'replace' THEN 'replace'
+                    ELSE 'update'
+                END;
+                IF op = 'update' THEN
+                    description := jsonb_build_object(
+                        'updatedFields', COALESCE((
+                            SELECT jsonb_object_agg(n.key, n.value) FROM jsonb_each(NEW._jsonb) n
+                            WHERE NOT OLD._jsonb ? n.key OR OLD._jsonb->n.key <> n.value
+                        ), '{{}}'::jsonb),
+                        'removedFields', COALESCE((
+                            SELECT jsonb_agg(o.key) FROM jsonb_each(OLD._jsonb) o
+                            WHERE NOT NEW._jsonb ? o.key
+                        ), '[]'::jsonb),
+                        'truncatedArrays', '[]'::jsonb
+                    );
+                END IF;
+                IF op <> 'insert' AND EXISTS (
+                    SELECT 1 FROM {pre_images} p
+                    WHERE p.db IN (TG_TABLE_SCHEMA, '') AND p.coll IN (TG_TABLE_NAME, '')
+                ) THEN
+                    before := OLD._jsonb;
+                END IF;
+                INSERT INTO {table} (db, coll, operation, document_key, full_document, full_document_before, update_description)
+                VALUES (
+                    TG_TABLE_SCHEMA,
+                    TG_TABLE_NAME,
+                    op,
+                    COALESCE(NEW._jsonb, OLD._jsonb)->'_id',
+                    NEW._jsonb,
+                    before,
+                    description
+                );
+                PERFORM pg_notify('{channel}', '');
+                RETURN NULL;
+            END
+            $$ LANGUAGE plpgsql;
+
+            CREATE OR REPLACE FUNCTION "{schema}".current_document(db text, coll text, key jsonb)
+            RETURNS jsonb AS $$
+            DECLARE
+                doc jsonb;
+            BEGIN
+                EXECUTE format('SELECT _jsonb FROM %I.%I WHERE _jsonb->''_id'' = $1', db, coll)
+                INTO doc USING key;
+                RETURN doc;
+            EXCEPTION WHEN undefined_table THEN
+                RETURN NULL;
+            END
+            $$ LANGUAGE plpgsql;
+            "#,
+            table = table,
+            pre_images = pre_images,
+            schema = CHANGES_SCHEMA,
+            channel = CHANGES_CHANNEL,
+        ))
+    }
+
+    /// Drops the changes older than the retention period, returning how many
+    /// were dropped.
+    pub fn expire_changes(&mut self) -> Result<u64> {
+        self.exec(
+            &format!(
+                "DELETE FROM {} WHERE cluster_time < now() - make_interval(hours => $1)",
+                SqlParam::new(CHANGES_SCHEMA, CHANGES_TABLE)
+            ),
+            &[&CHANGES_RETENTION_HOURS],
+        )
+    }
+
+    /// Makes the changes of a database and collection keep the document
+    /// before the change from now on. An empty `db` or `collection` stands
+    /// for all of them.
+    pub fn keep_pre_images(&mut self, db: &str, collection: &str) -> Result<()> {
+        self.exec(
+            &format!(
+                "INSERT INTO {} (db, coll) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+                SqlParam::new(CHANGES_SCHEMA, PRE_IMAGES_TABLE)
+            ),
+            &[&db, &collection],
+        )?;
+        Ok(())
+    }
+
+    /// The `server_version_num` of the PostgreSQL server, such as 140005.
+    pub fn server_version(&mut self) -> Result<i32> {
+        let row = self
+            .client
+            .query_one("SELECT current_setting('server_version_num')::int", &[])?;
+        Ok(row.get(0))
+    }
+
+    /// Adds the trigger that logs changes to collections created before
+    /// change streams were supported.
+    pub fn create_missing_changes_triggers(&mut self) -> Result<()> {
+        let rows = self.raw_query(
+            "
+            SELECT t.schemaname, t.tablename FROM pg_tables t
+            WHERE t.schemaname NOT IN ('pg_catalog', 'information_schema', $1, $2, $3, $4)
+            AND t.schemaname NOT LIKE 'pg\\_temp\\_%'
+            AND NOT EXISTS (
+                SELECT 1 FROM pg_trigger g
+                WHERE g.tgrelid = format('%I.%I', t.schemaname, t.tablename)::regclass
+                AND g.tgname = $5
+            )
+            ",
+            &[
+                &AUTH_SCHEMA,
+                &SESSIONS_SCHEMA,
+                &CHANGES_SCHEMA,
+                &FUNCTIONS_SCHEMA,
+                &CHANGES_TRIGGER,
+            ],
+        )?;
+        for row in rows {
+            let sp = SqlParam::new(row.get::<_, &str>(0), row.get::<_, &str>(1));
+            log::info!("Creating the changes trigger of {}...", sp);
+            if let Err(e) = self.batch_exec(&create_changes_trigger_sql(&sp)) {
+                log::warn!("Could not create the changes trigger of {}: {}", sp, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Id of the oldest transaction still running. Changes made by older
+    /// transactions are final.
+    pub fn oldest_running_transaction(&mut self) -> Result<i64> {
+        let row = self.client.query_one(
+            "SELECT pg_snapshot_xmin(pg_current_snapshot())::text::bigint",
+            &[],
+        )?;
+        Ok(row.get(0))
+    }
+
+    pub fn listen(&mut self, channel: &str) -> Result<()> {
+        self.batch_exec(&format!(r#"LISTEN "{}""#, channel))
+    }
+
+    pub fn unlisten(&mut self, channel: &str) -> Result<()> {
+        self.batch_exec(&format!(r#"UNLISTEN "{}""#, channel))
+    }
+
+    /// Waits up to `timeout` for a notification on the channels this
+    /// connection listens to, returning whether one arrived.
+    pub fn wait_for_notification(&mut self, timeout: std::time::Duration) -> Result<bool> {
+        let mut notifications = self.client.notifications();
+        let notification = notifications.timeout_iter(timeout).next()?;
+        // several changes in a row only need one wake up
+        while notifications.iter().next()?.is_some() {}
+        Ok(notification.is_some())
+    }
+
+    // the reply to the latest retryable write of each session is kept so
+    // a retry of it can be answered without running it again, like mongod's
+    // config.transactions
+
+    /// Creates the table of retryable writes, dropping the ones of sessions
+    /// idle for longer than `timeout_minutes`.
+    pub fn create_retryable_writes_table(&mut self, timeout_minutes: i32) -> Result<()> {
+        self.create_schema_if_not_exists(SESSIONS_SCHEMA)?;
+        let table = SqlParam::new(SESSIONS_SCHEMA, TRANSACTIONS_TABLE);
+        self.batch_exec(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                session_id bytea PRIMARY KEY,
+                txn_number bigint NOT NULL,
+                reply bytea NOT NULL,
+                last_used timestamptz NOT NULL DEFAULT now()
+            )",
+            table
+        ))?;
+        self.exec(
+            &format!(
+                "DELETE FROM {} WHERE last_used < now() - make_interval(mins => $1)",
+                table
+            ),
+            &[&timeout_minutes],
+        )?;
+        Ok(())
+    }
+
+    /// Copies the results of `sql` to a new `table`, numbered in order in `n`,
+    /// for a cursor to read them later from any connection.
+    pub fn materialize(&mut self, table: &str, sql: &str) -> Result<()> {
+        self.batch_exec(&format!(
+            "CREATE UNLOGGED TABLE {table} (n bigserial PRIMARY KEY, _jsonb jsonb);
+            INSERT INTO {table} (_jsonb) SELECT * FROM ({sql}) AS s_cursor",
+        ))
+    }
+
+    /// The number and reply of the latest retryable write of a session.
+    pub fn get_retryable_write(&mut self, session_id: &[u8]) -> Result<Option<(i64, Document)>> {
+        let sql = format!(
+            "SELECT txn_number, reply FROM {} WHERE session_id = $1",
+            SqlParam::new(SESSIONS_SCHEMA, TRANSACTIONS_TABLE)
+        );
+        match self.query_one(&sql, &[&session_id])? {
+            Some(row) => {
+                let reply: Vec<u8> = row.get(1);
+                Ok(Some((row.get(0), Document::from_reader(&mut &reply[..])?)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Records the reply of a retryable write, returning false if the
+    /// session already has a write with the same or a newer number.
+    pub fn save_retryable_write(
+        &mut self,
+        session_id: &[u8],
+        txn_number: i64,
+        reply: &Document,
+    ) -> Result<bool> {
+        let table = SqlParam::new(SESSIONS_SCHEMA, TRANSACTIONS_TABLE);
+        let sql = format!(
+            "INSERT INTO {table} (session_id, txn_number, reply) VALUES ($1, $2, $3)
+            ON CONFLICT (session_id) DO UPDATE
+            SET txn_number = excluded.txn_number, reply = excluded.reply, last_used = now()
+            WHERE {table}.txn_number < excluded.txn_number",
+            table = table
+        );
+        let reply = bson::to_vec(reply)?;
+        Ok(self.exec(&sql, &[&session_id, &txn_number, &reply])? == 1)
+    }
+
     pub fn create_index(&mut self, sp: &SqlParam, index: &Document) -> Result<u64> {
         let fields: Vec<String> = index
             .get_document("key")
@@ -616,6 +1458,136 @@ impl PgDb {
         self.exec(&sql, &[])
     }
 
+    pub fn get_user(&mut self, db: &str, user: &str) -> Result<Option<Document>> {
+        self.get_auth_doc(USERS_TABLE, db, user)
+    }
+
+    /// Returns the users defined on `db`, or on every database if it's `None`.
+    pub fn get_users(&mut self, db: Option<&str>) -> Result<Vec<Document>> {
+        self.get_auth_docs(USERS_TABLE, db)
+    }
+
+    pub fn has_users(&mut self) -> Result<bool> {
+        Ok(!self.get_users(None)?.is_empty())
+    }
+
+    /// Adds a user document, returning false if the user already exists.
+    pub fn insert_user(&mut self, user: &Document) -> Result<bool> {
+        self.insert_auth_doc(USERS_TABLE, user)
+    }
+
+    pub fn replace_user(&mut self, user: &Document) -> Result<u64> {
+        self.replace_auth_doc(USERS_TABLE, user)
+    }
+
+    pub fn delete_user(&mut self, db: &str, user: &str) -> Result<u64> {
+        self.delete_auth_doc(USERS_TABLE, db, user)
+    }
+
+    pub fn get_role(&mut self, db: &str, role: &str) -> Result<Option<Document>> {
+        self.get_auth_doc(ROLES_TABLE, db, role)
+    }
+
+    /// Adds a role document, returning false if the role already exists.
+    pub fn insert_role(&mut self, role: &Document) -> Result<bool> {
+        self.insert_auth_doc(ROLES_TABLE, role)
+    }
+
+    /// Returns the roles defined on `db`, or on every database if it's `None`.
+    pub fn get_roles(&mut self, db: Option<&str>) -> Result<Vec<Document>> {
+        self.get_auth_docs(ROLES_TABLE, db)
+    }
+
+    pub fn replace_role(&mut self, role: &Document) -> Result<u64> {
+        self.replace_auth_doc(ROLES_TABLE, role)
+    }
+
+    pub fn delete_role(&mut self, db: &str, role: &str) -> Result<u64> {
+        self.delete_auth_doc(ROLES_TABLE, db, role)
+    }
+
+    // users and roles are kept on tables of the auth schema, identified by
+    // an `_id` of "<db>.<name>" like mongod's admin.system.users and roles
+
+    fn get_auth_doc(&mut self, table: &str, db: &str, name: &str) -> Result<Option<Document>> {
+        if !self.table_exists(AUTH_SCHEMA, table)? {
+            return Ok(None);
+        }
+        let sql = format!(
+            "SELECT _jsonb FROM {} WHERE _jsonb->>'_id' = $1",
+            SqlParam::new(AUTH_SCHEMA, table).sanitize()
+        );
+        let id = format!("{}.{}", db, name);
+        let row = self.query_one(&sql, &[&id])?;
+        row.map(|row| {
+            let json: serde_json::Value = row.get(0);
+            Ok(json.from_psql_json()?.as_document().unwrap().clone())
+        })
+        .transpose()
+    }
+
+    fn get_auth_docs(&mut self, table: &str, db: Option<&str>) -> Result<Vec<Document>> {
+        if !self.table_exists(AUTH_SCHEMA, table)? {
+            return Ok(vec![]);
+        }
+        let table = SqlParam::new(AUTH_SCHEMA, table).sanitize();
+        let rows = match db {
+            Some(db) => self.raw_query(
+                &format!(
+                    "SELECT _jsonb FROM {} WHERE _jsonb->>'db' = $1 ORDER BY _jsonb->>'_id'",
+                    table
+                ),
+                &[&db],
+            )?,
+            None => self.raw_query(
+                &format!("SELECT _jsonb FROM {} ORDER BY _jsonb->>'_id'", table),
+                &[],
+            )?,
+        };
+        rows.iter()
+            .map(|row| {
+                let json: serde_json::Value = row.get(0);
+                Ok(json.from_psql_json()?.as_document().unwrap().clone())
+            })
+            .collect()
+    }
+
+    fn insert_auth_doc(&mut self, table: &str, doc: &Document) -> Result<bool> {
+        self.create_table_if_not_exists(AUTH_SCHEMA, table)?;
+        let sql = format!(
+            "INSERT INTO {} VALUES ($1)",
+            SqlParam::new(AUTH_SCHEMA, table).sanitize()
+        );
+        let json = Bson::Document(doc.clone()).into_psql_json();
+        match self.client.execute(&sql, &[&json]) {
+            Ok(_) => Ok(true),
+            Err(err) if err.code() == Some(&SqlState::UNIQUE_VIOLATION) => Ok(false),
+            Err(err) => Err(eyre! {err}),
+        }
+    }
+
+    fn replace_auth_doc(&mut self, table: &str, doc: &Document) -> Result<u64> {
+        let sql = format!(
+            "UPDATE {} SET _jsonb = $1 WHERE _jsonb->>'_id' = $2",
+            SqlParam::new(AUTH_SCHEMA, table).sanitize()
+        );
+        let id = doc.get_str("_id")?.to_string();
+        let json = Bson::Document(doc.clone()).into_psql_json();
+        self.exec(&sql, &[&json, &id])
+    }
+
+    fn delete_auth_doc(&mut self, table: &str, db: &str, name: &str) -> Result<u64> {
+        if !self.table_exists(AUTH_SCHEMA, table)? {
+            return Ok(0);
+        }
+        let sql = format!(
+            "DELETE FROM {} WHERE _jsonb->>'_id' = $1",
+            SqlParam::new(AUTH_SCHEMA, table).sanitize()
+        );
+        let id = format!("{}.{}", db, name);
+        self.exec(&sql, &[&id])
+    }
+
     pub fn schema_stats(&mut self, schema: &str, collection: Option<&str>) -> Result<Row> {
         let mut sql = format!(
             r#"
@@ -652,6 +1624,18 @@ impl PgDb {
     }
 }
 
+impl Drop for PgDb {
+    fn drop(&mut self) {
+        // the connection goes back to the pool, which can't be left inside a
+        // transaction nobody is going to finish
+        if self.in_transaction {
+            if let Err(e) = self.rollback() {
+                log::error!("Could not roll back an abandoned transaction: {}", e);
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SqlParam {
     pub db: String,
@@ -682,6 +1666,34 @@ impl SqlParam {
     pub fn sanitize(&self) -> String {
         format!(r#""{}"."{}""#, self.db, self.collection)
     }
+
+    /// Postgres name of the collection's `_id` index.
+    pub fn id_index_name(&self) -> String {
+        self.internal_index_name("_id")
+    }
+
+    /// Postgres name of the index on the numeric value of the `_id`, which
+    /// numbers are compared by, so that ints, longs, doubles and decimals
+    /// can't repeat an id either.
+    pub fn id_number_index_name(&self) -> String {
+        self.internal_index_name("_id_number")
+    }
+
+    // Index names have to be unique within the schema and fit in 63 bytes, so
+    // long collection names are cut and a hash of the whole name keeps them
+    // apart.
+    fn internal_index_name(&self, suffix: &str) -> String {
+        let suffix = format!(
+            "_{}_{:08x}",
+            suffix,
+            crc32c::crc32c(self.collection.as_bytes())
+        );
+        let mut prefix = self.collection.clone();
+        while prefix.len() + suffix.len() > 63 {
+            prefix.pop();
+        }
+        format!("{}{}", prefix, suffix)
+    }
 }
 
 impl fmt::Display for SqlParam {
@@ -690,9 +1702,38 @@ impl fmt::Display for SqlParam {
     }
 }
 
-fn update_from_operation(update: &UpdateDoc) -> String {
-    match update {
-        UpdateDoc::Set(set) => set
+// the trigger that logs the changes of a collection for change streams
+fn create_changes_trigger_sql(sp: &SqlParam) -> String {
+    if sp.db == AUTH_SCHEMA {
+        return "".to_string();
+    }
+    format!(
+        r#"CREATE OR REPLACE TRIGGER "{}" AFTER INSERT OR UPDATE OR DELETE ON {} FOR EACH ROW EXECUTE FUNCTION "{}".record_change()"#,
+        CHANGES_TRIGGER, sp, CHANGES_SCHEMA
+    )
+}
+
+fn create_id_index_sql(sp: &SqlParam) -> String {
+    format!(
+        r#"CREATE UNIQUE INDEX "{}" ON {} ((_jsonb->'_id'))"#,
+        sp.id_index_name(),
+        sp
+    )
+}
+
+// filters compare numbers by value, with `bson_number`
+fn create_id_number_index_sql(sp: &SqlParam) -> String {
+    format!(
+        r#"CREATE UNIQUE INDEX "{}" ON {} (({schema}.bson_number(_jsonb->'_id'))) WHERE {schema}.bson_number(_jsonb->'_id') IS NOT NULL"#,
+        sp.id_number_index_name(),
+        sp,
+        schema = FUNCTIONS_SCHEMA
+    )
+}
+
+fn update_from_operation(update: &UpdateDoc) -> Result<String> {
+    let sql = match update {
+        UpdateDoc::Set(set) | UpdateDoc::SetOnInsert(set) => set
             .keys()
             .map(|k| {
                 let field = format!("_jsonb['{}']", sanitize_string(k.clone()));
@@ -726,34 +1767,244 @@ fn update_from_operation(update: &UpdateDoc) -> String {
                 .collect::<Vec<String>>()
                 .join(" || ")
         ),
-        UpdateDoc::AddToSet(add_to_set) => {
-            let mut updates = vec![];
-            for (field, value) in add_to_set.iter() {
-                // comes as { "data.letters": { "name": "a" } }
-                // we need _jsonb['data']['letters'] and {data,letters}
-                let set_field = format!(
-                    "_jsonb{}",
-                    field
-                        .split(".")
-                        .map(|f| format!("['{}']", f))
-                        .collect::<Vec<_>>()
-                        .join("")
+        UpdateDoc::AddToSet(add_to_set) => add_to_set
+            .iter()
+            .map(|(field, value)| {
+                let items = match each_modifiers(value) {
+                    Some(modifiers) => modifiers.get("$each").unwrap().clone(),
+                    None => Bson::Array(vec![value.clone()]),
+                };
+                format!(
+                    "{} = {}.bson_add_to_set({}, {})",
+                    subscript_field(field),
+                    FUNCTIONS_SCHEMA,
+                    current_array(field),
+                    jsonb_literal(items)
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(", "),
+        UpdateDoc::Push(push) => push
+            .iter()
+            .map(|(field, value)| {
+                let modifiers = each_modifiers(value);
+                let items = match modifiers {
+                    Some(modifiers) => modifiers.get("$each").unwrap().clone(),
+                    None => Bson::Array(vec![value.clone()]),
+                };
+                let integer = |key: &str| {
+                    modifiers
+                        .and_then(|m| m.get(key))
+                        .and_then(as_integer)
+                        .map_or("NULL".to_string(), |v| v.to_string())
+                };
+                // the sort is either a direction or a document of them
+                let sort = match modifiers.and_then(|m| m.get("$sort")) {
+                    Some(Bson::Document(spec)) => {
+                        let spec = spec
+                            .iter()
+                            .map(|(k, v)| (k.clone(), as_integer(v).unwrap().into()))
+                            .collect::<serde_json::Map<String, serde_json::Value>>();
+                        format!("'{}'", escape(&serde_json::Value::Object(spec).to_string()))
+                    }
+                    Some(direction) => format!("'{}'", as_integer(direction).unwrap()),
+                    None => "NULL".to_string(),
+                };
+                format!(
+                    "{} = {}.bson_push({}, {}, {}, {}, {})",
+                    subscript_field(field),
+                    FUNCTIONS_SCHEMA,
+                    current_array(field),
+                    jsonb_literal(items),
+                    integer("$position"),
+                    integer("$slice"),
+                    sort
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(", "),
+        // missing fields are left alone, so the new arrays are set without
+        // creating them
+        UpdateDoc::Pull(pull) => {
+            let mut sql = "_jsonb".to_string();
+            for (field, value) in pull {
+                let array = current_array(field);
+                let pulled = match value {
+                    Bson::Document(cond) => {
+                        // a condition on the values, or a query on the
+                        // documents of the array
+                        let is_value_condition = !cond.is_empty()
+                            && cond.keys().all(|k| {
+                                k.starts_with('$')
+                                    && !["$and", "$or", "$nor", "$expr"].contains(&k.as_str())
+                            });
+                        let (element, filter) = if is_value_condition {
+                            (
+                                "jsonb_build_object('v', e)",
+                                super::parser::parse(doc! { "v": cond.clone() })?,
+                            )
+                        } else {
+                            ("e", super::parser::parse(cond.clone())?)
+                        };
+                        let filter = if filter.is_empty() {
+                            "TRUE".to_string()
+                        } else {
+                            filter
+                        };
+                        // each element is aliased as _jsonb so the filter applies to it
+                        format!(
+                            "COALESCE((SELECT jsonb_agg(e ORDER BY i) FROM jsonb_array_elements({array}) WITH ORDINALITY AS t(e, i), \
+                            LATERAL (SELECT {element} AS _jsonb) AS elem WHERE NOT COALESCE({filter}, FALSE)), '[]')"
+                        )
+                    }
+                    value => format!(
+                        "{}.bson_pull_all({}, {})",
+                        FUNCTIONS_SCHEMA,
+                        array,
+                        jsonb_literal(Bson::Array(vec![value.clone()]))
+                    ),
+                };
+                sql = format!(
+                    "jsonb_set({}, {}, {}, false)",
+                    sql,
+                    path_literal(field),
+                    pulled
                 );
-                let path = format!("{{{}}}", field.replace(".", ","));
-                let value = value.clone().into_psql_json().to_string();
-                updates.push(format!(
-                    r#"
-                    {set_field} = CASE
-                        WHEN _jsonb #> '{path}' @> '[{value}]' THEN {set_field}
-                        WHEN jsonb_typeof(_jsonb #> '{path}') = 'array' THEN {set_field} || '{value}'
-                        ELSE '[{value}]'
-                    END
-                    "#
-                ));
             }
-            updates.join(", ")
+            format!("_jsonb = {}", sql)
         }
-    }
+        UpdateDoc::PullAll(pull_all) => {
+            let mut sql = "_jsonb".to_string();
+            for (field, values) in pull_all {
+                let pulled = format!(
+                    "{}.bson_pull_all({}, {})",
+                    FUNCTIONS_SCHEMA,
+                    current_array(field),
+                    jsonb_literal(values.clone())
+                );
+                sql = format!(
+                    "jsonb_set({}, {}, {}, false)",
+                    sql,
+                    path_literal(field),
+                    pulled
+                );
+            }
+            format!("_jsonb = {}", sql)
+        }
+        UpdateDoc::Pop(pop) => {
+            let mut sql = "_jsonb".to_string();
+            for (field, value) in pop {
+                // removes the last element, at -1, or the first one
+                let index = if as_integer(value) == Some(1) { -1 } else { 0 };
+                let popped = format!("{} - {}", current_array(field), index);
+                sql = format!(
+                    "jsonb_set({}, {}, {}, false)",
+                    sql,
+                    path_literal(field),
+                    popped
+                );
+            }
+            format!("_jsonb = {}", sql)
+        }
+        // values are read from the document before the update, as the
+        // fields can't overlap
+        UpdateDoc::Rename(rename) => {
+            let mut sql = "_jsonb".to_string();
+            for (from, to) in rename {
+                sql = format!(
+                    "{}.bson_set_path({} #- {}, {}, _jsonb #> {})",
+                    FUNCTIONS_SCHEMA,
+                    sql,
+                    path_literal(from),
+                    path_literal(to.as_str().unwrap()),
+                    path_literal(from)
+                );
+            }
+            format!("_jsonb = {}", sql)
+        }
+        UpdateDoc::Min(fields) | UpdateDoc::Max(fields) => {
+            let oper = if matches!(update, UpdateDoc::Min(_)) {
+                "<"
+            } else {
+                ">"
+            };
+            let mut sql = "_jsonb".to_string();
+            for (field, value) in fields {
+                let path = path_literal(field);
+                let value = jsonb_literal(value.clone());
+                sql = format!(
+                    "{schema}.bson_set_path({sql}, {path}, CASE WHEN _jsonb #> {path} IS NULL OR {schema}.bson_compare({value}, _jsonb #> {path}) {oper} 0 THEN {value}::jsonb END)",
+                    schema = FUNCTIONS_SCHEMA,
+                );
+            }
+            format!("_jsonb = {}", sql)
+        }
+        UpdateDoc::Mul(mul) => {
+            let mut sql = "_jsonb".to_string();
+            for (field, value) in mul {
+                let path = path_literal(field);
+                sql = format!(
+                    "{schema}.bson_set_path({sql}, {path}, {schema}.bson_mul(_jsonb #> {path}, {value}))",
+                    schema = FUNCTIONS_SCHEMA,
+                    value = jsonb_literal(value.clone()),
+                );
+            }
+            format!("_jsonb = {}", sql)
+        }
+        UpdateDoc::CurrentDate(current_date) => {
+            let mut sql = "_jsonb".to_string();
+            for (field, value) in current_date {
+                let is_timestamp = value
+                    .as_document()
+                    .and_then(|spec| spec.get_str("$type").ok())
+                    == Some("timestamp");
+                let now = if is_timestamp {
+                    "jsonb_build_object('$timestamp', jsonb_build_object('t', extract(epoch FROM now())::bigint, 'i', 1))"
+                } else {
+                    "jsonb_build_object('$d', (extract(epoch FROM now()) * 1000)::bigint)"
+                };
+                sql = format!(
+                    "{}.bson_set_path({}, {}, {})",
+                    FUNCTIONS_SCHEMA,
+                    sql,
+                    path_literal(field),
+                    now
+                );
+            }
+            format!("_jsonb = {}", sql)
+        }
+    };
+    Ok(sql)
+}
+
+// `_jsonb['data']['letters']` for `data.letters`
+fn subscript_field(field: &str) -> String {
+    format!(
+        "_jsonb{}",
+        field
+            .split(".")
+            .map(|f| format!("['{}']", escape(f)))
+            .collect::<Vec<_>>()
+            .join("")
+    )
+}
+
+// `'{data,letters}'` for `data.letters`
+fn path_literal(field: &str) -> String {
+    format!("'{{{}}}'", escape(&field.replace(".", ",")))
+}
+
+// the array at a dotted path, empty when it's missing
+fn current_array(field: &str) -> String {
+    format!("COALESCE(_jsonb #> {}, '[]')", path_literal(field))
+}
+
+// a value as a jsonb literal, encoded like stored documents
+fn jsonb_literal(value: Bson) -> String {
+    format!(
+        "'{}'",
+        value.into_psql_json().to_string().replace('\'', "''")
+    )
 }
 
 pub fn get_where(filter: Option<&Document>) -> Option<String> {
@@ -768,16 +2019,34 @@ pub fn get_where(filter: Option<&Document>) -> Option<String> {
     }
 }
 
+pub fn get_where_clause(filter: Option<&Document>) -> Result<String> {
+    if let Some(f) = filter {
+        let filter_doc = expand_fields(f)?;
+        let filter = super::parser::parse(filter_doc)?;
+        if filter != "" {
+            return Ok(format!(" WHERE {}", filter));
+        }
+    }
+    Ok("".to_string())
+}
+
 pub fn get_order_by(sort: Option<&Document>) -> String {
     if let Some(s) = sort {
         let mut order_by = vec![];
         for (k, v) in s.iter() {
-            let v = if v.as_i32().unwrap() > 0 {
-                "ASC"
-            } else {
-                "DESC"
+            let descending = match v {
+                Bson::Int32(v) => *v < 0,
+                Bson::Int64(v) => *v < 0,
+                Bson::Double(v) => *v < 0.0,
+                _ => false,
             };
-            order_by.push(format!("_jsonb->'{}' {}", k, v));
+            let v = if descending { "DESC" } else { "ASC" };
+            let field = k
+                .split('.')
+                .map(|f| format!("'{}'", escape(f)))
+                .collect::<Vec<_>>()
+                .join("->");
+            order_by.push(format!("_jsonb->{} {}", field, v));
         }
         format!(" ORDER BY {}", order_by.join(", "))
     } else {
@@ -790,6 +2059,27 @@ mod tests {
     use super::*;
     use bson::doc;
 
+    #[test]
+    fn test_id_index_names() {
+        let sp = SqlParam::new("db", "col");
+        let hash = crc32c::crc32c(b"col");
+        assert_eq!(sp.id_index_name(), format!("col__id_{:08x}", hash));
+        assert_eq!(
+            sp.id_number_index_name(),
+            format!("col__id_number_{:08x}", hash)
+        );
+
+        // long names sharing their first 63 bytes
+        let a = SqlParam::new("db", &format!("{}a", "x".repeat(70)));
+        let b = SqlParam::new("db", &format!("{}b", "x".repeat(70)));
+        assert_ne!(a.id_index_name(), b.id_index_name());
+        assert_eq!(a.id_index_name().len(), 63);
+
+        let multibyte = SqlParam::new("db", &"é".repeat(40));
+        assert!(multibyte.id_number_index_name().len() <= 63);
+        assert!(multibyte.id_number_index_name().starts_with("éé"));
+    }
+
     #[test]
     fn test_update_set() {
         let doc = UpdateDoc::Set(doc! {
@@ -797,7 +2087,7 @@ mod tests {
             "b": 2,
         });
         assert_eq!(
-            update_from_operation(&doc),
+            update_from_operation(&doc).unwrap(),
             "_jsonb['a'] = '1', _jsonb['b'] = '2'"
         );
     }
@@ -808,7 +2098,10 @@ mod tests {
             "a": 1,
             "b": 1,
         });
-        assert_eq!(update_from_operation(&doc), "_jsonb = _jsonb - 'a' - 'b'");
+        assert_eq!(
+            update_from_operation(&doc).unwrap(),
+            "_jsonb = _jsonb - 'a' - 'b'"
+        );
     }
 
     #[test]
@@ -817,7 +2110,95 @@ mod tests {
             "a": 1,
             "b": 3,
         });
-        assert_eq!(update_from_operation(&doc), "_jsonb = _jsonb || json_build_object('a', COALESCE(_jsonb->'a')::numeric + 1)::jsonb || json_build_object('b', COALESCE(_jsonb->'b')::numeric + 3)::jsonb");
+        assert_eq!(update_from_operation(&doc).unwrap(), "_jsonb = _jsonb || json_build_object('a', COALESCE(_jsonb->'a')::numeric + 1)::jsonb || json_build_object('b', COALESCE(_jsonb->'b')::numeric + 3)::jsonb");
+    }
+
+    #[test]
+    fn test_update_push() {
+        let doc = UpdateDoc::Push(doc! {
+            "a.b": 1,
+            "c": { "$each": ["it's"], "$position": 0, "$slice": -2, "$sort": { "x": -1 } },
+        });
+        assert_eq!(
+            update_from_operation(&doc).unwrap(),
+            r#"_jsonb['a']['b'] = oxide_functions.bson_push(COALESCE(_jsonb #> '{a,b}', '[]'), '[1]', NULL, NULL, NULL), _jsonb['c'] = oxide_functions.bson_push(COALESCE(_jsonb #> '{c}', '[]'), '["it''s"]', 0, -2, '{"x":-1}')"#
+        );
+    }
+
+    #[test]
+    fn test_update_push_quoted_fields() {
+        let doc = UpdateDoc::Push(doc! {
+            "it's.a": { "$each": [1], "$sort": { "o'k": 1 } },
+        });
+        assert_eq!(
+            update_from_operation(&doc).unwrap(),
+            r#"_jsonb['it''s']['a'] = oxide_functions.bson_push(COALESCE(_jsonb #> '{it''s,a}', '[]'), '[1]', NULL, NULL, '{"o''k":1}')"#
+        );
+    }
+
+    #[test]
+    fn test_update_add_to_set() {
+        let doc = UpdateDoc::AddToSet(doc! {
+            "a": { "$each": [1, 2] },
+        });
+        assert_eq!(
+            update_from_operation(&doc).unwrap(),
+            "_jsonb['a'] = oxide_functions.bson_add_to_set(COALESCE(_jsonb #> '{a}', '[]'), '[1,2]')"
+        );
+    }
+
+    #[test]
+    fn test_update_pull() {
+        let doc = UpdateDoc::Pull(doc! {
+            "a": 1,
+            "b": { "$gte": 6 },
+        });
+        assert_eq!(
+            update_from_operation(&doc).unwrap(),
+            r#"_jsonb = jsonb_set(jsonb_set(_jsonb, '{a}', oxide_functions.bson_pull_all(COALESCE(_jsonb #> '{a}', '[]'), '[1]'), false), '{b}', COALESCE((SELECT jsonb_agg(e ORDER BY i) FROM jsonb_array_elements(COALESCE(_jsonb #> '{b}', '[]')) WITH ORDINALITY AS t(e, i), LATERAL (SELECT jsonb_build_object('v', e) AS _jsonb) AS elem WHERE NOT COALESCE(EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['v']) AS p(v) WHERE oxide_functions.bson_number(v) >= 6), FALSE)), '[]'), false)"#
+        );
+
+        let doc = UpdateDoc::Pull(doc! { "a": { "$foo": 1 } });
+        assert!(update_from_operation(&doc).is_err());
+    }
+
+    #[test]
+    fn test_update_pop() {
+        let doc = UpdateDoc::Pop(doc! { "a.b": 1, "c": -1 });
+        assert_eq!(
+            update_from_operation(&doc).unwrap(),
+            "_jsonb = jsonb_set(jsonb_set(_jsonb, '{a,b}', COALESCE(_jsonb #> '{a,b}', '[]') - -1, false), '{c}', COALESCE(_jsonb #> '{c}', '[]') - 0, false)"
+        );
+
+        let doc = UpdateDoc::Pop(doc! { "it's": 1 });
+        assert_eq!(
+            update_from_operation(&doc).unwrap(),
+            "_jsonb = jsonb_set(_jsonb, '{it''s}', COALESCE(_jsonb #> '{it''s}', '[]') - -1, false)"
+        );
+    }
+
+    #[test]
+    fn test_update_rename() {
+        let doc = UpdateDoc::Rename(doc! { "a": "b.c" });
+        assert_eq!(
+            update_from_operation(&doc).unwrap(),
+            "_jsonb = oxide_functions.bson_set_path(_jsonb #- '{a}', '{b,c}', _jsonb #> '{a}')"
+        );
+    }
+
+    #[test]
+    fn test_update_min_and_mul() {
+        let doc = UpdateDoc::Min(doc! { "a": 1 });
+        assert_eq!(
+            update_from_operation(&doc).unwrap(),
+            "_jsonb = oxide_functions.bson_set_path(_jsonb, '{a}', CASE WHEN _jsonb #> '{a}' IS NULL OR oxide_functions.bson_compare('1', _jsonb #> '{a}') < 0 THEN '1'::jsonb END)"
+        );
+
+        let doc = UpdateDoc::Mul(doc! { "a.b": 1.5 });
+        assert_eq!(
+            update_from_operation(&doc).unwrap(),
+            r#"_jsonb = oxide_functions.bson_set_path(_jsonb, '{a,b}', oxide_functions.bson_mul(_jsonb #> '{a,b}', '{"$f":1.5}'))"#
+        );
     }
 
     #[test]
@@ -827,7 +2208,7 @@ mod tests {
             "b": "2",
         };
         assert_eq!(
-            "(_jsonb->'a' = '\"1\"' AND _jsonb->'b' = '\"2\"')",
+            "(EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['a']) AS p(v) WHERE v = '\"1\"') AND EXISTS (SELECT 1 FROM oxide_functions.bson_path_values(_jsonb, ARRAY['b']) AS p(v) WHERE v = '\"2\"'))",
             get_where(Some(&doc)).unwrap()
         );
     }
@@ -843,4 +2224,16 @@ mod tests {
             get_order_by(Some(&doc))
         );
     }
+
+    #[test]
+    fn test_get_order_by_nested() {
+        let doc = doc! {
+            "a.b": -1_i64,
+            "c": 1.0,
+        };
+        assert_eq!(
+            " ORDER BY _jsonb->'a'->'b' DESC, _jsonb->'c' ASC",
+            get_order_by(Some(&doc))
+        );
+    }
 }
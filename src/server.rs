@@ -1,21 +1,66 @@
-use crate::handler::{handle, Response};
-use crate::threadpool::ThreadPool;
-use crate::wire::parse;
+use crate::auth::AuthState;
+use crate::change_stream::ChangeListener;
+use crate::cursor::{drop_stale_results, CursorRegistry};
+use crate::handler::{handle, Reply, Response};
+use crate::pg::PgDb;
+use crate::session::{
+    SessionRegistry, LOGICAL_SESSION_TIMEOUT_MINUTES, TRANSACTION_LIFETIME_LIMIT_SECONDS,
+};
+use crate::tls::{connection_manager, MakeRustlsConnect, TlsConfig, TlsOptions};
+use crate::wire::{error_reply, parse, HEADER_SIZE, MAX_MSG_LEN};
 use autoincrement::prelude::AsyncIncremental;
 use bson::{doc, Bson};
 use byteorder::{ByteOrder, LittleEndian};
-use r2d2_postgres::{postgres::NoTls, PostgresConnectionManager};
+use eyre::{eyre, Result};
+use r2d2_postgres::PostgresConnectionManager;
 use std::env;
-use std::io::prelude::*;
-use std::net::{Shutdown, TcpListener, TcpStream};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::{watch, Semaphore};
+
+/// Maximum number of simultaneous client connections, unless configured.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1000;
+
+// how long open connections are given to finish on shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// how long a client is given to complete the TLS handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// oldest PostgreSQL supported, as `server_version_num`
+const MIN_POSTGRES_VERSION: i32 = 140000;
+
+// how often changes past their retention are dropped from the log
+const CHANGES_EXPIRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// how often transactions past their lifetime are aborted
+const TRANSACTIONS_EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(AsyncIncremental, PartialEq, Eq, Debug)]
 struct RequestId(u32);
 
+/// State shared by every connection of a server.
+#[derive(Clone)]
+pub struct SharedState {
+    pub pool: r2d2::Pool<PostgresConnectionManager<MakeRustlsConnect>>,
+    pub cursors: CursorRegistry,
+    pub sessions: SessionRegistry,
+    pub changes: ChangeListener,
+}
+
 pub struct Server {
     listen_addr: String,
     port: u16,
     pg_url: String,
+    max_connections: usize,
+    auth: bool,
+    tls: Option<TlsOptions>,
+    transaction_lifetime: Duration,
+    shutdown: Arc<watch::Sender<bool>>,
 }
 
 impl Server {
@@ -28,13 +73,32 @@ impl Server {
     }
 
     pub fn new_with_pgurl(listen_addr: String, port: u16, pg_url: String) -> Self {
+        let (shutdown, _) = watch::channel(false);
         Server {
             listen_addr,
             port,
             pg_url,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            auth: false,
+            tls: None,
+            transaction_lifetime: Duration::from_secs(TRANSACTION_LIFETIME_LIMIT_SECONDS),
+            shutdown: Arc::new(shutdown),
         }
     }
 
+    /// Limits how many clients can be connected at once. Connections past the
+    /// limit wait to be accepted until another client disconnects, so the
+    /// limit has to be at least 1.
+    pub fn with_max_connections(mut self, max_connections: usize) -> Result<Self> {
+        if max_connections == 0 {
+            return Err(eyre!(
+                "the maximum number of connections must be at least 1"
+            ));
+        }
+        self.max_connections = max_connections;
+        Ok(self)
+    }
+
     pub fn start(&self) {
         let uri = &self.pg_url;
         let sanitized_uri = format!(
@@ -42,78 +106,381 @@ impl Server {
             uri.split("@").collect::<Vec<_>>()[1]
         );
         log::info!("Connecting to {}...", sanitized_uri);
-        let manager = PostgresConnectionManager::new(self.pg_url.parse().unwrap(), NoTls);
+        let manager = match connection_manager(&self.pg_url) {
+            Ok(manager) => manager,
+            Err(e) => {
+                log::error!("Invalid PostgreSQL connection settings: {}", e);
+                return;
+            }
+        };
         if let Ok(pool) = r2d2::Pool::new(manager) {
-            self.start_with_pool(pool);
+            self.run(pool, true);
         } else {
             log::error!("Failed to connect to PostgreSQL.");
         }
     }
 
-    pub fn start_with_pool(&self, pg_pool: r2d2::Pool<PostgresConnectionManager<NoTls>>) {
+    pub fn start_with_pool(
+        &self,
+        pg_pool: r2d2::Pool<PostgresConnectionManager<MakeRustlsConnect>>,
+    ) {
+        self.run(pg_pool, false);
+    }
+
+    /// Requires clients to authenticate before running commands other than
+    /// the handshake ones.
+    pub fn with_auth(mut self, enabled: bool) -> Self {
+        self.auth = enabled;
+        self
+    }
+
+    /// Only accepts TLS connections, using the given certificate files. They
+    /// are read again when the process receives SIGHUP.
+    pub fn with_tls(mut self, options: TlsOptions) -> Self {
+        self.tls = Some(options);
+        self
+    }
+
+    /// Aborts transactions that run for longer than `limit`, 60 seconds
+    /// unless configured.
+    pub fn with_transaction_lifetime_limit(mut self, limit: Duration) -> Self {
+        self.transaction_lifetime = limit;
+        self
+    }
+
+    /// Returns a handle that stops the server from another thread.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            sender: self.shutdown.clone(),
+        }
+    }
+
+    fn run(
+        &self,
+        pg_pool: r2d2::Pool<PostgresConnectionManager<MakeRustlsConnect>>,
+        handle_signals: bool,
+    ) {
+        // runs before the runtime is up, as the sync postgres client can't
+        // be used from within it
+        match PgDb::new_from_pool(pg_pool.clone()).server_version() {
+            Ok(version) if version < MIN_POSTGRES_VERSION => {
+                log::error!(
+                    "OxideDB requires PostgreSQL {} or newer, but the server is version {}",
+                    MIN_POSTGRES_VERSION / 10000,
+                    version / 10000
+                );
+                return;
+            }
+            Ok(_) => {}
+            Err(e) => log::error!("Could not check the PostgreSQL version: {}", e),
+        }
+        if let Err(e) = PgDb::new_from_pool(pg_pool.clone()).create_functions() {
+            log::error!("Could not create the SQL functions: {}", e);
+        }
+        if let Err(e) = PgDb::new_from_pool(pg_pool.clone()).create_missing_id_indexes() {
+            log::error!("Could not check the collections for _id indexes: {}", e);
+        }
+        if let Err(e) = PgDb::new_from_pool(pg_pool.clone())
+            .create_retryable_writes_table(LOGICAL_SESSION_TIMEOUT_MINUTES)
+        {
+            log::error!("Could not create the table of retryable writes: {}", e);
+        }
+        match drop_stale_results(&mut PgDb::new_from_pool(pg_pool.clone())) {
+            0 => {}
+            count => log::info!("Dropped the results of {} stale cursors", count),
+        }
+        {
+            let mut client = PgDb::new_from_pool(pg_pool.clone());
+            if let Err(e) = client
+                .create_changes_table()
+                .and_then(|_| client.create_missing_changes_triggers())
+            {
+                log::error!("Could not set up the log of changes: {}", e);
+            }
+        }
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            if handle_signals {
+                let handle = self.shutdown_handle();
+                tokio::spawn(async move {
+                    if tokio::signal::ctrl_c().await.is_ok() {
+                        log::info!("Received Ctrl-C, shutting down...");
+                        handle.shutdown();
+                    }
+                });
+            }
+            self.serve(&pg_pool).await;
+        });
+
+        // handlers still running on blocking threads are given the same grace
+        // period as the connections
+        runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+
+        // the sync postgres client can't be closed from within the runtime, so
+        // the last reference to the pool is only dropped here
+        drop(pg_pool);
+        log::info!("Shutting down.");
+    }
+
+    async fn serve(&self, pg_pool: &r2d2::Pool<PostgresConnectionManager<MakeRustlsConnect>>) {
+        let tls = match &self.tls {
+            Some(options) => match TlsConfig::load(options.clone()) {
+                Ok(tls) => {
+                    reload_on_hangup(tls.clone(), self.shutdown.subscribe());
+                    Some(tls)
+                }
+                Err(e) => {
+                    log::error!("Could not load the TLS certificates: {}", e);
+                    return;
+                }
+            },
+            None => None,
+        };
+
+        expire_changes(pg_pool.clone(), self.shutdown.subscribe());
+
         let addr = format!("{}:{}", self.listen_addr, self.port);
-        let listener = TcpListener::bind(&addr).unwrap();
-        let pool = ThreadPool::new(100);
+        let listener = TcpListener::bind(&addr).await.unwrap();
         let generator = RequestId::init();
+        let state = SharedState {
+            pool: pg_pool.clone(),
+            cursors: CursorRegistry::new(),
+            sessions: SessionRegistry::new().with_transaction_lifetime(self.transaction_lifetime),
+            changes: ChangeListener::new(pg_pool.clone()),
+        };
+        expire_transactions(state.sessions.clone(), self.shutdown.subscribe());
+        let connections = Arc::new(Semaphore::new(self.max_connections));
+        let mut shutdown = self.shutdown.subscribe();
+
+        if tls.is_some() {
+            log::info!("OxideDB listening on {} with TLS...", addr);
+        } else {
+            log::info!("OxideDB listening on {}...", addr);
+        }
+        while !*shutdown.borrow() {
+            if connections.available_permits() == 0 {
+                log::warn!(
+                    "Reached the limit of {} connections, waiting for one to close",
+                    self.max_connections
+                );
+            }
+
+            // new connections wait on the listen backlog until a slot frees up
+            let permit = tokio::select! {
+                permit = connections.clone().acquire_owned() => permit.unwrap(),
+                _ = shutdown.changed() => break,
+            };
+
+            let (stream, addr) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        log::error!("Error accepting connection: {}", e);
+                        continue;
+                    }
+                },
+                _ = shutdown.changed() => break,
+            };
 
-        log::info!("OxideDB listening on {}...", addr);
-        for stream in listener.incoming() {
-            let stream = stream.unwrap();
             let id = generator.pull();
-            let pg_pool = pg_pool.clone();
+            let state = state.clone();
+            let shutdown = shutdown.clone();
+            let auth = AuthState::new(self.auth);
+            let acceptor = tls.as_ref().map(|tls| tls.acceptor());
 
             stream.set_nodelay(true).unwrap();
 
-            pool.execute(|| {
-                handle_connection(stream, id, pg_pool);
+            tokio::spawn(async move {
+                match acceptor {
+                    // a client that stalls the handshake would hold its slot forever
+                    Some(acceptor) => {
+                        match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+                            .await
+                        {
+                            Ok(Ok(stream)) => {
+                                handle_connection(stream, addr, id, state, auth, shutdown).await
+                            }
+                            Ok(Err(e)) => log::error!("TLS handshake with {} failed: {}", addr, e),
+                            Err(_) => log::warn!(
+                                "TLS handshake with {} timed out after {:?}",
+                                addr,
+                                TLS_HANDSHAKE_TIMEOUT
+                            ),
+                        }
+                    }
+                    None => handle_connection(stream, addr, id, state, auth, shutdown).await,
+                }
+                drop(permit);
             });
         }
 
-        log::info!("Shutting down.");
+        drop(listener);
+
+        // waits for the open connections to finish their current request
+        let all = self.max_connections as u32;
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, connections.acquire_many(all))
+            .await
+            .is_err()
+        {
+            log::warn!(
+                "{} connections still open after {:?}",
+                self.max_connections - connections.available_permits(),
+                SHUTDOWN_TIMEOUT
+            );
+        }
     }
 }
 
-fn handle_connection(
-    mut stream: TcpStream,
+/// Stops a running `Server`. Connections finish the request they're serving
+/// and are closed, and no new connections are accepted.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    sender: Arc<watch::Sender<bool>>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        let _ = self.sender.send(true);
+    }
+}
+
+/// Reloads the TLS certificates whenever the process receives SIGHUP, until
+/// the server shuts down.
+#[cfg(unix)]
+fn reload_on_hangup(tls: TlsConfig, mut shutdown: watch::Receiver<bool>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            log::error!("Could not listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                received = hangup.recv() => if received.is_none() { break },
+                _ = shutdown.changed() => break,
+            }
+            match tls.reload() {
+                Ok(()) => log::info!("Reloaded the TLS certificates"),
+                Err(e) => log::error!("Could not reload the TLS certificates: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn reload_on_hangup(_tls: TlsConfig, _shutdown: watch::Receiver<bool>) {}
+
+/// Drops the changes past their retention from the log every
+/// `CHANGES_EXPIRY_INTERVAL`, until the server shuts down.
+fn expire_changes(
+    pg_pool: r2d2::Pool<PostgresConnectionManager<MakeRustlsConnect>>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CHANGES_EXPIRY_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.changed() => break,
+            }
+            let pg_pool = pg_pool.clone();
+            let expired =
+                tokio::task::spawn_blocking(move || PgDb::new_from_pool(pg_pool).expire_changes())
+                    .await;
+            match expired {
+                Ok(Ok(count)) => log::debug!("Dropped {} expired changes", count),
+                Ok(Err(e)) => log::error!("Could not drop the expired changes: {}", e),
+                Err(e) => log::error!("Could not drop the expired changes: {}", e),
+            }
+        }
+    });
+}
+
+/// Aborts the transactions past their lifetime every
+/// `TRANSACTIONS_EXPIRY_INTERVAL`, until the server shuts down.
+fn expire_transactions(sessions: SessionRegistry, mut shutdown: watch::Receiver<bool>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(TRANSACTIONS_EXPIRY_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.changed() => break,
+            }
+            let sessions = sessions.clone();
+            match tokio::task::spawn_blocking(move || sessions.expire()).await {
+                Ok(0) => {}
+                Ok(count) => log::info!("Aborted {} expired transactions", count),
+                Err(e) => log::error!("Could not abort the expired transactions: {}", e),
+            }
+        }
+    });
+}
+
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    addr: SocketAddr,
     id: RequestId,
-    pool: r2d2::Pool<PostgresConnectionManager<NoTls>>,
+    state: SharedState,
+    auth: AuthState,
+    mut shutdown: watch::Receiver<bool>,
 ) {
-    let addr = stream.peer_addr().unwrap();
     log::debug!("Client connected: {}", addr);
+    let auth = Arc::new(Mutex::new(auth));
 
-    loop {
-        let mut size_buffer = [0; 4];
-        let read = stream.peek(&mut size_buffer).unwrap();
-        let size = LittleEndian::read_u32(&size_buffer);
-        if size == 0 {
-            stream.flush().unwrap();
-            break;
-        }
-        let mut buffer = vec![0; size as usize];
-
-        match stream.read_exact(&mut buffer) {
-            Ok(_read) => {
-                use std::time::Instant;
-                let now = Instant::now();
-
-                let op_code = parse(&buffer);
-                log::trace!("{} {}bytes: {:?}", addr, read, op_code);
-                if op_code.is_err() {
-                    log::error!(
-                        "Could not understand - {} {} bytes: {:?}",
-                        addr,
-                        read,
-                        op_code
-                    );
-                    stream.write(&[0x00, 0x00, 0x00, 0x00]).unwrap();
-                    stream.write(&[0x00, 0x00, 0x00, 0x00]).unwrap();
-                    stream.write(&[0x00, 0x00, 0x00, 0x00]).unwrap();
-                    stream.write(&[0x00, 0x00, 0x00, 0x00]).unwrap();
-                    return;
+    'connection: loop {
+        let buffer = tokio::select! {
+            message = read_message(&mut stream) => match message {
+                Ok(Some(buffer)) => buffer,
+                Ok(None) => break,
+                Err(e) => {
+                    log::error!("Error on request id {}: {}", id.0, e);
+                    break;
+                }
+            },
+            _ = shutdown.changed() => break,
+        };
+
+        let now = Instant::now();
+        let read = buffer.len();
+
+        let mut op_code = match parse(&buffer) {
+            Ok(op_code) => op_code,
+            Err(e) => {
+                log::error!("Could not understand - {} {} bytes: {}", addr, read, e);
+                // malformed requests are answered when the connection is still
+                // usable, otherwise it's closed
+                match error_reply(&buffer, id.0, &e) {
+                    Some(reply) => {
+                        if let Err(e) = stream.write_all(&reply).await {
+                            log::error!("Error on request id {}: {}", id.0, e);
+                            break;
+                        }
+                        continue;
+                    }
+                    None => break,
                 }
+            }
+        };
+        log::trace!("{} {}bytes: {:?}", addr, read, op_code);
 
-                let op_code = op_code.unwrap();
-                let mut response = match handle(id.0, &pool, addr, &op_code) {
+        // a getMore on an exhaust cursor keeps streaming batches until the
+        // cursor is exhausted, each one in response to the previous reply
+        loop {
+            // commands run against the blocking postgres pool
+            let state = state.clone();
+            let auth = auth.clone();
+            let request_id = id.0;
+            let response = tokio::task::spawn_blocking(move || {
+                let reply = match handle(request_id, &state, &auth, addr, &op_code) {
                     Ok(reply) => reply,
                     Err(e) => {
                         log::error!("Error while handling: {}", e);
@@ -123,23 +490,70 @@ fn handle_connection(
                             "code": Bson::Int32(59),
                             "codeName": "CommandNotFound",
                         };
-                        let request = Response::new(id.0, &op_code, vec![err]);
-                        op_code.reply(request).unwrap()
+                        let request = Response::new(request_id, &op_code, vec![err]);
+                        Reply {
+                            bytes: op_code.reply(request).unwrap(),
+                            more_to_come: false,
+                        }
                     }
                 };
+                (op_code, reply)
+            })
+            .await;
 
-                response.flush().unwrap();
+            let (request, reply) = match response {
+                Ok(response) => response,
+                Err(e) => {
+                    log::error!("Handler for request id {} failed: {}", id.0, e);
+                    break 'connection;
+                }
+            };
 
-                let elapsed = now.elapsed();
-                log::trace!("Processed {}bytes in {:.2?}\n", response.len(), elapsed);
+            let elapsed = now.elapsed();
+            log::trace!("Processed {}bytes in {:.2?}\n", reply.bytes.len(), elapsed);
 
-                stream.write_all(&response).unwrap();
+            // requests flagged with moreToCome, like unacknowledged writes,
+            // don't get a reply
+            if !request.more_to_come() {
+                if let Err(e) = stream.write_all(&reply.bytes).await {
+                    log::error!("Error on request id {}: {}", id.0, e);
+                    break 'connection;
+                }
             }
-            Err(e) => {
-                log::error!("Error on request id {}: {}", id.0, e);
-                stream.shutdown(Shutdown::Both).unwrap();
-                return;
+
+            if !reply.more_to_come {
+                break;
             }
-        };
+            op_code = request.with_request_id(request_id);
+        }
+    }
+
+    let _ = stream.shutdown().await;
+}
+
+/// Reads one whole message from the stream, including its length prefix.
+/// Returns `None` once the client closes the connection.
+async fn read_message<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<Vec<u8>>> {
+    let mut size_buffer = [0; 4];
+    match stream.read_exact(&mut size_buffer).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
     }
+
+    let size = LittleEndian::read_u32(&size_buffer);
+    if size == 0 {
+        return Ok(None);
+    }
+    if !(HEADER_SIZE..=MAX_MSG_LEN).contains(&size) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid message length {}", size),
+        ));
+    }
+
+    let mut buffer = vec![0; size as usize];
+    buffer[..4].copy_from_slice(&size_buffer);
+    stream.read_exact(&mut buffer[4..]).await?;
+    Ok(Some(buffer))
 }
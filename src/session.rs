@@ -0,0 +1,514 @@
+use crate::pg::PgDb;
+use crate::tls::MakeRustlsConnect;
+use bson::spec::BinarySubtype;
+use bson::{doc, Binary, Bson, Document};
+use r2d2_postgres::PostgresConnectionManager;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Logical sessions and transactions.
+//
+// Drivers tag commands with the `lsid` of the session they belong to. Sessions
+// are created the first time one of their ids is seen, and forgotten when the
+// client ends them or after they're idle for longer than the timeout.
+//
+// A transaction pins a connection of the pool to its session and runs a real
+// SQL transaction on it. Every command that is part of the transaction runs on
+// that connection, so they see each other's writes, and committing or
+// aborting the transaction commits or rolls back the SQL one.
+//
+// Transactions that run for longer than the lifetime limit are aborted, so an
+// abandoned one doesn't keep its connection and locks forever.
+//
+// Writes outside of a transaction that carry a `txnNumber` are retryable: they
+// run in a SQL transaction that also records their reply in Postgres, and
+// running one again with the same number returns that reply instead.
+
+/// Minutes a session can be idle before it's discarded, advertised to drivers
+/// as `logicalSessionTimeoutMinutes`.
+pub const LOGICAL_SESSION_TIMEOUT_MINUTES: i32 = 30;
+
+/// Seconds a transaction can run before it's aborted, like MongoDB's
+/// `transactionLifetimeLimitSeconds`.
+pub const TRANSACTION_LIFETIME_LIMIT_SECONDS: u64 = 60;
+
+/// Commands that can run as part of a transaction, besides the ones that
+/// commit or abort it.
+const TRANSACTION_COMMANDS: [&str; 9] = [
+    "find",
+    "getMore",
+    "killCursors",
+    "count",
+    "aggregate",
+    "insert",
+    "update",
+    "delete",
+    "findAndModify",
+];
+
+/// Commands that drivers retry with the same `txnNumber` after a network
+/// error.
+pub const RETRYABLE_WRITE_COMMANDS: [&str; 4] = ["insert", "update", "delete", "findAndModify"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TransactionState {
+    InProgress,
+    Committed,
+    Aborted,
+}
+
+struct Transaction {
+    number: i64,
+    state: TransactionState,
+    started: Instant,
+    // only set while the transaction is in progress
+    client: Option<Arc<Mutex<PgDb>>>,
+}
+
+struct Session {
+    txn_number: Option<i64>,
+    transaction: Option<Transaction>,
+    last_used: Instant,
+}
+
+impl Session {
+    fn new() -> Self {
+        Session {
+            txn_number: None,
+            transaction: None,
+            last_used: Instant::now(),
+        }
+    }
+
+    // transaction `number` if it's the latest one of the session
+    fn transaction(&mut self, number: i64) -> Result<&mut Transaction, TransactionError> {
+        if let Some(latest) = self.txn_number {
+            if number < latest {
+                return Err(TransactionError::TooOld(number, latest));
+            }
+        }
+        match &mut self.transaction {
+            Some(transaction) if transaction.number == number => Ok(transaction),
+            _ => Err(TransactionError::NoSuchTransaction(number)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TransactionError {
+    NoSuchTransaction(i64),
+    TooOld(i64, i64),
+    RetryTooOld(i64, i64),
+    Committed(i64),
+    AlreadyStarted(i64),
+    NotSupported(String),
+    InvalidOptions(String),
+    Postgres(eyre::Report),
+}
+
+impl TransactionError {
+    pub fn code(&self) -> i32 {
+        match self {
+            TransactionError::NoSuchTransaction(_) => 251,
+            TransactionError::TooOld(_, _) | TransactionError::RetryTooOld(_, _) => 225,
+            TransactionError::Committed(_) => 256,
+            TransactionError::AlreadyStarted(_) => 117,
+            TransactionError::NotSupported(_) => 263,
+            TransactionError::InvalidOptions(_) => 72,
+            TransactionError::Postgres(_) => 1,
+        }
+    }
+
+    pub fn code_name(&self) -> &'static str {
+        match self {
+            TransactionError::NoSuchTransaction(_) => "NoSuchTransaction",
+            TransactionError::TooOld(_, _) | TransactionError::RetryTooOld(_, _) => {
+                "TransactionTooOld"
+            }
+            TransactionError::Committed(_) => "TransactionCommitted",
+            TransactionError::AlreadyStarted(_) => "ConflictingOperationInProgress",
+            TransactionError::NotSupported(_) => "OperationNotSupportedInTransaction",
+            TransactionError::InvalidOptions(_) => "InvalidOptions",
+            TransactionError::Postgres(_) => "InternalError",
+        }
+    }
+
+    /// The error reply, labeled so drivers know when retrying the whole
+    /// transaction can succeed.
+    pub fn to_doc(&self) -> Document {
+        let mut doc = doc! {
+            "ok": Bson::Double(0.0),
+            "errmsg": self.to_string(),
+            "code": Bson::Int32(self.code()),
+            "codeName": self.code_name(),
+        };
+        if let TransactionError::NoSuchTransaction(_) = self {
+            doc.insert("errorLabels", vec!["TransientTransactionError"]);
+        }
+        doc
+    }
+}
+
+impl std::fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TransactionError::NoSuchTransaction(n) => write!(
+                f,
+                "Given transaction number {} does not match any in-progress transactions",
+                n
+            ),
+            TransactionError::TooOld(n, latest) => write!(
+                f,
+                "Cannot start transaction {} on session because a newer transaction {} has already started",
+                n, latest
+            ),
+            TransactionError::RetryTooOld(n, latest) => write!(
+                f,
+                "Retryable write with txnNumber {} is prohibited on session because a newer retryable write with txnNumber {} has already started",
+                n, latest
+            ),
+            TransactionError::Committed(n) => {
+                write!(f, "Transaction {} has been committed", n)
+            }
+            TransactionError::AlreadyStarted(n) => {
+                write!(f, "Transaction {} has already been started", n)
+            }
+            TransactionError::NotSupported(command) => {
+                write!(f, "Cannot run '{}' in a multi-document transaction", command)
+            }
+            TransactionError::InvalidOptions(message) => write!(f, "{}", message),
+            TransactionError::Postgres(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+#[derive(Clone)]
+pub struct SessionRegistry {
+    sessions: Arc<Mutex<HashMap<Vec<u8>, Session>>>,
+    timeout: Duration,
+    transaction_lifetime: Duration,
+}
+
+impl Default for SessionRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SessionRegistry {
+    pub fn new() -> Self {
+        Self::with_timeout(Duration::from_secs(
+            LOGICAL_SESSION_TIMEOUT_MINUTES as u64 * 60,
+        ))
+    }
+
+    pub fn with_timeout(timeout: Duration) -> Self {
+        SessionRegistry {
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            timeout,
+            transaction_lifetime: Duration::from_secs(TRANSACTION_LIFETIME_LIMIT_SECONDS),
+        }
+    }
+
+    pub fn with_transaction_lifetime(mut self, transaction_lifetime: Duration) -> Self {
+        self.transaction_lifetime = transaction_lifetime;
+        self
+    }
+
+    /// Creates a session, returning its `lsid`.
+    pub fn start(&self) -> Document {
+        let id = uuid::Uuid::new_v4().as_bytes().to_vec();
+        let mut sessions = self.sessions.lock().unwrap();
+        self.reap(&mut sessions);
+        sessions.insert(id.clone(), Session::new());
+        lsid(id)
+    }
+
+    /// Forgets a session, rolling back its transaction if one is running.
+    pub fn end(&self, id: &[u8]) {
+        self.sessions.lock().unwrap().remove(id);
+    }
+
+    /// Keeps a session alive, creating it if it isn't known.
+    pub fn refresh(&self, id: &[u8]) {
+        let mut sessions = self.sessions.lock().unwrap();
+        self.reap(&mut sessions);
+        sessions
+            .entry(id.to_vec())
+            .or_insert_with(Session::new)
+            .last_used = Instant::now();
+    }
+
+    pub fn contains(&self, id: &[u8]) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        self.reap(&mut sessions);
+        sessions.contains_key(id)
+    }
+
+    /// Keeps track of the session and transaction a command belongs to,
+    /// returning the connection it has to run on when it's part of a
+    /// transaction. Transactions are started by the command that carries
+    /// `startTransaction`.
+    pub fn transaction(
+        &self,
+        pool: &r2d2::Pool<PostgresConnectionManager<MakeRustlsConnect>>,
+        command: &str,
+        doc: &Document,
+    ) -> Result<Option<Arc<Mutex<PgDb>>>, TransactionError> {
+        let id = match session_id(doc) {
+            Some(id) => id,
+            None if doc.contains_key("autocommit") => {
+                return Err(TransactionError::InvalidOptions(
+                    "Transactions require a logical session".to_string(),
+                ))
+            }
+            None => return Ok(None),
+        };
+        let txn_number = txn_number(doc);
+        self.refresh(&id);
+
+        let autocommit = match doc.get("autocommit") {
+            None => {
+                // a command outside of a transaction with a newer number
+                // leaves the previous transaction behind
+                if let Some(number) = txn_number {
+                    let mut sessions = self.sessions.lock().unwrap();
+                    let session = sessions.get_mut(&id).unwrap();
+                    if number > session.txn_number.unwrap_or(-1) {
+                        session.txn_number = Some(number);
+                        session.transaction = None;
+                    }
+                }
+                return Ok(None);
+            }
+            Some(autocommit) => autocommit.as_bool(),
+        };
+        if autocommit != Some(false) {
+            return Err(TransactionError::InvalidOptions(
+                "autocommit can only be specified as false".to_string(),
+            ));
+        }
+        let number = txn_number.ok_or_else(|| {
+            TransactionError::InvalidOptions(
+                "txnNumber must be specified in a transaction".to_string(),
+            )
+        })?;
+        if !TRANSACTION_COMMANDS.contains(&command) {
+            return Err(TransactionError::NotSupported(command.to_string()));
+        }
+
+        if !doc.get_bool("startTransaction").unwrap_or(false) {
+            let mut sessions = self.sessions.lock().unwrap();
+            let transaction = sessions.get_mut(&id).unwrap().transaction(number)?;
+            return match transaction.state {
+                TransactionState::InProgress => Ok(transaction.client.clone()),
+                TransactionState::Committed => Err(TransactionError::Committed(number)),
+                TransactionState::Aborted => Err(TransactionError::NoSuchTransaction(number)),
+            };
+        }
+
+        {
+            let sessions = self.sessions.lock().unwrap();
+            let session = sessions.get(&id).unwrap();
+            if let Some(latest) = session.txn_number {
+                if number < latest {
+                    return Err(TransactionError::TooOld(number, latest));
+                }
+                if number == latest {
+                    return Err(TransactionError::AlreadyStarted(number));
+                }
+            }
+        }
+
+        // the connection is taken from the pool without holding the lock
+        let mut client = PgDb::new_from_pool(pool.clone());
+        client.begin().map_err(TransactionError::Postgres)?;
+        let client = Arc::new(Mutex::new(client));
+
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.entry(id).or_insert_with(Session::new);
+        session.txn_number = Some(number);
+        session.transaction = Some(Transaction {
+            number,
+            state: TransactionState::InProgress,
+            started: Instant::now(),
+            client: Some(client.clone()),
+        });
+        Ok(Some(client))
+    }
+
+    /// Commits a transaction. Committing it again succeeds without doing
+    /// anything, so drivers can retry commits.
+    pub fn commit(&self, id: &[u8], number: i64) -> Result<(), TransactionError> {
+        let client = {
+            let mut sessions = self.sessions.lock().unwrap();
+            let session = sessions
+                .get_mut(id)
+                .ok_or(TransactionError::NoSuchTransaction(number))?;
+            let transaction = session.transaction(number)?;
+            match transaction.state {
+                TransactionState::Committed => return Ok(()),
+                TransactionState::Aborted => {
+                    return Err(TransactionError::NoSuchTransaction(number))
+                }
+                TransactionState::InProgress => {}
+            }
+            transaction.state = TransactionState::Committed;
+            transaction.client.take().unwrap()
+        };
+
+        let res = client.lock().unwrap().commit();
+        if let Err(e) = res {
+            self.set_state(id, number, TransactionState::Aborted);
+            return Err(TransactionError::Postgres(e));
+        }
+        Ok(())
+    }
+
+    /// Rolls back a transaction.
+    pub fn abort(&self, id: &[u8], number: i64) -> Result<(), TransactionError> {
+        let client = {
+            let mut sessions = self.sessions.lock().unwrap();
+            let session = sessions
+                .get_mut(id)
+                .ok_or(TransactionError::NoSuchTransaction(number))?;
+            let transaction = session.transaction(number)?;
+            match transaction.state {
+                TransactionState::Committed => return Err(TransactionError::Committed(number)),
+                TransactionState::Aborted => {
+                    return Err(TransactionError::NoSuchTransaction(number))
+                }
+                TransactionState::InProgress => {}
+            }
+            transaction.state = TransactionState::Aborted;
+            transaction.client.take().unwrap()
+        };
+
+        let res = client.lock().unwrap().rollback();
+        res.map_err(TransactionError::Postgres)
+    }
+
+    /// Forgets the expired sessions and aborts the transactions that ran past
+    /// their lifetime, returning their connections to the pool. Returns the
+    /// number of transactions aborted.
+    pub fn expire(&self) -> usize {
+        let clients = {
+            let mut sessions = self.sessions.lock().unwrap();
+            self.reap(&mut sessions);
+            sessions
+                .values_mut()
+                .filter_map(|session| session.transaction.as_mut())
+                .filter(|transaction| {
+                    transaction.state == TransactionState::InProgress
+                        && transaction.started.elapsed() >= self.transaction_lifetime
+                })
+                .map(|transaction| {
+                    transaction.state = TransactionState::Aborted;
+                    transaction.client.take().unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // waits for the commands still running on them
+        for client in &clients {
+            if let Err(e) = client.lock().unwrap().rollback() {
+                log::error!("Could not abort an expired transaction: {}", e);
+            }
+        }
+        clients.len()
+    }
+
+    fn set_state(&self, id: &[u8], number: i64, state: TransactionState) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(session) = sessions.get_mut(id) {
+            if let Ok(transaction) = session.transaction(number) {
+                transaction.state = state;
+                transaction.client = None;
+            }
+        }
+    }
+
+    fn reap(&self, sessions: &mut HashMap<Vec<u8>, Session>) {
+        let timeout = self.timeout;
+        // transactions of expired sessions are rolled back when their
+        // connection is dropped
+        sessions.retain(|_, session| session.last_used.elapsed() < timeout);
+    }
+}
+
+/// The id of the session a command belongs to, from its `lsid`.
+pub fn session_id(doc: &Document) -> Option<Vec<u8>> {
+    match doc.get_document("lsid").ok()?.get("id")? {
+        Bson::Binary(binary) => Some(binary.bytes.clone()),
+        _ => None,
+    }
+}
+
+pub fn txn_number(doc: &Document) -> Option<i64> {
+    match doc.get("txnNumber")? {
+        Bson::Int64(n) => Some(*n),
+        Bson::Int32(n) => Some(*n as i64),
+        _ => None,
+    }
+}
+
+pub fn lsid(id: Vec<u8>) -> Document {
+    doc! {
+        "id": Bson::Binary(Binary {
+            subtype: BinarySubtype::Uuid,
+            bytes: id,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_start_and_end() {
+        let registry = SessionRegistry::new();
+        let lsid = registry.start();
+        let id = session_id(&doc! { "lsid": lsid }).unwrap();
+        assert_eq!(id.len(), 16);
+        assert!(registry.contains(&id));
+
+        registry.end(&id);
+        assert!(!registry.contains(&id));
+    }
+
+    #[test]
+    fn test_refresh_creates_session() {
+        let registry = SessionRegistry::new();
+        registry.refresh(&[1, 2, 3]);
+        assert!(registry.contains(&[1, 2, 3]));
+    }
+
+    #[test]
+    fn test_session_timeout() {
+        let registry = SessionRegistry::with_timeout(Duration::from_millis(10));
+        let lsid = registry.start();
+        let id = session_id(&doc! { "lsid": lsid }).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!registry.contains(&id));
+    }
+
+    #[test]
+    fn test_commit_unknown_transaction() {
+        let registry = SessionRegistry::new();
+        registry.refresh(&[1]);
+        match registry.commit(&[1], 1) {
+            Err(TransactionError::NoSuchTransaction(1)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        let err = registry.abort(&[2], 1).unwrap_err();
+        assert_eq!(err.to_doc().get_i32("code").unwrap(), 251);
+        assert_eq!(
+            err.to_doc().get_array("errorLabels").unwrap(),
+            &vec![Bson::String("TransientTransactionError".to_string())]
+        );
+    }
+}
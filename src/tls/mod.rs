@@ -0,0 +1,185 @@
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use rustls_pemfile::Item;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tokio_rustls::TlsAcceptor;
+
+pub mod pg;
+
+pub use self::pg::{connection_manager, MakeRustlsConnect};
+
+/// Certificate files used to serve TLS connections, all PEM encoded. When a
+/// CA is given, clients must present a certificate signed by it.
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub ca: Option<PathBuf>,
+}
+
+impl TlsOptions {
+    pub fn new(cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        TlsOptions {
+            cert: cert.into(),
+            key: key.into(),
+            ca: None,
+        }
+    }
+
+    pub fn with_ca(mut self, ca: impl Into<PathBuf>) -> Self {
+        self.ca = Some(ca.into());
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsError {
+    pub message: String,
+}
+
+impl TlsError {
+    fn new(message: String) -> Self {
+        TlsError { message }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// The TLS configuration of the listener. It can be reloaded from the files
+/// while the server runs, and only new connections pick up the change.
+#[derive(Clone)]
+pub struct TlsConfig {
+    options: TlsOptions,
+    current: Arc<RwLock<Arc<ServerConfig>>>,
+}
+
+impl TlsConfig {
+    pub fn load(options: TlsOptions) -> Result<Self, TlsError> {
+        let config = build_server_config(&options)?;
+        Ok(TlsConfig {
+            options,
+            current: Arc::new(RwLock::new(Arc::new(config))),
+        })
+    }
+
+    /// Reads the certificate files again. The current configuration is kept
+    /// if any of them is invalid.
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let config = build_server_config(&self.options)?;
+        *self.current.write().unwrap() = Arc::new(config);
+        Ok(())
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.current.read().unwrap().clone())
+    }
+}
+
+fn build_server_config(options: &TlsOptions) -> Result<ServerConfig, TlsError> {
+    let certs = read_certs(&options.cert)?;
+    let key = read_key(&options.key)?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &options.ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(ca)? {
+                roots.add(&cert).map_err(|e| {
+                    TlsError::new(format!("Invalid CA certificate in {}: {}", ca.display(), e))
+                })?;
+            }
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    builder
+        .with_single_cert(certs, key)
+        .map_err(|e| TlsError::new(format!("Invalid certificate or key: {}", e)))
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, TlsError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| TlsError::new(format!("Could not open {}: {}", path.display(), e)))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>, TlsError> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .map_err(|e| TlsError::new(format!("Could not read {}: {}", path.display(), e)))?;
+    if certs.is_empty() {
+        return Err(TlsError::new(format!(
+            "No PEM-encoded certificates in {}",
+            path.display()
+        )));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_key(path: &Path) -> Result<PrivateKey, TlsError> {
+    let mut reader = open(path)?;
+    loop {
+        match rustls_pemfile::read_one(&mut reader) {
+            Ok(Some(Item::PKCS8Key(key)))
+            | Ok(Some(Item::RSAKey(key)))
+            | Ok(Some(Item::ECKey(key))) => return Ok(PrivateKey(key)),
+            Ok(Some(_)) => continue,
+            Ok(None) => {
+                return Err(TlsError::new(format!(
+                    "No PEM-encoded private key in {}",
+                    path.display()
+                )))
+            }
+            Err(e) => {
+                return Err(TlsError::new(format!(
+                    "Could not read {}: {}",
+                    path.display(),
+                    e
+                )))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/tls")
+            .join(name)
+    }
+
+    #[test]
+    fn test_load() {
+        let options = TlsOptions::new(fixture("server.pem"), fixture("server.key"));
+        assert!(TlsConfig::load(options.clone()).is_ok());
+        assert!(TlsConfig::load(options.with_ca(fixture("ca.pem"))).is_ok());
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let options = TlsOptions::new(fixture("missing.pem"), fixture("server.key"));
+        let err = TlsConfig::load(options).err().unwrap();
+        assert!(err.message.starts_with("Could not open"), "{}", err);
+
+        let options = TlsOptions::new(fixture("server.pem"), fixture("server.pem"));
+        let err = TlsConfig::load(options).err().unwrap();
+        assert!(
+            err.message.starts_with("No PEM-encoded private key"),
+            "{}",
+            err
+        );
+    }
+}
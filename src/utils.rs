@@ -1,5 +1,6 @@
 #![allow(dead_code)]
 use crate::deserializer::PostgresJsonDeserializer;
+use crate::parser::escape;
 use bson::{doc, Bson, Document};
 use postgres::Row;
 use regex::Regex;
@@ -274,18 +275,18 @@ mod tests {
     }
 }
 
-pub fn pg_rows_to_bson(rows: Vec<Row>) -> Vec<Bson> {
+pub fn pg_rows_to_bson(rows: Vec<Row>) -> eyre::Result<Vec<Bson>> {
     let mut res: Vec<Bson> = vec![];
     for row in rows.iter() {
         let json_value: serde_json::Value = row.get(0);
-        let bson_value = json_value.from_psql_json();
+        let bson_value = json_value.from_psql_json()?;
         res.push(bson_value);
     }
-    res
+    Ok(res)
 }
 
 pub fn field_to_jsonb(key: &str) -> String {
-    format!("_jsonb->'{}'", key)
+    format!("_jsonb->'{}'", escape(key))
 }
 
 pub fn convert_if_numeric(field: &str) -> String {
@@ -5,6 +5,7 @@ use pretty_hex::*;
 use std::ffi::CString;
 use std::io::{BufRead, Cursor, Read, Write};
 
+mod op_compressed;
 mod op_msg;
 mod op_query;
 mod op_reply;
@@ -12,6 +13,7 @@ mod util;
 
 use crate::handler::{Request, Response};
 
+pub use self::op_compressed::{compress, Compressor, OpCompressed, SUPPORTED_COMPRESSORS};
 pub use self::op_msg::OpMsg;
 pub use self::op_msg::OpMsgSection;
 pub use self::op_query::OpQuery;
@@ -21,6 +23,7 @@ pub use self::op_reply::OpReply;
 pub const OP_MSG: u32 = 2013;
 pub const OP_REPLY: u32 = 1;
 pub const OP_QUERY: u32 = 2004;
+pub const OP_COMPRESSED: u32 = 2012;
 
 pub const MAX_DOCUMENT_LEN: u32 = 16777216;
 pub const MAX_MSG_LEN: u32 = 48000000;
@@ -30,9 +33,92 @@ pub const CHECKSUM_PRESENT: u32 = 1 << 0;
 pub const MORE_TO_COME: u32 = 1 << 1;
 pub const EXHAUST_ALLOWED: u32 = 1 << 16;
 
-#[derive(Debug, Clone)]
-pub struct OpCodeNotImplementedError {
-    op_code: u32,
+// bodies can be slightly larger than a stored document so that commands can
+// carry one along with their own fields
+const MAX_COMMAND_LEN: u32 = MAX_DOCUMENT_LEN + 16 * 1024;
+
+/// A message that couldn't be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// The message ends before the data its fields describe.
+    Truncated,
+    /// The header declares a length outside of the accepted range.
+    InvalidMessageLength(u32),
+    UnsupportedOpCode(u32),
+    UnknownSectionKind(u8),
+    /// The sections or fields of the message don't fit together.
+    InvalidMessage(String),
+    InvalidDocument(String),
+    DocumentTooLarge { size: u32, max: u32 },
+    ChecksumMismatch { expected: u32, actual: u32 },
+    Compression(String),
+}
+
+impl ProtocolError {
+    pub fn code(&self) -> i32 {
+        match self {
+            ProtocolError::InvalidDocument(_) => 22,
+            ProtocolError::DocumentTooLarge { .. } => 10334,
+            _ => 17,
+        }
+    }
+
+    pub fn code_name(&self) -> &'static str {
+        match self {
+            ProtocolError::InvalidDocument(_) => "InvalidBSON",
+            ProtocolError::DocumentTooLarge { .. } => "BSONObjectTooLarge",
+            _ => "ProtocolError",
+        }
+    }
+
+    /// Whether the connection can't be trusted anymore and must be closed
+    /// instead of answering with an error.
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            ProtocolError::InvalidMessageLength(_)
+                | ProtocolError::UnsupportedOpCode(_)
+                | ProtocolError::ChecksumMismatch { .. }
+                | ProtocolError::Compression(_)
+        )
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ProtocolError::Truncated => write!(f, "message is truncated"),
+            ProtocolError::InvalidMessageLength(len) => {
+                write!(f, "invalid message length {}", len)
+            }
+            ProtocolError::UnsupportedOpCode(op_code) => {
+                write!(f, "unsupported opcode {}", op_code)
+            }
+            ProtocolError::UnknownSectionKind(kind) => {
+                write!(f, "unknown OP_MSG section kind {}", kind)
+            }
+            ProtocolError::InvalidMessage(message) => write!(f, "{}", message),
+            ProtocolError::InvalidDocument(message) => write!(f, "invalid BSON: {}", message),
+            ProtocolError::DocumentTooLarge { size, max } => {
+                write!(f, "document of {} bytes exceeds the {} bytes limit", size, max)
+            }
+            ProtocolError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "checksum mismatch: expected {:#x}, got {:#x}",
+                expected, actual
+            ),
+            ProtocolError::Compression(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+// reads past the end of a message are the only io errors of a cursor
+impl From<std::io::Error> for ProtocolError {
+    fn from(_: std::io::Error) -> Self {
+        ProtocolError::Truncated
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -47,18 +133,8 @@ pub struct MsgHeader {
 }
 
 impl MsgHeader {
-    pub fn from_bytes(bytes: Vec<u8>) -> Result<MsgHeader, UnknownMessageKindError> {
-        let mut cursor = Cursor::new(bytes);
-        let message_length = cursor.read_u32::<LittleEndian>().unwrap();
-        let request_id = cursor.read_u32::<LittleEndian>().unwrap();
-        let response_to = cursor.read_u32::<LittleEndian>().unwrap();
-        let op_code = cursor.read_u32::<LittleEndian>().unwrap();
-        Ok(MsgHeader {
-            message_length,
-            request_id,
-            response_to,
-            op_code,
-        })
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<MsgHeader, ProtocolError> {
+        MsgHeader::parse(&mut Cursor::new(&bytes))
     }
 
     pub fn get_response(&self, request_id: u32, message_length: u32) -> MsgHeader {
@@ -85,6 +161,7 @@ pub enum OpCode {
     OpMsg(OpMsg),
     OpQuery(OpQuery),
     OpReply(OpReply),
+    OpCompressed(OpCompressed),
 }
 
 impl OpCode {
@@ -92,12 +169,49 @@ impl OpCode {
         match self {
             OpCode::OpMsg(op_msg) => Ok(op_msg.reply(response).unwrap()),
             OpCode::OpQuery(op_query) => Ok(op_query.reply(response).unwrap()),
+            OpCode::OpCompressed(op_compressed) => op_compressed.reply(response),
             _ => {
                 log::error!("Unknown message during reply - {:#?}", self);
                 Err(UnknownMessageKindError)
             }
         }
     }
+
+    /// Whether the client flagged the message with moreToCome, in which case
+    /// it doesn't expect a reply.
+    pub fn more_to_come(&self) -> bool {
+        match self {
+            OpCode::OpMsg(op_msg) => op_msg.flags & MORE_TO_COME != 0,
+            OpCode::OpCompressed(op_compressed) => op_compressed.message.more_to_come(),
+            _ => false,
+        }
+    }
+
+    /// Whether the client accepts several replies streamed with moreToCome.
+    pub fn exhaust_allowed(&self) -> bool {
+        match self {
+            OpCode::OpMsg(op_msg) => op_msg.flags & EXHAUST_ALLOWED != 0,
+            OpCode::OpCompressed(op_compressed) => op_compressed.message.exhaust_allowed(),
+            _ => false,
+        }
+    }
+
+    /// Returns the same message with a different request id, so that the
+    /// reply to it is sent in response to that id. Used to chain the replies
+    /// streamed to exhaust cursors.
+    pub fn with_request_id(&self, request_id: u32) -> OpCode {
+        let mut op_code = self.clone();
+        match &mut op_code {
+            OpCode::OpMsg(op_msg) => op_msg.header.request_id = request_id,
+            OpCode::OpQuery(op_query) => op_query.header.request_id = request_id,
+            OpCode::OpReply(op_reply) => op_reply.header.request_id = request_id,
+            OpCode::OpCompressed(op_compressed) => {
+                op_compressed.header.request_id = request_id;
+                op_compressed.message = Box::new(op_compressed.message.with_request_id(request_id));
+            }
+        }
+        op_code
+    }
 }
 
 pub trait Replyable {
@@ -110,23 +224,62 @@ pub trait Serializable {
     fn to_vec(&self) -> Vec<u8>;
 }
 
-pub fn parse(buffer: &[u8]) -> Result<OpCode, OpCodeNotImplementedError> {
-    let mut cursor = Cursor::new(buffer);
-    let header = MsgHeader::parse(&mut cursor);
-
-    if header.op_code == OP_MSG {
-        let mut msg_buffer: Vec<u8> = vec![0; header.message_length as usize];
-        cursor.set_position(0);
-        cursor.read_exact(&mut msg_buffer).unwrap();
-
-        Ok(OpCode::OpMsg(OpMsg::from_bytes(&msg_buffer).unwrap()))
-    } else if header.op_code == OP_QUERY {
-        Ok(OpCode::OpQuery(OpQuery::parse(header, &mut cursor)))
-    } else {
-        Err(OpCodeNotImplementedError {
-            op_code: header.op_code,
-        })
+pub fn parse(buffer: &[u8]) -> Result<OpCode, ProtocolError> {
+    let header = MsgHeader::parse(&mut Cursor::new(buffer))?;
+    let length = header.message_length;
+    if !(HEADER_SIZE..=MAX_MSG_LEN).contains(&length) {
+        return Err(ProtocolError::InvalidMessageLength(length));
+    }
+    if buffer.len() < length as usize {
+        return Err(ProtocolError::Truncated);
+    }
+
+    let message = &buffer[..length as usize];
+    let mut cursor = Cursor::new(message);
+    cursor.set_position(HEADER_SIZE as u64);
+
+    match header.op_code {
+        OP_MSG => Ok(OpCode::OpMsg(OpMsg::from_bytes(message)?)),
+        OP_QUERY => Ok(OpCode::OpQuery(OpQuery::parse(header, &mut cursor)?)),
+        OP_COMPRESSED => Ok(OpCode::OpCompressed(OpCompressed::parse(
+            header,
+            &mut cursor,
+        )?)),
+        op_code => Err(ProtocolError::UnsupportedOpCode(op_code)),
+    }
+}
+
+/// Builds the error reply to a message that couldn't be parsed, as long as
+/// the error leaves the connection usable and the header tells how to reply.
+/// Returns `None` when the connection should be closed instead.
+pub fn error_reply(buffer: &[u8], request_id: u32, error: &ProtocolError) -> Option<Vec<u8>> {
+    if error.is_fatal() {
+        return None;
     }
+    let header = MsgHeader::parse(&mut Cursor::new(buffer)).ok()?;
+    let op_code = match header.op_code {
+        OP_MSG => OpCode::OpMsg(OpMsg::new_with_body_kind(header, 0, None, &doc! {})),
+        OP_QUERY => OpCode::OpQuery(OpQuery {
+            header,
+            flags: 0,
+            collection: String::new(),
+            number_to_skip: 0,
+            number_to_return: 0,
+            query: doc! {},
+            return_fields: None,
+        }),
+        _ => return None,
+    };
+
+    let err = doc! {
+        "ok": Bson::Double(0.0),
+        "errmsg": error.to_string(),
+        "code": error.code(),
+        "codeName": error.code_name(),
+    };
+    op_code
+        .reply(Response::new(request_id, &op_code, vec![err]))
+        .ok()
 }
 
 impl MsgHeader {
@@ -139,17 +292,17 @@ impl MsgHeader {
         }
     }
 
-    fn parse(cursor: &mut Cursor<&[u8]>) -> MsgHeader {
-        let message_length = cursor.read_u32::<LittleEndian>().unwrap();
-        let request_id = cursor.read_u32::<LittleEndian>().unwrap();
-        let response_to = cursor.read_u32::<LittleEndian>().unwrap();
-        let op_code = cursor.read_u32::<LittleEndian>().unwrap();
-        MsgHeader {
+    fn parse<T: AsRef<[u8]>>(cursor: &mut Cursor<T>) -> Result<MsgHeader, ProtocolError> {
+        let message_length = cursor.read_u32::<LittleEndian>()?;
+        let request_id = cursor.read_u32::<LittleEndian>()?;
+        let response_to = cursor.read_u32::<LittleEndian>()?;
+        let op_code = cursor.read_u32::<LittleEndian>()?;
+        Ok(MsgHeader {
             message_length,
             request_id,
             response_to,
             op_code,
-        }
+        })
     }
 
     fn to_vec(&self) -> Vec<u8> {
@@ -0,0 +1,254 @@
+use crate::handler::Response;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use std::io::{self, Cursor, Read, Write};
+
+use super::{
+    parse, MsgHeader, OpCode, ProtocolError, Replyable, UnknownMessageKindError, HEADER_SIZE,
+    MAX_MSG_LEN, OP_COMPRESSED,
+};
+
+/// Compressors offered to clients during the handshake, in order of
+/// preference.
+pub const SUPPORTED_COMPRESSORS: [Compressor; 2] = [Compressor::Zstd, Compressor::Zlib];
+
+// originalOpcode, uncompressedSize and compressorId
+const COMPRESSED_HEADER_SIZE: u32 = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compressor {
+    Noop,
+    Zlib,
+    Zstd,
+}
+
+impl Compressor {
+    pub fn from_id(id: u8) -> Option<Compressor> {
+        match id {
+            0 => Some(Compressor::Noop),
+            2 => Some(Compressor::Zlib),
+            3 => Some(Compressor::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Compressor> {
+        SUPPORTED_COMPRESSORS
+            .into_iter()
+            .find(|compressor| compressor.name() == name)
+    }
+
+    pub fn id(&self) -> u8 {
+        match self {
+            Compressor::Noop => 0,
+            Compressor::Zlib => 2,
+            Compressor::Zstd => 3,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compressor::Noop => "noop",
+            Compressor::Zlib => "zlib",
+            Compressor::Zstd => "zstd",
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compressor::Noop => Ok(data.to_vec()),
+            Compressor::Zlib => {
+                let mut encoder = ZlibEncoder::new(vec![], flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Compressor::Zstd => zstd::encode_all(data, zstd::DEFAULT_COMPRESSION_LEVEL),
+        }
+    }
+
+    /// Decompresses `data`, reading at most `max_len` bytes of output.
+    pub fn decompress(&self, data: &[u8], max_len: u64) -> io::Result<Vec<u8>> {
+        let mut decoded = vec![];
+        match self {
+            Compressor::Noop => data.take(max_len).read_to_end(&mut decoded)?,
+            Compressor::Zlib => ZlibDecoder::new(data)
+                .take(max_len)
+                .read_to_end(&mut decoded)?,
+            Compressor::Zstd => zstd::Decoder::new(data)?
+                .take(max_len)
+                .read_to_end(&mut decoded)?,
+        };
+        Ok(decoded)
+    }
+}
+
+/// A message sent inside OP_COMPRESSED. Replies to it are compressed with the
+/// same compressor the client used.
+#[derive(Debug, Clone)]
+pub struct OpCompressed {
+    pub header: MsgHeader,
+    pub compressor: Compressor,
+    pub message: Box<OpCode>,
+}
+
+impl OpCompressed {
+    pub fn parse(
+        header: MsgHeader,
+        cursor: &mut Cursor<&[u8]>,
+    ) -> Result<OpCompressed, ProtocolError> {
+        let original_op_code = cursor.read_u32::<LittleEndian>()?;
+        let uncompressed_size = cursor.read_u32::<LittleEndian>()?;
+        let compressor_id = cursor.read_u8()?;
+
+        let compressor = Compressor::from_id(compressor_id).ok_or_else(|| {
+            ProtocolError::Compression(format!("unsupported compressor id {}", compressor_id))
+        })?;
+        if original_op_code == OP_COMPRESSED {
+            return Err(ProtocolError::Compression(
+                "OP_COMPRESSED can't be nested".to_string(),
+            ));
+        }
+        if uncompressed_size > MAX_MSG_LEN - HEADER_SIZE {
+            return Err(ProtocolError::InvalidMessageLength(
+                HEADER_SIZE.saturating_add(uncompressed_size),
+            ));
+        }
+
+        let start = cursor.position() as usize;
+        let compressed = &cursor.get_ref()[start..];
+        // reading one byte more than announced tells if the size is wrong
+        let max_len = uncompressed_size as u64 + 1;
+        let body = compressor.decompress(compressed, max_len).map_err(|e| {
+            ProtocolError::Compression(format!(
+                "could not decompress {} message: {}",
+                compressor.name(),
+                e
+            ))
+        })?;
+        if body.len() != uncompressed_size as usize {
+            return Err(ProtocolError::Compression(format!(
+                "compressed message has {} bytes, expected {}",
+                body.len(),
+                uncompressed_size
+            )));
+        }
+
+        let original_header = MsgHeader::new(
+            HEADER_SIZE + uncompressed_size,
+            header.request_id,
+            header.response_to,
+            original_op_code,
+        );
+        let mut buffer = original_header.to_vec();
+        buffer.extend(body);
+
+        Ok(OpCompressed {
+            header,
+            compressor,
+            message: Box::new(parse(&buffer)?),
+        })
+    }
+}
+
+impl Replyable for OpCompressed {
+    fn reply(&self, res: Response) -> Result<Vec<u8>, UnknownMessageKindError> {
+        let response = Response::new(res.get_id(), &self.message, vec![res.get_doc().clone()])
+            .with_more_to_come(res.more_to_come());
+        let reply = self.message.reply(response)?;
+        Ok(compress(&reply, self.compressor))
+    }
+}
+
+/// Wraps a serialized message in OP_COMPRESSED.
+pub fn compress(message: &[u8], compressor: Compressor) -> Vec<u8> {
+    let mut cursor = Cursor::new(&message[..HEADER_SIZE as usize]);
+    let header = MsgHeader::parse(&mut cursor).unwrap();
+    let body = &message[HEADER_SIZE as usize..];
+    let compressed = compressor.compress(body).unwrap();
+
+    let message_length = HEADER_SIZE + COMPRESSED_HEADER_SIZE + compressed.len() as u32;
+    let compressed_header = MsgHeader::new(
+        message_length,
+        header.request_id,
+        header.response_to,
+        OP_COMPRESSED,
+    );
+
+    let mut writer = Cursor::new(compressed_header.to_vec());
+    writer.set_position(HEADER_SIZE as u64);
+    writer.write_u32::<LittleEndian>(header.op_code).unwrap();
+    writer.write_u32::<LittleEndian>(body.len() as u32).unwrap();
+    writer.write_u8(compressor.id()).unwrap();
+    writer.write_all(&compressed).unwrap();
+    writer.into_inner()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::{OpMsg, Serializable, OP_MSG};
+    use bson::doc;
+
+    fn op_msg() -> OpMsg {
+        let header = MsgHeader::new(0, 7, 0, OP_MSG);
+        let mut msg =
+            OpMsg::new_with_body_kind(header, 0, None, &doc! { "ping": 1, "$db": "test" });
+        msg.header.message_length = msg.to_vec().len() as u32;
+        msg
+    }
+
+    #[test]
+    fn test_compressors() {
+        let data = b"hello hello hello hello hello hello".to_vec();
+        for compressor in [Compressor::Noop, Compressor::Zlib, Compressor::Zstd] {
+            let compressed = compressor.compress(&data).unwrap();
+            assert_eq!(compressor.decompress(&compressed, 1024).unwrap(), data);
+            assert_eq!(compressor.decompress(&compressed, 5).unwrap(), &data[..5]);
+            assert_eq!(Compressor::from_id(compressor.id()), Some(compressor));
+        }
+        assert_eq!(Compressor::from_id(1), None);
+        assert_eq!(Compressor::from_name("zstd"), Some(Compressor::Zstd));
+        assert_eq!(Compressor::from_name("snappy"), None);
+        assert_eq!(Compressor::from_name("noop"), None);
+    }
+
+    #[test]
+    fn test_parse_compressed() {
+        for compressor in [Compressor::Noop, Compressor::Zlib, Compressor::Zstd] {
+            let buffer = compress(&op_msg().to_vec(), compressor);
+            assert_eq!(
+                u32::from_le_bytes(buffer[..4].try_into().unwrap()) as usize,
+                buffer.len()
+            );
+
+            match parse(&buffer).unwrap() {
+                OpCode::OpCompressed(op_compressed) => {
+                    assert_eq!(op_compressed.compressor, compressor);
+                    assert_eq!(op_compressed.header.op_code, OP_COMPRESSED);
+                    match *op_compressed.message {
+                        OpCode::OpMsg(msg) => {
+                            assert_eq!(msg.header.request_id, 7);
+                            assert_eq!(msg.sections[0].documents[0].get_i32("ping"), Ok(1));
+                        }
+                        other => panic!("unexpected message {:?}", other),
+                    }
+                }
+                other => panic!("unexpected message {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid_compressed() {
+        let mut buffer = compress(&op_msg().to_vec(), Compressor::Zlib);
+        // snappy isn't supported
+        buffer[24] = 1;
+        assert!(parse(&buffer).is_err());
+
+        // wrong uncompressed size
+        let mut buffer = compress(&op_msg().to_vec(), Compressor::Zstd);
+        buffer[20] += 1;
+        assert!(parse(&buffer).is_err());
+    }
+}
@@ -1,9 +1,12 @@
 use crate::handler::{Request, Response};
 use crate::utils::to_cstring;
 use crate::wire::Replyable;
-use crate::wire::{OpCode, UnknownMessageKindError, CHECKSUM_PRESENT, HEADER_SIZE, OP_MSG};
+use crate::wire::{
+    OpCode, ProtocolError, UnknownMessageKindError, CHECKSUM_PRESENT, HEADER_SIZE, MORE_TO_COME,
+    OP_MSG,
+};
 use bson::{doc, ser, Bson, Document};
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 use pretty_hex::pretty_hex;
 use std::ffi::CString;
 use std::io::{BufRead, Cursor, Read, Write};
@@ -27,9 +30,7 @@ pub struct OpMsgSection {
 }
 
 impl OpMsgSection {
-    pub fn from_bytes(
-        mut bytes: Vec<u8>,
-    ) -> Result<(OpMsgSection, Vec<u8>), UnknownMessageKindError> {
+    pub fn from_bytes(mut bytes: Vec<u8>) -> Result<(OpMsgSection, Vec<u8>), ProtocolError> {
         parse_section(&mut bytes)
     }
 }
@@ -53,41 +54,94 @@ impl OpMsg {
         }
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Result<OpMsg, UnknownMessageKindError> {
-        let mut cursor = Cursor::new(bytes);
-        let mut header_buffer: Vec<u8> = vec![0u8; HEADER_SIZE as usize];
-        cursor.read_exact(&mut header_buffer).unwrap();
+    pub fn from_bytes(message: &[u8]) -> Result<OpMsg, ProtocolError> {
+        let mut cursor = Cursor::new(message);
+        let header = MsgHeader::parse(&mut cursor)?;
+        let flags = cursor.read_u32::<LittleEndian>()?;
+        let mut bytes = message[cursor.position() as usize..].to_vec();
 
-        let header = MsgHeader::from_bytes(header_buffer).unwrap();
-        let flags = cursor.read_u32::<LittleEndian>().unwrap();
-
-        let mut bytes: Vec<u8> = vec![];
-        cursor.read_to_end(&mut bytes).unwrap();
-
-        let mut sections = vec![];
-        loop {
-            let (section, remaining) = parse_section(&mut bytes).unwrap();
-            bytes = remaining;
-            sections.push(section);
-            if bytes.is_empty() {
-                break;
+        // the checksum covers the whole message up to the checksum itself
+        let mut checksum = None;
+        if flags & CHECKSUM_PRESENT != 0 {
+            if bytes.len() < 4 {
+                return Err(ProtocolError::Truncated);
             }
-            if (bytes.len() as u64) <= 4 {
-                break;
+            let tail = bytes.split_off(bytes.len() - 4);
+            let expected = LittleEndian::read_u32(&tail);
+            let actual = crc32c::crc32c(&message[..message.len() - 4]);
+            if expected != actual {
+                return Err(ProtocolError::ChecksumMismatch { expected, actual });
             }
+            checksum = Some(expected);
         }
 
-        let mut checksum = None;
-        if flags & CHECKSUM_PRESENT != 0 {
-            checksum = Some(cursor.read_u32::<LittleEndian>().unwrap());
+        let mut sections = vec![];
+        while !bytes.is_empty() {
+            let (section, remaining) = parse_section(&mut bytes)?;
+            bytes = remaining;
+            sections.push(section);
         }
 
-        Ok(OpMsg {
+        let msg = OpMsg {
             header,
             flags,
             sections,
             checksum,
-        })
+        };
+        msg.validate()?;
+        Ok(msg)
+    }
+
+    /// Returns the body with each document sequence added to it as an array
+    /// under the sequence identifier, which is how commands see them.
+    pub fn to_command(&self) -> Document {
+        let mut command = self
+            .sections
+            .iter()
+            .find(|section| section.kind == 0)
+            .map(|section| section.documents[0].clone())
+            .unwrap_or_default();
+        for section in self.sections.iter().filter(|section| section.kind == 1) {
+            let documents: Vec<Bson> = section
+                .documents
+                .iter()
+                .cloned()
+                .map(Bson::Document)
+                .collect();
+            command.insert(section.identifier.clone().unwrap_or_default(), documents);
+        }
+        command
+    }
+
+    // there must be exactly one body, and sequences can't repeat a field
+    fn validate(&self) -> Result<(), ProtocolError> {
+        let mut bodies = self.sections.iter().filter(|section| section.kind == 0);
+        let body = match (bodies.next(), bodies.next()) {
+            (Some(body), None) => &body.documents[0],
+            (None, _) => {
+                return Err(ProtocolError::InvalidMessage(
+                    "OP_MSG requires a body section".to_string(),
+                ))
+            }
+            (Some(_), Some(_)) => {
+                return Err(ProtocolError::InvalidMessage(
+                    "OP_MSG contains more than one body section".to_string(),
+                ))
+            }
+        };
+
+        let mut identifiers = vec![];
+        for section in self.sections.iter().filter(|section| section.kind == 1) {
+            let identifier = section.identifier.as_deref().unwrap_or_default();
+            if body.contains_key(identifier) || identifiers.contains(&identifier) {
+                return Err(ProtocolError::InvalidMessage(format!(
+                    "duplicate field name in OP_MSG: {}",
+                    identifier
+                )));
+            }
+            identifiers.push(identifier);
+        }
+        Ok(())
     }
 }
 
@@ -96,27 +150,22 @@ impl Replyable for OpMsg {
         // FIXME extract this serialization of a document to a helper
         let bson_vec = ser::to_vec(&res.get_doc()).unwrap();
         let bson_data: &[u8] = &bson_vec;
-        let message_length = HEADER_SIZE + 5 + bson_data.len() as u32;
+
+        // checksums are only sent back to clients that send them
+        let mut flags = self.flags & CHECKSUM_PRESENT;
+        if res.more_to_come() {
+            flags |= MORE_TO_COME;
+        }
+        let checksum_len = if flags & CHECKSUM_PRESENT != 0 { 4 } else { 0 };
+        let message_length = HEADER_SIZE + 5 + bson_data.len() as u32 + checksum_len;
 
         if let OpCode::OpMsg(op_msg) = res.get_op_code().to_owned() {
             let header = op_msg.header.get_response(res.get_id(), message_length);
 
             if self.sections.len() > 0 && self.sections[0].kind == 0 {
-                return Ok(OpMsg::new_with_body_kind(
-                    header,
-                    self.flags,
-                    self.checksum,
-                    res.get_doc(),
-                )
-                .to_vec());
+                return Ok(OpMsg::new_with_body_kind(header, flags, None, res.get_doc()).to_vec());
             } else if self.sections.len() > 0 && self.sections[0].kind == 1 {
-                return Ok(OpMsg::new_with_body_kind(
-                    header,
-                    self.flags,
-                    self.checksum,
-                    res.get_doc(),
-                )
-                .to_vec());
+                return Ok(OpMsg::new_with_body_kind(header, flags, None, res.get_doc()).to_vec());
             }
         }
 
@@ -135,17 +184,23 @@ impl Serializable for OpMsg {
         writer.write_all(&self.header.to_vec()).unwrap();
         writer.write_u32::<LittleEndian>(self.flags).unwrap();
         for section in &self.sections {
-            writer.write(&[section.kind]).unwrap();
+            writer.write_u8(section.kind).unwrap();
+            let mut documents = vec![];
             for doc in &section.documents {
-                let bson_vec = ser::to_vec(&doc).unwrap();
-                let bson_data: &[u8] = &bson_vec;
-                writer.write(bson_data).unwrap();
+                documents.extend(ser::to_vec(&doc).unwrap());
+            }
+            if let Some(identifier) = &section.identifier {
+                // document sequences are prefixed with their size and identifier
+                let size = 4 + identifier.len() + 1 + documents.len();
+                writer.write_u32::<LittleEndian>(size as u32).unwrap();
+                writer.write_all(identifier.as_bytes()).unwrap();
+                writer.write_u8(0).unwrap();
             }
+            writer.write_all(&documents).unwrap();
         }
         if (self.flags & CHECKSUM_PRESENT) != 0 {
-            writer
-                .write_u32::<LittleEndian>(self.checksum.unwrap())
-                .unwrap();
+            let checksum = crc32c::crc32c(writer.get_ref());
+            writer.write_u32::<LittleEndian>(checksum).unwrap();
         }
         writer.into_inner()
     }
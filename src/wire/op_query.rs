@@ -5,9 +5,10 @@ use std::io::{BufRead, Cursor, Read, Write};
 
 use crate::handler::{Request, Response};
 
+use super::util::{read_cstring, read_document};
 use super::{
-    MsgHeader, OpCode, OpReply, Replyable, Serializable, UnknownMessageKindError, HEADER_SIZE,
-    OP_REPLY,
+    MsgHeader, OpCode, OpReply, ProtocolError, Replyable, Serializable, UnknownMessageKindError,
+    HEADER_SIZE, MAX_COMMAND_LEN, MAX_DOCUMENT_LEN, OP_REPLY,
 };
 
 #[derive(Debug, Clone)]
@@ -22,44 +23,21 @@ pub struct OpQuery {
 }
 
 impl OpQuery {
-    pub fn parse(header: MsgHeader, cursor: &mut Cursor<&[u8]>) -> OpQuery {
-        let flags = cursor.read_u32::<LittleEndian>().unwrap();
+    pub fn parse(header: MsgHeader, cursor: &mut Cursor<&[u8]>) -> Result<OpQuery, ProtocolError> {
+        let flags = cursor.read_u32::<LittleEndian>()?;
+        let collection = read_cstring(cursor)?;
+        let number_to_skip = cursor.read_u32::<LittleEndian>()?;
+        let number_to_return = cursor.read_u32::<LittleEndian>()?;
+        let query = read_document(cursor, MAX_COMMAND_LEN)?;
 
-        // collection is a CString
-        let mut buffer: Vec<u8> = vec![];
-        cursor.read_until(0, &mut buffer).unwrap();
-        let collection = unsafe { CString::from_vec_unchecked(buffer) }
-            .to_string_lossy()
-            .to_string();
-
-        let number_to_skip = cursor.read_u32::<LittleEndian>().unwrap();
-        let number_to_return = cursor.read_u32::<LittleEndian>().unwrap();
-        let mut new_cursor = cursor.clone();
-        new_cursor.set_position(cursor.position());
-
-        let len = cursor.get_ref().len();
-        if (cursor.position() as usize) < len - 1 {
-            return OpQuery {
-                header,
-                flags,
-                collection,
-                number_to_skip,
-                number_to_return,
-                query: doc! {},
-                return_fields: None,
-            };
-        }
-
-        let query = Document::from_reader(cursor).unwrap();
-        let bson_vec = ser::to_vec(&query).unwrap();
-        let query_size: u64 = bson_vec.len().try_into().unwrap();
-        new_cursor.set_position(new_cursor.position() + query_size);
-        let return_fields = match Document::from_reader(new_cursor) {
-            Ok(doc) => Some(doc),
-            Err(_) => None,
+        // the projection is optional
+        let return_fields = if (cursor.position() as usize) < cursor.get_ref().len() {
+            Some(read_document(cursor, MAX_DOCUMENT_LEN)?)
+        } else {
+            None
         };
 
-        OpQuery {
+        Ok(OpQuery {
             header,
             flags,
             collection,
@@ -67,7 +45,7 @@ impl OpQuery {
             number_to_return,
             query,
             return_fields,
-        }
+        })
     }
 }
 
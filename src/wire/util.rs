@@ -1,107 +1,117 @@
-use crate::utils::to_cstring;
 use crate::wire::{OpMsg, OpMsgSection};
-use bson::{ser, Document};
+use bson::Document;
 use byteorder::{LittleEndian, ReadBytesExt};
 use indoc::indoc;
-use std::io::{BufRead, Cursor, Read};
+use std::io::{BufRead, Cursor};
 
-use super::UnknownMessageKindError;
+use super::{ProtocolError, MAX_COMMAND_LEN, MAX_DOCUMENT_LEN};
 
-pub fn parse_section(
-    bytes: &mut Vec<u8>,
-) -> Result<(OpMsgSection, Vec<u8>), UnknownMessageKindError> {
-    let kind = bytes[0];
-    if kind == 0 {
-        return Ok(parse_kind0(bytes.clone()));
-    } else if kind == 1 {
-        return Ok(parse_kind1(bytes.clone()));
+pub fn parse_section(bytes: &mut Vec<u8>) -> Result<(OpMsgSection, Vec<u8>), ProtocolError> {
+    match bytes.first() {
+        Some(0) => parse_kind0(bytes),
+        Some(1) => parse_kind1(bytes),
+        Some(kind) => Err(ProtocolError::UnknownSectionKind(*kind)),
+        None => Err(ProtocolError::Truncated),
     }
-
-    // FIXME add the kind to the error
-    Err(UnknownMessageKindError {})
 }
 
-fn parse_kind0(bytes: Vec<u8>) -> (OpMsgSection, Vec<u8>) {
-    let mut cursor = Cursor::new(bytes);
+/// Reads a BSON document, checking its declared size against `max_len` and
+/// the bytes left before parsing it.
+pub fn read_document(cursor: &mut Cursor<&[u8]>, max_len: u32) -> Result<Document, ProtocolError> {
+    let start = cursor.position() as usize;
+    let size = cursor.read_i32::<LittleEndian>()?;
+    if size < 5 {
+        return Err(ProtocolError::InvalidDocument(format!(
+            "invalid document size {}",
+            size
+        )));
+    }
+    if size as u32 > max_len {
+        return Err(ProtocolError::DocumentTooLarge {
+            size: size as u32,
+            max: max_len,
+        });
+    }
 
-    let kind = cursor.read_u8().unwrap();
+    let end = start + size as usize;
+    let data = *cursor.get_ref();
+    if end > data.len() {
+        return Err(ProtocolError::Truncated);
+    }
+    let document = Document::from_reader(&data[start..end])
+        .map_err(|e| ProtocolError::InvalidDocument(e.to_string()))?;
+    cursor.set_position(end as u64);
+    Ok(document)
+}
 
-    let mut new_cursor = cursor.clone();
-    let document = Document::from_reader(cursor).unwrap();
-    let bson_vec = ser::to_vec(&document).unwrap();
-    new_cursor.set_position(new_cursor.position() + bson_vec.len() as u64);
+/// Reads a null terminated UTF-8 string.
+pub fn read_cstring(cursor: &mut Cursor<&[u8]>) -> Result<String, ProtocolError> {
+    let mut buffer: Vec<u8> = vec![];
+    cursor.read_until(0, &mut buffer)?;
+    if buffer.pop() != Some(0) {
+        return Err(ProtocolError::Truncated);
+    }
+    String::from_utf8(buffer)
+        .map_err(|_| ProtocolError::InvalidMessage("string is not valid UTF-8".to_string()))
+}
 
-    let mut tail: Vec<u8> = vec![];
-    new_cursor.read_to_end(&mut tail).unwrap();
+fn parse_kind0(bytes: &[u8]) -> Result<(OpMsgSection, Vec<u8>), ProtocolError> {
+    let mut cursor = Cursor::new(bytes);
+    let kind = cursor.read_u8()?;
+    let document = read_document(&mut cursor, MAX_COMMAND_LEN)?;
+    let tail = bytes[cursor.position() as usize..].to_vec();
 
-    (
+    Ok((
         OpMsgSection {
             kind,
             identifier: None,
             documents: vec![document],
         },
         tail,
-    )
+    ))
 }
 
-fn parse_kind1_documents(data: &[u8]) -> Vec<Document> {
-    let size = data.len();
-    let mut cursor = Cursor::new(data);
-    let mut documents = Vec::new();
-
-    let mut read_size = 0;
-    while read_size < size as usize {
-        let doc_cursor = cursor.clone();
-        let document = Document::from_reader(doc_cursor).unwrap();
-        let bson_vec = ser::to_vec(&document).unwrap();
-        let document_size = bson_vec.len();
-        cursor.set_position(cursor.position() + document_size as u64);
-
-        documents.push(document);
-        read_size += document_size;
-    }
-
-    return documents;
-}
-
-fn parse_kind1(bytes: Vec<u8>) -> (OpMsgSection, Vec<u8>) {
+fn parse_kind1(bytes: &[u8]) -> Result<(OpMsgSection, Vec<u8>), ProtocolError> {
     let mut cursor = Cursor::new(bytes);
+    let kind = cursor.read_u8()?;
+
+    // the size counts itself, the identifier and the documents
+    let size = cursor.read_i32::<LittleEndian>()?;
+    if size < 5 {
+        return Err(ProtocolError::InvalidMessage(format!(
+            "invalid document sequence size {}",
+            size
+        )));
+    }
+    let end = 1 + size as usize;
+    if end > bytes.len() {
+        return Err(ProtocolError::Truncated);
+    }
 
-    // session kind
-    let kind = cursor.read_u8().unwrap();
-
-    // session contents size
-    let size = cursor.read_u32::<LittleEndian>().unwrap();
-
-    // identifier
-    let mut identifier_buffer: Vec<u8> = vec![];
-    cursor.read_until(0, &mut identifier_buffer).unwrap();
-    let identifier_size: u32 = identifier_buffer.len() as u32;
-    let identifier = to_cstring(identifier_buffer);
-
-    // whole section = size - sizeof(size) - sizeof(identifier)
-    //                 size - 4 - len(identifier_buffer)
-    let remaining_size: u32 = size - 4 - identifier_size;
-    let mut section_buffer: Vec<u8> = vec![0u8; remaining_size as usize];
-    cursor.read_exact(&mut section_buffer).unwrap();
+    let mut cursor = Cursor::new(&bytes[..end]);
+    cursor.set_position(5);
+    let identifier = read_cstring(&mut cursor)?;
 
-    let documents = parse_kind1_documents(&section_buffer);
-    let mut tail: Vec<u8> = vec![];
-    cursor.read_to_end(&mut tail).unwrap();
+    let mut documents = vec![];
+    while (cursor.position() as usize) < end {
+        documents.push(read_document(&mut cursor, MAX_DOCUMENT_LEN)?);
+    }
 
-    (
+    Ok((
         OpMsgSection {
             kind,
             identifier: Some(identifier),
             documents,
         },
-        tail,
-    )
+        bytes[end..].to_vec(),
+    ))
 }
 
 #[cfg(test)]
 mod tests {
     use crate::utils::hexstring_to_bytes;
+    use crate::wire::{MsgHeader, Serializable, HEADER_SIZE, OP_MSG};
+    use bson::doc;
 
     use super::*;
 
@@ -121,7 +131,7 @@ mod tests {
         let mut bytes = hexstring_to_bytes(kind1kind0);
         let (section1, mut bytes) = parse_section(&mut bytes).unwrap();
         assert_eq!(section1.kind, 1);
-        assert_eq!(section1.identifier.unwrap(), "documents\0");
+        assert_eq!(section1.identifier.unwrap(), "documents");
         assert_eq!(section1.documents.len(), 1);
         assert_eq!(
             section1.documents[0].get_object_id("_id").unwrap(),
@@ -211,7 +221,7 @@ mod tests {
         let identifier = section1.identifier.clone().unwrap();
         assert_eq!(section1.kind, 1);
         assert_eq!(section1.documents.len(), 1);
-        assert_eq!(identifier, "documents\0");
+        assert_eq!(identifier, "documents");
         assert_eq!(
             section1.documents[0].get_object_id("_id").unwrap(),
             bson::oid::ObjectId::parse_str("62ced69a337879a1acc29d40").unwrap()
@@ -226,4 +236,138 @@ mod tests {
         assert_eq!(section0.documents[0].get_bool("ordered").unwrap(), true);
         assert_eq!(section0.documents[0].get_str("$db").unwrap(), "test");
     }
+
+    fn op_msg(sections: Vec<OpMsgSection>) -> Vec<u8> {
+        let header = MsgHeader {
+            message_length: 0,
+            request_id: 1,
+            response_to: 0,
+            op_code: OP_MSG,
+        };
+        let mut msg = OpMsg {
+            header,
+            flags: 0,
+            sections,
+            checksum: None,
+        };
+        msg.header.message_length = msg.to_vec().len() as u32;
+        msg.to_vec()
+    }
+
+    fn body(doc: Document) -> OpMsgSection {
+        OpMsgSection {
+            kind: 0,
+            identifier: None,
+            documents: vec![doc],
+        }
+    }
+
+    fn sequence(identifier: &str, documents: Vec<Document>) -> OpMsgSection {
+        OpMsgSection {
+            kind: 1,
+            identifier: Some(identifier.to_string()),
+            documents,
+        }
+    }
+
+    #[test]
+    fn test_document_sequences() {
+        let bytes = op_msg(vec![
+            sequence("documents", vec![doc! { "x": 1 }, doc! { "x": 2 }]),
+            body(doc! { "insert": "col", "$db": "test" }),
+            sequence(
+                "other",
+                vec![doc! { "y": 1 }, doc! { "y": 2 }, doc! { "y": 3 }],
+            ),
+        ]);
+        let msg = OpMsg::from_bytes(&bytes).unwrap();
+        assert_eq!(msg.sections.len(), 3);
+        assert_eq!(msg.sections[0].documents.len(), 2);
+        assert_eq!(msg.sections[2].identifier.as_deref(), Some("other"));
+        assert_eq!(msg.sections[2].documents.len(), 3);
+
+        let command = msg.to_command();
+        assert_eq!(command.get_str("insert").unwrap(), "col");
+        assert_eq!(command.get_array("documents").unwrap().len(), 2);
+        assert_eq!(command.get_array("other").unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_invalid_sections() {
+        let no_body = op_msg(vec![sequence("documents", vec![doc! { "x": 1 }])]);
+        assert!(matches!(
+            OpMsg::from_bytes(&no_body),
+            Err(ProtocolError::InvalidMessage(_))
+        ));
+
+        let two_bodies = op_msg(vec![body(doc! { "ping": 1 }), body(doc! { "ping": 1 })]);
+        assert!(matches!(
+            OpMsg::from_bytes(&two_bodies),
+            Err(ProtocolError::InvalidMessage(_))
+        ));
+
+        let duplicate = op_msg(vec![
+            body(doc! { "insert": "col", "documents": [] }),
+            sequence("documents", vec![doc! { "x": 1 }]),
+        ]);
+        assert!(matches!(
+            OpMsg::from_bytes(&duplicate),
+            Err(ProtocolError::InvalidMessage(_))
+        ));
+
+        let mut unknown_kind = op_msg(vec![body(doc! { "ping": 1 })]);
+        unknown_kind[20] = 3;
+        assert_eq!(
+            OpMsg::from_bytes(&unknown_kind).unwrap_err(),
+            ProtocolError::UnknownSectionKind(3)
+        );
+    }
+
+    #[test]
+    fn test_truncated_message() {
+        let bytes = op_msg(vec![
+            body(doc! { "insert": "col" }),
+            sequence("documents", vec![doc! { "x": 1 }, doc! { "x": 2 }]),
+        ]);
+        for len in [0, 10, HEADER_SIZE as usize + 2, 30, bytes.len() - 1] {
+            assert_eq!(
+                OpMsg::from_bytes(&bytes[..len]).unwrap_err(),
+                ProtocolError::Truncated,
+                "{} bytes",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn test_read_document() {
+        let bytes = bson::to_vec(&doc! { "x": "y".repeat(100) }).unwrap();
+        let mut cursor = Cursor::new(&bytes[..]);
+        assert!(read_document(&mut cursor, 1024).is_ok());
+        assert_eq!(cursor.position() as usize, bytes.len());
+
+        let mut cursor = Cursor::new(&bytes[..]);
+        assert_eq!(
+            read_document(&mut cursor, 64).unwrap_err(),
+            ProtocolError::DocumentTooLarge {
+                size: bytes.len() as u32,
+                max: 64
+            }
+        );
+
+        let mut invalid = bytes.clone();
+        invalid[4] = 0x7f;
+        let mut cursor = Cursor::new(&invalid[..]);
+        assert!(matches!(
+            read_document(&mut cursor, 1024),
+            Err(ProtocolError::InvalidDocument(_))
+        ));
+
+        let negative = (-1i32).to_le_bytes();
+        let mut cursor = Cursor::new(&negative[..]);
+        assert!(matches!(
+            read_document(&mut cursor, 1024),
+            Err(ProtocolError::InvalidDocument(_))
+        ));
+    }
 }
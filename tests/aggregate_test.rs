@@ -30,6 +30,31 @@ fn test_match() {
     assert_unique_row_value!(res, "age", 30);
 }
 
+#[test]
+fn test_match_expr() {
+    let col = insert!(
+        doc! {
+            "name": "John",
+            "spent": 120,
+            "budget": 100,
+        },
+        doc! {
+            "name": "Paul",
+            "spent": 80,
+            "budget": 100,
+        }
+    );
+
+    let pipeline = doc! {
+        "$match": doc! {
+            "$expr": { "$gt": ["$spent", "$budget"] }
+        }
+    };
+
+    let res = col.aggregate(vec![pipeline], None).unwrap();
+    assert_unique_row_value!(res, "spent", 120);
+}
+
 #[test]
 fn test_match_regex() {
     let col = insert!(
@@ -827,6 +852,31 @@ fn test_project_literal() {
     assert_eq!(row.get("literal_str").unwrap().as_str().unwrap(), "value");
 }
 
+#[test]
+fn test_project_expressions() {
+    let col = insert!(doc! {
+        "name": "John",
+        "qty": 3,
+        "price": 2.5,
+    });
+
+    let pipeline = doc! {
+        "$project": {
+            "total": { "$multiply": ["$qty", "$price"] },
+            "next": { "$add": ["$qty", 1] },
+            "size": { "$cond": [{ "$gte": ["$qty", 3] }, "big", "small"] },
+            "label": { "$concat": ["$name", "-", { "$toUpper": "$name" }] },
+        }
+    };
+
+    let rows = common::get_rows(col.aggregate([pipeline], None).unwrap());
+    let row = rows[0].clone();
+    assert_eq!(row.get_f64("total").unwrap(), 7.5);
+    assert_eq!(row.get_i32("next").unwrap(), 4);
+    assert_eq!(row.get_str("size").unwrap(), "big");
+    assert_eq!(row.get_str("label").unwrap(), "John-JOHN");
+}
+
 #[test]
 fn test_project_rename() {
     let col = insert!(doc! {
@@ -0,0 +1,289 @@
+use bson::{doc, Document};
+use mongodb::change_stream::event::{ChangeStreamEvent, OperationType};
+use mongodb::options::{ChangeStreamOptions, FullDocumentBeforeChangeType, FullDocumentType};
+use mongodb::sync::ChangeStream;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod common;
+
+fn next(stream: &mut ChangeStream<ChangeStreamEvent<Document>>) -> ChangeStreamEvent<Document> {
+    stream.next().unwrap().unwrap()
+}
+
+#[test]
+fn test_watch_collection() {
+    let ctx = common::setup();
+    let col = ctx.col();
+    let mut stream = col.watch(None, None).unwrap();
+
+    col.insert_one(doc! { "_id": 1, "x": 1, "y": 1 }, None)
+        .unwrap();
+    col.update_one(doc! { "_id": 1 }, doc! { "$set": { "x": 2 } }, None)
+        .unwrap();
+    col.update_one(doc! { "_id": 1 }, doc! { "$unset": { "y": "" } }, None)
+        .unwrap();
+    col.replace_one(doc! { "_id": 1 }, doc! { "_id": 1, "z": 3 }, None)
+        .unwrap();
+    col.delete_one(doc! { "_id": 1 }, None).unwrap();
+
+    let event = next(&mut stream);
+    assert_eq!(event.operation_type, OperationType::Insert);
+    assert_eq!(event.document_key, Some(doc! { "_id": 1 }));
+    assert_eq!(event.full_document, Some(doc! { "_id": 1, "x": 1, "y": 1 }));
+    let ns = event.ns.unwrap();
+    assert_eq!(ns.db, ctx.db);
+    assert_eq!(ns.coll, Some(ctx.collection.clone()));
+    assert!(event.cluster_time.is_some());
+
+    let event = next(&mut stream);
+    assert_eq!(event.operation_type, OperationType::Update);
+    assert_eq!(event.full_document, None);
+    let description = event.update_description.unwrap();
+    assert_eq!(description.updated_fields, doc! { "x": 2 });
+    assert!(description.removed_fields.is_empty());
+
+    let event = next(&mut stream);
+    let description = event.update_description.unwrap();
+    assert_eq!(description.updated_fields, doc! {});
+    assert_eq!(description.removed_fields, vec!["y".to_string()]);
+
+    let event = next(&mut stream);
+    assert_eq!(event.operation_type, OperationType::Replace);
+    assert_eq!(event.full_document, Some(doc! { "_id": 1, "z": 3 }));
+
+    let event = next(&mut stream);
+    assert_eq!(event.operation_type, OperationType::Delete);
+    assert_eq!(event.document_key, Some(doc! { "_id": 1 }));
+    assert_eq!(event.full_document, None);
+}
+
+#[test]
+fn test_watch_only_sees_its_collection() {
+    let ctx = common::setup();
+    let other = ctx
+        .db()
+        .collection::<Document>(&format!("{}_other", ctx.collection));
+    other.insert_one(doc! { "x": 0 }, None).unwrap();
+    let mut stream = ctx.col().watch(None, None).unwrap();
+
+    other.insert_one(doc! { "x": 1 }, None).unwrap();
+    ctx.col().insert_one(doc! { "x": 2 }, None).unwrap();
+
+    let event = next(&mut stream);
+    assert_eq!(event.full_document.unwrap().get_i32("x").unwrap(), 2);
+}
+
+#[test]
+fn test_watch_database() {
+    let ctx = common::setup();
+    let other_name = format!("{}_db_watch", ctx.collection);
+    let other = ctx.db().collection::<Document>(&other_name);
+    ctx.col().insert_one(doc! { "x": 0 }, None).unwrap();
+    other.insert_one(doc! { "x": 0 }, None).unwrap();
+    let mut stream = ctx.db().watch(None, None).unwrap();
+
+    ctx.col().insert_one(doc! { "x": 1 }, None).unwrap();
+    other.insert_one(doc! { "x": 2 }, None).unwrap();
+
+    let mut seen = vec![];
+    while seen.len() < 2 {
+        let event = next(&mut stream);
+        let coll = event.ns.unwrap().coll.unwrap();
+        if coll == ctx.collection || coll == other_name {
+            seen.push(coll);
+        }
+    }
+    assert_eq!(seen, vec![ctx.collection.clone(), other_name]);
+}
+
+#[test]
+fn test_full_document_options() {
+    let ctx = common::setup();
+    let col = ctx.col();
+    col.insert_one(doc! { "_id": 1, "x": 1 }, None).unwrap();
+    let options = ChangeStreamOptions::builder()
+        .full_document(Some(FullDocumentType::UpdateLookup))
+        .full_document_before_change(Some(FullDocumentBeforeChangeType::WhenAvailable))
+        .build();
+    let mut stream = col.watch(None, options).unwrap();
+
+    col.update_one(doc! { "_id": 1 }, doc! { "$inc": { "x": 1 } }, None)
+        .unwrap();
+    let event = next(&mut stream);
+    assert_eq!(event.operation_type, OperationType::Update);
+    assert_eq!(event.full_document, Some(doc! { "_id": 1, "x": 2 }));
+    assert_eq!(
+        event.full_document_before_change,
+        Some(doc! { "_id": 1, "x": 1 })
+    );
+}
+
+#[test]
+fn test_pre_images_are_kept_once_requested() {
+    let ctx = common::setup();
+    let col = ctx.col();
+    let mut stream = col.watch(None, None).unwrap();
+
+    col.insert_one(doc! { "_id": 1, "x": 1 }, None).unwrap();
+    col.update_one(doc! { "_id": 1 }, doc! { "$set": { "x": 2 } }, None)
+        .unwrap();
+    let inserted = next(&mut stream);
+    drop(stream);
+
+    let options = ChangeStreamOptions::builder()
+        .resume_after(Some(inserted.id))
+        .full_document_before_change(Some(FullDocumentBeforeChangeType::WhenAvailable))
+        .build();
+    let mut stream = col.watch(None, options).unwrap();
+    col.update_one(doc! { "_id": 1 }, doc! { "$set": { "x": 3 } }, None)
+        .unwrap();
+
+    let event = next(&mut stream);
+    assert_eq!(
+        event.update_description.unwrap().updated_fields,
+        doc! { "x": 2 }
+    );
+    assert_eq!(event.full_document_before_change, None);
+    let event = next(&mut stream);
+    assert_eq!(
+        event.full_document_before_change,
+        Some(doc! { "_id": 1, "x": 2 })
+    );
+}
+
+#[test]
+fn test_update_with_many_operators_is_one_event() {
+    let ctx = common::setup();
+    let col = ctx.col();
+    col.insert_one(doc! { "_id": 1, "x": 1, "y": 1 }, None)
+        .unwrap();
+    let mut stream = col.watch(None, None).unwrap();
+
+    col.update_one(
+        doc! { "_id": 1 },
+        doc! { "$set": { "z": 1 }, "$inc": { "x": 1 }, "$unset": { "y": "" } },
+        None,
+    )
+    .unwrap();
+    col.delete_one(doc! { "_id": 1 }, None).unwrap();
+
+    let event = next(&mut stream);
+    assert_eq!(event.operation_type, OperationType::Update);
+    let description = event.update_description.unwrap();
+    assert_eq!(description.updated_fields, doc! { "x": 2, "z": 1 });
+    assert_eq!(description.removed_fields, vec!["y".to_string()]);
+    assert_eq!(next(&mut stream).operation_type, OperationType::Delete);
+}
+
+#[test]
+fn test_pipeline() {
+    let ctx = common::setup();
+    let col = ctx.col();
+    let pipeline = vec![doc! { "$match": { "operationType": "delete" } }];
+    let mut stream = col.watch(pipeline, None).unwrap();
+
+    col.insert_one(doc! { "_id": 1 }, None).unwrap();
+    col.delete_one(doc! { "_id": 1 }, None).unwrap();
+
+    let event = next(&mut stream);
+    assert_eq!(event.operation_type, OperationType::Delete);
+}
+
+#[test]
+fn test_waiting_streams_release_their_connections() {
+    let ctx = common::setup();
+    let col = ctx.col();
+    let options = ChangeStreamOptions::builder()
+        .max_await_time(Some(Duration::from_secs(5)))
+        .build();
+
+    // more streams waiting than connections in the pool
+    let (tx, rx) = mpsc::channel();
+    for _ in 0..2 {
+        let mut stream = col.watch(None, options.clone()).unwrap();
+        let tx = tx.clone();
+        thread::spawn(move || tx.send(next(&mut stream)).unwrap());
+    }
+    thread::sleep(Duration::from_millis(500));
+
+    let started = Instant::now();
+    ctx.db()
+        .run_command(doc! { "listCollections": 1 }, None)
+        .unwrap();
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    col.insert_one(doc! { "x": 1 }, None).unwrap();
+    for _ in 0..2 {
+        let event = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(event.operation_type, OperationType::Insert);
+    }
+}
+
+#[test]
+fn test_resume_after() {
+    let ctx = common::setup();
+    let col = ctx.col();
+    let mut stream = col.watch(None, None).unwrap();
+
+    col.insert_one(doc! { "x": 1 }, None).unwrap();
+    col.insert_one(doc! { "x": 2 }, None).unwrap();
+    let event = next(&mut stream);
+    assert_eq!(event.full_document.unwrap().get_i32("x").unwrap(), 1);
+    drop(stream);
+
+    let options = ChangeStreamOptions::builder()
+        .resume_after(Some(event.id))
+        .build();
+    let mut stream = col.watch(None, options).unwrap();
+    let event = next(&mut stream);
+    assert_eq!(event.full_document.unwrap().get_i32("x").unwrap(), 2);
+}
+
+#[test]
+fn test_resume_after_unknown_token() {
+    let ctx = common::setup();
+    let res = ctx.db().run_command(
+        doc! {
+            "aggregate": &ctx.collection,
+            "pipeline": [{ "$changeStream": { "resumeAfter": { "_data": "7FFFFFFFFFFFFFFF0000000000000001" } } }],
+            "cursor": {},
+        },
+        None,
+    );
+    match *res.unwrap_err().kind {
+        mongodb::error::ErrorKind::Command(err) => assert_eq!(err.code, 286),
+        kind => panic!("unexpected error {:?}", kind),
+    }
+}
+
+#[test]
+fn test_cluster_watch_requires_admin() {
+    let ctx = common::setup();
+    let res = ctx.db().run_command(
+        doc! {
+            "aggregate": 1,
+            "pipeline": [{ "$changeStream": { "allChangesForCluster": true } }],
+            "cursor": {},
+        },
+        None,
+    );
+    assert!(res.is_err());
+
+    let res = ctx
+        .mongodb()
+        .database("admin")
+        .run_command(
+            doc! {
+                "aggregate": 1,
+                "pipeline": [{ "$changeStream": { "allChangesForCluster": true } }],
+                "cursor": {},
+            },
+            None,
+        )
+        .unwrap();
+    let cursor = res.get_document("cursor").unwrap();
+    assert_ne!(cursor.get_i64("id").unwrap(), 0);
+    assert!(cursor.contains_key("postBatchResumeToken"));
+}
@@ -3,14 +3,15 @@ use bson::Bson;
 use mongodb::bson::Document;
 use mongodb::sync::Cursor;
 use oxide::pg::PgDb;
-use oxide::server::Server;
+use oxide::server::{Server, ShutdownHandle};
+use oxide::tls::{connection_manager, MakeRustlsConnect};
 use r2d2::Pool;
-use r2d2_postgres::{postgres::NoTls, PostgresConnectionManager};
+use r2d2_postgres::PostgresConnectionManager;
 use std::fs::{self, File};
 use std::io::{Read, Write};
 use std::net::{Shutdown, TcpStream};
 use std::sync::Once;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{env, thread};
 
 static INIT: Once = Once::new();
@@ -38,6 +39,10 @@ impl TestContext {
         }
     }
 
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
     pub fn mongodb(&self) -> &mongodb::sync::Client {
         &self.mongodb
     }
@@ -72,7 +77,7 @@ impl TestContext {
     }
 }
 
-pub fn initialize(pool: Pool<PostgresConnectionManager<NoTls>>) {
+pub fn initialize(pool: Pool<PostgresConnectionManager<MakeRustlsConnect>>) {
     INIT.call_once(|| {
         let mut pg = PgDb::new_from_pool(pool);
         pg.exec(
@@ -96,6 +101,13 @@ pub fn initialize(pool: Pool<PostgresConnectionManager<NoTls>>) {
 }
 
 pub fn setup_with_pg_db(name: &str, drop: bool) -> TestContext {
+    setup_with_server(name, drop, |server| server).0
+}
+
+pub fn setup_with_server<F>(name: &str, drop: bool, configure: F) -> (TestContext, ShutdownHandle)
+where
+    F: FnOnce(Server) -> Server,
+{
     // static ID_COUNTER: AtomicU32 = AtomicU32::new(0);
     // let id = ID_COUNTER.fetch_add(1, Ordering::Relaxed);
 
@@ -108,24 +120,41 @@ pub fn setup_with_pg_db(name: &str, drop: bool) -> TestContext {
 
     PgDb::new().create_db_if_not_exists(name).unwrap();
 
-    let pg_url = format!("{}/{}", env::var("TEST_DATABASE_URL").unwrap(), name);
+    let pg_url = database_url(&env::var("TEST_DATABASE_URL").unwrap(), name);
     let port: u16 = portpicker::pick_unused_port().unwrap();
 
-    let manager = PostgresConnectionManager::new(pg_url.parse().unwrap(), NoTls);
+    let manager = connection_manager(&pg_url).unwrap();
     let pool = r2d2::Pool::builder().max_size(2).build(manager).unwrap();
     initialize(pool.clone());
 
+    let server = configure(Server::new("localhost".to_string(), port));
+    let shutdown = server.shutdown_handle();
     thread::spawn(move || {
-        Server::new("localhost".to_string(), port).start_with_pool(pool);
+        server.start_with_pool(pool);
     });
 
-    TestContext::new(port, "db_test".to_string())
+    let start = Instant::now();
+    while TcpStream::connect(format!("localhost:{}", port)).is_err() {
+        assert!(start.elapsed().as_secs() < 5, "server didn't start");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    (TestContext::new(port, "db_test".to_string()), shutdown)
 }
 
 pub fn setup() -> TestContext {
     setup_with_pg_db("db_test", false)
 }
 
+/// Appends the database name to the server URL, keeping any parameters such
+/// as `sslmode` at the end.
+pub fn database_url(server_url: &str, name: &str) -> String {
+    match server_url.split_once('?') {
+        Some((base, params)) => format!("{}/{}?{}", base.trim_end_matches('/'), name, params),
+        None => format!("{}/{}", server_url.trim_end_matches('/'), name),
+    }
+}
+
 pub fn setup_with_drop(drop: bool) -> TestContext {
     setup_with_pg_db("test", drop)
 }
@@ -0,0 +1,94 @@
+use bson::{doc, Document};
+use mongodb::sync::Client;
+use oxide::wire::{
+    compress, parse, Compressor, MsgHeader, OpCode, OpMsg, Serializable, OP_COMPRESSED, OP_MSG,
+};
+
+mod common;
+
+fn connect(ctx: &common::TestContext, compressors: &str) -> Client {
+    let uri = format!(
+        "mongodb://localhost:{}/?compressors={}",
+        ctx.port(),
+        compressors
+    );
+    Client::with_uri_str(&uri).unwrap()
+}
+
+#[test]
+fn test_compressed_commands() {
+    let ctx = common::setup();
+
+    for compressors in ["zstd", "zlib"] {
+        let client = connect(&ctx, compressors);
+        let col = client
+            .database(&ctx.db)
+            .collection::<Document>(&ctx.collection);
+        col.insert_one(
+            doc! { "compressor": compressors, "text": "x".repeat(10000) },
+            None,
+        )
+        .unwrap();
+
+        let doc = col
+            .find_one(doc! { "compressor": compressors }, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(doc.get_str("text").unwrap().len(), 10000);
+    }
+}
+
+#[test]
+fn test_hello_compression() {
+    let ctx = common::setup();
+
+    let res = ctx
+        .db()
+        .run_command(
+            doc! { "hello": 1, "compression": ["snappy", "zlib", "zstd"] },
+            None,
+        )
+        .unwrap();
+    assert_eq!(
+        res.get_array("compression").unwrap(),
+        &vec!["zlib".into(), "zstd".into()]
+    );
+
+    let res = ctx
+        .db()
+        .run_command(doc! { "isMaster": 1, "compression": ["snappy"] }, None)
+        .unwrap();
+    assert!(!res.contains_key("compression"));
+}
+
+#[test]
+fn test_compressed_reply() {
+    let ctx = common::setup();
+
+    let header = MsgHeader {
+        message_length: 0,
+        request_id: 42,
+        response_to: 0,
+        op_code: OP_MSG,
+    };
+    let mut msg = OpMsg::new_with_body_kind(header, 0, None, &doc! { "ping": 1, "$db": "test" });
+    msg.header.message_length = msg.to_vec().len() as u32;
+
+    let reply = ctx.send(&compress(&msg.to_vec(), Compressor::Zlib));
+    let len = u32::from_le_bytes(reply[..4].try_into().unwrap()) as usize;
+    match parse(&reply[..len]).unwrap() {
+        OpCode::OpCompressed(compressed) => {
+            assert_eq!(compressed.header.op_code, OP_COMPRESSED);
+            assert_eq!(compressed.header.response_to, 42);
+            assert_eq!(compressed.compressor, Compressor::Zlib);
+            match *compressed.message {
+                OpCode::OpMsg(msg) => {
+                    let doc = &msg.sections[0].documents[0];
+                    assert_eq!(doc.get_f64("ok").unwrap(), 1.0);
+                }
+                other => panic!("unexpected reply {:?}", other),
+            }
+        }
+        other => panic!("unexpected reply {:?}", other),
+    }
+}
@@ -51,3 +51,31 @@ fn test_count_for_non_existent_collection() {
 
     assert_eq!(res.get_i32("n").unwrap(), 0);
 }
+
+#[test]
+fn test_count_with_expr() {
+    let ctx = common::setup();
+    ctx.col()
+        .insert_many(
+            vec![
+                doc! { "spent": 120, "budget": 100 },
+                doc! { "spent": 50, "budget": 100 },
+                doc! { "spent": 200, "budget": 150 },
+            ],
+            None,
+        )
+        .unwrap();
+
+    let res = ctx
+        .db()
+        .run_command(
+            doc! {
+                "count": &ctx.collection,
+                "filter": { "$expr": { "$gt": ["$spent", "$budget"] } },
+            },
+            None,
+        )
+        .unwrap();
+
+    assert_eq!(res.get_i32("n").unwrap(), 2);
+}
@@ -0,0 +1,268 @@
+use bson::{doc, Bson, Document};
+use mongodb::options::{AggregateOptions, FindOptions};
+use oxide::pg::PgDb;
+use std::env;
+
+mod common;
+
+fn insert_many(ctx: &common::TestContext, total: i32) {
+    let docs: Vec<Document> = (0..total).map(|i| doc! { "i": i }).collect();
+    ctx.col().insert_many(docs, None).unwrap();
+}
+
+#[test]
+fn test_find_with_get_more() {
+    let ctx = common::setup();
+    insert_many(&ctx, 250);
+
+    let options = FindOptions::builder().batch_size(20).build();
+    let cursor = ctx.col().find(None, options).unwrap();
+    let rows = common::get_rows(cursor);
+    assert_eq!(rows.len(), 250);
+    assert_eq!(rows[0].get_i32("i").unwrap(), 0);
+    assert_eq!(rows[249].get_i32("i").unwrap(), 249);
+}
+
+#[test]
+fn test_find_default_first_batch() {
+    let ctx = common::setup();
+    insert_many(&ctx, 150);
+
+    let res = ctx
+        .db()
+        .run_command(doc! { "find": &ctx.collection }, None)
+        .unwrap();
+    let cursor = res.get_document("cursor").unwrap();
+    assert_eq!(cursor.get_array("firstBatch").unwrap().len(), 101);
+    assert_ne!(cursor.get_i64("id").unwrap(), 0);
+
+    let res = ctx
+        .db()
+        .run_command(
+            doc! {
+                "getMore": cursor.get_i64("id").unwrap(),
+                "collection": &ctx.collection,
+            },
+            None,
+        )
+        .unwrap();
+    let cursor = res.get_document("cursor").unwrap();
+    assert_eq!(cursor.get_array("nextBatch").unwrap().len(), 49);
+    assert_eq!(cursor.get_i64("id").unwrap(), 0);
+}
+
+#[test]
+fn test_find_exhausted_first_batch() {
+    let ctx = common::setup();
+    insert_many(&ctx, 5);
+
+    let res = ctx
+        .db()
+        .run_command(doc! { "find": &ctx.collection, "batchSize": 5 }, None)
+        .unwrap();
+    let cursor = res.get_document("cursor").unwrap();
+    assert_eq!(cursor.get_array("firstBatch").unwrap().len(), 5);
+    assert_eq!(cursor.get_i64("id").unwrap(), 0);
+}
+
+#[test]
+fn test_aggregate_with_get_more() {
+    let ctx = common::setup();
+    insert_many(&ctx, 30);
+
+    let options = AggregateOptions::builder().batch_size(7).build();
+    let cursor = ctx
+        .col()
+        .aggregate(vec![doc! { "$match": { "i": { "$gte": 10 } } }], options)
+        .unwrap();
+    let rows = common::get_rows(cursor);
+    assert_eq!(rows.len(), 20);
+}
+
+#[test]
+fn test_kill_cursors() {
+    let ctx = common::setup();
+    insert_many(&ctx, 10);
+
+    let res = ctx
+        .db()
+        .run_command(doc! { "find": &ctx.collection, "batchSize": 2 }, None)
+        .unwrap();
+    let id = res.get_document("cursor").unwrap().get_i64("id").unwrap();
+
+    let res = ctx
+        .db()
+        .run_command(
+            doc! {
+                "killCursors": &ctx.collection,
+                "cursors": [id, 42_i64],
+            },
+            None,
+        )
+        .unwrap();
+    assert_eq!(
+        res.get_array("cursorsKilled").unwrap(),
+        &vec![Bson::Int64(id)]
+    );
+    assert_eq!(
+        res.get_array("cursorsNotFound").unwrap(),
+        &vec![Bson::Int64(42)]
+    );
+
+    let res = ctx.db().run_command(
+        doc! {
+            "getMore": id,
+            "collection": &ctx.collection,
+        },
+        None,
+    );
+    assert!(res.is_err());
+    assert_eq!(
+        res.unwrap_err().to_string(),
+        format!(
+            "Command failed (CursorNotFound): cursor id {} not found)",
+            id
+        )
+    );
+}
+
+#[test]
+fn test_find_cursor_with_concurrent_updates() {
+    let ctx = common::setup();
+    insert_many(&ctx, 10);
+
+    let options = FindOptions::builder()
+        .batch_size(3)
+        .sort(doc! { "i": -1 })
+        .skip(1)
+        .limit(8)
+        .build();
+    let mut cursor = ctx.col().find(None, options).unwrap();
+    let first = cursor.next().unwrap().unwrap();
+    assert_eq!(first.get_i32("i").unwrap(), 8);
+
+    // rewritten rows move around in the table, but not in the results
+    ctx.col()
+        .update_many(doc! {}, doc! { "$set": { "seen": true } }, None)
+        .unwrap();
+
+    let mut values = vec![8];
+    for row in cursor {
+        values.push(row.unwrap().get_i32("i").unwrap());
+    }
+    assert_eq!(values, vec![8, 7, 6, 5, 4, 3, 2, 1]);
+}
+
+#[test]
+fn test_find_cursor_with_ties_and_missing_sort_keys() {
+    let ctx = common::setup();
+    let docs: Vec<Document> = (0..9)
+        .map(|i| match i % 3 {
+            0 => doc! { "_id": i },
+            _ => doc! { "_id": i, "k": i % 3 },
+        })
+        .collect();
+    ctx.col().insert_many(docs, None).unwrap();
+
+    let options = FindOptions::builder()
+        .batch_size(2)
+        .sort(doc! { "k": 1 })
+        .build();
+    let ids = common::get_rows(ctx.col().find(None, options).unwrap())
+        .iter()
+        .map(|row| row.get_i32("_id").unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(ids, vec![1, 4, 7, 2, 5, 8, 0, 3, 6]);
+}
+
+#[test]
+fn test_aggregate_cursor_is_a_snapshot() {
+    let ctx = common::setup();
+    insert_many(&ctx, 10);
+
+    let options = AggregateOptions::builder().batch_size(4).build();
+    let mut cursor = ctx
+        .col()
+        .aggregate(vec![doc! { "$sort": { "i": 1 } }], options)
+        .unwrap();
+    assert_eq!(cursor.next().unwrap().unwrap().get_i32("i").unwrap(), 0);
+
+    ctx.col().delete_many(doc! {}, None).unwrap();
+
+    let values = cursor
+        .map(|row| row.unwrap().get_i32("i").unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(values, (1..10).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_stale_results_dropped_on_startup() {
+    let ctx = common::setup();
+    insert_many(&ctx, 10);
+
+    // left behind by a server that stopped long ago
+    let stale = format!("cursor_0_{}", uuid::Uuid::new_v4().simple());
+    let url = common::database_url(&env::var("TEST_DATABASE_URL").unwrap(), &ctx.db);
+    let mut client = PgDb::new_with_uri(&url);
+    client
+        .exec(
+            &format!(r#"CREATE TABLE "oxide_sessions"."{}" (n bigint)"#, stale),
+            &[],
+        )
+        .unwrap();
+
+    let options = AggregateOptions::builder().batch_size(4).build();
+    let mut cursor = ctx
+        .col()
+        .aggregate(vec![doc! { "$sort": { "i": 1 } }], options)
+        .unwrap();
+    assert_eq!(cursor.next().unwrap().unwrap().get_i32("i").unwrap(), 0);
+
+    let _restarted = common::setup();
+    let rows = client
+        .raw_query(
+            "SELECT count(*) FROM pg_tables WHERE schemaname = 'oxide_sessions' AND tablename = $1",
+            &[&stale],
+        )
+        .unwrap();
+    assert_eq!(rows[0].get::<_, i64>(0), 0);
+
+    // the results of cursors still open are kept
+    let values = cursor
+        .map(|row| row.unwrap().get_i32("i").unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(values, (1..10).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_kill_cursors_of_other_namespace() {
+    let ctx = common::setup();
+    insert_many(&ctx, 10);
+
+    let res = ctx
+        .db()
+        .run_command(doc! { "find": &ctx.collection, "batchSize": 2 }, None)
+        .unwrap();
+    let id = res.get_document("cursor").unwrap().get_i64("id").unwrap();
+
+    let res = ctx
+        .db()
+        .run_command(doc! { "killCursors": "other", "cursors": [id] }, None)
+        .unwrap();
+    assert!(res.get_array("cursorsKilled").unwrap().is_empty());
+    assert_eq!(
+        res.get_array("cursorsNotFound").unwrap(),
+        &vec![Bson::Int64(id)]
+    );
+
+    // the cursor is still there
+    let res = ctx
+        .db()
+        .run_command(
+            doc! { "getMore": id, "collection": &ctx.collection, "batchSize": 2 },
+            None,
+        )
+        .unwrap();
+    let cursor = res.get_document("cursor").unwrap();
+    assert_eq!(cursor.get_array("nextBatch").unwrap().len(), 2);
+}
@@ -21,7 +21,6 @@ fn test_delete_basic() {
 
 #[test]
 fn test_delete_one() {
-    // FIXME right now it only crashes
     let ctx = common::setup();
 
     ctx.col()
@@ -49,3 +48,177 @@ fn test_delete_inexistent() {
 
     assert_eq!(res.deleted_count, 0);
 }
+
+#[test]
+fn test_delete_one_string_id() {
+    let ctx = common::setup();
+
+    ctx.col()
+        .insert_many(
+            vec![
+                doc! { "_id": "a", "x": 1 },
+                doc! { "_id": "b", "x": 1 },
+                doc! { "_id": "c", "x": 2 },
+            ],
+            None,
+        )
+        .unwrap();
+
+    let res = ctx.col().delete_one(doc! { "x": 1 }, None).unwrap();
+    assert_eq!(res.deleted_count, 1);
+    let cursor = ctx.col().find(doc! {}, None).unwrap();
+    assert_eq!(common::get_rows(cursor).len(), 2);
+}
+
+#[test]
+fn test_delete_multiple_statements() {
+    let ctx = common::setup();
+
+    ctx.col()
+        .insert_many(
+            vec![
+                doc! { "x": 1 },
+                doc! { "x": 1 },
+                doc! { "x": 2 },
+                doc! { "x": 3 },
+            ],
+            None,
+        )
+        .unwrap();
+
+    let res = ctx
+        .db()
+        .run_command(
+            doc! {
+                "delete": &ctx.collection,
+                "deletes": [
+                    { "q": { "x": 1 }, "limit": 1 },
+                    { "q": { "x": 2 }, "limit": 0 },
+                ],
+            },
+            None,
+        )
+        .unwrap();
+    assert_eq!(res.get_i64("n").unwrap(), 2);
+    assert!(!res.contains_key("writeErrors"));
+
+    let cursor = ctx.col().find(doc! {}, None).unwrap();
+    assert_eq!(common::get_rows(cursor).len(), 2);
+}
+
+#[test]
+fn test_delete_ordered_stops_at_error() {
+    let ctx = common::setup();
+
+    ctx.col()
+        .insert_many(
+            vec![doc! { "x": 1 }, doc! { "x": 2 }, doc! { "x": 3 }],
+            None,
+        )
+        .unwrap();
+
+    let res = ctx
+        .db()
+        .run_command(
+            doc! {
+                "delete": &ctx.collection,
+                "deletes": [
+                    { "q": { "x": 1 }, "limit": 0 },
+                    { "q": { "x": { "$regex": 1 } }, "limit": 0 },
+                    { "q": { "x": 3 }, "limit": 0 },
+                ],
+            },
+            None,
+        )
+        .unwrap();
+    assert_eq!(res.get_i64("n").unwrap(), 1);
+    let errors = res.get_array("writeErrors").unwrap();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(
+        errors[0].as_document().unwrap().get_i32("index").unwrap(),
+        1
+    );
+
+    let cursor = ctx.col().find(doc! {}, None).unwrap();
+    let rows = common::get_rows(cursor);
+    assert_eq!(rows.len(), 2);
+    assert!(rows.iter().all(|row| row.get_i32("x").unwrap() != 1));
+}
+
+#[test]
+fn test_delete_unordered_continues_after_error() {
+    let ctx = common::setup();
+
+    ctx.col()
+        .insert_many(
+            vec![doc! { "x": 1 }, doc! { "x": 2 }, doc! { "x": 3 }],
+            None,
+        )
+        .unwrap();
+
+    let res = ctx
+        .db()
+        .run_command(
+            doc! {
+                "delete": &ctx.collection,
+                "deletes": [
+                    { "q": { "x": { "$regex": 1 } }, "limit": 0 },
+                    { "q": { "x": 1 }, "limit": 0 },
+                    { "q": { "x": { "$regex": 2 } }, "limit": 0 },
+                    { "q": { "x": 3 }, "limit": 1 },
+                ],
+                "ordered": false,
+            },
+            None,
+        )
+        .unwrap();
+    assert_eq!(res.get_i64("n").unwrap(), 2);
+    let indexes: Vec<i32> = res
+        .get_array("writeErrors")
+        .unwrap()
+        .iter()
+        .map(|e| e.as_document().unwrap().get_i32("index").unwrap())
+        .collect();
+    assert_eq!(indexes, vec![0, 2]);
+
+    let cursor = ctx.col().find(doc! {}, None).unwrap();
+    assert_eq!(common::get_rows(cursor).len(), 1);
+}
+
+#[test]
+fn test_delete_invalid_limit() {
+    let ctx = common::setup();
+
+    let res = ctx.db().run_command(
+        doc! {
+            "delete": &ctx.collection,
+            "deletes": [{ "q": {}, "limit": 2 }],
+        },
+        None,
+    );
+    assert!(res.is_err());
+}
+
+#[test]
+fn test_delete_with_expr() {
+    let ctx = common::setup();
+
+    ctx.col()
+        .insert_many(
+            vec![
+                doc! { "x": 1, "y": 1 },
+                doc! { "x": 2, "y": 1 },
+                doc! { "x": 3, "y": 3 },
+            ],
+            None,
+        )
+        .unwrap();
+
+    ctx.col()
+        .delete_many(doc! { "$expr": { "$eq": ["$x", "$y"] } }, None)
+        .unwrap();
+    let cursor = ctx.col().find(doc! {}, None).unwrap();
+    let rows = common::get_rows(cursor);
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].get_i32("x").unwrap(), 2);
+}
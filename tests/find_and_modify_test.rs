@@ -84,6 +84,57 @@ fn test_find_and_modify_upsert_with_returning_new() {
     assert_eq!(row.get_i32("age").unwrap(), 44);
 }
 
+#[test]
+fn test_find_and_modify_upsert_with_operators() {
+    let ctx = common::setup();
+
+    let res = ctx
+        .db()
+        .run_command(
+            doc! {
+                "findAndModify": &ctx.collection,
+                "query": { "name": "Mike", "age": { "$gte": 18 } },
+                "update": { "$setOnInsert": { "age": 18 } },
+                "upsert": true,
+                "new": true,
+            },
+            None,
+        )
+        .unwrap();
+    let value = res.get_document("value").unwrap();
+    assert_eq!(value.get_str("name").unwrap(), "Mike");
+    assert_eq!(value.get_i32("age").unwrap(), 18);
+    let last_error = res.get_document("lastErrorObject").unwrap();
+    assert_eq!(last_error.get_bool("updatedExisting").unwrap(), false);
+    assert_eq!(last_error.get("upserted"), value.get("_id"));
+}
+
+#[test]
+fn test_find_and_modify_set_on_insert_only_matching() {
+    let ctx = common::setup();
+    ctx.col()
+        .insert_one(doc! { "_id": 1, "name": "Mike" }, None)
+        .unwrap();
+
+    let res = ctx
+        .db()
+        .run_command(
+            doc! {
+                "findAndModify": &ctx.collection,
+                "query": { "name": "Mike" },
+                "update": { "$setOnInsert": { "age": 18 } },
+                "upsert": true,
+                "new": true,
+            },
+            None,
+        )
+        .unwrap();
+    assert_eq!(
+        res.get_document("value").unwrap(),
+        &doc! { "_id": 1, "name": "Mike" }
+    );
+}
+
 #[test]
 fn test_find_and_modify_empty() {
     let ctx = common::setup();
@@ -63,6 +63,36 @@ fn test_find_string() {
     assert!(cursor.next().is_none());
 }
 
+#[test]
+fn test_find_with_quotes() {
+    let ctx = common::setup();
+
+    ctx.col()
+        .insert_many(
+            vec![
+                doc! { "x": 1, "it's": "O'Brien" },
+                doc! { "x": 2, "it's": "O'Neil" },
+            ],
+            None,
+        )
+        .unwrap();
+
+    let mut cursor = ctx.col().find(doc! { "it's": "O'Brien" }, None).unwrap();
+    let row1 = cursor.next().unwrap().unwrap();
+    assert_eq!(row1.get_i32("x").unwrap(), 1);
+    assert!(cursor.next().is_none());
+
+    let filter = doc! { "it's": { "$gt": "O'C", "$exists": true } };
+    let mut cursor = ctx.col().find(filter, None).unwrap();
+    let row1 = cursor.next().unwrap().unwrap();
+    assert_eq!(row1.get_i32("x").unwrap(), 2);
+    assert!(cursor.next().is_none());
+
+    // a value crafted to close the literal is compared as a whole
+    let filter = doc! { "it's": "x') OR TRUE OR ('" };
+    assert!(ctx.col().find(filter, None).unwrap().next().is_none());
+}
+
 #[test]
 fn test_find_with_or() {
     let ctx = common::setup();
@@ -319,3 +349,597 @@ fn test_with_nested_arrays() {
     assert_eq!(5, rows.len());
     assert_eq!(ids, [1, 2, 3, 4, 5]);
 }
+
+fn insert_people(ctx: &common::TestContext) {
+    ctx.col()
+        .insert_many(
+            vec![
+                doc! { "name": "Ana", "age": 31, "address": { "city": "Lisbon", "zip": "1000" }, "tags": ["a", "b", "c", "d"] },
+                doc! { "name": "Bruno", "age": 25, "address": { "city": "Porto", "zip": "4000" }, "tags": ["e"] },
+                doc! { "name": "Carla", "age": 42, "address": { "city": "Faro", "zip": "8000" }, "tags": [] },
+            ],
+            None,
+        )
+        .unwrap();
+}
+
+fn run_find(ctx: &common::TestContext, options: Document) -> Vec<Bson> {
+    // the command name has to be the first key
+    let mut command = doc! { "find": &ctx.collection };
+    command.extend(options);
+    let res = ctx.db().run_command(command, None).unwrap();
+    res.get_document("cursor")
+        .unwrap()
+        .get_array("firstBatch")
+        .unwrap()
+        .clone()
+}
+
+#[test]
+fn test_find_with_sort_skip_and_limit() {
+    let ctx = common::setup();
+    insert_people(&ctx);
+
+    let rows = run_find(&ctx, doc! { "sort": { "age": -1 } });
+    let names: Vec<&str> = rows
+        .iter()
+        .map(|r| r.as_document().unwrap().get_str("name").unwrap())
+        .collect();
+    assert_eq!(names, vec!["Carla", "Ana", "Bruno"]);
+
+    let rows = run_find(&ctx, doc! { "sort": { "age": 1 }, "skip": 1, "limit": 1 });
+    assert_eq!(rows.len(), 1);
+    assert_eq!(
+        rows[0].as_document().unwrap().get_str("name").unwrap(),
+        "Ana"
+    );
+
+    let rows = run_find(&ctx, doc! { "sort": { "address.city": 1 }, "limit": 2 });
+    let names: Vec<&str> = rows
+        .iter()
+        .map(|r| r.as_document().unwrap().get_str("name").unwrap())
+        .collect();
+    assert_eq!(names, vec!["Carla", "Ana"]);
+}
+
+#[test]
+fn test_find_with_inclusion_projection() {
+    let ctx = common::setup();
+    insert_people(&ctx);
+
+    let rows = run_find(
+        &ctx,
+        doc! { "filter": { "name": "Ana" }, "projection": { "name": 1, "address.city": 1, "missing": 1 } },
+    );
+    let row = rows[0].as_document().unwrap();
+    assert!(row.contains_key("_id"));
+    assert_eq!(row.get_str("name").unwrap(), "Ana");
+    assert_eq!(
+        row.get_document("address").unwrap(),
+        &doc! { "city": "Lisbon" }
+    );
+    assert!(!row.contains_key("age"));
+    assert!(!row.contains_key("missing"));
+
+    let rows = run_find(
+        &ctx,
+        doc! { "filter": { "name": "Ana" }, "projection": { "_id": 0, "age": 1 } },
+    );
+    assert_eq!(rows[0].as_document().unwrap(), &doc! { "age": 31 });
+}
+
+#[test]
+fn test_find_with_exclusion_projection() {
+    let ctx = common::setup();
+    insert_people(&ctx);
+
+    let rows = run_find(
+        &ctx,
+        doc! { "filter": { "name": "Bruno" }, "projection": { "_id": 0, "tags": 0, "address.zip": 0 } },
+    );
+    assert_eq!(
+        rows[0].as_document().unwrap(),
+        &doc! { "name": "Bruno", "age": 25, "address": { "city": "Porto" } }
+    );
+}
+
+#[test]
+fn test_find_with_slice_projection() {
+    let ctx = common::setup();
+    insert_people(&ctx);
+
+    let rows = run_find(
+        &ctx,
+        doc! { "filter": { "name": "Ana" }, "projection": { "_id": 0, "tags": { "$slice": 2 } } },
+    );
+    // $slice alone doesn't make the projection an inclusion
+    assert_eq!(
+        rows[0].as_document().unwrap(),
+        &doc! { "name": "Ana", "age": 31, "address": { "city": "Lisbon", "zip": "1000" }, "tags": ["a", "b"] }
+    );
+
+    let rows = run_find(
+        &ctx,
+        doc! { "filter": { "name": "Ana" }, "projection": { "tags": { "$slice": -1 }, "address": 0, "_id": 0 } },
+    );
+    assert_eq!(
+        rows[0].as_document().unwrap(),
+        &doc! { "name": "Ana", "age": 31, "tags": ["d"] }
+    );
+
+    let rows = run_find(
+        &ctx,
+        doc! { "filter": { "name": "Ana" }, "projection": { "_id": 0, "name": 1, "tags": { "$slice": [1, 2] } } },
+    );
+    assert_eq!(
+        rows[0].as_document().unwrap(),
+        &doc! { "name": "Ana", "tags": ["b", "c"] }
+    );
+}
+
+#[test]
+fn test_find_with_elem_match_projection() {
+    let ctx = common::setup();
+    ctx.col()
+        .insert_one(
+            doc! { "x": 1, "items": [{ "k": "a", "v": 1 }, { "k": "b", "v": 2 }, { "k": "b", "v": 3 }] },
+            None,
+        )
+        .unwrap();
+
+    let rows = run_find(
+        &ctx,
+        doc! { "projection": { "_id": 0, "items": { "$elemMatch": { "k": "b" } } } },
+    );
+    assert_eq!(
+        rows[0].as_document().unwrap(),
+        &doc! { "items": [{ "k": "b", "v": 2 }] }
+    );
+
+    let rows = run_find(
+        &ctx,
+        doc! { "projection": { "_id": 0, "x": 1, "items": { "$elemMatch": { "k": "z" } } } },
+    );
+    assert_eq!(rows[0].as_document().unwrap(), &doc! { "x": 1 });
+}
+
+#[test]
+fn test_find_single_batch() {
+    let ctx = common::setup();
+    let docs: Vec<Document> = (0..20).map(|i| doc! { "i": i }).collect();
+    ctx.col().insert_many(docs, None).unwrap();
+
+    let res = ctx
+        .db()
+        .run_command(
+            doc! { "find": &ctx.collection, "batchSize": 5, "singleBatch": true },
+            None,
+        )
+        .unwrap();
+    let cursor = res.get_document("cursor").unwrap();
+    assert_eq!(cursor.get_array("firstBatch").unwrap().len(), 5);
+    assert_eq!(cursor.get_i64("id").unwrap(), 0);
+
+    let res = ctx
+        .db()
+        .run_command(doc! { "find": &ctx.collection, "limit": -3 }, None)
+        .unwrap();
+    let cursor = res.get_document("cursor").unwrap();
+    assert_eq!(cursor.get_array("firstBatch").unwrap().len(), 3);
+    assert_eq!(cursor.get_i64("id").unwrap(), 0);
+}
+
+#[test]
+fn test_find_with_quoted_projection_and_sort_keys() {
+    let ctx = common::setup();
+    ctx.col()
+        .insert_many(
+            vec![
+                doc! { "_id": 1, "it's": 2, "a": { "b'": [1, 2, 3] } },
+                doc! { "_id": 2, "it's": 1, "a": { "b'": [4, 5, 6] } },
+            ],
+            None,
+        )
+        .unwrap();
+
+    let find = |command: Document| {
+        let res = ctx.db().run_command(command, None).unwrap();
+        let cursor = res.get_document("cursor").unwrap();
+        cursor
+            .get_array("firstBatch")
+            .unwrap()
+            .iter()
+            .map(|doc| doc.as_document().unwrap().clone())
+            .collect::<Vec<Document>>()
+    };
+
+    let rows = find(doc! {
+        "find": &ctx.collection,
+        "projection": { "it's": 1, "a.b'": { "$slice": 1 } },
+        "sort": { "it's": 1 },
+        "singleBatch": true,
+    });
+    assert_eq!(
+        rows,
+        vec![
+            doc! { "_id": 2, "it's": 1, "a": { "b'": [4] } },
+            doc! { "_id": 1, "it's": 2, "a": { "b'": [1] } },
+        ]
+    );
+
+    let rows = find(doc! {
+        "find": &ctx.collection,
+        "projection": { "it's": 0, "a.b'": 0 },
+        "sort": { "it's": -1 },
+    });
+    assert_eq!(
+        rows,
+        vec![doc! { "_id": 1, "a": {} }, doc! { "_id": 2, "a": {} }]
+    );
+
+    // keys crafted to close the literal are only field names
+    let rows = find(doc! {
+        "find": &ctx.collection,
+        "projection": { "x') || (SELECT '{}'::jsonb) || ('": 1 },
+        "sort": { "y') DESC, (SELECT 1": 1 },
+        "singleBatch": true,
+    });
+    assert_eq!(rows, vec![doc! { "_id": 1 }, doc! { "_id": 2 }]);
+}
+
+#[test]
+fn test_find_with_limit_and_get_more() {
+    let ctx = common::setup();
+    let docs: Vec<Document> = (0..20).map(|i| doc! { "i": i }).collect();
+    ctx.col().insert_many(docs, None).unwrap();
+
+    let options = mongodb::options::FindOptions::builder()
+        .sort(doc! { "i": 1 })
+        .limit(12)
+        .batch_size(5)
+        .build();
+    let cursor = ctx.col().find(None, options).unwrap();
+    let rows = common::get_rows(cursor);
+    assert_eq!(rows.len(), 12);
+    assert_eq!(rows[11].get_i32("i").unwrap(), 11);
+}
+
+fn find_ids(col: &mongodb::sync::Collection<Document>, filter: Document) -> Vec<i32> {
+    let options = mongodb::options::FindOptions::builder()
+        .sort(doc! { "_id": 1 })
+        .build();
+    common::get_rows(col.find(filter, options).unwrap())
+        .iter()
+        .map(|row| row.get_i32("_id").unwrap())
+        .collect()
+}
+
+#[test]
+fn test_find_with_nor() {
+    let col = insert!(
+        doc! { "_id": 1, "a": 1, "b": 1 },
+        doc! { "_id": 2, "a": 2, "b": 2 },
+        doc! { "_id": 3, "b": 3 },
+    );
+    assert_eq!(
+        find_ids(&col, doc! { "$nor": [{ "a": 1 }, { "b": 2 }] }),
+        vec![3]
+    );
+}
+
+#[test]
+fn test_find_with_array_operators() {
+    let col = insert!(
+        doc! { "_id": 1, "tags": ["a", "b", "c"], "n": 7 },
+        doc! { "_id": 2, "tags": ["a"], "n": 8.0 },
+        doc! { "_id": 3, "tags": "a", "n": -9 },
+        doc! { "_id": 4, "tags": [], "n": "x" },
+    );
+    assert_eq!(
+        find_ids(&col, doc! { "tags": { "$all": ["a", "b"] } }),
+        vec![1]
+    );
+    assert_eq!(
+        find_ids(&col, doc! { "tags": { "$all": ["a"] } }),
+        vec![1, 2, 3]
+    );
+    assert_eq!(find_ids(&col, doc! { "tags": { "$size": 0 } }), vec![4]);
+    assert_eq!(find_ids(&col, doc! { "tags": { "$size": 1 } }), vec![2]);
+    assert_eq!(find_ids(&col, doc! { "n": { "$mod": [4, 0] } }), vec![2]);
+    assert_eq!(find_ids(&col, doc! { "n": { "$mod": [2, -1] } }), vec![3]);
+}
+
+#[test]
+fn test_find_with_elem_match() {
+    let col = insert!(
+        doc! { "_id": 1, "scores": [82, 85, 88], "items": [{ "sku": "a", "qty": 5 }, { "sku": "b", "qty": 1 }] },
+        doc! { "_id": 2, "scores": [75, 88, 89], "items": [{ "sku": "a", "qty": 1 }] },
+        doc! { "_id": 3, "scores": 85, "nested": { "items": [{ "sku": "a", "qty": 5 }] } },
+    );
+    assert_eq!(
+        find_ids(
+            &col,
+            doc! { "scores": { "$elemMatch": { "$gte": 80, "$lt": 85 } } }
+        ),
+        vec![1]
+    );
+    assert_eq!(
+        find_ids(
+            &col,
+            doc! { "items": { "$elemMatch": { "sku": "a", "qty": { "$gt": 2 } } } }
+        ),
+        vec![1]
+    );
+    assert_eq!(
+        find_ids(
+            &col,
+            doc! { "nested.items": { "$elemMatch": { "sku": "a", "qty": 5 } } }
+        ),
+        vec![3]
+    );
+    assert_eq!(
+        find_ids(
+            &col,
+            doc! { "$or": [{ "items": { "$elemMatch": { "qty": 1, "sku": "b" } } }, { "nested.items.sku": "a" }] }
+        ),
+        vec![1, 3]
+    );
+}
+
+#[test]
+fn test_find_with_invalid_query() {
+    let ctx = common::setup();
+    ctx.col().insert_one(doc! { "a": 1 }, None).unwrap();
+    for filter in [
+        doc! { "a": { "$foo": 1 } },
+        doc! { "a": { "$mod": [0, 1] } },
+        doc! { "a": { "$size": -1 } },
+        doc! { "a": { "$elemMatch": 1 } },
+        doc! { "$nor": [] },
+        doc! { "$foo": 1 },
+    ] {
+        let res = ctx
+            .db()
+            .run_command(doc! { "find": &ctx.collection, "filter": filter }, None);
+        match *res.unwrap_err().kind {
+            mongodb::error::ErrorKind::Command(err) => assert_eq!(err.code, 2),
+            kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+}
+
+#[test]
+fn test_find_with_numeric_equivalence() {
+    let col = insert!(
+        doc! { "_id": 1, "n": 5 },
+        doc! { "_id": 2, "n": Bson::Int64(5) },
+        doc! { "_id": 3, "n": 6.5 },
+        doc! { "_id": 4, "n": Bson::Int64(5_000_000_000) },
+        doc! { "_id": 5, "n": "6" },
+    );
+    assert_eq!(find_ids(&col, doc! { "n": 5.0 }), vec![1, 2]);
+    assert_eq!(find_ids(&col, doc! { "n": { "$gt": 5 } }), vec![3, 4]);
+    assert_eq!(find_ids(&col, doc! { "n": { "$lte": 6.5 } }), vec![1, 2, 3]);
+    assert_eq!(
+        find_ids(&col, doc! { "n": { "$in": [6.5, "6"] } }),
+        vec![3, 5]
+    );
+    assert_eq!(find_ids(&col, doc! { "n": { "$ne": 5 } }), vec![3, 4, 5]);
+}
+
+#[test]
+fn test_find_with_type_bracketing() {
+    let date = bson::DateTime::from_millis(1659448486285);
+    let col = insert!(
+        doc! { "_id": 1, "v": date },
+        doc! { "_id": 2, "v": bson::DateTime::from_millis(1559448486285) },
+        doc! { "_id": 3, "v": "b" },
+        doc! { "_id": 4, "v": 10 },
+        doc! { "_id": 5, "v": null },
+        doc! { "_id": 6 },
+    );
+    assert_eq!(find_ids(&col, doc! { "v": { "$lt": date } }), vec![2]);
+    assert_eq!(find_ids(&col, doc! { "v": { "$gte": "a" } }), vec![3]);
+    assert_eq!(find_ids(&col, doc! { "v": { "$lt": 20 } }), vec![4]);
+    assert_eq!(find_ids(&col, doc! { "v": null }), vec![5, 6]);
+}
+
+#[test]
+fn test_find_with_type() {
+    let col = insert!(
+        doc! { "_id": 1, "v": 1 },
+        doc! { "_id": 2, "v": Bson::Int64(5_000_000_000) },
+        doc! { "_id": 3, "v": 1.5 },
+        doc! { "_id": 4, "v": "x" },
+        doc! { "_id": 5, "v": null },
+        doc! { "_id": 6, "v": [1] },
+        doc! { "_id": 7, "v": { "a": 1 } },
+        doc! { "_id": 8, "v": bson::oid::ObjectId::new() },
+        doc! { "_id": 9, "v": bson::DateTime::now() },
+        doc! { "_id": 10, "v": true },
+        doc! { "_id": 11 },
+    );
+    assert_eq!(
+        find_ids(&col, doc! { "v": { "$type": "number" } }),
+        vec![1, 2, 3]
+    );
+    assert_eq!(find_ids(&col, doc! { "v": { "$type": "int" } }), vec![1]);
+    assert_eq!(find_ids(&col, doc! { "v": { "$type": 18 } }), vec![2]);
+    assert_eq!(find_ids(&col, doc! { "v": { "$type": "double" } }), vec![3]);
+    assert_eq!(
+        find_ids(&col, doc! { "v": { "$type": ["string", 10] } }),
+        vec![4, 5]
+    );
+    assert_eq!(find_ids(&col, doc! { "v": { "$type": "array" } }), vec![6]);
+    assert_eq!(find_ids(&col, doc! { "v": { "$type": "object" } }), vec![7]);
+    assert_eq!(
+        find_ids(&col, doc! { "v": { "$type": "objectId" } }),
+        vec![8]
+    );
+    assert_eq!(find_ids(&col, doc! { "v": { "$type": 9 } }), vec![9]);
+    assert_eq!(find_ids(&col, doc! { "v": { "$type": "bool" } }), vec![10]);
+
+    let res = col
+        .find(doc! { "v": { "$type": "foo" } }, None)
+        .unwrap_err()
+        .to_string();
+    assert!(res.contains("Unknown type name alias: foo"), "{}", res);
+}
+
+#[test]
+fn test_find_through_arrays() {
+    let col = insert!(
+        doc! { "_id": 1, "tags": ["x", "y"], "items": [{ "sku": "a", "qty": 5 }, { "sku": "b", "qty": 1 }] },
+        doc! { "_id": 2, "tags": "x", "items": [{ "sku": "b", "qty": 7 }] },
+        doc! { "_id": 3, "tags": [["x"]], "items": { "sku": "c" } },
+        doc! { "_id": 4, "items": [{ "qty": 2 }] },
+    );
+    assert_eq!(find_ids(&col, doc! { "tags": "x" }), vec![1, 2]);
+    assert_eq!(find_ids(&col, doc! { "tags": ["x"] }), vec![3]);
+    assert_eq!(find_ids(&col, doc! { "tags": { "$ne": "x" } }), vec![3, 4]);
+    assert_eq!(find_ids(&col, doc! { "items.sku": "b" }), vec![1, 2]);
+    assert_eq!(find_ids(&col, doc! { "items.sku": null }), vec![4]);
+    assert_eq!(find_ids(&col, doc! { "items.qty": { "$gt": 6 } }), vec![2]);
+    assert_eq!(find_ids(&col, doc! { "items.qty": { "$lt": 2 } }), vec![1]);
+    assert_eq!(find_ids(&col, doc! { "items.0.sku": "b" }), vec![2]);
+    assert_eq!(
+        find_ids(&col, doc! { "items.1.qty": { "$gte": 1 } }),
+        vec![1]
+    );
+    assert_eq!(
+        find_ids(&col, doc! { "items.sku": { "$in": ["a", "c"] } }),
+        vec![1, 3]
+    );
+    assert_eq!(
+        find_ids(&col, doc! { "items.sku": { "$nin": ["a", "c"] } }),
+        vec![2, 4]
+    );
+    assert_eq!(
+        find_ids(
+            &col,
+            doc! { "items.sku": { "$regex": "^B", "$options": "i" } }
+        ),
+        vec![1, 2]
+    );
+    assert_eq!(find_ids(&col, doc! { "tags": { "$regex": "^y" } }), vec![1]);
+}
+
+#[test]
+fn test_find_with_expr() {
+    let col = insert!(
+        doc! { "_id": 1, "spent": 120, "budget": 100, "name": "Ann" },
+        doc! { "_id": 2, "spent": 50.5, "budget": 100, "name": "bob" },
+        doc! { "_id": 3, "spent": 200, "budget": 150.0, "name": "Cid" },
+    );
+    assert_eq!(
+        find_ids(&col, doc! { "$expr": { "$gt": ["$spent", "$budget"] } }),
+        vec![1, 3]
+    );
+    assert_eq!(
+        find_ids(
+            &col,
+            doc! { "$expr": { "$lt": [{ "$subtract": ["$budget", "$spent"] }, 0] } }
+        ),
+        vec![1, 3]
+    );
+    assert_eq!(
+        find_ids(
+            &col,
+            doc! { "$expr": { "$eq": [{ "$toLower": "$name" }, "bob"] } }
+        ),
+        vec![2]
+    );
+    assert_eq!(
+        find_ids(
+            &col,
+            doc! {
+                "_id": { "$gt": 1 },
+                "$expr": {
+                    "$cond": {
+                        "if": { "$gte": ["$budget", 150] },
+                        "then": true,
+                        "else": { "$lt": ["$spent", 100] },
+                    }
+                },
+            }
+        ),
+        vec![2, 3]
+    );
+
+    for filter in [
+        doc! { "$expr": { "$foo": 1 } },
+        doc! { "$expr": { "$gt": ["$spent"] } },
+        doc! { "$expr": "$$unknown" },
+    ] {
+        let res = col.find(filter, None);
+        match *res.unwrap_err().kind {
+            mongodb::error::ErrorKind::Command(err) => assert_eq!(err.code, 2),
+            kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+}
+
+#[test]
+fn test_find_with_regex_options() {
+    let regex = |pattern: &str, options: &str| {
+        Bson::RegularExpression(bson::Regex {
+            pattern: pattern.to_string(),
+            options: options.to_string(),
+        })
+    };
+    let col = insert!(
+        doc! { "_id": 1, "s": "Apple pie" },
+        doc! { "_id": 2, "s": "banana\napple" },
+        doc! { "_id": 3, "s": "cherry" },
+        doc! { "_id": 4, "s": ["x", "APPLE"] },
+    );
+    assert!(find_ids(&col, doc! { "s": regex("^apple", "") }).is_empty());
+    assert_eq!(
+        find_ids(&col, doc! { "s": regex("^apple", "i") }),
+        vec![1, 4]
+    );
+    assert_eq!(
+        find_ids(&col, doc! { "s": regex("^apple", "im") }),
+        vec![1, 2, 4]
+    );
+    assert!(find_ids(&col, doc! { "s": regex("banana.apple", "") }).is_empty());
+    assert_eq!(
+        find_ids(&col, doc! { "s": regex("banana.apple", "s") }),
+        vec![2]
+    );
+    assert_eq!(
+        find_ids(
+            &col,
+            doc! { "s": { "$regex": "ch err y # a comment", "$options": "x" } }
+        ),
+        vec![3]
+    );
+    assert_eq!(
+        find_ids(&col, doc! { "s": { "$regex": r"\bpie\b" } }),
+        vec![1]
+    );
+    assert_eq!(
+        find_ids(&col, doc! { "s": { "$in": [regex("^ch", ""), "x"] } }),
+        vec![3, 4]
+    );
+    assert_eq!(
+        find_ids(&col, doc! { "s": { "$nin": [regex("apple", "i")] } }),
+        vec![3]
+    );
+    assert_eq!(
+        find_ids(&col, doc! { "s": { "$not": regex("^[ab]", "") } }),
+        vec![1, 3, 4]
+    );
+
+    for filter in [
+        doc! { "s": { "$regex": "a", "$options": "q" } },
+        doc! { "s": { "$regex": r"(?<name>a)" } },
+        doc! { "s": { "$regex": "a++" } },
+    ] {
+        let res = col.find(filter, None);
+        match *res.unwrap_err().kind {
+            mongodb::error::ErrorKind::Command(err) => assert_eq!(err.code, 2),
+            kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+}
@@ -1,6 +1,8 @@
 use bson::Document;
 use indoc::indoc;
 use mongodb::bson::doc;
+use mongodb::options::IndexOptions;
+use mongodb::IndexModel;
 use oxide::utils::hexdump_to_bytes;
 
 mod common;
@@ -119,3 +121,167 @@ fn test_raw_jetbrains_idea_insert() {
 
     assert_eq!(count_documents(ctx), count + 1);
 }
+
+fn create_unique_index(ctx: &common::TestContext, keys: Document) {
+    let options = IndexOptions::builder().unique(true).build();
+    let model = IndexModel::builder().keys(keys).options(options).build();
+    ctx.col().create_index(model, None).unwrap();
+}
+
+#[test]
+fn test_insert_duplicate_key() {
+    let ctx = common::setup();
+    create_unique_index(&ctx, doc! { "serial": 1 });
+
+    let res = ctx
+        .db()
+        .run_command(
+            doc! {
+                "insert": &ctx.collection,
+                "documents": [{ "serial": 1 }, { "serial": 2 }, { "serial": 1 }, { "serial": 3 }],
+            },
+            None,
+        )
+        .unwrap();
+    assert_eq!(res.get_i64("n").unwrap(), 2);
+
+    let errors = res.get_array("writeErrors").unwrap();
+    assert_eq!(errors.len(), 1);
+    let error = errors[0].as_document().unwrap();
+    assert_eq!(error.get_i32("index").unwrap(), 2);
+    assert_eq!(error.get_i32("code").unwrap(), 11000);
+    assert_eq!(
+        error.get_document("keyPattern").unwrap(),
+        &doc! { "serial": 1 }
+    );
+    assert_eq!(
+        error.get_document("keyValue").unwrap(),
+        &doc! { "serial": 1 }
+    );
+    assert!(error
+        .get_str("errmsg")
+        .unwrap()
+        .starts_with("E11000 duplicate key error"));
+
+    let cursor = ctx.col().find(None, None).unwrap();
+    assert_eq!(common::get_rows(cursor).len(), 2);
+}
+
+#[test]
+fn test_insert_unordered() {
+    let ctx = common::setup();
+    create_unique_index(&ctx, doc! { "sku": 1 });
+
+    let res = ctx
+        .db()
+        .run_command(
+            doc! {
+                "insert": &ctx.collection,
+                "documents": [
+                    { "sku": 1 },
+                    { "sku": 1 },
+                    { "_id": [1], "sku": 2 },
+                    { "sku": 3 },
+                ],
+                "ordered": false,
+            },
+            None,
+        )
+        .unwrap();
+    assert_eq!(res.get_i64("n").unwrap(), 2);
+
+    let errors: Vec<(i32, i32)> = res
+        .get_array("writeErrors")
+        .unwrap()
+        .iter()
+        .map(|e| {
+            let e = e.as_document().unwrap();
+            (e.get_i32("index").unwrap(), e.get_i32("code").unwrap())
+        })
+        .collect();
+    assert_eq!(errors, vec![(1, 11000), (2, 53)]);
+}
+
+#[test]
+fn test_insert_many_duplicate_key_error() {
+    let ctx = common::setup();
+    create_unique_index(&ctx, doc! { "email": 1 });
+
+    let err = ctx
+        .col()
+        .insert_many(vec![doc! { "email": 1 }, doc! { "email": 1 }], None)
+        .unwrap_err();
+    match *err.kind {
+        mongodb::error::ErrorKind::BulkWrite(failure) => {
+            let errors = failure.write_errors.unwrap();
+            assert_eq!(errors[0].index, 1);
+            assert_eq!(errors[0].code, 11000);
+        }
+        kind => panic!("unexpected error {:?}", kind),
+    }
+}
+
+#[test]
+fn test_insert_large_batch() {
+    let ctx = common::setup();
+
+    let docs: Vec<Document> = (0..2500).map(|x| doc! { "x": x }).collect();
+    let res = ctx.col().insert_many(docs, None).unwrap();
+    assert_eq!(res.inserted_ids.len(), 2500);
+
+    let cursor = ctx.col().find(None, None).unwrap();
+    assert_eq!(common::get_rows(cursor).len(), 2500);
+}
+
+#[test]
+fn test_insert_duplicate_id() {
+    let ctx = common::setup();
+
+    ctx.col()
+        .insert_one(doc! { "_id": 1, "x": 1 }, None)
+        .unwrap();
+    let res = ctx
+        .db()
+        .run_command(
+            doc! {
+                "insert": &ctx.collection,
+                "documents": [{ "_id": 2 }, { "_id": 1, "x": 2 }],
+            },
+            None,
+        )
+        .unwrap();
+    assert_eq!(res.get_i64("n").unwrap(), 1);
+
+    let errors = res.get_array("writeErrors").unwrap();
+    let error = errors[0].as_document().unwrap();
+    assert_eq!(error.get_i32("index").unwrap(), 1);
+    assert_eq!(error.get_i32("code").unwrap(), 11000);
+    assert_eq!(
+        error.get_document("keyPattern").unwrap(),
+        &doc! { "_id": 1 }
+    );
+    assert_eq!(error.get_document("keyValue").unwrap(), &doc! { "_id": 1 });
+    assert!(error.get_str("errmsg").unwrap().contains("index: _id_"));
+
+    let doc = ctx
+        .col()
+        .find_one(doc! { "_id": 1 }, None)
+        .unwrap()
+        .unwrap();
+    assert_eq!(doc.get_i32("x").unwrap(), 1);
+}
+
+#[test]
+fn test_insert_duplicate_numeric_id() {
+    let ctx = common::setup();
+    ctx.col().insert_one(doc! { "_id": 1 }, None).unwrap();
+
+    // 1.0 is the same id as 1
+    let err = ctx
+        .col()
+        .insert_one(doc! { "_id": 1.0 }, None)
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("E11000"), "{}", err);
+    assert!(err.contains("index: _id_"), "{}", err);
+}
@@ -1,4 +1,6 @@
 use bson::doc;
+use oxide::pg::PgDb;
+use std::env;
 
 mod common;
 
@@ -19,7 +21,10 @@ fn test_list_indexes_basic() {
         .unwrap();
 
     let cursor = res.get_document("cursor").unwrap();
-    assert_eq!(cursor.get_array("firstBatch").unwrap(), &vec![]);
+    assert_eq!(
+        cursor.get_array("firstBatch").unwrap(),
+        &vec![doc! { "v": 2, "key": { "_id": 1 }, "name": "_id_" }.into()]
+    );
     assert_eq!(cursor.get_i64("id").unwrap(), 0);
     assert_eq!(
         cursor.get_str("ns").unwrap(),
@@ -47,3 +52,85 @@ fn test_list_indexes_collection_not_found() {
         )
     );
 }
+
+#[test]
+fn test_list_indexes_only_collection() {
+    let ctx = common::setup();
+    let other = common::setup();
+
+    ctx.col().insert_one(doc! { "x": 1 }, None).unwrap();
+    other.col().insert_one(doc! { "x": 1 }, None).unwrap();
+
+    let names: Vec<String> = ctx
+        .col()
+        .list_indexes(None)
+        .unwrap()
+        .map(|index| index.unwrap().options.unwrap().name.unwrap())
+        .collect();
+    assert_eq!(names, vec!["_id_".to_string()]);
+}
+
+#[test]
+fn test_id_index_created_on_startup() {
+    let ctx = common::setup();
+
+    // a collection from before every table had an _id index
+    let url = common::database_url(&env::var("TEST_DATABASE_URL").unwrap(), &ctx.db);
+    PgDb::new_with_uri(&url)
+        .exec(
+            &format!(
+                r#"CREATE TABLE "{}"."{}" (_jsonb jsonb)"#,
+                ctx.db, ctx.collection
+            ),
+            &[],
+        )
+        .unwrap();
+
+    let restarted = common::setup();
+    let col = restarted.db().collection::<bson::Document>(&ctx.collection);
+    let keys: Vec<bson::Document> = col
+        .list_indexes(None)
+        .unwrap()
+        .map(|index| index.unwrap().keys)
+        .collect();
+    assert_eq!(keys, vec![doc! { "_id": 1 }]);
+}
+
+#[test]
+fn test_id_indexes_found_by_definition_on_startup() {
+    let ctx = common::setup();
+    let collection = format!("{}_ção", ctx.collection);
+    ctx.db()
+        .collection::<bson::Document>(&collection)
+        .insert_one(doc! { "_id": 1 }, None)
+        .unwrap();
+
+    // restarting doesn't add them again
+    let restarted = common::setup();
+    let url = common::database_url(&env::var("TEST_DATABASE_URL").unwrap(), &restarted.db);
+    let mut client = PgDb::new_with_uri(&url);
+    let rows = client
+        .raw_query(
+            "SELECT count(*) FROM pg_indexes WHERE schemaname = $1 AND tablename = $2",
+            &[&restarted.db, &collection],
+        )
+        .unwrap();
+    assert_eq!(rows[0].get::<_, i64>(0), 2);
+
+    // lookups by a number of any type use the index on its value
+    client.exec("SET enable_seqscan = off", &[]).unwrap();
+    let plan = client
+        .raw_query(
+            &format!(
+                r#"EXPLAIN SELECT _jsonb FROM "{}"."{}" WHERE oxide_functions.bson_number(_jsonb->'_id') = 1"#,
+                restarted.db, collection
+            ),
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .map(|row| row.get::<_, String>(0))
+        .collect::<Vec<_>>()
+        .join("\n");
+    assert!(plan.contains("Index"), "{}", plan);
+}
@@ -0,0 +1,69 @@
+use bson::doc;
+use oxide::server::Server;
+use std::net::TcpStream;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod common;
+
+#[test]
+fn test_many_idle_connections() {
+    let ctx = common::setup();
+
+    // more idle clients than the old fixed pool of workers
+    let idle: Vec<TcpStream> = (0..150)
+        .map(|_| TcpStream::connect(format!("localhost:{}", ctx.port())).unwrap())
+        .collect();
+
+    ctx.col().insert_one(doc! { "x": 1 }, None).unwrap();
+    assert!(ctx.col().find_one(doc! { "x": 1 }, None).unwrap().is_some());
+
+    drop(idle);
+}
+
+#[test]
+fn test_max_connections() {
+    let (ctx, _) = common::setup_with_server("db_test", false, |server| {
+        server.with_max_connections(2).unwrap()
+    });
+
+    // holds one of the two slots, the driver needs both (monitor and command)
+    let idle = TcpStream::connect(format!("localhost:{}", ctx.port())).unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    let (tx, rx) = mpsc::channel();
+    let db = ctx.db();
+    thread::spawn(move || {
+        tx.send(db.run_command(doc! { "ping": 1 }, None).is_ok())
+            .unwrap();
+    });
+    assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+
+    drop(idle);
+    assert!(rx.recv_timeout(Duration::from_secs(10)).unwrap());
+}
+
+#[test]
+fn test_shutdown() {
+    let (ctx, shutdown) = common::setup_with_server("db_test", false, |server| server);
+    let res = ctx.db().run_command(doc! { "ping": 1 }, None).unwrap();
+    assert_eq!(res.get_f64("ok").unwrap(), 1.0);
+
+    shutdown.shutdown();
+
+    let start = Instant::now();
+    while TcpStream::connect(format!("localhost:{}", ctx.port())).is_ok() {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "server still accepting connections"
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn test_max_connections_must_be_positive() {
+    let server = Server::new_with_pgurl("localhost".to_string(), 0, "".to_string());
+    assert!(server.with_max_connections(0).is_err());
+}
@@ -0,0 +1,377 @@
+use bson::{doc, Bson, Document};
+use mongodb::sync::ClientSession;
+use std::thread;
+use std::time::Duration;
+
+mod common;
+
+fn run(ctx: &common::TestContext, session: &mut ClientSession, command: Document) -> Document {
+    ctx.db()
+        .run_command_with_session(command, None, session)
+        .unwrap()
+}
+
+fn run_admin(
+    ctx: &common::TestContext,
+    session: &mut ClientSession,
+    command: Document,
+) -> Result<Document, mongodb::error::Error> {
+    ctx.mongodb()
+        .database("admin")
+        .run_command_with_session(command, None, session)
+}
+
+fn error_code(err: mongodb::error::Error) -> i32 {
+    match *err.kind {
+        mongodb::error::ErrorKind::Command(err) => err.code,
+        kind => panic!("unexpected error {:?}", kind),
+    }
+}
+
+fn count(ctx: &common::TestContext) -> usize {
+    let cursor = ctx.col().find(None, None).unwrap();
+    common::get_rows(cursor).len()
+}
+
+#[test]
+fn test_hello_session_timeout() {
+    let ctx = common::setup();
+
+    let res = ctx.db().run_command(doc! { "hello": 1 }, None).unwrap();
+    assert_eq!(res.get_i32("logicalSessionTimeoutMinutes").unwrap(), 30);
+}
+
+#[test]
+fn test_start_refresh_end_sessions() {
+    let ctx = common::setup();
+    let admin = ctx.mongodb().database("admin");
+
+    let res = admin.run_command(doc! { "startSession": 1 }, None).unwrap();
+    let lsid = res.get_document("id").unwrap().clone();
+    match lsid.get("id") {
+        Some(Bson::Binary(binary)) => assert_eq!(binary.bytes.len(), 16),
+        other => panic!("unexpected session id {:?}", other),
+    }
+    assert_eq!(res.get_i32("timeoutMinutes").unwrap(), 30);
+
+    let res = admin
+        .run_command(doc! { "refreshSessions": [&lsid] }, None)
+        .unwrap();
+    assert_eq!(res.get_f64("ok").unwrap(), 1.0);
+
+    let res = admin
+        .run_command(doc! { "endSessions": [&lsid] }, None)
+        .unwrap();
+    assert_eq!(res.get_f64("ok").unwrap(), 1.0);
+
+    assert!(admin.run_command(doc! { "endSessions": 1 }, None).is_err());
+}
+
+#[test]
+fn test_commit_transaction() {
+    let ctx = common::setup();
+    ctx.col().insert_one(doc! { "x": 0 }, None).unwrap();
+    let mut session = ctx.mongodb().start_session(None).unwrap();
+
+    run(
+        &ctx,
+        &mut session,
+        doc! {
+            "insert": &ctx.collection,
+            "documents": [{ "x": 1 }],
+            "txnNumber": 1i64,
+            "startTransaction": true,
+            "autocommit": false,
+        },
+    );
+    run(
+        &ctx,
+        &mut session,
+        doc! {
+            "update": &ctx.collection,
+            "updates": [{ "q": { "x": 0 }, "u": { "$set": { "y": 1 } } }],
+            "txnNumber": 1i64,
+            "autocommit": false,
+        },
+    );
+
+    // the transaction sees its own writes, but nobody else does
+    let res = run(
+        &ctx,
+        &mut session,
+        doc! {
+            "find": &ctx.collection,
+            "txnNumber": 1i64,
+            "autocommit": false,
+        },
+    );
+    let batch = res
+        .get_document("cursor")
+        .unwrap()
+        .get_array("firstBatch")
+        .unwrap();
+    assert_eq!(batch.len(), 2);
+    assert_eq!(count(&ctx), 1);
+    assert!(ctx.col().find_one(doc! { "y": 1 }, None).unwrap().is_none());
+
+    let commit = doc! {
+        "commitTransaction": 1,
+        "txnNumber": 1i64,
+        "autocommit": false,
+    };
+    run_admin(&ctx, &mut session, commit.clone()).unwrap();
+    assert_eq!(count(&ctx), 2);
+    assert!(ctx.col().find_one(doc! { "y": 1 }, None).unwrap().is_some());
+
+    // committing again is a no-op so drivers can retry
+    run_admin(&ctx, &mut session, commit).unwrap();
+}
+
+#[test]
+fn test_abort_transaction() {
+    let ctx = common::setup();
+    ctx.col().insert_one(doc! { "x": 0 }, None).unwrap();
+    let mut session = ctx.mongodb().start_session(None).unwrap();
+
+    run(
+        &ctx,
+        &mut session,
+        doc! {
+            "delete": &ctx.collection,
+            "deletes": [{ "q": {}, "limit": 0 }],
+            "txnNumber": 1i64,
+            "startTransaction": true,
+            "autocommit": false,
+        },
+    );
+    assert_eq!(count(&ctx), 1);
+
+    run_admin(
+        &ctx,
+        &mut session,
+        doc! { "abortTransaction": 1, "txnNumber": 1i64, "autocommit": false },
+    )
+    .unwrap();
+    assert_eq!(count(&ctx), 1);
+
+    let err = run_admin(
+        &ctx,
+        &mut session,
+        doc! { "commitTransaction": 1, "txnNumber": 1i64, "autocommit": false },
+    )
+    .unwrap_err();
+    assert_eq!(error_code(err), 251);
+}
+
+#[test]
+fn test_write_error_aborts_transaction() {
+    let ctx = common::setup();
+    ctx.col().insert_one(doc! { "_id": 1 }, None).unwrap();
+    let mut session = ctx.mongodb().start_session(None).unwrap();
+
+    let res = run(
+        &ctx,
+        &mut session,
+        doc! {
+            "insert": &ctx.collection,
+            "documents": [{ "_id": 2 }, { "_id": 1 }],
+            "txnNumber": 1i64,
+            "startTransaction": true,
+            "autocommit": false,
+        },
+    );
+    assert!(res.contains_key("writeErrors"));
+
+    let err = ctx
+        .db()
+        .run_command_with_session(
+            doc! {
+                "find": &ctx.collection,
+                "txnNumber": 1i64,
+                "autocommit": false,
+            },
+            None,
+            &mut session,
+        )
+        .unwrap_err();
+    assert_eq!(error_code(err), 251);
+    assert_eq!(count(&ctx), 1);
+}
+
+#[test]
+fn test_expired_transaction_is_aborted() {
+    let (ctx, _) = common::setup_with_server("db_test", false, |server| {
+        server.with_transaction_lifetime_limit(Duration::from_secs(1))
+    });
+    ctx.col().insert_one(doc! { "x": 0 }, None).unwrap();
+
+    // each transaction pins one of the two connections of the pool
+    let mut sessions = (0..2)
+        .map(|_| ctx.mongodb().start_session(None).unwrap())
+        .collect::<Vec<ClientSession>>();
+    for session in sessions.iter_mut() {
+        run(
+            &ctx,
+            session,
+            doc! {
+                "insert": &ctx.collection,
+                "documents": [{ "x": 1 }],
+                "txnNumber": 1i64,
+                "startTransaction": true,
+                "autocommit": false,
+            },
+        );
+    }
+    thread::sleep(Duration::from_secs(3));
+
+    // the connections are back in the pool and the writes rolled back
+    assert_eq!(count(&ctx), 1);
+    for session in sessions.iter_mut() {
+        let err = run_admin(
+            &ctx,
+            session,
+            doc! { "commitTransaction": 1, "txnNumber": 1i64, "autocommit": false },
+        )
+        .unwrap_err();
+        assert_eq!(error_code(err), 251);
+    }
+}
+
+#[test]
+fn test_transaction_requires_number() {
+    let ctx = common::setup();
+    let mut session = ctx.mongodb().start_session(None).unwrap();
+
+    let err = ctx
+        .db()
+        .run_command_with_session(
+            doc! {
+                "find": &ctx.collection,
+                "startTransaction": true,
+                "autocommit": false,
+            },
+            None,
+            &mut session,
+        )
+        .unwrap_err();
+    assert_eq!(error_code(err), 72);
+
+    let err = ctx
+        .db()
+        .run_command_with_session(
+            doc! {
+                "create": "in_a_transaction",
+                "txnNumber": 1i64,
+                "startTransaction": true,
+                "autocommit": false,
+            },
+            None,
+            &mut session,
+        )
+        .unwrap_err();
+    assert_eq!(error_code(err), 263);
+}
+
+#[test]
+fn test_retried_insert_runs_once() {
+    let ctx = common::setup();
+    let mut session = ctx.mongodb().start_session(None).unwrap();
+
+    let insert = doc! {
+        "insert": &ctx.collection,
+        "documents": [{ "x": 1 }, { "x": 2 }],
+        "txnNumber": 1i64,
+    };
+    let first = run(&ctx, &mut session, insert.clone());
+    let retry = run(&ctx, &mut session, insert.clone());
+    assert_eq!(first, retry);
+    assert_eq!(count(&ctx), 2);
+
+    // a new number is a new write
+    let mut next = insert;
+    next.insert("txnNumber", 2i64);
+    run(&ctx, &mut session, next);
+    assert_eq!(count(&ctx), 4);
+}
+
+#[test]
+fn test_retried_find_and_modify_runs_once() {
+    let ctx = common::setup();
+    ctx.col().insert_one(doc! { "_id": 1, "n": 0 }, None).unwrap();
+    let mut session = ctx.mongodb().start_session(None).unwrap();
+
+    let inc = doc! {
+        "findAndModify": &ctx.collection,
+        "query": { "_id": 1 },
+        "update": { "$inc": { "n": 1 } },
+        "new": true,
+        "txnNumber": 5i64,
+    };
+    let first = run(&ctx, &mut session, inc.clone());
+    let retry = run(&ctx, &mut session, inc);
+    assert_eq!(first, retry);
+
+    let doc = ctx.col().find_one(doc! { "_id": 1 }, None).unwrap().unwrap();
+    assert_eq!(doc.get_i32("n").unwrap(), 1);
+}
+
+#[test]
+fn test_retried_write_too_old() {
+    let ctx = common::setup();
+    let mut session = ctx.mongodb().start_session(None).unwrap();
+
+    for number in [1i64, 2] {
+        run(
+            &ctx,
+            &mut session,
+            doc! {
+                "insert": &ctx.collection,
+                "documents": [{ "x": number }],
+                "txnNumber": number,
+            },
+        );
+    }
+
+    let err = ctx
+        .db()
+        .run_command_with_session(
+            doc! {
+                "insert": &ctx.collection,
+                "documents": [{ "x": 1 }],
+                "txnNumber": 1i64,
+            },
+            None,
+            &mut session,
+        )
+        .unwrap_err();
+    assert_eq!(error_code(err), 225);
+    assert_eq!(count(&ctx), 2);
+}
+
+#[test]
+fn test_failed_write_is_not_recorded() {
+    let ctx = common::setup();
+    let mut session = ctx.mongodb().start_session(None).unwrap();
+
+    let delete = doc! {
+        "delete": &ctx.collection,
+        "deletes": [{ "q": {}, "limit": 5 }],
+        "txnNumber": 1i64,
+    };
+    assert!(ctx
+        .db()
+        .run_command_with_session(delete, None, &mut session)
+        .is_err());
+
+    // the same number can still be used once the command is fixed
+    run(
+        &ctx,
+        &mut session,
+        doc! {
+            "insert": &ctx.collection,
+            "documents": [{ "x": 1 }],
+            "txnNumber": 1i64,
+        },
+    );
+    assert_eq!(count(&ctx), 1);
+}
@@ -0,0 +1,125 @@
+use bson::{doc, Document};
+use mongodb::sync::Client;
+use oxide::tls::TlsOptions;
+use std::fs;
+use std::io::Read;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod common;
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/tls")
+        .join(name)
+}
+
+fn connect(ctx: &common::TestContext, options: &str) -> Client {
+    let uri = format!(
+        "mongodb://localhost:{}/?serverSelectionTimeoutMS=2000{}",
+        ctx.port(),
+        options
+    );
+    Client::with_uri_str(&uri).unwrap()
+}
+
+fn connect_tls(ctx: &common::TestContext, ca: &str, options: &str) -> Client {
+    let options = format!("&tls=true&tlsCAFile={}{}", fixture(ca).display(), options);
+    connect(ctx, &options)
+}
+
+fn ping(client: &Client, db: &str) -> Result<Document, mongodb::error::Error> {
+    client.database(db).run_command(doc! { "ping": 1 }, None)
+}
+
+#[test]
+fn test_tls_connection() {
+    let options = TlsOptions::new(fixture("server.pem"), fixture("server.key"));
+    let (ctx, _) = common::setup_with_server("db_test", false, |server| server.with_tls(options));
+
+    let client = connect_tls(&ctx, "ca.pem", "");
+    let col = client.database(&ctx.db).collection(&ctx.collection);
+    col.insert_one(doc! { "x": 1 }, None).unwrap();
+    assert!(col.find_one(doc! { "x": 1 }, None).unwrap().is_some());
+
+    // plain connections and untrusted certificates are rejected
+    assert!(ping(&connect(&ctx, ""), &ctx.db).is_err());
+    assert!(ping(&connect_tls(&ctx, "other-ca.pem", ""), &ctx.db).is_err());
+}
+
+#[test]
+fn test_mutual_tls() {
+    let options =
+        TlsOptions::new(fixture("server.pem"), fixture("server.key")).with_ca(fixture("ca.pem"));
+    let (ctx, _) = common::setup_with_server("db_test", false, |server| server.with_tls(options));
+
+    assert!(ping(&connect_tls(&ctx, "ca.pem", ""), &ctx.db).is_err());
+
+    let bundle = format!(
+        "&tlsCertificateKeyFile={}",
+        fixture("client-bundle.pem").display()
+    );
+    assert!(ping(&connect_tls(&ctx, "ca.pem", &bundle), &ctx.db).is_ok());
+}
+
+#[test]
+fn test_stalled_handshakes_release_their_connections() {
+    let options = TlsOptions::new(fixture("server.pem"), fixture("server.key"));
+    let (ctx, _) = common::setup_with_server("db_test", false, |server| {
+        server.with_tls(options).with_max_connections(2).unwrap()
+    });
+
+    // take every slot without ever starting the handshake
+    let stalled = (0..2)
+        .map(|_| TcpStream::connect(("localhost", ctx.port())).unwrap())
+        .collect::<Vec<TcpStream>>();
+    let started = Instant::now();
+    for mut stream in stalled {
+        stream
+            .set_read_timeout(Some(Duration::from_secs(30)))
+            .unwrap();
+        assert_eq!(stream.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+    assert!(started.elapsed() < Duration::from_secs(20));
+
+    // the client needs both slots, one to monitor the server and one to ping it
+    assert!(ping(&connect_tls(&ctx, "ca.pem", ""), &ctx.db).is_ok());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_reload_on_sighup() {
+    let dir = std::env::temp_dir().join(format!("oxide_tls_{}", uuid::Uuid::new_v4().simple()));
+    fs::create_dir_all(&dir).unwrap();
+    let cert = dir.join("cert.pem");
+    let key = dir.join("key.pem");
+    fs::copy(fixture("server.pem"), &cert).unwrap();
+    fs::copy(fixture("server.key"), &key).unwrap();
+
+    let options = TlsOptions::new(&cert, &key);
+    let (ctx, _) = common::setup_with_server("db_test", false, |server| server.with_tls(options));
+    assert!(ping(&connect_tls(&ctx, "ca.pem", ""), &ctx.db).is_ok());
+    assert!(ping(&connect_tls(&ctx, "other-ca.pem", ""), &ctx.db).is_err());
+
+    fs::copy(fixture("other-server.pem"), &cert).unwrap();
+    fs::copy(fixture("other-server.key"), &key).unwrap();
+    let status = Command::new("kill")
+        .args(["-HUP", &std::process::id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let start = Instant::now();
+    while ping(&connect_tls(&ctx, "other-ca.pem", ""), &ctx.db).is_err() {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "certificates weren't reloaded"
+        );
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
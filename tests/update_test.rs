@@ -1,6 +1,9 @@
 use bson::Bson;
 use chrono::Utc;
-use mongodb::{bson::doc, options::ReplaceOptions};
+use mongodb::{
+    bson::doc,
+    options::{ReplaceOptions, UpdateOptions},
+};
 
 mod common;
 
@@ -357,8 +360,9 @@ fn test_upsert() {
             ReplaceOptions::builder().upsert(true).build(),
         )
         .unwrap();
-    let count = res.modified_count;
-    assert_eq!(count, 1);
+    assert_eq!(res.matched_count, 0);
+    assert_eq!(res.modified_count, 0);
+    assert!(res.upserted_id.is_some());
 
     let cursor = ctx.col().find(doc! { "x": 1 }, None).unwrap();
     let results = cursor.collect::<Vec<_>>();
@@ -646,7 +650,7 @@ fn test_update_with_add_to_set_for_non_array() {
             None,
         )
         .unwrap_err();
-    assert!(err.to_string().contains("Cannot apply $addToSet to a non-array field. Field named 'letters' has a non-array type int in the document _id: 1"));
+    assert!(err.to_string().contains("Cannot apply $addToSet to a non-array field. Field named 'letters' has a non-array type string in the document _id: 1"));
 }
 
 #[test]
@@ -667,7 +671,7 @@ fn test_update_with_add_to_set_nested_for_non_array() {
             None,
         )
         .unwrap_err();
-    assert!(err.to_string().contains("Cannot apply $addToSet to a non-array field. Field named 'a' has a non-array type int in the document _id: 1"));
+    assert!(err.to_string().contains("Cannot apply $addToSet to a non-array field. Field named 'a' has a non-array type string in the document _id: 1"));
 }
 
 #[test]
@@ -695,3 +699,662 @@ fn test_update_with_add_to_set_date() {
         "1996-12-20T00:39:57Z"
     );
 }
+
+#[test]
+fn test_update_with_expr() {
+    let ctx = common::setup();
+
+    ctx.col()
+        .insert_many(
+            vec![
+                doc! { "_id": 1, "qty": 5, "limit": 10 },
+                doc! { "_id": 2, "qty": 15, "limit": 10 },
+            ],
+            None,
+        )
+        .unwrap();
+
+    ctx.col()
+        .update_many(
+            doc! { "$expr": { "$gt": ["$qty", "$limit"] } },
+            doc! { "$set": { "over": true } },
+            None,
+        )
+        .unwrap();
+
+    let rows = common::get_rows(ctx.col().find(doc! { "over": true }, None).unwrap());
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].get_i32("_id").unwrap(), 2);
+}
+
+#[test]
+fn test_update_with_push() {
+    let ctx = common::setup();
+    ctx.col()
+        .insert_many(
+            vec![
+                doc! { "_id": 1, "scores": [3, 1] },
+                doc! { "_id": 2, "name": "empty" },
+            ],
+            None,
+        )
+        .unwrap();
+
+    ctx.col()
+        .update_many(doc! {}, doc! { "$push": { "scores": 2 } }, None)
+        .unwrap();
+    ctx.col()
+        .update_one(
+            doc! { "_id": 1 },
+            doc! { "$push": { "scores": { "$each": [9, 8], "$position": 1 } } },
+            None,
+        )
+        .unwrap();
+    ctx.col()
+        .update_one(
+            doc! { "_id": 2 },
+            doc! { "$push": { "nested.list": { "$each": [5, 4, 7, 6], "$sort": -1, "$slice": 3 } } },
+            None,
+        )
+        .unwrap();
+
+    let rows = common::get_rows(ctx.col().find(doc! {}, None).unwrap());
+    assert_eq!(
+        rows[0].get_array("scores").unwrap(),
+        &vec![
+            Bson::Int32(3),
+            Bson::Int32(9),
+            Bson::Int32(8),
+            Bson::Int32(1),
+            Bson::Int32(2)
+        ]
+    );
+    assert_eq!(rows[1].get_array("scores").unwrap(), &vec![Bson::Int32(2)]);
+    assert_eq!(
+        rows[1]
+            .get_document("nested")
+            .unwrap()
+            .get_array("list")
+            .unwrap(),
+        &vec![Bson::Int32(7), Bson::Int32(6), Bson::Int32(5)]
+    );
+}
+
+#[test]
+fn test_update_with_push_and_add_to_set_quoted_fields() {
+    let ctx = common::setup();
+    ctx.col()
+        .insert_one(doc! { "_id": 1, "it's": [{ "o'k": 2 }] }, None)
+        .unwrap();
+
+    ctx.col()
+        .update_one(
+            doc! { "_id": 1 },
+            doc! {
+                "$push": { "it's": { "$each": [{ "o'k": 1 }], "$sort": { "o'k": 1 } } },
+                "$addToSet": { "a.b'": "x') || ('" },
+            },
+            None,
+        )
+        .unwrap();
+
+    let rows = common::get_rows(ctx.col().find(doc! {}, None).unwrap());
+    assert_eq!(
+        rows,
+        vec![doc! { "_id": 1, "it's": [{ "o'k": 1 }, { "o'k": 2 }], "a": { "b'": ["x') || ('"] } }]
+    );
+}
+
+#[test]
+fn test_update_with_push_sort_by_field() {
+    let ctx = common::setup();
+    ctx.col()
+        .insert_one(
+            doc! { "_id": 1, "items": [{ "n": "b", "qty": 2 }, { "n": "a", "qty": 5 }] },
+            None,
+        )
+        .unwrap();
+
+    ctx.col()
+        .update_one(
+            doc! { "_id": 1 },
+            doc! { "$push": { "items": {
+                "$each": [{ "n": "c", "qty": 1 }],
+                "$sort": { "qty": -1 },
+                "$slice": -2,
+            } } },
+            None,
+        )
+        .unwrap();
+
+    let rows = common::get_rows(ctx.col().find(doc! {}, None).unwrap());
+    let names = rows[0]
+        .get_array("items")
+        .unwrap()
+        .iter()
+        .map(|item| item.as_document().unwrap().get_str("n").unwrap())
+        .collect::<Vec<&str>>();
+    assert_eq!(names, vec!["b", "c"]);
+}
+
+#[test]
+fn test_update_with_push_invalid() {
+    let ctx = common::setup();
+    ctx.col()
+        .insert_many(vec![doc! { "_id": 1, "a": 1 }, doc! { "_id": 2 }], None)
+        .unwrap();
+
+    let err = ctx
+        .col()
+        .update_one(doc! { "_id": 1 }, doc! { "$push": { "a": 2 } }, None)
+        .unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("The field 'a' must be an array but is of type int in document {_id: 1}"));
+
+    // only the matched documents need to hold an array
+    ctx.col()
+        .update_one(doc! { "_id": 2 }, doc! { "$push": { "a": 2 } }, None)
+        .unwrap();
+
+    let err = ctx
+        .col()
+        .update_one(
+            doc! { "_id": 2 },
+            doc! { "$push": { "a": { "$each": [1], "$sort": 0 } } },
+            None,
+        )
+        .unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("The $sort element value must be either 1 or -1"));
+}
+
+#[test]
+fn test_update_with_add_to_set_each() {
+    let ctx = common::setup();
+    ctx.col()
+        .insert_one(doc! { "_id": 1, "tags": ["a", { "k": 1 }], "n": [1] }, None)
+        .unwrap();
+
+    ctx.col()
+        .update_one(
+            doc! { "_id": 1 },
+            doc! { "$addToSet": {
+                "tags": { "$each": ["b", "a", { "k": 1 }, { "k": 1, "m": 2 }, "b"] },
+                "n": 1.0,
+            } },
+            None,
+        )
+        .unwrap();
+
+    let rows = common::get_rows(ctx.col().find(doc! {}, None).unwrap());
+    assert_eq!(
+        rows[0].get_array("tags").unwrap(),
+        &vec![
+            Bson::String("a".to_string()),
+            Bson::Document(doc! { "k": 1 }),
+            Bson::String("b".to_string()),
+            Bson::Document(doc! { "k": 1, "m": 2 }),
+        ]
+    );
+    assert_eq!(rows[0].get_array("n").unwrap(), &vec![Bson::Int32(1)]);
+}
+
+#[test]
+fn test_update_with_pull() {
+    let ctx = common::setup();
+    ctx.col()
+        .insert_many(
+            vec![
+                doc! {
+                    "_id": 1,
+                    "nums": [1, 6, 2.0, 8, 2],
+                    "items": [{ "sku": "a", "qty": 3 }, { "sku": "b", "qty": 7 }, { "sku": "c", "qty": 1 }],
+                    "tags": ["x", "y"],
+                },
+                doc! { "_id": 2, "name": "no arrays" },
+            ],
+            None,
+        )
+        .unwrap();
+
+    ctx.col()
+        .update_many(
+            doc! {},
+            doc! { "$pull": {
+                "nums": 2,
+                "items": { "qty": { "$lt": 5 } },
+                "tags": { "$in": ["y", "z"] },
+            } },
+            None,
+        )
+        .unwrap();
+
+    let rows = common::get_rows(ctx.col().find(doc! {}, None).unwrap());
+    assert_eq!(
+        rows[0].get_array("nums").unwrap(),
+        &vec![Bson::Int32(1), Bson::Int32(6), Bson::Int32(8)]
+    );
+    assert_eq!(
+        rows[0].get_array("items").unwrap(),
+        &vec![Bson::Document(doc! { "qty": 7, "sku": "b" })]
+    );
+    assert_eq!(
+        rows[0].get_array("tags").unwrap(),
+        &vec![Bson::String("x".to_string())]
+    );
+    // missing arrays aren't created
+    assert_eq!(rows[1], doc! { "_id": 2, "name": "no arrays" });
+
+    ctx.col()
+        .update_one(
+            doc! { "_id": 1 },
+            doc! { "$pull": { "nums": { "$gte": 6 } } },
+            None,
+        )
+        .unwrap();
+    let rows = common::get_rows(ctx.col().find(doc! { "_id": 1 }, None).unwrap());
+    assert_eq!(rows[0].get_array("nums").unwrap(), &vec![Bson::Int32(1)]);
+
+    let err = ctx
+        .col()
+        .update_one(
+            doc! { "_id": 1 },
+            doc! { "$pull": { "nums": { "$foo": 1 } } },
+            None,
+        )
+        .unwrap_err();
+    match *err.kind {
+        mongodb::error::ErrorKind::Command(err) => assert_eq!(err.code, 2),
+        kind => panic!("unexpected error {:?}", kind),
+    }
+
+    let err = ctx
+        .col()
+        .update_one(doc! { "_id": 2 }, doc! { "$pull": { "name": "x" } }, None)
+        .unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("Cannot apply $pull to a non-array value"));
+}
+
+#[test]
+fn test_update_with_pull_all_and_pop() {
+    let ctx = common::setup();
+    ctx.col()
+        .insert_one(
+            doc! { "_id": 1, "a": [1, 2, 3, 2, [1]], "b": { "c": [1, 2, 3] }, "d": 5 },
+            None,
+        )
+        .unwrap();
+
+    ctx.col()
+        .update_one(
+            doc! { "_id": 1 },
+            doc! { "$pullAll": { "a": [2, [1]] }, "$pop": { "b.c": 1 } },
+            None,
+        )
+        .unwrap();
+    ctx.col()
+        .update_one(doc! { "_id": 1 }, doc! { "$pop": { "a": -1 } }, None)
+        .unwrap();
+
+    let rows = common::get_rows(ctx.col().find(doc! {}, None).unwrap());
+    assert_eq!(rows[0].get_array("a").unwrap(), &vec![Bson::Int32(3)]);
+    assert_eq!(
+        rows[0].get_document("b").unwrap().get_array("c").unwrap(),
+        &vec![Bson::Int32(1), Bson::Int32(2)]
+    );
+
+    let err = ctx
+        .col()
+        .update_one(doc! { "_id": 1 }, doc! { "$pop": { "d": 1 } }, None)
+        .unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("Path 'd' contains an element of non-array type 'int'"));
+
+    let err = ctx
+        .col()
+        .update_one(doc! { "_id": 1 }, doc! { "$pop": { "a": 2 } }, None)
+        .unwrap_err();
+    assert!(err.to_string().contains("$pop expects 1 or -1, found: 2"));
+}
+
+#[test]
+fn test_update_with_pull_and_pop_quoted_fields() {
+    let ctx = common::setup();
+    ctx.col()
+        .insert_one(
+            doc! { "_id": 1, "it's": [1, 2, 3], "a": { "b'": [{ "c'": 1 }, { "c'": 2 }] }, "d'": [1, 2] },
+            None,
+        )
+        .unwrap();
+
+    ctx.col()
+        .update_one(
+            doc! { "_id": 1 },
+            doc! {
+                "$pullAll": { "it's": [2] },
+                "$pull": { "a.b'": { "c'": 1 } },
+                "$pop": { "d'": 1 },
+            },
+            None,
+        )
+        .unwrap();
+
+    let rows = common::get_rows(ctx.col().find(doc! {}, None).unwrap());
+    assert_eq!(
+        rows,
+        vec![doc! { "_id": 1, "it's": [1, 3], "a": { "b'": [{ "c'": 2 }] }, "d'": [1] }]
+    );
+}
+
+#[test]
+fn test_update_with_rename() {
+    let ctx = common::setup();
+    ctx.col()
+        .insert_many(
+            vec![
+                doc! { "_id": 1, "name": "John", "info": { "age": 30 }, "old": 1 },
+                doc! { "_id": 2, "other": true },
+            ],
+            None,
+        )
+        .unwrap();
+
+    ctx.col()
+        .update_many(
+            doc! {},
+            doc! { "$rename": { "name": "profile.name", "info.age": "age", "missing": "x" } },
+            None,
+        )
+        .unwrap();
+
+    let rows = common::get_rows(ctx.col().find(doc! {}, None).unwrap());
+    assert_eq!(
+        rows[0],
+        doc! { "_id": 1, "age": 30, "old": 1, "info": {}, "profile": { "name": "John" } }
+    );
+    assert_eq!(rows[1], doc! { "_id": 2, "other": true });
+}
+
+#[test]
+fn test_update_with_min_and_max() {
+    let ctx = common::setup();
+    ctx.col()
+        .insert_one(
+            doc! { "_id": 1, "low": 5, "high": 5, "when": common::get_datetime("2020-01-01T00:00:00Z"), "mixed": "a" },
+            None,
+        )
+        .unwrap();
+
+    ctx.col()
+        .update_one(
+            doc! { "_id": 1 },
+            doc! { "$min": { "low": 2.5, "when": common::get_datetime("2019-01-01T00:00:00Z"), "new": 1 }, "$max": { "high": 3 } },
+            None,
+        )
+        .unwrap();
+    // numbers sort before strings
+    ctx.col()
+        .update_one(doc! { "_id": 1 }, doc! { "$max": { "mixed": 100 } }, None)
+        .unwrap();
+
+    let rows = common::get_rows(ctx.col().find(doc! {}, None).unwrap());
+    let row = &rows[0];
+    assert_eq!(row.get_f64("low").unwrap(), 2.5);
+    assert_eq!(row.get_i32("high").unwrap(), 5);
+    assert_eq!(row.get_i32("new").unwrap(), 1);
+    assert_eq!(row.get_str("mixed").unwrap(), "a");
+    assert_eq!(
+        row.get_datetime("when")
+            .unwrap()
+            .try_to_rfc3339_string()
+            .unwrap(),
+        "2019-01-01T00:00:00Z"
+    );
+}
+
+#[test]
+fn test_update_with_mul() {
+    let ctx = common::setup();
+    ctx.col()
+        .insert_one(
+            doc! { "_id": 1, "int": 3, "double": 1.5, "name": "x", "nested": { "n": 2 } },
+            None,
+        )
+        .unwrap();
+
+    ctx.col()
+        .update_one(
+            doc! { "_id": 1 },
+            doc! { "$mul": { "int": 2, "double": 2, "nested.n": 0.5, "missing": 4 } },
+            None,
+        )
+        .unwrap();
+
+    let rows = common::get_rows(ctx.col().find(doc! {}, None).unwrap());
+    let row = &rows[0];
+    assert_eq!(row.get("int").unwrap(), &Bson::Int32(6));
+    assert_eq!(row.get("double").unwrap(), &Bson::Double(3.0));
+    assert_eq!(
+        row.get_document("nested").unwrap().get("n").unwrap(),
+        &Bson::Double(1.0)
+    );
+    assert_eq!(row.get("missing").unwrap(), &Bson::Int32(0));
+
+    let err = ctx
+        .col()
+        .update_one(doc! { "_id": 1 }, doc! { "$mul": { "name": 2 } }, None)
+        .unwrap_err();
+    assert!(err.to_string().contains(
+        "Cannot apply $mul to a value of non-numeric type. {_id: 1} has the field 'name' of non-numeric type string"
+    ));
+}
+
+#[test]
+fn test_update_with_current_date() {
+    let ctx = common::setup();
+    ctx.col().insert_one(doc! { "_id": 1 }, None).unwrap();
+
+    let before = Utc::now().timestamp();
+    ctx.col()
+        .update_one(
+            doc! { "_id": 1 },
+            doc! { "$currentDate": { "updated": true, "meta.ts": { "$type": "timestamp" } } },
+            None,
+        )
+        .unwrap();
+
+    let rows = common::get_rows(ctx.col().find(doc! {}, None).unwrap());
+    let updated = rows[0].get_datetime("updated").unwrap();
+    assert!(updated.timestamp_millis() / 1000 >= before - 1);
+    let ts = rows[0]
+        .get_document("meta")
+        .unwrap()
+        .get_timestamp("ts")
+        .unwrap();
+    assert!(ts.time as i64 >= before - 1);
+}
+
+#[test]
+fn test_update_with_rename_min_max_mul_quoted_fields() {
+    let ctx = common::setup();
+    ctx.col()
+        .insert_one(
+            doc! { "_id": 1, "it's": "x", "lo'w": 5, "hi'gh": 5, "n'": { "m'": 3 } },
+            None,
+        )
+        .unwrap();
+
+    ctx.col()
+        .update_one(
+            doc! { "_id": 1 },
+            doc! {
+                "$rename": { "it's": "a'.b" },
+                "$min": { "lo'w": 2 },
+                "$max": { "hi'gh": 7 },
+                "$mul": { "n'.m'": 2 },
+                "$currentDate": { "when'": true },
+            },
+            None,
+        )
+        .unwrap();
+
+    let rows = common::get_rows(ctx.col().find(doc! {}, None).unwrap());
+    let row = &rows[0];
+    assert!(!row.contains_key("it's"));
+    assert_eq!(row.get_document("a'").unwrap(), &doc! { "b": "x" });
+    assert_eq!(row.get_i32("lo'w").unwrap(), 2);
+    assert_eq!(row.get_i32("hi'gh").unwrap(), 7);
+    assert_eq!(row.get_document("n'").unwrap(), &doc! { "m'": 6 });
+    assert!(row.get_datetime("when'").is_ok());
+}
+
+#[test]
+fn test_upsert_with_operators() {
+    let ctx = common::setup();
+
+    let res = ctx
+        .col()
+        .update_one(
+            doc! { "name": "Mike", "age": { "$gt": 40 }, "address.city": "Lisbon" },
+            doc! { "$set": { "active": true }, "$setOnInsert": { "visits": 0 }, "$push": { "tags": "new" } },
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .unwrap();
+    assert_eq!(res.matched_count, 0);
+    assert_eq!(res.modified_count, 0);
+    let id = res.upserted_id.unwrap();
+
+    let rows = common::get_rows(ctx.col().find(doc! {}, None).unwrap());
+    assert_eq!(rows.len(), 1);
+    let row = &rows[0];
+    assert_eq!(row.get("_id").unwrap(), &id);
+    assert_eq!(row.get_str("name").unwrap(), "Mike");
+    assert!(!row.contains_key("age"));
+    assert_eq!(
+        row.get_document("address").unwrap(),
+        &doc! { "city": "Lisbon" }
+    );
+    assert_eq!(row.get_bool("active").unwrap(), true);
+    assert_eq!(row.get_i32("visits").unwrap(), 0);
+    assert_eq!(row.get_array("tags").unwrap(), &vec![Bson::from("new")]);
+}
+
+#[test]
+fn test_upsert_with_and_filter() {
+    let ctx = common::setup();
+
+    let res = ctx
+        .db()
+        .run_command(
+            doc! {
+                "update": &ctx.collection,
+                "updates": [
+                    {
+                        "q": { "$and": [{ "_id": 7 }, { "kind": { "$eq": "a" } }] },
+                        "u": { "$set": { "x": 1 } },
+                        "upsert": true,
+                    },
+                    {
+                        "q": { "_id": 7 },
+                        "u": { "$set": { "x": 2 }, "$setOnInsert": { "y": 1 } },
+                        "upsert": true,
+                    },
+                ],
+            },
+            None,
+        )
+        .unwrap();
+    assert_eq!(res.get_i64("n").unwrap(), 2);
+    assert_eq!(res.get_i64("nModified").unwrap(), 1);
+    assert_eq!(
+        res.get_array("upserted").unwrap(),
+        &vec![Bson::Document(doc! { "index": 0, "_id": 7 })]
+    );
+
+    let rows = common::get_rows(ctx.col().find(doc! {}, None).unwrap());
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].get_i32("_id").unwrap(), 7);
+    assert_eq!(rows[0].get_str("kind").unwrap(), "a");
+    assert_eq!(rows[0].get_i32("x").unwrap(), 2);
+    assert!(!rows[0].contains_key("y"));
+}
+
+#[test]
+fn test_update_counts_documents_once() {
+    let ctx = common::setup();
+    ctx.col()
+        .insert_many(vec![doc! { "x": 1, "y": 1 }, doc! { "x": 2, "y": 1 }], None)
+        .unwrap();
+
+    let res = ctx
+        .col()
+        .update_one(
+            doc! { "x": 1 },
+            doc! { "$set": { "z": 1 }, "$inc": { "y": 1 } },
+            None,
+        )
+        .unwrap();
+    assert_eq!(res.matched_count, 1);
+    assert_eq!(res.modified_count, 1);
+
+    // documents the operators leave as they were are matched, not modified
+    let res = ctx
+        .col()
+        .update_many(
+            doc! {},
+            doc! { "$set": { "z": 1 }, "$max": { "y": 2 } },
+            None,
+        )
+        .unwrap();
+    assert_eq!(res.matched_count, 2);
+    assert_eq!(res.modified_count, 1);
+
+    let rows = common::get_rows(ctx.col().find(doc! {}, None).unwrap());
+    assert_eq!(rows[0].get_i32("y").unwrap(), 2);
+    assert_eq!(rows[1].get_i32("z").unwrap(), 1);
+}
+
+#[test]
+fn test_upsert_with_set_on_insert_only_matching() {
+    let ctx = common::setup();
+    ctx.col()
+        .insert_one(doc! { "_id": 1, "x": 1 }, None)
+        .unwrap();
+
+    let res = ctx
+        .col()
+        .update_one(
+            doc! { "_id": 1 },
+            doc! { "$setOnInsert": { "y": 1 } },
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .unwrap();
+    assert_eq!(res.matched_count, 1);
+    assert_eq!(res.modified_count, 0);
+    assert!(res.upserted_id.is_none());
+
+    let rows = common::get_rows(ctx.col().find(doc! {}, None).unwrap());
+    assert_eq!(rows, vec![doc! { "_id": 1, "x": 1 }]);
+}
+
+#[test]
+fn test_upsert_with_repeated_equality_fields() {
+    let ctx = common::setup();
+
+    let res = ctx.col().update_one(
+        doc! { "$and": [{ "x": 1 }, { "x": 2 }] },
+        doc! { "$set": { "y": 1 } },
+        UpdateOptions::builder().upsert(true).build(),
+    );
+
+    assert!(res
+        .unwrap_err()
+        .to_string()
+        .contains("Conflicting keys 'x' and 'x'"));
+    assert!(common::get_rows(ctx.col().find(doc! {}, None).unwrap()).is_empty());
+}
@@ -0,0 +1,323 @@
+use bson::{doc, Bson, Document};
+use oxide::wire::{
+    MsgHeader, OpMsg, OpMsgSection, Serializable, CHECKSUM_PRESENT, EXHAUST_ALLOWED, MORE_TO_COME,
+    OP_MSG,
+};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+mod common;
+
+fn connect(ctx: &common::TestContext) -> TcpStream {
+    let stream = TcpStream::connect(format!("localhost:{}", ctx.port())).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream
+}
+
+fn send(stream: &mut TcpStream, request_id: u32, flags: u32, doc: Document) {
+    let header = MsgHeader {
+        message_length: 0,
+        request_id,
+        response_to: 0,
+        op_code: OP_MSG,
+    };
+    let mut msg = OpMsg::new_with_body_kind(header, flags, None, &doc);
+    msg.header.message_length = msg.to_vec().len() as u32;
+    stream.write_all(&msg.to_vec()).unwrap();
+}
+
+fn receive(stream: &mut TcpStream) -> OpMsg {
+    let mut size = [0u8; 4];
+    stream.read_exact(&mut size).unwrap();
+    let mut buffer = size.to_vec();
+    buffer.resize(u32::from_le_bytes(size) as usize, 0);
+    stream.read_exact(&mut buffer[4..]).unwrap();
+    OpMsg::from_bytes(&buffer).unwrap()
+}
+
+fn body(msg: &OpMsg) -> &Document {
+    &msg.sections[0].documents[0]
+}
+
+#[test]
+fn test_checksum() {
+    let ctx = common::setup();
+    let mut stream = connect(&ctx);
+
+    send(
+        &mut stream,
+        1,
+        CHECKSUM_PRESENT,
+        doc! { "ping": 1, "$db": &ctx.db },
+    );
+    let reply = receive(&mut stream);
+    assert_eq!(reply.header.response_to, 1);
+    assert_eq!(reply.flags, CHECKSUM_PRESENT);
+    assert!(reply.checksum.is_some());
+    assert_eq!(body(&reply).get_f64("ok").unwrap(), 1.0);
+
+    // without a checksum on the request there's none on the reply
+    send(&mut stream, 2, 0, doc! { "ping": 1, "$db": &ctx.db });
+    let reply = receive(&mut stream);
+    assert_eq!(reply.flags, 0);
+    assert_eq!(reply.checksum, None);
+}
+
+#[test]
+fn test_checksum_mismatch() {
+    let ctx = common::setup();
+    let mut stream = connect(&ctx);
+
+    let header = MsgHeader {
+        message_length: 0,
+        request_id: 1,
+        response_to: 0,
+        op_code: OP_MSG,
+    };
+    let mut msg = OpMsg::new_with_body_kind(
+        header,
+        CHECKSUM_PRESENT,
+        None,
+        &doc! { "ping": 1, "$db": "test" },
+    );
+    msg.header.message_length = msg.to_vec().len() as u32;
+    let mut bytes = msg.to_vec();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    stream.write_all(&bytes).unwrap();
+
+    // the connection is dropped without running the command
+    let mut buffer = vec![];
+    let _ = stream.read_to_end(&mut buffer);
+    assert!(buffer.iter().all(|b| *b == 0));
+}
+
+#[test]
+fn test_more_to_come() {
+    let ctx = common::setup();
+    let mut stream = connect(&ctx);
+
+    send(
+        &mut stream,
+        1,
+        MORE_TO_COME,
+        doc! {
+            "insert": &ctx.collection,
+            "documents": [{ "x": 1 }, { "x": 2 }],
+            "writeConcern": { "w": 0 },
+            "$db": &ctx.db,
+        },
+    );
+    send(
+        &mut stream,
+        2,
+        0,
+        doc! { "count": &ctx.collection, "$db": &ctx.db },
+    );
+
+    // the first reply is the one to the count
+    let reply = receive(&mut stream);
+    assert_eq!(reply.header.response_to, 2);
+    assert_eq!(body(&reply).get_i32("n").unwrap(), 2);
+}
+
+#[test]
+fn test_exhaust_cursor() {
+    let ctx = common::setup();
+    let docs: Vec<Document> = (0..10).map(|x| doc! { "x": x }).collect();
+    ctx.col().insert_many(docs, None).unwrap();
+
+    let mut stream = connect(&ctx);
+    send(
+        &mut stream,
+        1,
+        0,
+        doc! { "find": &ctx.collection, "batchSize": 2, "$db": &ctx.db },
+    );
+    let reply = receive(&mut stream);
+    let cursor = body(&reply).get_document("cursor").unwrap();
+    let cursor_id = cursor.get_i64("id").unwrap();
+    assert_ne!(cursor_id, 0);
+    let mut seen = cursor.get_array("firstBatch").unwrap().len();
+
+    send(
+        &mut stream,
+        2,
+        EXHAUST_ALLOWED,
+        doc! {
+            "getMore": cursor_id,
+            "collection": &ctx.collection,
+            "batchSize": 3,
+            "$db": &ctx.db,
+        },
+    );
+
+    // batches keep coming until the cursor is exhausted
+    let mut response_to = 2;
+    loop {
+        let reply = receive(&mut stream);
+        assert_eq!(reply.header.response_to, response_to);
+        let cursor = body(&reply).get_document("cursor").unwrap();
+        seen += cursor.get_array("nextBatch").unwrap().len();
+
+        if cursor.get_i64("id").unwrap() == 0 {
+            assert_eq!(reply.flags & MORE_TO_COME, 0);
+            break;
+        }
+        assert_eq!(reply.flags & MORE_TO_COME, MORE_TO_COME);
+        response_to = reply.header.request_id;
+    }
+    assert_eq!(seen, 10);
+
+    // the connection is usable again once the stream ends
+    send(&mut stream, 3, 0, doc! { "ping": 1, "$db": &ctx.db });
+    let reply = receive(&mut stream);
+    assert_eq!(reply.header.response_to, 3);
+    assert_eq!(body(&reply).get("ok"), Some(&Bson::Double(1.0)));
+}
+
+fn send_sections(stream: &mut TcpStream, request_id: u32, sections: Vec<OpMsgSection>) {
+    let header = MsgHeader {
+        message_length: 0,
+        request_id,
+        response_to: 0,
+        op_code: OP_MSG,
+    };
+    let mut msg = OpMsg {
+        header,
+        flags: 0,
+        sections,
+        checksum: None,
+    };
+    msg.header.message_length = msg.to_vec().len() as u32;
+    stream.write_all(&msg.to_vec()).unwrap();
+}
+
+fn body_section(doc: Document) -> OpMsgSection {
+    OpMsgSection {
+        kind: 0,
+        identifier: None,
+        documents: vec![doc],
+    }
+}
+
+fn sequence_section(identifier: &str, documents: Vec<Document>) -> OpMsgSection {
+    OpMsgSection {
+        kind: 1,
+        identifier: Some(identifier.to_string()),
+        documents,
+    }
+}
+
+#[test]
+fn test_document_sequences() {
+    let ctx = common::setup();
+    let mut stream = connect(&ctx);
+
+    send_sections(
+        &mut stream,
+        1,
+        vec![
+            body_section(doc! { "insert": &ctx.collection, "$db": &ctx.db }),
+            sequence_section(
+                "documents",
+                vec![doc! { "x": 1 }, doc! { "x": 2 }, doc! { "x": 3 }],
+            ),
+        ],
+    );
+    assert_eq!(body(&receive(&mut stream)).get_i64("n").unwrap(), 3);
+
+    send_sections(
+        &mut stream,
+        2,
+        vec![
+            sequence_section(
+                "updates",
+                vec![
+                    doc! { "q": { "x": 1 }, "u": { "$set": { "y": 1 } } },
+                    doc! { "q": { "x": 2 }, "u": { "$set": { "y": 2 } } },
+                ],
+            ),
+            body_section(doc! { "update": &ctx.collection, "$db": &ctx.db }),
+        ],
+    );
+    assert_eq!(body(&receive(&mut stream)).get_i64("nModified").unwrap(), 2);
+
+    let cursor = ctx
+        .col()
+        .find(doc! { "y": { "$exists": true } }, None)
+        .unwrap();
+    assert_eq!(common::get_rows(cursor).len(), 2);
+}
+
+#[test]
+fn test_malformed_message_reply() {
+    let ctx = common::setup();
+    let mut stream = connect(&ctx);
+
+    // the body declares more bytes than the message has
+    let mut bytes = vec![];
+    bytes.extend(26u32.to_le_bytes());
+    bytes.extend(1u32.to_le_bytes());
+    bytes.extend(0u32.to_le_bytes());
+    bytes.extend(OP_MSG.to_le_bytes());
+    bytes.extend(0u32.to_le_bytes());
+    bytes.push(0);
+    bytes.extend(100i32.to_le_bytes());
+    bytes.push(0);
+    stream.write_all(&bytes).unwrap();
+
+    let reply = receive(&mut stream);
+    assert_eq!(reply.header.response_to, 1);
+    assert_eq!(body(&reply).get_f64("ok").unwrap(), 0.0);
+    assert_eq!(body(&reply).get_str("codeName").unwrap(), "ProtocolError");
+
+    // two bodies
+    send_sections(
+        &mut stream,
+        2,
+        vec![
+            body_section(doc! { "ping": 1, "$db": &ctx.db }),
+            body_section(doc! { "ping": 1, "$db": &ctx.db }),
+        ],
+    );
+    let reply = receive(&mut stream);
+    assert_eq!(reply.header.response_to, 2);
+    assert_eq!(body(&reply).get_i32("code").unwrap(), 17);
+
+    // the connection is still usable
+    send(&mut stream, 3, 0, doc! { "ping": 1, "$db": &ctx.db });
+    let reply = receive(&mut stream);
+    assert_eq!(body(&reply).get_f64("ok").unwrap(), 1.0);
+}
+
+#[test]
+fn test_invalid_message_disconnects() {
+    let ctx = common::setup();
+
+    let mut too_short = vec![];
+    too_short.extend(8u32.to_le_bytes());
+    too_short.extend(1u32.to_le_bytes());
+
+    let mut unknown_op_code = vec![];
+    unknown_op_code.extend(20u32.to_le_bytes());
+    unknown_op_code.extend(1u32.to_le_bytes());
+    unknown_op_code.extend(0u32.to_le_bytes());
+    unknown_op_code.extend(2002u32.to_le_bytes());
+    unknown_op_code.extend(0u32.to_le_bytes());
+
+    for bytes in [too_short, unknown_op_code] {
+        let mut stream = connect(&ctx);
+        stream.write_all(&bytes).unwrap();
+        let mut buffer = vec![];
+        stream.read_to_end(&mut buffer).unwrap();
+        assert!(buffer.is_empty());
+    }
+
+    // the server is still up
+    let res = ctx.db().run_command(doc! { "ping": 1 }, None).unwrap();
+    assert_eq!(res.get_f64("ok").unwrap(), 1.0);
+}